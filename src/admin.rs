@@ -0,0 +1,102 @@
+//! `GET /admin/status`: a snapshot of the server for operators, behind `ADMIN_API_KEY`.
+
+use axum::{
+    extract::State,
+    http::HeaderMap,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde_json::json;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{apikeys, disk, unauthorized, AppState};
+
+pub async fn status(State(state): State<Arc<AppState>>, headers: HeaderMap) -> Response {
+    if !apikeys::from_headers(&headers).is_some_and(|key| state.is_admin_key(key)) {
+        state.metrics.auth_failures.inc();
+        return unauthorized().into_response();
+    }
+
+    let uptime = state.started_at.elapsed();
+    let started_at = SystemTime::now().checked_sub(uptime).unwrap_or(UNIX_EPOCH);
+    let metrics = &state.metrics;
+    let cache = state.cache.as_ref().map(|_| {
+        let (hits, misses) = (metrics.cache_hits.get(), metrics.cache_misses.get());
+        // No rate until something has been looked up
+        let hit_rate = (hits + misses > 0).then(|| hits as f64 / (hits + misses) as f64);
+        json!({"hits": hits, "misses": misses, "hit_rate": hit_rate})
+    });
+
+    Json(json!({
+        "started_at": rfc3339(started_at.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()),
+        "uptime_seconds": uptime.as_secs(),
+        "conversions": {
+            "success": metrics.conversions.get("success"),
+            "failure": metrics.conversions.get("failure"),
+            "active": metrics.active_conversions.get(),
+        },
+        "conversion_slots": {
+            "max": state.max_concurrent_conversions,
+            "in_use": metrics.conversion_slots_in_use.get(),
+            "waiting": metrics.conversion_slots_waiting.get(),
+        },
+        "cache": cache,
+        "circuit_breaker": {
+            "state": state.circuit_breaker.state(),
+            "failure_count": state.circuit_breaker.failure_count(),
+        },
+        "libreoffice_version": state.libreoffice.version().await.ok(),
+        "work_dir_free_bytes": disk::available_bytes(&state.work_dir).ok(),
+        "features": enabled_features(&state),
+    }))
+    .into_response()
+}
+
+/// The settings that switched on an optional feature.
+pub fn enabled_features(state: &AppState) -> Vec<&'static str> {
+    [
+        ("API_KEYS", state.api_keys.is_some()),
+        ("JWT_PUBLIC_KEY_FILE", state.jwt.is_some()),
+        ("METRICS_API_KEY", state.metrics_api_key.is_some()),
+        ("RATE_LIMIT_RPS", state.rate_limiter.is_some()),
+        ("S3_BUCKET", state.s3.is_some()),
+        ("CACHE_MAX_ENTRIES", state.cache.is_some()),
+        ("CACHE_CONTROL_MAX_AGE", state.cache_control_max_age > 0),
+        ("ALLOW_HTTP_CALLBACKS", state.allow_http_callbacks),
+        ("ALLOW_HTTP_DOWNLOADS", state.allow_http_downloads),
+        ("AUDIT_LOG_FILE", state.audit_log.is_some()),
+    ]
+    .into_iter()
+    .filter_map(|(name, enabled)| enabled.then_some(name))
+    .chain(state.converter.enabled_features())
+    .collect()
+}
+
+/// Formats seconds since the Unix epoch as a UTC timestamp like `2024-05-01T09:00:00Z`.
+pub fn rfc3339(unix_secs: u64) -> String {
+    let (days, secs) = (unix_secs / 86_400, unix_secs % 86_400);
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm)
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", year, month, day, secs / 3600, secs % 3600 / 60, secs % 60)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rfc3339() {
+        assert_eq!(rfc3339(0), "1970-01-01T00:00:00Z");
+        assert_eq!(rfc3339(951_782_400), "2000-02-29T00:00:00Z");
+        assert_eq!(rfc3339(1_714_554_000), "2024-05-01T09:00:00Z");
+    }
+}
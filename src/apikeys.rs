@@ -0,0 +1,81 @@
+//! API keys accepted in the `X-Api-Key` header: the client keys from `API_KEYS` and the
+//! `ADMIN_API_KEY`.
+//!
+//! Keys are held as SHA-256 digests and a presented key is hashed before it is looked up, so
+//! finding it in the set takes one hash lookup however many keys there are, and how long the
+//! lookup takes says nothing about the stored keys. Single keys are compared with
+//! `constant_time_eq`.
+
+use axum::http::HeaderMap;
+use std::collections::HashSet;
+
+use crate::crypto::{constant_time_eq, sha256};
+
+pub const HEADER: &str = "X-Api-Key";
+
+/// The `X-Api-Key` header, if present and readable.
+pub fn from_headers(headers: &HeaderMap) -> Option<&str> {
+    headers.get(HEADER).and_then(|v| v.to_str().ok())
+}
+
+/// A short public name for `key`, the start of its SHA-256 in hex: what rate limits and metrics
+/// know the client by, so the key itself appears in neither.
+pub fn key_id(key: &str) -> String {
+    sha256(key.as_bytes())[..6].iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Whether `presented` is `expected`, without leaking through timing where they differ.
+pub fn matches(expected: &str, presented: &str) -> bool {
+    constant_time_eq(&sha256(expected.as_bytes()), &sha256(presented.as_bytes()))
+}
+
+/// A set of client keys, any of which is accepted.
+pub struct ApiKeys {
+    digests: HashSet<[u8; 32]>,
+}
+
+impl ApiKeys {
+    /// Parses a comma-separated list of keys, e.g. the old and new key during a rotation.
+    pub fn parse(raw: &str) -> Result<Self, String> {
+        let digests: HashSet<_> = raw
+            .split(',')
+            .map(str::trim)
+            .filter(|k| !k.is_empty())
+            .map(|k| sha256(k.as_bytes()))
+            .collect();
+        if digests.is_empty() {
+            return Err("API_KEYS must list at least one key".to_string());
+        }
+        Ok(Self { digests })
+    }
+
+    pub fn contains(&self, presented: &str) -> bool {
+        self.digests.contains(&sha256(presented.as_bytes()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.digests.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_api_keys() {
+        let keys = ApiKeys::parse(" old-key, new-key ,,").unwrap();
+        assert_eq!(keys.len(), 2);
+        assert!(keys.contains("old-key"));
+        assert!(keys.contains("new-key"));
+        assert!(!keys.contains("new-key "));
+        assert!(!keys.contains(""));
+        assert!(ApiKeys::parse(" , ").is_err());
+
+        assert_eq!(key_id("old-key"), key_id("old-key"));
+        assert_eq!(key_id("abc"), "ba7816bf8f01");
+
+        assert!(matches("admin", "admin"));
+        assert!(!matches("admin", "admin2"));
+    }
+}
@@ -0,0 +1,154 @@
+//! Uploads that are a plain zip archive holding one office document, as some clients send a
+//! document bundled with its media. The document is taken out and converted in the archive's
+//! place; everything else in the archive is ignored.
+
+use axum::http::StatusCode;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+use tracing::{error, info};
+
+use crate::error::ConversionError;
+use crate::{sanitize_filename, zip};
+
+/// Extensions of the documents looked for in an archive. Text formats, PDFs and images are left
+/// out: they commonly ship next to the document (a README, an exported copy) and would make
+/// nearly every archive ambiguous.
+const DOCUMENT_EXTENSIONS: &[&str] = &[
+    "docx", "docm", "dotx", "dotm", "doc", "dot", "xlsx", "xlsm", "xltx", "xltm", "xls", "pptx", "pptm", "potx", "potm",
+    "ppt", "odt", "ott", "ods", "ots", "odp", "otp", "rtf",
+];
+
+fn invalid(code: &'static str, message: impl Into<String>) -> ConversionError {
+    ConversionError::new(StatusCode::BAD_REQUEST, code, message)
+}
+
+/// Whether extracting `name` as a path would stay inside the directory it is extracted to.
+fn is_safe_entry_name(name: &str) -> bool {
+    let bytes = name.as_bytes();
+    let absolute = name.starts_with(['/', '\\']) || (bytes.len() >= 2 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':');
+    !absolute && !name.contains('\0') && !name.split(['/', '\\']).any(|part| part == "..")
+}
+
+/// The one document in an archive listing, among those with an extension in `allowed_extensions`.
+fn find_document<'a>(names: impl Iterator<Item = &'a str>, allowed_extensions: &[String]) -> Result<&'a str, ConversionError> {
+    let documents: Vec<&str> = names
+        // macOS puts resource forks named after every file under __MACOSX/
+        .filter(|name| !name.ends_with('/') && !name.starts_with("__MACOSX/"))
+        .filter(|name| {
+            let extension = Path::new(name).extension().unwrap_or_default().to_string_lossy().to_ascii_lowercase();
+            DOCUMENT_EXTENSIONS.contains(&extension.as_str()) && allowed_extensions.contains(&extension)
+        })
+        .collect();
+    match documents[..] {
+        [document] => Ok(document),
+        [] => Err(invalid("NO_DOCUMENT_IN_ZIP", "The zip archive contains no supported document")),
+        _ => Err(invalid("AMBIGUOUS_ZIP", "The zip archive contains more than one document").with_detail("documents", documents)),
+    }
+}
+
+/// Replaces the archive at `path` with the document inside it and returns the document's path
+/// and size. The document may be at most `max_len` bytes once extracted.
+pub async fn extract_document(path: &Path, allowed_extensions: &[String], max_len: u64) -> Result<(PathBuf, u64), ConversionError> {
+    let archive = fs::read(path).await.map_err(|e| {
+        error!("Failed to read uploaded archive: {}", e);
+        ConversionError::internal()
+    })?;
+    let allowed_extensions = allowed_extensions.to_vec();
+    // Inflating can take a while on a large archive
+    let extracted = tokio::task::spawn_blocking(move || -> Result<(String, Vec<u8>), ConversionError> {
+        let reader = zip::ZipReader::new(&archive).ok_or_else(|| invalid("INVALID_ZIP", "The zip archive could not be read"))?;
+        if let Some(name) = reader.names().find(|name| !is_safe_entry_name(name)) {
+            return Err(invalid("UNSAFE_ZIP_ENTRY", format!("The zip archive has an entry outside its root: {:?}", name)));
+        }
+        if reader.has_encrypted_entries() {
+            return Err(invalid("ENCRYPTED_ZIP", "The zip archive contains password-protected entries"));
+        }
+        let name = find_document(reader.names(), &allowed_extensions)?;
+        let content = reader.read(name, max_len as usize).ok_or_else(|| {
+            invalid("INVALID_ZIP", format!("{:?} could not be extracted: it is corrupt or larger than {} bytes", name, max_len))
+        })?;
+        Ok((name.to_string(), content))
+    })
+    .await
+    .map_err(|e| {
+        error!("Archive extraction panicked: {}", e);
+        ConversionError::internal()
+    })?;
+    let (name, content) = extracted?;
+
+    // Only the base name is kept, so the document lands next to the archive whatever its path
+    let document = path.with_file_name(sanitize_filename(&name));
+    let _ = fs::remove_file(path).await;
+    fs::write(&document, &content).await.map_err(|e| {
+        error!("Failed to write extracted document: {}", e);
+        ConversionError::internal()
+    })?;
+    info!("Extracted {:?} from uploaded archive", name);
+    Ok((document, content.len() as u64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::detect;
+
+    fn archive(entries: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut zip = zip::ZipWriter::new();
+        for (name, data) in entries {
+            zip.add(name, data);
+        }
+        zip.finish()
+    }
+
+    #[test]
+    fn test_entry_names() {
+        for safe in ["report.docx", "docs/report.docx", "a..b/c.docx", "docs/"] {
+            assert!(is_safe_entry_name(safe), "{}", safe);
+        }
+        for unsafe_name in ["../report.docx", "docs/../../report.docx", "/etc/passwd", "..\\x.docx", "C:\\x.docx", "\\x.docx"] {
+            assert!(!is_safe_entry_name(unsafe_name), "{}", unsafe_name);
+        }
+    }
+
+    #[test]
+    fn test_find_document() {
+        let allowed = detect::parse_allowed_extensions(None);
+        let names = ["media/", "media/logo.png", "README.txt", "export.pdf", "__MACOSX/._Report.DOCX", "Report.DOCX"];
+        assert_eq!(find_document(names.into_iter(), &allowed).unwrap(), "Report.DOCX");
+        assert_eq!(find_document(["a.txt", "b.pdf"].into_iter(), &allowed).unwrap_err().code, "NO_DOCUMENT_IN_ZIP");
+        let err = find_document(["a.docx", "b.xlsx"].into_iter(), &allowed).unwrap_err();
+        assert_eq!(err.code, "AMBIGUOUS_ZIP");
+        // Only extensions the server accepts count
+        assert_eq!(find_document(["a.docx", "b.xlsx"].into_iter(), &["xlsx".to_string()]).unwrap(), "b.xlsx");
+    }
+
+    #[tokio::test]
+    async fn test_extract_document() {
+        let allowed = detect::parse_allowed_extensions(None);
+        let dir = std::env::temp_dir().join(format!("archive-test-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).await.unwrap();
+        let path = dir.join("bundle.zip");
+
+        fs::write(&path, archive(&[("docs/notes.rtf", b"{\\rtf1 Hi}"), ("docs/logo.png", b"\x89PNG")])).await.unwrap();
+        let (document, size) = extract_document(&path, &allowed, 1024).await.unwrap();
+        assert_eq!(document, dir.join("notes.rtf"));
+        assert_eq!(size, 10);
+        assert!(!path.exists());
+        assert_eq!(detect::detect_file(&document, "rtf").await.unwrap(), "rtf");
+
+        fs::write(&path, archive(&[("../../notes.rtf", b"{\\rtf1 Hi}")])).await.unwrap();
+        assert_eq!(extract_document(&path, &allowed, 1024).await.unwrap_err().code, "UNSAFE_ZIP_ENTRY");
+
+        fs::write(&path, archive(&[("notes.rtf", &[b'x'; 2048])])).await.unwrap();
+        assert_eq!(extract_document(&path, &allowed, 1024).await.unwrap_err().code, "INVALID_ZIP");
+
+        // Set the encryption bit in the central directory header
+        let mut encrypted = archive(&[("notes.rtf", b"{\\rtf1 Hi}")]);
+        let central = encrypted.windows(4).position(|w| w == b"PK\x01\x02").unwrap();
+        encrypted[central + 8] |= 1;
+        fs::write(&path, encrypted).await.unwrap();
+        assert_eq!(extract_document(&path, &allowed, 1024).await.unwrap_err().code, "ENCRYPTED_ZIP");
+
+        let _ = fs::remove_dir_all(&dir).await;
+    }
+}
@@ -0,0 +1,182 @@
+//! The conversion audit log: with `AUDIT_LOG_FILE` set, one JSON line is appended for every
+//! conversion attempt, successful or not. The log is there for compliance, so a conversion whose
+//! record cannot be written fails instead of going unrecorded.
+
+use axum::{
+    extract::ConnectInfo,
+    http::{HeaderMap, StatusCode},
+};
+use serde_json::{json, Value};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::sync::Mutex;
+use tracing::error;
+use uuid::Uuid;
+
+use crate::error::ConversionError;
+use crate::{admin, ratelimit, AppState, UploadedFile};
+
+/// Who asked for a conversion.
+#[derive(Clone, Copy, Debug)]
+pub struct Requester {
+    pub request_id: Uuid,
+    /// `None` when the connection's address is unknown, as in tests.
+    pub client_ip: Option<IpAddr>,
+}
+
+impl Requester {
+    pub fn new(state: &AppState, request_id: Uuid, headers: &HeaderMap, peer: Option<ConnectInfo<SocketAddr>>) -> Self {
+        Self {
+            request_id,
+            client_ip: peer.map(|ConnectInfo(peer)| ratelimit::client_ip(headers, peer.ip(), state.trusted_proxy_count)),
+        }
+    }
+}
+
+/// One conversion attempt.
+pub struct AuditRecord<'a> {
+    pub requester: Requester,
+    pub filename: &'a str,
+    pub size: u64,
+    pub detected_type: &'a str,
+    pub output_format: &'a str,
+    pub duration: Duration,
+    /// The error code of a failed conversion.
+    pub error_code: Option<&'a str>,
+}
+
+impl AuditRecord<'_> {
+    fn to_json(&self, at: SystemTime) -> Value {
+        json!({
+            "timestamp": admin::rfc3339(at.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()),
+            "request_id": self.requester.request_id.to_string(),
+            "client_ip": self.requester.client_ip.map(|ip| ip.to_string()),
+            "filename": self.filename,
+            "size_bytes": self.size,
+            "detected_type": self.detected_type,
+            "output_format": self.output_format,
+            "duration_ms": self.duration.as_millis() as u64,
+            "success": self.error_code.is_none(),
+            "error_code": self.error_code,
+        })
+    }
+}
+
+pub struct AuditLog {
+    writer: Arc<Mutex<BufWriter<File>>>,
+}
+
+impl AuditLog {
+    /// Opens `path` for appending, creating it if needed.
+    pub async fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path).await?;
+        Ok(Self {
+            writer: Arc::new(Mutex::new(BufWriter::new(file))),
+        })
+    }
+
+    pub async fn write(&self, record: &AuditRecord<'_>) -> io::Result<()> {
+        let mut line = record.to_json(SystemTime::now()).to_string();
+        line.push('\n');
+        let mut writer = self.writer.lock().await;
+        writer.write_all(line.as_bytes()).await?;
+        // Flushed every time: a record still in the buffer when the process dies would be lost,
+        // and a failed write only shows up on flush
+        writer.flush().await
+    }
+}
+
+/// Records the outcome of converting `file`, which took `duration`, if there is an audit log. A
+/// successful conversion that cannot be recorded turns into a failure.
+pub async fn record<T>(
+    state: &AppState,
+    requester: Requester,
+    file: &UploadedFile,
+    output_format: &str,
+    duration: Duration,
+    result: Result<T, ConversionError>,
+) -> Result<T, ConversionError> {
+    let Some(ref log) = state.audit_log else {
+        return result;
+    };
+    let filename = file.path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+    let record = AuditRecord {
+        requester,
+        filename: &filename,
+        size: file.size,
+        detected_type: file.kind,
+        output_format,
+        duration,
+        error_code: result.as_ref().err().map(|e| e.code),
+    };
+    match log.write(&record).await {
+        Ok(()) => result,
+        Err(e) => {
+            error!("Failed to write the audit log: {}", e);
+            // A failed conversion keeps its own error
+            result.and(Err(ConversionError::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "AUDIT_LOG_FAILED",
+                "The conversion could not be recorded",
+            )))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_audit_log() {
+        let path = std::env::temp_dir().join(format!("audit-test-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let request_id = Uuid::new_v4();
+        let requester = Requester { request_id, client_ip: Some("192.0.2.1".parse().unwrap()) };
+        let mut record = AuditRecord {
+            requester,
+            filename: "report.docx",
+            size: 5,
+            detected_type: "docx",
+            output_format: "pdf",
+            duration: Duration::from_millis(1500),
+            error_code: None,
+        };
+        let log = AuditLog::open(&path).await.unwrap();
+        log.write(&record).await.unwrap();
+        record.error_code = Some("CONVERSION_FAILED");
+        log.write(&record).await.unwrap();
+
+        // Reopening appends
+        AuditLog::open(&path).await.unwrap().write(&record).await.unwrap();
+        let lines: Vec<Value> = std::fs::read_to_string(&path).unwrap().lines().map(|l| serde_json::from_str(l).unwrap()).collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0]["request_id"], request_id.to_string());
+        assert_eq!(lines[0]["client_ip"], "192.0.2.1");
+        assert_eq!(lines[0]["duration_ms"], 1500);
+        assert_eq!((&lines[0]["success"], &lines[0]["error_code"]), (&json!(true), &Value::Null));
+        assert_eq!((&lines[1]["success"], &lines[1]["error_code"]), (&json!(false), &json!("CONVERSION_FAILED")));
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_timestamp() {
+        let record = AuditRecord {
+            requester: Requester { request_id: Uuid::nil(), client_ip: None },
+            filename: "a.csv",
+            size: 0,
+            detected_type: "csv",
+            output_format: "pdf",
+            duration: Duration::ZERO,
+            error_code: None,
+        };
+        let json = record.to_json(UNIX_EPOCH + Duration::from_secs(1_714_554_000));
+        assert_eq!(json["timestamp"], "2024-05-01T09:00:00Z");
+        assert_eq!(json["client_ip"], Value::Null);
+    }
+}
@@ -0,0 +1,96 @@
+//! Which program converts a file. LibreOffice handles everything, except that HTML goes to
+//! headless Chromium when `CHROMIUM_PATH` is set: its print-to-PDF lays pages out like a browser
+//! does, where LibreOffice's HTML import loses much of the styling. Likewise Markdown goes to
+//! Pandoc when `PANDOC_PATH` is set; without it, Markdown is rendered to HTML first (see
+//! `markdown`) and that HTML takes the HTML route.
+
+use std::path::Path;
+use tokio::process::Command;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConverterBackend<'a> {
+    LibreOffice,
+    /// Headless Chromium at this path.
+    Chromium(&'a str),
+    /// Pandoc at this path, typesetting through XeLaTeX.
+    Pandoc(&'a str),
+}
+
+impl ConverterBackend<'_> {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ConverterBackend::LibreOffice => "LibreOffice",
+            ConverterBackend::Chromium(_) => "Chromium",
+            ConverterBackend::Pandoc(_) => "Pandoc",
+        }
+    }
+}
+
+/// Picks the backend for an input with extension `file_ext`. Chromium and Pandoc only make plain
+/// PDFs, so any PDF export option (`plain_pdf == false`) keeps the file with LibreOffice.
+pub fn select_backend<'a>(file_ext: &str, chromium_path: Option<&'a str>, pandoc_path: Option<&'a str>, plain_pdf: bool) -> ConverterBackend<'a> {
+    match (file_ext.to_ascii_lowercase().as_str(), chromium_path, pandoc_path) {
+        ("html" | "htm", Some(chromium), _) if plain_pdf => ConverterBackend::Chromium(chromium),
+        ("md" | "markdown", _, Some(pandoc)) if plain_pdf => ConverterBackend::Pandoc(pandoc),
+        _ => ConverterBackend::LibreOffice,
+    }
+}
+
+/// Whether `file_ext` is Markdown, which LibreOffice cannot read as such.
+pub fn is_markdown(file_ext: &str) -> bool {
+    matches!(file_ext.to_ascii_lowercase().as_str(), "md" | "markdown")
+}
+
+/// Prints `input` to the PDF `output`, keeping Chromium's profile in `user_data_dir`.
+pub fn chromium_command(binary: &str, input: &Path, output: &Path, user_data_dir: &Path) -> Command {
+    let mut print_to_pdf = "--print-to-pdf=".to_string();
+    print_to_pdf.push_str(&output.to_string_lossy());
+    let mut user_data = "--user-data-dir=".to_string();
+    user_data.push_str(&user_data_dir.to_string_lossy());
+
+    let mut command = Command::new(binary);
+    command
+        .arg("--headless")
+        .arg("--disable-gpu")
+        // No date, title and URL printed around every page
+        .arg("--no-pdf-header-footer")
+        .arg(user_data)
+        .arg(print_to_pdf)
+        .arg(input);
+    command
+}
+
+/// Typesets the Markdown `input` into the PDF `output`.
+pub fn pandoc_command(binary: &str, input: &Path, output: &Path) -> Command {
+    let mut command = Command::new(binary);
+    command.arg("--pdf-engine=xelatex").arg("-o").arg(output).arg(input);
+    command
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_select_backend() {
+        let chromium = Some("/usr/bin/chromium");
+        let pandoc = Some("pandoc");
+        assert_eq!(select_backend("HTM", chromium, None, true), ConverterBackend::Chromium("/usr/bin/chromium"));
+        assert_eq!(select_backend("html", chromium, pandoc, false), ConverterBackend::LibreOffice);
+        assert_eq!(select_backend("docx", chromium, pandoc, true), ConverterBackend::LibreOffice);
+        assert_eq!(select_backend("html", None, pandoc, true), ConverterBackend::LibreOffice);
+        assert_eq!(select_backend("md", chromium, pandoc, true), ConverterBackend::Pandoc("pandoc"));
+        assert_eq!(select_backend("markdown", chromium, pandoc, false), ConverterBackend::LibreOffice);
+        assert_eq!(select_backend("md", chromium, None, true), ConverterBackend::LibreOffice);
+
+        let command = chromium_command("chromium", Path::new("/w/0/a.html"), Path::new("/w/0/out/a.pdf"), Path::new("/w/chrome"));
+        let args: Vec<_> = command.as_std().get_args().map(|a| a.to_string_lossy().to_string()).collect();
+        assert!(args.contains(&"--print-to-pdf=/w/0/out/a.pdf".to_string()));
+        assert!(args.contains(&"--user-data-dir=/w/chrome".to_string()));
+        assert_eq!(args.last().map(String::as_str), Some("/w/0/a.html"));
+
+        let command = pandoc_command("pandoc", Path::new("/w/0/README.md"), Path::new("/w/0/out/README.pdf"));
+        let args: Vec<_> = command.as_std().get_args().map(|a| a.to_string_lossy().to_string()).collect();
+        assert_eq!(args, ["--pdf-engine=xelatex", "-o", "/w/0/out/README.pdf", "/w/0/README.md"]);
+    }
+}
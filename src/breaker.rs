@@ -0,0 +1,264 @@
+//! A circuit breaker in front of the converter. When LibreOffice is broken (mis-installed, or
+//! killed for memory as soon as it starts) every conversion fails the same way, and spawning it
+//! again for each request only adds load. After `CB_FAILURE_THRESHOLD` consecutive server-side
+//! failures within `CB_WINDOW_SECS` the breaker opens and conversions are refused straight away.
+//! Once `CB_RESET_SECS` have passed one conversion is let through as a probe: if it succeeds the
+//! breaker closes again, otherwise it stays open for another `CB_RESET_SECS`.
+//!
+//! Only failures that say the converter itself is unhealthy count: it could not be started, or it
+//! was killed by a signal (by the kernel's OOM killer, say). A timeout or a non-zero exit is as
+//! likely to come from one bad document, and any client could send five of those, so they are
+//! neutral: they neither count nor end a run of failures. A probe that ends that way, or is
+//! dropped unfinished (its client went away, or its job was cancelled), leaves the breaker open
+//! for another `CB_RESET_SECS` before the next probe.
+
+use axum::http::StatusCode;
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicU8, Ordering};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+use crate::error::ConversionError;
+
+/// Error codes of failures that mean the converter is unhealthy rather than the document bad.
+const BACKEND_FAILURES: &[&str] = &["CONVERSION_EXECUTION_FAILED", "CONVERSION_CRASHED"];
+
+pub const DEFAULT_FAILURE_THRESHOLD: u32 = 5;
+pub const DEFAULT_WINDOW_SECS: u64 = 60;
+pub const DEFAULT_RESET_SECS: u64 = 30;
+
+const CLOSED: u8 = 0;
+const OPEN: u8 = 1;
+/// Open, with the probe conversion in progress.
+const HALF_OPEN: u8 = 2;
+
+pub struct CircuitBreaker {
+    state: AtomicU8,
+    /// Consecutive failures in the current window.
+    failure_count: AtomicU32,
+    /// When the window of the first of those failures began, and when the breaker last opened,
+    /// in milliseconds since `epoch`.
+    window_started: AtomicU64,
+    opened_at: AtomicU64,
+    epoch: Instant,
+    threshold: u32,
+    window: Duration,
+    reset: Duration,
+}
+
+impl CircuitBreaker {
+    pub fn new(threshold: u32, window: Duration, reset: Duration) -> Self {
+        Self {
+            state: AtomicU8::new(CLOSED),
+            failure_count: AtomicU32::new(0),
+            window_started: AtomicU64::new(0),
+            opened_at: AtomicU64::new(0),
+            epoch: Instant::now(),
+            threshold,
+            window,
+            reset,
+        }
+    }
+
+    fn now(&self) -> u64 {
+        self.epoch.elapsed().as_millis() as u64
+    }
+
+    /// `closed`, `open` or `half_open`.
+    pub fn state(&self) -> &'static str {
+        match self.state.load(Ordering::SeqCst) {
+            CLOSED => "closed",
+            OPEN => "open",
+            _ => "half_open",
+        }
+    }
+
+    pub fn failure_count(&self) -> u32 {
+        self.failure_count.load(Ordering::SeqCst)
+    }
+
+    /// Whether a conversion may start, and if so a permit to record its outcome with. While open,
+    /// the first caller after `reset` becomes the probe; everyone else is refused until its
+    /// outcome is recorded or its permit dropped.
+    pub fn check(&self) -> Result<Permit<'_>, ConversionError> {
+        match self.state.load(Ordering::SeqCst) {
+            CLOSED => return Ok(Permit { breaker: self, probe: false }),
+            OPEN => {
+                let open_for = Duration::from_millis(self.now().saturating_sub(self.opened_at.load(Ordering::SeqCst)));
+                if open_for >= self.reset && self.state.compare_exchange(OPEN, HALF_OPEN, Ordering::SeqCst, Ordering::SeqCst).is_ok() {
+                    info!("Circuit breaker half-open, probing LibreOffice");
+                    return Ok(Permit { breaker: self, probe: true });
+                }
+            }
+            _ => {}
+        }
+        let opened_at = Duration::from_millis(self.opened_at.load(Ordering::SeqCst));
+        let retry_after = (opened_at + self.reset).saturating_sub(self.epoch.elapsed()).as_secs_f64().ceil().max(1.0) as u64;
+        Err(ConversionError::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "CIRCUIT_OPEN",
+            "Conversions are failing repeatedly and are paused, retry later",
+        )
+        .with_detail("retry_after_secs", retry_after))
+    }
+
+    /// Records the outcome of a conversion let through by `check`. Only failures of the converter
+    /// itself count against it; a request rejected for its own content counts as a success, and
+    /// other server-side failures as neither.
+    fn record<T>(&self, result: &Result<T, ConversionError>) {
+        match result {
+            Err(e) if BACKEND_FAILURES.contains(&e.code) => self.record_failure(),
+            Err(e) if e.status.is_server_error() => self.reopen(),
+            _ => self.record_success(),
+        }
+    }
+
+    /// Ends a probe that proved nothing, leaving the breaker open for another `reset`.
+    fn reopen(&self) {
+        if self.state.load(Ordering::SeqCst) == HALF_OPEN {
+            self.opened_at.store(self.now(), Ordering::SeqCst);
+            if self.state.compare_exchange(HALF_OPEN, OPEN, Ordering::SeqCst, Ordering::SeqCst).is_ok() {
+                info!("Circuit breaker probe inconclusive; staying open for {}s", self.reset.as_secs());
+            }
+        }
+    }
+
+    fn record_success(&self) {
+        self.failure_count.store(0, Ordering::SeqCst);
+        if self.state.swap(CLOSED, Ordering::SeqCst) != CLOSED {
+            info!("Circuit breaker closed");
+        }
+    }
+
+    fn record_failure(&self) {
+        let now = self.now();
+        if self.state.load(Ordering::SeqCst) == HALF_OPEN {
+            self.trip(now);
+            return;
+        }
+        let window_started = self.window_started.load(Ordering::SeqCst);
+        let count = if Duration::from_millis(now.saturating_sub(window_started)) > self.window {
+            self.window_started.store(now, Ordering::SeqCst);
+            self.failure_count.store(1, Ordering::SeqCst);
+            1
+        } else {
+            self.failure_count.fetch_add(1, Ordering::SeqCst) + 1
+        };
+        if count >= self.threshold && self.state.load(Ordering::SeqCst) == CLOSED {
+            self.trip(now);
+        }
+    }
+
+    fn trip(&self, now: u64) {
+        self.opened_at.store(now, Ordering::SeqCst);
+        self.state.store(OPEN, Ordering::SeqCst);
+        warn!(
+            "Circuit breaker open after {} failed conversions; pausing conversions for {}s",
+            self.failure_count(),
+            self.reset.as_secs()
+        );
+    }
+}
+
+/// A conversion let through by `CircuitBreaker::check`, whose outcome is to be recorded.
+pub struct Permit<'a> {
+    breaker: &'a CircuitBreaker,
+    /// Whether this is the half-open probe, which must not leave the breaker half-open for good.
+    probe: bool,
+}
+
+impl Permit<'_> {
+    pub fn record<T>(mut self, result: &Result<T, ConversionError>) {
+        self.probe = false;
+        self.breaker.record(result);
+    }
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        if self.probe {
+            self.breaker.reopen();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn failed() -> Result<(), ConversionError> {
+        Err(ConversionError::new(StatusCode::INTERNAL_SERVER_ERROR, "CONVERSION_CRASHED", "crashed"))
+    }
+
+    #[test]
+    fn test_trips_and_recovers() {
+        let breaker = CircuitBreaker::new(3, Duration::from_secs(60), Duration::ZERO);
+        breaker.record(&failed());
+        breaker.record(&failed());
+        // A client error ends the run of failures like a success
+        breaker.record::<()>(&Err(ConversionError::new(StatusCode::BAD_REQUEST, "PASSWORD_PROTECTED", "")));
+        assert_eq!(breaker.failure_count(), 0);
+        breaker.record(&failed());
+        breaker.record(&failed());
+        assert_eq!(breaker.state(), "closed");
+        breaker.record(&failed());
+        assert_eq!(breaker.state(), "open");
+
+        // With no reset delay the next caller probes at once, and only that one
+        let probe = breaker.check().unwrap();
+        assert_eq!(breaker.state(), "half_open");
+        assert_eq!(breaker.check().err().unwrap().code, "CIRCUIT_OPEN");
+        probe.record(&failed());
+        assert_eq!(breaker.state(), "open");
+
+        breaker.check().unwrap().record(&Ok(()));
+        assert_eq!(breaker.state(), "closed");
+        assert!(breaker.check().is_ok());
+    }
+
+    #[test]
+    fn test_document_failures_are_neutral() {
+        let breaker = CircuitBreaker::new(2, Duration::from_secs(60), Duration::ZERO);
+        let bad_document = || Err::<(), _>(ConversionError::new(StatusCode::INTERNAL_SERVER_ERROR, "CONVERSION_FAILED", "failed"));
+        let timeout = || Err::<(), _>(ConversionError::new(StatusCode::GATEWAY_TIMEOUT, "CONVERSION_TIMEOUT", "timed out"));
+        for _ in 0..5 {
+            breaker.check().unwrap().record(&bad_document());
+            breaker.check().unwrap().record(&timeout());
+        }
+        assert_eq!((breaker.state(), breaker.failure_count()), ("closed", 0));
+
+        // Nor do they end a run of real failures
+        breaker.record(&failed());
+        breaker.record(&bad_document());
+        breaker.record(&failed());
+        assert_eq!(breaker.state(), "open");
+
+        // A probe that ends that way proves nothing either way
+        breaker.check().unwrap().record(&timeout());
+        assert_eq!(breaker.state(), "open");
+        breaker.check().unwrap().record(&Ok(()));
+        assert_eq!(breaker.state(), "closed");
+    }
+
+    #[test]
+    fn test_dropped_probe() {
+        let breaker = CircuitBreaker::new(1, Duration::from_secs(60), Duration::ZERO);
+        breaker.record(&failed());
+        // Its client went away, or its job was cancelled
+        drop(breaker.check().unwrap());
+        assert_eq!(breaker.state(), "open");
+        let probe = breaker.check().unwrap();
+        assert_eq!(breaker.state(), "half_open");
+        probe.record(&Ok(()));
+        assert_eq!(breaker.state(), "closed");
+    }
+
+    #[test]
+    fn test_open_refuses() {
+        let breaker = CircuitBreaker::new(1, Duration::from_secs(60), Duration::from_secs(30));
+        breaker.record(&failed());
+        let error = breaker.check().err().unwrap();
+        assert_eq!(error.status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(error.details, vec![("retry_after_secs", 30.into())]);
+        assert_eq!(breaker.state(), "open");
+    }
+}
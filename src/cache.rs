@@ -0,0 +1,307 @@
+//! In-memory cache of conversion results, so that a file converted again with the same options
+//! (a company template, say) skips LibreOffice.
+//!
+//! Entries are keyed on the SHA-256 of the input together with the options, and the least
+//! recently used one is dropped when `CACHE_MAX_ENTRIES` is reached. Outputs are held in full,
+//! so the cache can take up to that many converted files' worth of memory. Uploads are hashed as
+//! they are received, so a hit does not read the input back from disk. `SIGHUP` empties the
+//! cache, for operators who want everything converted afresh.
+//!
+//! Identical conversions that arrive while one is under way (a browser retrying an upload, say)
+//! wait for it and take its result from the cache instead of running LibreOffice again; if it
+//! fails, they fail with the same error.
+//!
+//! The key doubles as the output's `ETag`, so a client sending it back in `If-None-Match` gets a
+//! `304 Not Modified` instead of the same PDF again.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use tokio::io::AsyncReadExt;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+use tracing::info;
+
+use crate::crypto::Sha256;
+use crate::error::ConversionError;
+use crate::{AppState, ConvertOptions, ConvertedFile};
+
+pub const DEFAULT_MAX_ENTRIES: usize = 256;
+
+pub type CacheKey = [u8; 32];
+
+/// A cached output. The filename is not kept: it follows the name of each upload.
+struct CachedOutput {
+    content_type: &'static str,
+    content: Vec<u8>,
+    headers: Vec<(&'static str, String)>,
+}
+
+#[derive(Default)]
+struct Entries {
+    outputs: HashMap<CacheKey, (CachedOutput, u64)>,
+    /// Keys by when they were last used, oldest first.
+    recency: BTreeMap<u64, CacheKey>,
+    clock: u64,
+}
+
+/// How a conversion under way ended, for the requests waiting on it; `None` until it has.
+type Outcome = Option<Result<(), ConversionError>>;
+
+pub struct ResultCache {
+    entries: Mutex<Entries>,
+    /// Conversions under way, by key.
+    in_flight: Mutex<HashMap<CacheKey, watch::Receiver<Outcome>>>,
+    max_entries: usize,
+}
+
+/// Whether a request runs a conversion itself or waits for an identical one.
+pub enum Flight<'a> {
+    Lead(Lead<'a>),
+    Follow(watch::Receiver<Outcome>),
+}
+
+/// Held by the request running a conversion that others may be waiting for. Dropping it
+/// without `finish` (the request was cancelled) lets them run it themselves.
+pub struct Lead<'a> {
+    cache: &'a ResultCache,
+    key: CacheKey,
+    outcome: watch::Sender<Outcome>,
+}
+
+impl Lead<'_> {
+    /// Wakes the waiting requests. A successful output must be in the cache by then.
+    pub fn finish(self, outcome: Result<(), ConversionError>) {
+        self.outcome.send_replace(Some(outcome));
+    }
+}
+
+impl Drop for Lead<'_> {
+    fn drop(&mut self) {
+        self.cache.in_flight.lock().unwrap().remove(&self.key);
+    }
+}
+
+impl ResultCache {
+    pub fn new(max_entries: usize) -> Self {
+        Self {
+            entries: Mutex::new(Entries::default()),
+            in_flight: Mutex::new(HashMap::new()),
+            max_entries,
+        }
+    }
+
+    /// Whether a conversion with `opts` may be cached. Encrypted outputs are not kept in memory,
+    /// nor outputs of encrypted inputs, which would be served to anyone uploading the same file
+    /// with any password. Multi-page image outputs are zips whose entries are named after the
+    /// input.
+    pub fn is_cacheable(opts: &ConvertOptions) -> bool {
+        opts.password.is_none() && opts.input_password.is_none() && !opts.format.is_paged()
+    }
+
+    /// The key for converting content with the SHA-256 `digest` with `opts`.
+    pub fn key(digest: &[u8; 32], opts: &ConvertOptions) -> CacheKey {
+        let mut hasher = Sha256::new();
+        hasher.update(digest);
+        // Debug covers every field of the options
+        hasher.update(format!("{:?}", opts).as_bytes());
+        hasher.finish()
+    }
+
+    /// The quoted `ETag` of outputs cached under `key`.
+    pub fn etag(key: &CacheKey) -> String {
+        let mut etag = key.iter().fold(String::from("\""), |mut out, b| {
+            let _ = write!(out, "{:02x}", b);
+            out
+        });
+        etag.push('"');
+        etag
+    }
+
+    /// Whether an `If-None-Match` header value names `etag`: `*`, or a list of tags compared
+    /// weakly, as RFC 9110 asks for `If-None-Match`.
+    pub fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+        if_none_match.trim() == "*" || if_none_match.split(',').any(|tag| tag.trim().trim_start_matches("W/") == etag)
+    }
+
+    /// The SHA-256 of the file at `path`, for inputs that were not hashed as they were received.
+    pub async fn digest(path: &Path) -> io::Result<[u8; 32]> {
+        let mut hasher = Sha256::new();
+        let mut file = tokio::fs::File::open(path).await?;
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            let n = file.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf[..n]);
+        }
+        Ok(hasher.finish())
+    }
+
+    /// The cached output for `key`, named `filename`.
+    pub fn get(&self, key: &CacheKey, filename: String) -> Option<ConvertedFile> {
+        let mut entries = self.entries.lock().unwrap();
+        let entries = &mut *entries;
+        entries.clock += 1;
+        let (output, last_used) = entries.outputs.get_mut(key)?;
+        entries.recency.remove(last_used);
+        entries.recency.insert(entries.clock, *key);
+        *last_used = entries.clock;
+        Some(ConvertedFile {
+            filename,
+            content_type: output.content_type,
+            content: output.content.clone(),
+            headers: output.headers.clone(),
+        })
+    }
+
+    pub fn insert(&self, key: CacheKey, converted: &ConvertedFile) {
+        let output = CachedOutput {
+            content_type: converted.content_type,
+            content: converted.content.clone(),
+            headers: converted.headers.clone(),
+        };
+        let mut entries = self.entries.lock().unwrap();
+        entries.clock += 1;
+        let clock = entries.clock;
+        if let Some((_, last_used)) = entries.outputs.insert(key, (output, clock)) {
+            entries.recency.remove(&last_used);
+        }
+        entries.recency.insert(clock, key);
+        while entries.outputs.len() > self.max_entries {
+            let Some((_, oldest)) = entries.recency.pop_first() else {
+                break;
+            };
+            entries.outputs.remove(&oldest);
+        }
+    }
+
+    /// Leads the conversion for `key`, or follows the one already under way.
+    pub fn flight(&self, key: CacheKey) -> Flight<'_> {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        if let Some(outcome) = in_flight.get(&key) {
+            return Flight::Follow(outcome.clone());
+        }
+        let (sender, receiver) = watch::channel(None);
+        in_flight.insert(key, receiver);
+        Flight::Lead(Lead { cache: self, key, outcome: sender })
+    }
+
+    /// Waits for the conversion being followed to end. `None` if its request went away first.
+    pub async fn wait(mut outcome: watch::Receiver<Outcome>) -> Outcome {
+        outcome.wait_for(Option::is_some).await.ok().and_then(|outcome| outcome.clone())
+    }
+
+    /// Drops every entry, returning how many there were.
+    pub fn clear(&self) -> usize {
+        let mut entries = self.entries.lock().unwrap();
+        let count = entries.outputs.len();
+        entries.outputs.clear();
+        entries.recency.clear();
+        count
+    }
+}
+
+/// Empties the cache each time the process receives `SIGHUP`.
+pub async fn clear_on_hangup(state: Arc<AppState>) {
+    let Some(ref cache) = state.cache else {
+        return;
+    };
+    let mut hangup = signal(SignalKind::hangup()).expect("failed to install SIGHUP handler");
+    while hangup.recv().await.is_some() {
+        info!("SIGHUP received, dropped {} cached results", cache.clear());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::formats;
+
+    fn converted(content: &[u8]) -> ConvertedFile {
+        ConvertedFile {
+            filename: "a.pdf".to_string(),
+            content_type: "application/pdf",
+            content: content.to_vec(),
+            headers: vec![("X-Conversion-Backend", "libreoffice".to_string())],
+        }
+    }
+
+    #[test]
+    fn test_lru_eviction() {
+        let cache = ResultCache::new(2);
+        cache.insert([1; 32], &converted(b"one"));
+        cache.insert([2; 32], &converted(b"two"));
+        // Using the first entry makes the second the least recently used
+        let hit = cache.get(&[1; 32], "b.pdf".to_string()).unwrap();
+        assert_eq!((hit.filename.as_str(), hit.content.as_slice()), ("b.pdf", &b"one"[..]));
+        assert_eq!(hit.headers, [("X-Conversion-Backend", "libreoffice".to_string())]);
+        cache.insert([3; 32], &converted(b"three"));
+        assert_eq!(cache.entries.lock().unwrap().outputs.len(), 2);
+        assert!(cache.get(&[2; 32], "b.pdf".to_string()).is_none());
+        assert!(cache.get(&[1; 32], "b.pdf".to_string()).is_some());
+        assert!(cache.get(&[3; 32], "b.pdf".to_string()).is_some());
+
+        // Replacing an entry does not grow the cache
+        cache.insert([3; 32], &converted(b"again"));
+        assert_eq!(cache.entries.lock().unwrap().outputs.len(), 2);
+        assert_eq!(cache.get(&[3; 32], "b.pdf".to_string()).unwrap().content, b"again");
+
+        assert_eq!(cache.clear(), 2);
+        assert!(cache.get(&[3; 32], "b.pdf".to_string()).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_key() {
+        let path = std::env::temp_dir().join(format!("cache-key-{}", std::process::id()));
+        tokio::fs::write(&path, b"same content").await.unwrap();
+        let pdf = ConvertOptions::default();
+        let docx = ConvertOptions {
+            format: formats::output_format("docx").unwrap(),
+            ..Default::default()
+        };
+        let digest = ResultCache::digest(&path).await.unwrap();
+        let _ = tokio::fs::remove_file(&path).await;
+        assert_eq!(digest, crate::crypto::sha256(b"same content"));
+        let key = ResultCache::key(&digest, &pdf);
+        assert_eq!(ResultCache::key(&digest, &pdf), key);
+        assert_ne!(ResultCache::key(&digest, &docx), key);
+
+        assert!(ResultCache::is_cacheable(&docx));
+        assert!(!ResultCache::is_cacheable(&ConvertOptions {
+            format: formats::output_format("png").unwrap(),
+            ..Default::default()
+        }));
+    }
+
+    #[tokio::test]
+    async fn test_flight() {
+        let cache = ResultCache::new(2);
+        let Flight::Lead(lead) = cache.flight([1; 32]) else { panic!("nothing under way yet") };
+        let Flight::Follow(follower) = cache.flight([1; 32]) else { panic!("the first is under way") };
+        assert!(matches!(cache.flight([2; 32]), Flight::Lead(_)));
+        let waiting = tokio::spawn(ResultCache::wait(follower));
+        lead.finish(Err(ConversionError::internal()));
+        assert_eq!(waiting.await.unwrap().unwrap().unwrap_err().code, "INTERNAL_ERROR");
+
+        // Once finished, or abandoned, the next one leads
+        let Flight::Lead(abandoned) = cache.flight([1; 32]) else { panic!("the first has finished") };
+        let Flight::Follow(follower) = cache.flight([1; 32]) else { panic!("under way again") };
+        drop(abandoned);
+        assert!(ResultCache::wait(follower).await.is_none());
+        assert!(matches!(cache.flight([1; 32]), Flight::Lead(_)));
+    }
+
+    #[test]
+    fn test_etag() {
+        let etag = ResultCache::etag(&[0xab; 32]);
+        assert_eq!(etag, format!("\"{}\"", "ab".repeat(32)));
+        assert!(ResultCache::etag_matches(&etag, &etag));
+        assert!(ResultCache::etag_matches(&format!("\"other\", W/{}", etag), &etag));
+        assert!(ResultCache::etag_matches("*", &etag));
+        assert!(!ResultCache::etag_matches("\"other\"", &etag));
+    }
+}
@@ -0,0 +1,102 @@
+//! Delivery of conversion results to a caller-supplied `callback_url`.
+
+use axum::http::StatusCode;
+use std::time::Duration;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::error::ConversionError;
+use crate::outbound::{self, AllowedNetworks};
+use crate::ConvertedFile;
+
+/// Retries after the first attempt, waiting 1s, 2s, 4s... in between.
+const MAX_RETRIES: u32 = 3;
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const ATTEMPT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Accepts `https://` URLs, and `http://` ones only if `allow_http` is set (for development).
+pub fn validate_url(url: &str, allow_http: bool) -> Result<(), ConversionError> {
+    if outbound::is_allowed_url(url, allow_http) {
+        return Ok(());
+    }
+    let expected = if allow_http { "an http:// or https://" } else { "an https://" };
+    Err(ConversionError::new(
+        StatusCode::BAD_REQUEST,
+        "INVALID_CALLBACK_URL",
+        format!("callback_url must be {} URL", expected),
+    ))
+}
+
+/// Refuses a `callback_url` whose host resolves to an internal address (see `outbound::resolve`),
+/// so that the caller hears about it before the job runs rather than never.
+pub async fn check_address(url: &str, allowed: &AllowedNetworks) -> Result<(), ConversionError> {
+    outbound::resolve(url, allowed).await.map(|_| ()).map_err(|e| {
+        ConversionError::new(StatusCode::BAD_REQUEST, "INVALID_CALLBACK_URL", format!("callback_url is not allowed: {}", e))
+    })
+}
+
+/// POSTs the result of request `request_id` to `url`: the converted file on success, the JSON
+/// error otherwise. Failed deliveries are retried with exponential back-off. The host is resolved
+/// and checked again for each attempt, and the attempt pinned to the address checked.
+pub async fn deliver(url: &str, allowed: &AllowedNetworks, request_id: Uuid, result: &Result<ConvertedFile, ConversionError>) -> bool {
+    let mut headers = vec![("X-Request-Id", request_id.to_string())];
+    let (content_type, body) = match result {
+        Ok(converted) => {
+            headers.push(("X-Conversion-Status", "success".to_string()));
+            let escaped_filename = converted.filename.replace('"', "\\\"");
+            headers.push(("Content-Disposition", format!("attachment; filename=\"{}\"", escaped_filename)));
+            (converted.content_type, converted.content.clone())
+        }
+        Err(e) => {
+            headers.push(("X-Conversion-Status", "failed".to_string()));
+            ("application/json", serde_json::to_vec(e).unwrap_or_default())
+        }
+    };
+
+    let mut backoff = INITIAL_BACKOFF;
+    for attempt in 0..=MAX_RETRIES {
+        if attempt > 0 {
+            tokio::time::sleep(backoff).await;
+            backoff *= 2;
+        }
+        let pinned = match outbound::resolve(url, allowed).await {
+            Ok(pinned) => pinned,
+            Err(e) => {
+                warn!("Callback for {} refused: {} (attempt {})", request_id, e, attempt + 1);
+                continue;
+            }
+        };
+        match outbound::post(url, Some(&pinned), content_type, &headers, &body, ATTEMPT_TIMEOUT).await {
+            Ok(status) if (200..300).contains(&status) => {
+                info!("Delivered result of {} to callback", request_id);
+                return true;
+            }
+            Ok(status) => warn!("Callback for {} returned HTTP {} (attempt {})", request_id, status, attempt + 1),
+            Err(e) => warn!("Callback for {} failed: {} (attempt {})", request_id, e, attempt + 1),
+        }
+    }
+    warn!("Giving up on callback for {} after {} attempts", request_id, MAX_RETRIES + 1);
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_url() {
+        assert!(validate_url("https://example.com/hook", false).is_ok());
+        assert!(validate_url("http://example.com/hook", false).is_err());
+        assert!(validate_url("http://localhost:8080/hook", true).is_ok());
+        assert_eq!(validate_url("ftp://example.com", true).unwrap_err().code, "INVALID_CALLBACK_URL");
+    }
+
+    #[tokio::test]
+    async fn test_check_address() {
+        let none = AllowedNetworks::default();
+        assert_eq!(check_address("http://localhost:8080/hook", &none).await.unwrap_err().code, "INVALID_CALLBACK_URL");
+        assert!(check_address("https://169.254.169.254/", &none).await.is_err());
+        let local = AllowedNetworks::parse(Some("127.0.0.0/8, ::1")).unwrap();
+        assert!(check_address("http://127.0.0.1:8080/hook", &local).await.is_ok());
+    }
+}
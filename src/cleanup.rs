@@ -0,0 +1,153 @@
+//! Removal of work directories leaked by a crash, which nothing else would ever delete.
+//!
+//! Every `STALE_DIR_AGE_SECS`-old per-request directory under `WORK_DIR` is removed, at startup
+//! and then every `CLEANUP_INTERVAL_SECS`, except those of requests still in progress: an async
+//! job can wait for a slot, and a slow upload can take a while, longer than any threshold.
+
+use std::collections::HashSet;
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tracing::{error, warn};
+use uuid::Uuid;
+
+use crate::AppState;
+
+pub const DEFAULT_STALE_DIR_AGE_SECS: u64 = 3600;
+pub const DEFAULT_CLEANUP_INTERVAL_SECS: u64 = 300;
+
+/// The requests whose work directories are in use.
+#[derive(Clone, Default)]
+pub struct ActiveDirs(Arc<Mutex<HashSet<Uuid>>>);
+
+impl ActiveDirs {
+    /// Marks the work directory of `request_id` as in use until the returned guard is dropped.
+    pub fn track(&self, request_id: Uuid) -> ActiveDir {
+        self.0.lock().unwrap().insert(request_id);
+        ActiveDir { dirs: self.clone(), request_id }
+    }
+
+    fn contains(&self, request_id: &Uuid) -> bool {
+        self.0.lock().unwrap().contains(request_id)
+    }
+}
+
+/// Keeps a work directory out of the cleanup scan; owned, so async jobs can take it along.
+pub struct ActiveDir {
+    dirs: ActiveDirs,
+    request_id: Uuid,
+}
+
+impl Drop for ActiveDir {
+    fn drop(&mut self) {
+        self.dirs.0.lock().unwrap().remove(&self.request_id);
+    }
+}
+
+/// A removed directory, for the log.
+#[derive(Debug)]
+pub struct LeakedDir {
+    pub name: String,
+    pub age: Duration,
+    pub size: u64,
+}
+
+/// Removes the per-request directories under `work_dir` last modified at least `max_age` ago,
+/// other than the `active` ones. Worker profiles and parked downloads are not named by UUID, so
+/// they are left alone.
+pub fn remove_stale_dirs(work_dir: &Path, max_age: Duration, active: &ActiveDirs) -> io::Result<Vec<LeakedDir>> {
+    let now = SystemTime::now();
+    let mut removed = Vec::new();
+    // Entries can vanish mid-scan as requests finish; whatever cannot be read is skipped
+    for entry in std::fs::read_dir(work_dir)?.flatten() {
+        let name = entry.file_name().to_string_lossy().to_string();
+        let Ok(request_id) = Uuid::parse_str(&name) else {
+            continue;
+        };
+        let Ok(metadata) = entry.metadata() else {
+            continue;
+        };
+        // A clock step backwards makes everything look new, which errs on the side of keeping it
+        let age = metadata.modified().map_or(Duration::ZERO, |modified| now.duration_since(modified).unwrap_or_default());
+        if !metadata.is_dir() || age < max_age || active.contains(&request_id) {
+            continue;
+        }
+        let size = dir_size(&entry.path());
+        match std::fs::remove_dir_all(entry.path()) {
+            Ok(()) => removed.push(LeakedDir { name, age, size }),
+            Err(e) => error!("Failed to remove leaked work directory {}: {}", name, e),
+        }
+    }
+    Ok(removed)
+}
+
+/// Total size of the files under `path`, counting what can be read.
+fn dir_size(path: &Path) -> u64 {
+    let Ok(entries) = std::fs::read_dir(path) else {
+        return 0;
+    };
+    entries
+        .flatten()
+        .map(|entry| match entry.metadata() {
+            Ok(metadata) if metadata.is_dir() => dir_size(&entry.path()),
+            Ok(metadata) => metadata.len(),
+            Err(_) => 0,
+        })
+        .sum()
+}
+
+/// Scans for leaked directories now and then every `interval`.
+pub async fn run_cleanup(state: Arc<AppState>, max_age: Duration, interval: Duration) {
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        interval.tick().await;
+        for root in state.work_roots().into_iter().map(Path::to_path_buf) {
+            let scan_state = state.clone();
+            let scan_root = root.clone();
+            let scanned =
+                tokio::task::spawn_blocking(move || remove_stale_dirs(&scan_root, max_age, &scan_state.active_dirs)).await;
+            match scanned {
+                Ok(Ok(removed)) => {
+                    for dir in removed {
+                        warn!("Removed leaked work directory {} ({}s old, {} bytes)", dir.name, dir.age.as_secs(), dir.size);
+                        state.metrics.leaked_dirs_cleaned.inc();
+                    }
+                }
+                Ok(Err(e)) => error!("Failed to clean up {}: {}", root.display(), e),
+                Err(e) => error!("Work directory cleanup panicked: {}", e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_remove_stale_dirs() {
+        let work_dir = std::env::temp_dir().join(format!("cleanup-test-{}", std::process::id()));
+        let leaked = Uuid::new_v4();
+        let running = Uuid::new_v4();
+        std::fs::create_dir_all(work_dir.join(leaked.to_string()).join("0")).unwrap();
+        std::fs::write(work_dir.join(leaked.to_string()).join("0/a.docx"), b"12345").unwrap();
+        std::fs::create_dir_all(work_dir.join(running.to_string())).unwrap();
+        std::fs::create_dir_all(work_dir.join("pool/0")).unwrap();
+
+        let active = ActiveDirs::default();
+        // Nothing is old enough yet
+        assert!(remove_stale_dirs(&work_dir, Duration::from_secs(3600), &active).unwrap().is_empty());
+
+        let guard = active.track(running);
+        let removed = remove_stale_dirs(&work_dir, Duration::ZERO, &active).unwrap();
+        assert_eq!(removed.len(), 1);
+        assert_eq!((removed[0].name.as_str(), removed[0].size), (leaked.to_string().as_str(), 5));
+        assert!(work_dir.join(running.to_string()).exists());
+        assert!(work_dir.join("pool/0").exists());
+
+        drop(guard);
+        assert_eq!(remove_stale_dirs(&work_dir, Duration::ZERO, &active).unwrap().len(), 1);
+        let _ = std::fs::remove_dir_all(&work_dir);
+    }
+}
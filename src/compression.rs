@@ -0,0 +1,185 @@
+//! Compressed `/convert` responses for clients that ask for them with `Accept-Encoding`: the text
+//! of a PDF is compressed per page already, but fonts, structure and cross-reference tables often
+//! are not, and outputs such as HTML or plain text shrink a lot.
+//!
+//! `br`, `gzip` and `deflate` are offered, in that order of preference when a client rates them
+//! equally, as Brotli's larger window and fitted codes compress best. Only responses held in
+//! memory whole are compressed, and the result is only used when it is at most 95% of the original
+//! size, so outputs made mostly of JPEG images are sent as they are rather than grown, unless the
+//! client refuses uncompressed responses with `identity;q=0`. A compressed response's `ETag` is
+//! made weak, as its bytes differ from the uncompressed one's.
+
+use axum::{
+    body::{Body, HttpBody},
+    extract::Request,
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use tracing::debug;
+
+use crate::brotli;
+use crate::deflate;
+use crate::error::ConversionError;
+
+/// The largest compressed-to-original size ratio worth sending compressed.
+const MAX_RATIO: f64 = 0.95;
+/// Bodies smaller than this are not worth compressing.
+const MIN_SIZE: u64 = 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Encoding {
+    Brotli,
+    Gzip,
+    Deflate,
+}
+
+impl Encoding {
+    fn name(self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+        }
+    }
+
+    fn encode(self, data: &[u8]) -> Vec<u8> {
+        match self {
+            Encoding::Brotli => brotli::compress(data),
+            Encoding::Gzip => deflate::gzip(data),
+            Encoding::Deflate => deflate::zlib(data),
+        }
+    }
+}
+
+/// The codings of `accept_encoding`, lowercased, with their quality values.
+fn qualities(accept_encoding: &str) -> Vec<(String, f32)> {
+    accept_encoding
+        .split(',')
+        .map(|item| {
+            let mut parts = item.split(';');
+            let coding = parts.next().unwrap_or_default().trim().to_ascii_lowercase();
+            let quality = parts
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|q| q.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            (coding, quality)
+        })
+        .filter(|(coding, _)| !coding.is_empty())
+        .collect()
+}
+
+/// The quality `accept_encoding` gives `names`: their own, or that of `*` if none is listed
+/// (RFC 9110, section 12.5.3).
+fn quality(qualities: &[(String, f32)], names: &[&str]) -> Option<f32> {
+    let listed = |names: &[&str]| qualities.iter().find(|(coding, _)| names.contains(&coding.as_str())).map(|&(_, q)| q);
+    listed(names).or_else(|| listed(&["*"]))
+}
+
+/// The supported coding `accept_encoding` prefers, br then gzip on a tie.
+fn negotiate(accept_encoding: &str) -> Option<Encoding> {
+    let qualities = qualities(accept_encoding);
+    [
+        (Encoding::Brotli, quality(&qualities, &["br"])),
+        (Encoding::Gzip, quality(&qualities, &["gzip", "x-gzip"])),
+        (Encoding::Deflate, quality(&qualities, &["deflate"])),
+    ]
+    .into_iter()
+    .filter_map(|(encoding, q)| q.filter(|&q| q > 0.0).map(|q| (encoding, q)))
+    // `max_by` keeps the last of equals, so the list is walked from the least preferred
+    .rev()
+    .max_by(|(_, a), (_, b)| a.total_cmp(b))
+    .map(|(encoding, _)| encoding)
+}
+
+/// Whether `accept_encoding` refuses uncompressed responses, with `identity;q=0` or `*;q=0`.
+/// Such clients get a compressed response however little it saves.
+fn identity_refused(accept_encoding: &str) -> bool {
+    quality(&qualities(accept_encoding), &["identity"]) == Some(0.0)
+}
+
+fn weaken_etag(headers: &mut HeaderMap) {
+    if let Some(etag) = headers.get(header::ETAG).and_then(|v| v.to_str().ok())
+        && !etag.starts_with("W/")
+        && let Ok(weak) = HeaderValue::from_str(&format!("W/{}", etag))
+    {
+        headers.insert(header::ETAG, weak);
+    }
+}
+
+/// Compresses successful responses in the coding the request's `Accept-Encoding` prefers.
+pub async fn compress(req: Request, next: Next) -> Response {
+    let accept_encoding = req.headers().get(header::ACCEPT_ENCODING).and_then(|v| v.to_str().ok()).unwrap_or_default();
+    let encoding = negotiate(accept_encoding);
+    let required = identity_refused(accept_encoding);
+    let mut response = next.run(req).await;
+    if response.status() == StatusCode::NOT_MODIFIED && encoding.is_some() {
+        weaken_etag(response.headers_mut());
+        return response;
+    }
+    let size = response.body().size_hint().exact();
+    if response.status() != StatusCode::OK
+        || response.headers().contains_key(header::CONTENT_ENCODING)
+        || size.is_none_or(|size| size < MIN_SIZE && !required)
+    {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    // Caches must not hand a compressed response to a client that did not ask for one
+    if !parts.headers.get_all(header::VARY).iter().any(|v| v.to_str().is_ok_and(|v| v.to_ascii_lowercase().contains("accept-encoding"))) {
+        parts.headers.append(header::VARY, HeaderValue::from_static("accept-encoding"));
+    }
+    let Some(encoding) = encoding else {
+        return Response::from_parts(parts, body);
+    };
+    let Ok(data) = axum::body::to_bytes(body, usize::MAX).await else {
+        return ConversionError::internal().into_response();
+    };
+    let Ok((data, compressed)) = tokio::task::spawn_blocking(move || {
+        let compressed = encoding.encode(&data);
+        (data, compressed)
+    })
+    .await
+    else {
+        return ConversionError::internal().into_response();
+    };
+
+    let ratio = compressed.len() as f64 / data.len() as f64;
+    debug!("{} compressed {} bytes to {} ({:.2})", encoding.name(), data.len(), compressed.len(), ratio);
+    if ratio > MAX_RATIO && !required {
+        return Response::from_parts(parts, Body::from(data));
+    }
+    parts.headers.insert(header::CONTENT_ENCODING, HeaderValue::from_static(encoding.name()));
+    parts.headers.remove(header::CONTENT_LENGTH);
+    weaken_etag(&mut parts.headers);
+    Response::from_parts(parts, Body::from(compressed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(negotiate("br, gzip, deflate"), Some(Encoding::Brotli));
+        assert_eq!(negotiate("br;q=0.8, gzip, deflate"), Some(Encoding::Gzip));
+        assert_eq!(negotiate("deflate, gzip;q=0.5"), Some(Encoding::Deflate));
+        assert_eq!(negotiate("deflate;q=0.5, GZIP;q=0.5"), Some(Encoding::Gzip));
+        assert_eq!(negotiate("*"), Some(Encoding::Brotli));
+        assert_eq!(negotiate("br"), Some(Encoding::Brotli));
+        assert_eq!(negotiate("compress"), None);
+        assert_eq!(negotiate("gzip;q=0, identity"), None);
+        assert_eq!(negotiate(""), None);
+        // `*` only stands for the codings not listed
+        assert_eq!(negotiate("br;q=0, gzip;q=0, *"), Some(Encoding::Deflate));
+        assert_eq!(negotiate("*;q=0.5, br;q=0.2, gzip;q=0.2"), Some(Encoding::Deflate));
+        assert_eq!(negotiate("br;q=0, x-gzip;q=0, deflate;q=0, *"), None);
+
+        assert!(identity_refused("gzip, identity;q=0"));
+        assert!(identity_refused("gzip, *;q=0"));
+        assert!(!identity_refused("gzip, *;q=0, identity"));
+        assert!(!identity_refused("gzip;q=0"));
+        assert!(!identity_refused(""));
+    }
+}
@@ -0,0 +1,465 @@
+//! Settings read from the environment at startup.
+//!
+//! Every variable is checked before the server starts, and all problems are reported together
+//! so a misconfigured deployment can be fixed in one go.
+
+use std::collections::HashMap;
+use std::env::{self, VarError};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+use tower_http::cors::CorsLayer;
+
+use crate::apikeys::ApiKeys;
+use crate::breaker::CircuitBreaker;
+use crate::jwt::JwtVerifier;
+use crate::outbound::AllowedNetworks;
+use crate::ratelimit::{Rate, RateLimiter};
+use crate::s3::S3Config;
+use crate::security::SecurityHeaders;
+use crate::service::RetryPolicy;
+use crate::useragents::BlockedUserAgents;
+use crate::{breaker, cache, cleanup, cors_layer, detect, disk, downloads, service, shutdown};
+
+pub const DEFAULT_MAX_BODY_SIZE: usize = 10 * 1024 * 1024; // 10MB
+pub const DEFAULT_CONVERSION_TIMEOUT_SECS: u64 = 120;
+pub const DEFAULT_JOB_TTL_SECS: u64 = 3600;
+pub const DEFAULT_JOB_MAX_AGE_SECS: u64 = 3600;
+pub const DEFAULT_MAX_FILES_PER_REQUEST: usize = 20;
+pub const DEFAULT_MAX_CONCURRENT_CONVERSIONS: usize = 4;
+pub const DEFAULT_DOWNLOAD_TIMEOUT_SECS: u64 = 60;
+pub const DEFAULT_MAX_OUTPUT_BYTES: u64 = 200 * 1024 * 1024; // 200MB
+pub const DEFAULT_MAX_RENDER_BYTES: u64 = 500 * 1024 * 1024; // 500MB
+const DEFAULT_RATE_LIMIT_BURST: u32 = 10;
+const RATE_LIMIT_KEY_PREFIX: &str = "RATE_LIMIT_KEY_";
+const DEFAULT_JOB_STATUS_RPS: f64 = 2.0;
+const DEFAULT_JOB_STATUS_BURST: u32 = 10;
+const DEFAULT_WORK_DIR: &str = "/tmp/convert";
+pub const DEFAULT_LIBREOFFICE_PATH: &str = "libreoffice";
+pub const DEFAULT_LISTEN_ADDR: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 3000);
+/// Directory created in `XDG_RUNTIME_DIR` for work directories.
+const RUNTIME_SUBDIR: &str = "office2pdf";
+
+pub struct Config {
+    /// The address and port the server binds to.
+    pub listen_addr: SocketAddr,
+    pub api_keys: Option<ApiKeys>,
+    /// Also accepted wherever `api_keys` are, and the only key for admin endpoints.
+    pub admin_api_key: Option<String>,
+    pub jwt: Option<JwtVerifier>,
+    pub metrics_api_key: Option<String>,
+    pub max_body_size: usize,
+    pub conversion_timeout: Duration,
+    pub job_ttl: Duration,
+    /// Async jobs still unfinished this long after submission are cancelled.
+    pub job_max_age: Duration,
+    pub download_token_ttl: Duration,
+    pub max_files_per_request: usize,
+    pub allowed_input_types: Vec<String>,
+    pub allowed_extensions: Vec<String>,
+    /// Accept macro-enabled Office files (`.docm`, `.xlsm`, ...).
+    pub allow_macros: bool,
+    pub file_size_limits: HashMap<String, u64>,
+    pub min_free_bytes: u64,
+    pub max_concurrent_conversions: usize,
+    pub allow_http_callbacks: bool,
+    pub download_timeout: Duration,
+    pub max_download_bytes: u64,
+    pub max_output_bytes: u64,
+    /// Largest `/render` zip, all pages together.
+    pub max_render_bytes: u64,
+    pub allow_http_downloads: bool,
+    /// Internal networks `url` inputs and callbacks may reach.
+    pub outbound_allowed_networks: AllowedNetworks,
+    /// Return LibreOffice's stderr in conversion errors (development only).
+    pub include_libreoffice_errors: bool,
+    pub cors: Option<CorsLayer>,
+    pub security_headers: SecurityHeaders,
+    pub blocked_user_agents: BlockedUserAgents,
+    pub rate_limiter: Option<RateLimiter>,
+    pub job_status_limiter: RateLimiter,
+    /// Reverse proxies in front of the service, for working out client addresses.
+    pub trusted_proxy_count: usize,
+    pub s3: Option<S3Config>,
+    pub lo_pool_size: usize,
+    /// Start LibreOffice with `--safe-mode`.
+    pub lo_safe_mode: bool,
+    /// Further arguments for every LibreOffice process.
+    pub lo_extra_args: Vec<String>,
+    /// Retries of one-off LibreOffice runs that failed starting up.
+    pub lo_retry: RetryPolicy,
+    /// Pauses conversions while LibreOffice keeps failing.
+    pub circuit_breaker: CircuitBreaker,
+    /// Fonts (e.g. for Chinese, Japanese and Korean) added to LibreOffice's own.
+    pub cjk_font_dir: Option<PathBuf>,
+    /// Conversion results kept in memory; 0 disables the cache.
+    pub cache_max_entries: usize,
+    /// `max-age` of cached results' `Cache-Control`, in seconds; 0 sends no `Cache-Control`.
+    pub cache_control_max_age: u64,
+    /// The LibreOffice executable, as a path or a name looked up in `PATH`.
+    pub libreoffice_path: String,
+    /// Headless Chromium, used for HTML inputs when set.
+    pub chromium_path: Option<String>,
+    /// Pandoc, used for Markdown inputs when set.
+    pub pandoc_path: Option<String>,
+    /// qpdf, used to linearize PDFs when set.
+    pub qpdf_path: Option<String>,
+    /// msoffcrypto-tool, used to decrypt password-protected Office files when set.
+    pub msoffcrypto_path: Option<String>,
+    /// Holds per-request work directories, pool profiles and parked downloads.
+    pub work_dir: PathBuf,
+    /// `$XDG_RUNTIME_DIR/office2pdf`, preferred for per-request work directories while usable.
+    pub runtime_dir: Option<PathBuf>,
+    /// How long shutdown waits for running conversions before killing them.
+    pub shutdown_grace: Duration,
+    /// Work directories untouched for this long are removed, unless still in use.
+    pub stale_dir_age: Duration,
+    pub cleanup_interval: Duration,
+    /// Conversions are recorded here when set.
+    pub audit_log_file: Option<PathBuf>,
+}
+
+impl Config {
+    /// Reads every setting, collecting one message per invalid variable.
+    pub fn from_env() -> Result<Self, Vec<String>> {
+        let mut env = Env::default();
+
+        // TLS is terminated in front of the service; refuse to start rather than silently serve
+        // plain HTTP to someone who expects these to take effect
+        for name in ["TLS_CERT_FILE", "TLS_KEY_FILE"] {
+            if env::var_os(name).is_some() {
+                env.errors.push(format!("{} is set, but TLS is not supported; terminate TLS at a reverse proxy instead", name));
+            }
+        }
+        // Ignoring it would leave the API open
+        if env::var_os("API_KEY").is_some() {
+            env.errors.push("API_KEY is no longer supported; set API_KEYS (a comma-separated list) instead".to_string());
+        }
+        let api_keys = {
+            let raw = env.var("API_KEYS");
+            env.check(raw.as_deref().map(ApiKeys::parse).transpose())
+        };
+
+        let jwt = env.var("JWT_PUBLIC_KEY_FILE").and_then(|path| {
+            let verifier = std::fs::read_to_string(&path)
+                .map_err(|e| format!("Failed to read JWT_PUBLIC_KEY_FILE {}: {}", path, e))
+                .and_then(|pem| JwtVerifier::from_pem(&pem).map_err(|e| format!("Invalid JWT_PUBLIC_KEY_FILE {}: {}", path, e)));
+            env.check(verifier.map(Some))
+        });
+        let jwt = jwt.map(|mut verifier| {
+            verifier.required_sub = env.var("JWT_REQUIRED_SUB");
+            verifier.required_scope = env.var("JWT_REQUIRED_SCOPE");
+            verifier
+        });
+
+        let max_body_size = env.positive("MAX_BODY_SIZE", DEFAULT_MAX_BODY_SIZE);
+        let allowed_input_types = detect::parse_allowed_types(env.var("ALLOWED_INPUT_TYPES").as_deref());
+        let outbound_allowed_networks = {
+            let raw = env.var("OUTBOUND_ALLOWED_NETWORKS");
+            env.check(AllowedNetworks::parse(raw.as_deref()))
+        };
+        let file_size_limits = {
+            let raw = env.var("FILE_SIZE_LIMITS");
+            env.check(parse_size_limits(raw.as_deref()))
+        };
+        let cors = {
+            let raw = env.var("CORS_ORIGINS");
+            env.check(raw.as_deref().map(cors_layer).transpose())
+        };
+        let security_headers = {
+            let headers = SecurityHeaders::new(|name| env.var(name));
+            env.check(headers)
+        };
+        let s3 = env.check(S3Config::from_env());
+        let lo_extra_args = {
+            let raw = env.var("LO_EXTRA_ARGS");
+            env.check(raw.as_deref().map(service::parse_extra_args).transpose()).unwrap_or_default()
+        };
+        let listen_addr = {
+            let raw = env.var("LISTEN_ADDR");
+            env.check(parse_listen_addr(raw.as_deref()).map(Some)).unwrap_or(DEFAULT_LISTEN_ADDR)
+        };
+        let trusted_proxy_count = env.parse("TRUSTED_PROXY_COUNT", 0);
+        let rate_limiter = {
+            let raw = env.var("RATE_LIMIT_RPS");
+            let per_second = env.check(raw.as_deref().map(|raw| parse_rate("RATE_LIMIT_RPS", raw)).transpose());
+            let burst = env.positive("RATE_LIMIT_BURST", DEFAULT_RATE_LIMIT_BURST);
+            let raw_overrides = env.prefixed(RATE_LIMIT_KEY_PREFIX);
+            let overrides = env.check(parse_key_rates(raw_overrides));
+            if per_second.is_none() && !overrides.is_empty() {
+                env.errors.push(format!("{}* is set but RATE_LIMIT_RPS is not", RATE_LIMIT_KEY_PREFIX));
+            }
+            per_second.map(|per_second| RateLimiter::new(burst, per_second, trusted_proxy_count).with_overrides(overrides))
+        };
+        let job_status_limiter = {
+            let raw = env.var("JOB_STATUS_RPS");
+            let per_second = env.check(raw.as_deref().map(|raw| parse_rate("JOB_STATUS_RPS", raw)).transpose());
+            let burst = env.positive("JOB_STATUS_BURST", DEFAULT_JOB_STATUS_BURST);
+            RateLimiter::new(burst, per_second.unwrap_or(DEFAULT_JOB_STATUS_RPS), trusted_proxy_count)
+        };
+
+        let config = Config {
+            listen_addr,
+            api_keys,
+            admin_api_key: env.var("ADMIN_API_KEY").filter(|k| !k.trim().is_empty()),
+            jwt,
+            metrics_api_key: env.var("METRICS_API_KEY"),
+            max_body_size,
+            conversion_timeout: Duration::from_secs(env.positive("CONVERSION_TIMEOUT_SECS", DEFAULT_CONVERSION_TIMEOUT_SECS)),
+            job_ttl: Duration::from_secs(env.positive("JOB_TTL_SECS", DEFAULT_JOB_TTL_SECS)),
+            job_max_age: Duration::from_secs(env.positive("JOB_MAX_AGE_SECS", DEFAULT_JOB_MAX_AGE_SECS)),
+            download_token_ttl: Duration::from_secs(env.positive("DOWNLOAD_TOKEN_TTL_SECS", downloads::DEFAULT_TOKEN_TTL_SECS)),
+            max_files_per_request: env.positive("MAX_FILES_PER_REQUEST", DEFAULT_MAX_FILES_PER_REQUEST),
+            allowed_input_types,
+            allowed_extensions: detect::parse_allowed_extensions(env.var("ALLOWED_EXTENSIONS").as_deref()),
+            allow_macros: env.parse("ALLOW_MACROS", false),
+            file_size_limits,
+            min_free_bytes: env.parse("MIN_FREE_BYTES", disk::DEFAULT_MIN_FREE_BYTES),
+            max_concurrent_conversions: env.positive("MAX_CONCURRENT_CONVERSIONS", DEFAULT_MAX_CONCURRENT_CONVERSIONS),
+            allow_http_callbacks: env.parse("ALLOW_HTTP_CALLBACKS", false),
+            download_timeout: Duration::from_secs(env.positive("DOWNLOAD_TIMEOUT_SECS", DEFAULT_DOWNLOAD_TIMEOUT_SECS)),
+            max_download_bytes: env.positive("MAX_DOWNLOAD_BYTES", max_body_size as u64),
+            max_output_bytes: env.positive("MAX_OUTPUT_BYTES", DEFAULT_MAX_OUTPUT_BYTES),
+            max_render_bytes: env.positive("MAX_RENDER_BYTES", DEFAULT_MAX_RENDER_BYTES),
+            allow_http_downloads: env.parse("ALLOW_HTTP_DOWNLOADS", false),
+            outbound_allowed_networks,
+            include_libreoffice_errors: env.parse("INCLUDE_LIBREOFFICE_ERRORS", false),
+            cors,
+            security_headers,
+            blocked_user_agents: BlockedUserAgents::parse(env.var("BLOCKED_USER_AGENTS").as_deref()),
+            rate_limiter,
+            job_status_limiter,
+            trusted_proxy_count,
+            s3,
+            lo_pool_size: env.parse("LO_POOL_SIZE", 0),
+            lo_safe_mode: env.parse("LO_SAFE_MODE", true),
+            lo_extra_args,
+            lo_retry: RetryPolicy {
+                max_retries: env.parse("LO_MAX_RETRIES", service::DEFAULT_MAX_RETRIES),
+                patterns: match env.var("LO_RETRY_PATTERNS") {
+                    Some(raw) => raw.split(',').map(str::trim).filter(|p| !p.is_empty()).map(String::from).collect(),
+                    None => RetryPolicy::default().patterns,
+                },
+            },
+            circuit_breaker: CircuitBreaker::new(
+                env.positive("CB_FAILURE_THRESHOLD", breaker::DEFAULT_FAILURE_THRESHOLD),
+                Duration::from_secs(env.positive("CB_WINDOW_SECS", breaker::DEFAULT_WINDOW_SECS)),
+                Duration::from_secs(env.positive("CB_RESET_SECS", breaker::DEFAULT_RESET_SECS)),
+            ),
+            cjk_font_dir: env.var("CJK_FONT_DIR").filter(|d| !d.trim().is_empty()).map(PathBuf::from),
+            cache_max_entries: env.parse("CACHE_MAX_ENTRIES", cache::DEFAULT_MAX_ENTRIES),
+            cache_control_max_age: env.parse("CACHE_CONTROL_MAX_AGE", 0),
+            libreoffice_path: env
+                .var("LIBREOFFICE_PATH")
+                .filter(|p| !p.trim().is_empty())
+                .unwrap_or_else(|| DEFAULT_LIBREOFFICE_PATH.to_string()),
+            chromium_path: env.var("CHROMIUM_PATH").filter(|p| !p.trim().is_empty()),
+            pandoc_path: env.var("PANDOC_PATH").filter(|p| !p.trim().is_empty()),
+            qpdf_path: env.var("QPDF_PATH").filter(|p| !p.trim().is_empty()),
+            msoffcrypto_path: env.var("MSOFFCRYPTO_PATH").filter(|p| !p.trim().is_empty()),
+            // TEMP_DIR is the setting's old name
+            work_dir: env
+                .var("WORK_DIR")
+                .or_else(|| env.var("TEMP_DIR"))
+                .map_or_else(|| PathBuf::from(DEFAULT_WORK_DIR), PathBuf::from),
+            // Usually a tmpfs on systemd hosts; only relative values are ignored, as the spec says
+            runtime_dir: env
+                .var("XDG_RUNTIME_DIR")
+                .map(PathBuf::from)
+                .filter(|dir| dir.is_absolute())
+                .map(|dir| dir.join(RUNTIME_SUBDIR)),
+            shutdown_grace: Duration::from_secs(env.parse("SHUTDOWN_GRACE_SECS", shutdown::DEFAULT_GRACE_SECS)),
+            stale_dir_age: Duration::from_secs(env.positive("STALE_DIR_AGE_SECS", cleanup::DEFAULT_STALE_DIR_AGE_SECS)),
+            cleanup_interval: Duration::from_secs(env.positive("CLEANUP_INTERVAL_SECS", cleanup::DEFAULT_CLEANUP_INTERVAL_SECS)),
+            audit_log_file: env.var("AUDIT_LOG_FILE").map(PathBuf::from),
+        };
+        if !config.work_dir.is_absolute() {
+            env.errors.push(format!("Invalid WORK_DIR {:?}: expected an absolute path", config.work_dir));
+        }
+        // Linked into profiles that live elsewhere, so a relative path would dangle
+        if let Some(ref dir) = config.cjk_font_dir
+            && !(dir.is_absolute() && dir.is_dir())
+        {
+            env.errors.push(format!("Invalid CJK_FONT_DIR {:?}: expected the absolute path of a directory", dir));
+        }
+
+        if env.errors.is_empty() { Ok(config) } else { Err(env.errors) }
+    }
+}
+
+/// Reads variables, recording what is wrong with them instead of failing on the first problem.
+#[derive(Default)]
+struct Env {
+    errors: Vec<String>,
+}
+
+impl Env {
+    fn var(&mut self, name: &str) -> Option<String> {
+        match env::var(name) {
+            Ok(value) => Some(value),
+            Err(VarError::NotPresent) => None,
+            Err(VarError::NotUnicode(_)) => {
+                self.errors.push(format!("Invalid {}: not valid UTF-8", name));
+                None
+            }
+        }
+    }
+
+    /// The variables whose names start with `prefix`, with the prefix taken off their names.
+    fn prefixed(&mut self, prefix: &str) -> Vec<(String, String)> {
+        let mut vars = Vec::new();
+        for (name, value) in env::vars_os() {
+            let Some(name) = name.to_str().and_then(|n| n.strip_prefix(prefix)) else {
+                continue;
+            };
+            match value.into_string() {
+                Ok(value) => vars.push((name.to_string(), value)),
+                Err(_) => self.errors.push(format!("Invalid {}{}: not valid UTF-8", prefix, name)),
+            }
+        }
+        vars
+    }
+
+    fn parse<T: FromStr + Default>(&mut self, name: &str, default: T) -> T {
+        let raw = self.var(name);
+        self.check(parse_env(name, raw.as_deref(), default))
+    }
+
+    fn positive<T: FromStr + PartialOrd + Default>(&mut self, name: &str, default: T) -> T {
+        let raw = self.var(name);
+        self.check(parse_positive_env(name, raw.as_deref(), default))
+    }
+
+    /// Records the error, if any, and carries on with a placeholder; the config being built is
+    /// discarded anyway once there are errors.
+    fn check<T: Default>(&mut self, result: Result<T, String>) -> T {
+        result.unwrap_or_else(|e| {
+            self.errors.push(e);
+            T::default()
+        })
+    }
+}
+
+/// Parses an optional env var value, falling back to `default` when unset.
+pub fn parse_env<T: FromStr>(name: &str, raw: Option<&str>, default: T) -> Result<T, String> {
+    match raw {
+        None => Ok(default),
+        Some(value) => value
+            .trim()
+            .parse::<T>()
+            .map_err(|_| format!("Invalid {} {:?}", name, value)),
+    }
+}
+
+/// Parses an optional env var value as a positive integer, falling back to `default` when unset.
+pub fn parse_positive_env<T>(name: &str, raw: Option<&str>, default: T) -> Result<T, String>
+where
+    T: FromStr + PartialOrd + Default,
+{
+    match raw {
+        None => Ok(default),
+        Some(value) => match value.trim().parse::<T>() {
+            Ok(parsed) if parsed > T::default() => Ok(parsed),
+            _ => Err(format!("Invalid {} {:?}: expected a positive integer", name, value)),
+        },
+    }
+}
+
+/// Parses `LISTEN_ADDR`, an IP address and port such as `127.0.0.1:3000` or `[::1]:3000`.
+fn parse_listen_addr(raw: Option<&str>) -> Result<SocketAddr, String> {
+    parse_env("LISTEN_ADDR", raw, DEFAULT_LISTEN_ADDR)
+        .map_err(|e| format!("{}: expected an IP address and port, such as 0.0.0.0:3000 or [::1]:3000", e))
+}
+
+/// Parses a requests-per-second setting such as `RATE_LIMIT_RPS`, which may be fractional (`0.5`
+/// is one request every two seconds).
+fn parse_rate(name: &str, raw: &str) -> Result<f64, String> {
+    match raw.trim().parse::<f64>() {
+        Ok(rate) if rate > 0.0 && rate.is_finite() => Ok(rate),
+        _ => Err(format!("Invalid {} {:?}: expected a positive number", name, raw)),
+    }
+}
+
+/// Parses `RATE_LIMIT_KEY_<identity>=<rps>:<burst>` overrides, given as identity and value.
+fn parse_key_rates(vars: Vec<(String, String)>) -> Result<HashMap<String, Rate>, String> {
+    let mut rates = HashMap::new();
+    for (identity, raw) in vars {
+        let invalid = || format!("Invalid {}{} {:?}: expected rps:burst", RATE_LIMIT_KEY_PREFIX, identity, raw);
+        let (per_second, burst) = raw.split_once(':').ok_or_else(invalid)?;
+        let per_second = parse_rate("", per_second).map_err(|_| invalid())?;
+        let burst = burst.trim().parse().ok().filter(|&b: &u32| b > 0).ok_or_else(invalid)?;
+        if identity.is_empty() {
+            return Err(invalid());
+        }
+        rates.insert(identity, Rate { per_second, burst });
+    }
+    Ok(rates)
+}
+
+/// Parses `FILE_SIZE_LIMITS`, a comma-separated list of `ext=bytes` pairs.
+fn parse_size_limits(raw: Option<&str>) -> Result<HashMap<String, u64>, String> {
+    let mut limits = HashMap::new();
+    for pair in raw.unwrap_or_default().split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        let invalid = || format!("Invalid FILE_SIZE_LIMITS entry {:?}: expected ext=bytes", pair);
+        let (ext, bytes) = pair.split_once('=').ok_or_else(invalid)?;
+        let bytes: u64 = bytes.trim().parse().map_err(|_| invalid())?;
+        limits.insert(ext.trim().trim_start_matches('.').to_ascii_lowercase(), bytes);
+    }
+    Ok(limits)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_positive_env() {
+        assert_eq!(parse_positive_env("MAX_BODY_SIZE", None, DEFAULT_MAX_BODY_SIZE), Ok(DEFAULT_MAX_BODY_SIZE));
+        assert_eq!(parse_positive_env("MAX_BODY_SIZE", Some("104857600"), DEFAULT_MAX_BODY_SIZE), Ok(104857600));
+        assert!(parse_positive_env("MAX_BODY_SIZE", Some("0"), DEFAULT_MAX_BODY_SIZE).is_err());
+        assert!(parse_positive_env("MAX_BODY_SIZE", Some("-5"), DEFAULT_MAX_BODY_SIZE).is_err());
+        assert!(parse_positive_env("MAX_BODY_SIZE", Some("10MB"), DEFAULT_MAX_BODY_SIZE).is_err());
+    }
+
+    #[test]
+    fn test_parse_listen_addr() {
+        assert_eq!(parse_listen_addr(None), Ok(DEFAULT_LISTEN_ADDR));
+        assert_eq!(parse_listen_addr(Some(" 10.0.0.5:8080 ")).unwrap().to_string(), "10.0.0.5:8080");
+        assert_eq!(parse_listen_addr(Some("[::1]:3000")).unwrap().to_string(), "[::1]:3000");
+        for bad in ["localhost:3000", "0.0.0.0", ":3000", "::1:3000"] {
+            assert!(parse_listen_addr(Some(bad)).unwrap_err().starts_with("Invalid LISTEN_ADDR"), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_parse_size_limits() {
+        let limits = parse_size_limits(Some("csv=1048576, .DOCX=52428800")).unwrap();
+        assert_eq!(limits.get("csv"), Some(&1048576));
+        assert_eq!(limits.get("docx"), Some(&52428800));
+        assert!(parse_size_limits(None).unwrap().is_empty());
+        assert!(parse_size_limits(Some("csv")).is_err());
+        assert!(parse_size_limits(Some("csv=1MB")).is_err());
+    }
+
+    #[test]
+    fn test_parse_key_rates() {
+        let vars = |pairs: &[(&str, &str)]| pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        let rates = parse_key_rates(vars(&[("ba7816bf8f01", "0.5:3"), ("ci-bot", " 20 : 40 ")])).unwrap();
+        assert_eq!(rates["ba7816bf8f01"], Rate { per_second: 0.5, burst: 3 });
+        assert_eq!(rates["ci-bot"], Rate { per_second: 20.0, burst: 40 });
+        assert!(parse_key_rates(vars(&[("ci-bot", "20")])).is_err());
+        assert!(parse_key_rates(vars(&[("ci-bot", "0:5")])).is_err());
+        assert!(parse_key_rates(vars(&[("ci-bot", "1:0")])).is_err());
+        assert!(parse_key_rates(vars(&[("", "1:1")])).is_err());
+    }
+
+    #[test]
+    fn test_env_collects_every_error() {
+        let mut env = Env::default();
+        assert_eq!(env.check(parse_positive_env("MAX_BODY_SIZE", Some("0"), 1usize)), 0);
+        assert_eq!(env.check(parse_size_limits(Some("csv"))), HashMap::new());
+        assert_eq!(env.check(parse_env("LO_POOL_SIZE", Some("2"), 0usize)), 2);
+        assert_eq!(env.errors.len(), 2);
+    }
+}
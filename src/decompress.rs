@@ -0,0 +1,299 @@
+//! Request bodies sent with `Content-Encoding: gzip` or `zstd`, for clients compressing large
+//! uploads before sending them over slow links.
+//!
+//! The body is decompressed as it arrives, on a blocking thread, and handed on as a stream that
+//! counts the decompressed bytes going through it. The route's body limit applies to those: the
+//! stream fails as soon as they pass it, so a small body that expands enormously (a "zip bomb") is
+//! refused with 413 without ever being expanded in full, and never more than the decoder's window
+//! and a few chunks of it are held in memory. The compressed body is held to the same limit.
+
+use std::io::{self, Read};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+
+use axum::{
+    body::{Body, BodyDataStream, Bytes},
+    extract::{Request, State},
+    http::{header, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use futures_util::{Stream, StreamExt};
+use tokio::{runtime::Handle, sync::mpsc};
+
+use crate::error::ConversionError;
+use crate::inflate;
+use crate::window::DecodeError;
+use crate::zstd;
+
+/// Decompressed chunks the decoder may get ahead of the handler by.
+const CHUNKS_AHEAD: usize = 4;
+
+#[derive(Clone, Copy)]
+enum Coding {
+    Gzip,
+    Zstd,
+}
+
+impl Coding {
+    fn name(self) -> &'static str {
+        match self {
+            Coding::Gzip => "gzip",
+            Coding::Zstd => "zstd",
+        }
+    }
+}
+
+/// Why the decompressed body broke off, for the response in place of the handler's own. The
+/// first reason recorded wins, as the later ones follow from it.
+#[derive(Clone, Default)]
+struct Failure(Arc<Mutex<Option<ConversionError>>>);
+
+impl Failure {
+    fn record(&self, error: ConversionError) {
+        self.0.lock().unwrap().get_or_insert(error);
+    }
+
+    fn take(&self) -> Option<ConversionError> {
+        self.0.lock().unwrap().take()
+    }
+}
+
+/// Decompresses gzip and zstd request bodies into bodies of up to `limit` bytes (the route's
+/// limit, compressed or not) and hands them on without their `Content-Encoding`. Other encodings
+/// are refused.
+pub async fn decompress(State(limit): State<usize>, req: Request, next: Next) -> Response {
+    let encoding = match req.headers().get(header::CONTENT_ENCODING).map(|v| v.to_str().map(|e| e.trim().to_ascii_lowercase())) {
+        None => return next.run(req).await,
+        Some(Ok(encoding)) => encoding,
+        Some(Err(_)) => String::new(),
+    };
+    let coding = match encoding.as_str() {
+        "identity" => return next.run(req).await,
+        "gzip" | "x-gzip" => Coding::Gzip,
+        "zstd" => Coding::Zstd,
+        _ => {
+            return ConversionError::new(
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "UNSUPPORTED_CONTENT_ENCODING",
+                format!("Content-Encoding {:?} is not supported; use gzip or zstd", encoding),
+            )
+            .with_detail("supported", vec!["gzip", "zstd"])
+            .into_response();
+        }
+    };
+
+    let (mut parts, body) = req.into_parts();
+    parts.headers.remove(header::CONTENT_ENCODING);
+    parts.headers.remove(header::CONTENT_LENGTH);
+    let failure = Failure::default();
+    let body = decompressed(body, coding, limit, failure.clone());
+    let response = next.run(Request::from_parts(parts, body)).await;
+    // Whatever the handler made of a body that broke off, why it broke off is what to report
+    match failure.take() {
+        Some(error) => error.into_response(),
+        None => response,
+    }
+}
+
+/// `body` decompressed on a blocking thread, as a body that fails once past `limit` bytes.
+fn decompressed(body: Body, coding: Coding, limit: usize, failure: Failure) -> Body {
+    let (sender, chunks) = mpsc::channel(CHUNKS_AHEAD);
+    let input = BodyReader { stream: body.into_data_stream(), chunk: Bytes::new(), read: 0, limit, failure: failure.clone(), handle: Handle::current() };
+    let decoder_failure = failure.clone();
+    tokio::task::spawn_blocking(move || {
+        let mut sink = |chunk: &[u8]| sender.blocking_send(Ok(Bytes::copy_from_slice(chunk))).map_err(|_| DecodeError::Closed);
+        let result = match coding {
+            Coding::Gzip => inflate::gunzip_from(input, usize::MAX, &mut sink),
+            Coding::Zstd => zstd::decode_from(input, usize::MAX, &mut sink),
+        };
+        if let Err(DecodeError::Corrupt | DecodeError::TooLarge) = result {
+            decoder_failure.record(ConversionError::new(
+                StatusCode::BAD_REQUEST,
+                "INVALID_CONTENT_ENCODING",
+                format!("The request body is not valid {}", coding.name()),
+            ));
+            let _ = sender.blocking_send(Err(()));
+        }
+    });
+    Body::from_stream(Counted { chunks: Some(chunks), count: 0, limit, failure })
+}
+
+/// The compressed body, read on the decoder's blocking thread. Fails, recording why, if it
+/// breaks off or passes `limit` bytes.
+struct BodyReader {
+    stream: BodyDataStream,
+    chunk: Bytes,
+    read: usize,
+    limit: usize,
+    failure: Failure,
+    handle: Handle,
+}
+
+impl Read for BodyReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.chunk.is_empty() {
+            let error = match self.handle.block_on(self.stream.next()) {
+                None => return Ok(0),
+                Some(Ok(chunk)) if self.read + chunk.len() <= self.limit => {
+                    self.read += chunk.len();
+                    self.chunk = chunk;
+                    continue;
+                }
+                Some(Ok(_)) => too_large(self.limit),
+                Some(Err(_)) => ConversionError::new(StatusCode::BAD_REQUEST, "STREAM_INTERRUPTED", "Stream interrupted"),
+            };
+            self.failure.record(error);
+            return Err(io::Error::other("request body refused"));
+        }
+        let n = buf.len().min(self.chunk.len());
+        buf[..n].copy_from_slice(&self.chunk.split_to(n));
+        Ok(n)
+    }
+}
+
+/// The decompressed body as handed on, counting the bytes passed. Once past `limit`, it fails,
+/// recording why, and stops taking chunks, which stops the decoder.
+struct Counted {
+    chunks: Option<mpsc::Receiver<Result<Bytes, ()>>>,
+    count: usize,
+    limit: usize,
+    failure: Failure,
+}
+
+impl Stream for Counted {
+    type Item = io::Result<Bytes>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let Some(chunks) = self.chunks.as_mut() else {
+            return Poll::Ready(None);
+        };
+        let error = match ready!(chunks.poll_recv(cx)) {
+            Some(Ok(chunk)) if self.count + chunk.len() <= self.limit => {
+                self.count += chunk.len();
+                return Poll::Ready(Some(Ok(chunk)));
+            }
+            None => None,
+            Some(Ok(_)) => {
+                self.failure.record(too_large(self.limit));
+                Some(io::Error::other("request body too large once decompressed"))
+            }
+            Some(Err(())) => Some(io::Error::other("request body could not be decompressed")),
+        };
+        self.chunks = None;
+        Poll::Ready(error.map(Err))
+    }
+}
+
+fn too_large(limit: usize) -> ConversionError {
+    ConversionError::new(
+        StatusCode::PAYLOAD_TOO_LARGE,
+        "FILE_TOO_LARGE",
+        format!("Request bodies are limited to {} bytes once decompressed", limit),
+    )
+    .with_detail("limit", limit)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{middleware, routing::post, Router};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tower::ServiceExt;
+
+    /// `All work and no play makes Jack a dull boy.\n` 233 times (10252 bytes), gzipped.
+    const DULL_BOY_GZ: &[u8] = &[
+        0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x03, 0xed, 0xca, 0x3b, 0x0d, 0x80, 0x30, 0x14, 0x00, 0xc0,
+        0x1d, 0x15, 0x4f, 0x01, 0x1e, 0x58, 0x71, 0x51, 0x3e, 0x13, 0x85, 0x12, 0x08, 0x21, 0x75, 0x4f, 0x65, 0x30, 0xdc,
+        0x7a, 0xb9, 0x21, 0xe7, 0x78, 0xcb, 0xb5, 0x45, 0x3a, 0x96, 0x38, 0x4a, 0x9c, 0x39, 0xd5, 0xd8, 0xd3, 0xb6, 0xde,
+        0x31, 0xa6, 0xb9, 0x71, 0x2c, 0x4f, 0x2b, 0x53, 0xa9, 0x7d, 0x37, 0xb8, 0xae, 0xeb, 0xba, 0xae, 0xeb, 0xba, 0xae,
+        0xeb, 0xba, 0xae, 0xeb, 0xba, 0xae, 0xeb, 0xba, 0xae, 0xeb, 0xba, 0xae, 0xeb, 0xba, 0xae, 0xeb, 0xba, 0xae, 0xeb,
+        0xba, 0xae, 0xfb, 0xbb, 0xfb, 0x01, 0x01, 0x44, 0x39, 0xee, 0x0c, 0x28, 0x00, 0x00,
+    ];
+
+    /// The same, from `zstd -19 --check`.
+    const DULL_BOY_ZST: &[u8] = &[
+        0x28, 0xb5, 0x2f, 0xfd, 0x64, 0x0c, 0x27, 0x95, 0x01, 0x00, 0xc2, 0x02, 0x0a, 0x10, 0xb0, 0xeb, 0x24, 0xcc, 0x2c,
+        0x6b, 0xb5, 0x49, 0xb2, 0x8b, 0x58, 0x8a, 0x63, 0xde, 0x2a, 0x06, 0x20, 0xbc, 0x24, 0xfd, 0x9b, 0xa9, 0x8b, 0xc5,
+        0xa3, 0xec, 0xa0, 0x51, 0x9f, 0xaf, 0x9a, 0xa9, 0x26, 0xb9, 0xb8, 0xd2, 0xf5, 0x17, 0x01, 0x01, 0x00, 0xec, 0x3e,
+        0x8f, 0x57, 0x65, 0x37, 0xcc, 0xce, 0x45,
+    ];
+
+    async fn send(limit: usize, encoding: &str, body: impl Into<Body>) -> Response {
+        // Echoes the body it got, which comes without a length as it is streamed
+        let echo = |headers: axum::http::HeaderMap, body: Bytes| async move {
+            assert!(!headers.contains_key(header::CONTENT_ENCODING));
+            assert!(!headers.contains_key(header::CONTENT_LENGTH));
+            body
+        };
+        let app = Router::new().route("/convert", post(echo)).layer(middleware::from_fn_with_state(limit, decompress));
+        let request = Request::builder()
+            .method("POST")
+            .uri("/convert")
+            .header(header::CONTENT_ENCODING, encoding)
+            .body(body.into())
+            .unwrap();
+        app.oneshot(request).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_decompress() {
+        for (encoding, compressed) in [("gzip", DULL_BOY_GZ), ("zstd", DULL_BOY_ZST)] {
+            assert!(compressed.len() < 1024);
+            let response = send(16 * 1024, encoding, compressed).await;
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            assert_eq!(body, "All work and no play makes Jack a dull boy.\n".repeat(233));
+        }
+
+        let response = send(16 * 1024, "GZIP", "not gzip").await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let error: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(error["code"], "INVALID_CONTENT_ENCODING");
+        assert_eq!(send(16 * 1024, "zstd", DULL_BOY_GZ).await.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(send(16 * 1024, "zstd", &DULL_BOY_ZST[..40]).await.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(send(16 * 1024, "br", DULL_BOY_GZ).await.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+
+    #[tokio::test]
+    async fn test_limit_applies_to_decompressed_size() {
+        // Well within the limit compressed, past it decompressed
+        for (encoding, compressed) in [("gzip", DULL_BOY_GZ), ("zstd", DULL_BOY_ZST)] {
+            let response = send(8 * 1024, encoding, compressed).await;
+            assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            let error: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(error["code"], "FILE_TOO_LARGE");
+            assert_eq!(error["limit"], 8192);
+
+            assert_eq!(send(64, encoding, compressed).await.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_body_is_streamed() {
+        // A MiB of zeros in a few KiB of gzip, against a limit of 256 KiB
+        let bomb = crate::deflate::gzip(&vec![0; 1024 * 1024]);
+        assert!(bomb.len() < 16 * 1024);
+        let limit = 256 * 1024;
+        let seen = Arc::new(AtomicUsize::new(0));
+        let counted = seen.clone();
+        // Reads the body as it comes, as multipart uploads are read
+        let handler = move |body: Body| async move {
+            let mut stream = body.into_data_stream();
+            while let Some(Ok(chunk)) = stream.next().await {
+                counted.fetch_add(chunk.len(), Ordering::Relaxed);
+            }
+            StatusCode::BAD_REQUEST
+        };
+        let app = Router::new().route("/convert", post(handler)).layer(middleware::from_fn_with_state(limit, decompress));
+        let request = Request::builder().method("POST").uri("/convert").header(header::CONTENT_ENCODING, "gzip").body(Body::from(bomb)).unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        // The handler got no more than the limit, in pieces, before the body failed
+        let seen = seen.load(Ordering::Relaxed);
+        assert!(seen > 0 && seen <= limit, "{}", seen);
+    }
+}
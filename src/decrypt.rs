@@ -0,0 +1,119 @@
+//! Decryption of password-protected Office Open XML inputs, which LibreOffice cannot open without
+//! someone to type the password. An encrypted `.docx`, `.xlsx` or `.pptx` is not a zip but an OLE2
+//! container holding `EncryptionInfo` and `EncryptedPackage` streams; msoffcrypto-tool
+//! (`MSOFFCRYPTO_PATH`) turns it back into the package with the `input_password` form field.
+//!
+//! The plain copy is written beside the upload, inside the request's work directory, and removed
+//! as soon as it is converted. The password is passed to the tool as an argument and never logged.
+
+use axum::http::StatusCode;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::fs;
+use tokio::process::Command;
+use tokio::time::Instant;
+use tracing::{error, info};
+
+use crate::error::ConversionError;
+use crate::{conversion_timeout_error, run_until, Secret};
+
+/// What msoffcrypto-tool reports on stderr when the password is wrong.
+const WRONG_PASSWORD_MARKERS: &[&str] = &["InvalidKeyError", "incorrect password", "Key verification failed"];
+
+fn password_protected() -> ConversionError {
+    ConversionError::new(
+        StatusCode::BAD_REQUEST,
+        "PASSWORD_PROTECTED",
+        "The document is password-protected; send its password as input_password",
+    )
+}
+
+/// Decrypts `input` with `password` into a `decrypted` directory beside it, keeping its name so
+/// the output is named after the upload. `tool` is `MSOFFCRYPTO_PATH`, if set.
+pub async fn decrypt(
+    tool: Option<&str>,
+    input: &Path,
+    password: Option<&Secret>,
+    deadline: Instant,
+    timeout: Duration,
+) -> Result<PathBuf, ConversionError> {
+    let Some(Secret(password)) = password else {
+        return Err(password_protected());
+    };
+    let Some(tool) = tool else {
+        return Err(ConversionError::new(
+            StatusCode::BAD_REQUEST,
+            "DECRYPTION_UNAVAILABLE",
+            "input_password needs msoffcrypto-tool; set MSOFFCRYPTO_PATH",
+        ));
+    };
+    let failed = || ConversionError::new(StatusCode::INTERNAL_SERVER_ERROR, "DECRYPTION_FAILED", "Decrypting the document failed");
+    let dir = input.parent().unwrap_or(Path::new(".")).join("decrypted");
+    fs::create_dir_all(&dir).await.map_err(|e| {
+        error!("Failed to create decryption dir: {}", e);
+        ConversionError::internal()
+    })?;
+    let output = dir.join(input.file_name().unwrap_or_default());
+
+    // Not printed: the arguments include the password
+    let mut command = Command::new(tool);
+    command.arg("-p").arg(password).arg(input).arg(&output);
+    match run_until(command, deadline).await {
+        Ok(Some(out)) if out.status.success() => {
+            info!("Decrypted {:?}", input);
+            Ok(output)
+        }
+        Ok(Some(out)) => {
+            let _ = fs::remove_file(&output).await;
+            let stderr = String::from_utf8_lossy(&out.stderr);
+            if WRONG_PASSWORD_MARKERS.iter().any(|marker| stderr.contains(marker)) {
+                info!("Wrong input_password for {:?}", input);
+                return Err(ConversionError::new(StatusCode::BAD_REQUEST, "INVALID_INPUT_PASSWORD", "input_password does not open the document"));
+            }
+            error!("msoffcrypto-tool failed: stderr: {}", stderr);
+            Err(failed())
+        }
+        Ok(None) => {
+            let _ = fs::remove_file(&output).await;
+            error!("msoffcrypto-tool timed out");
+            Err(conversion_timeout_error(timeout))
+        }
+        Err(e) => {
+            error!("Failed to run msoffcrypto-tool: {}", e);
+            Err(failed())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[tokio::test]
+    async fn test_decrypt() {
+        let dir = std::env::temp_dir().join(format!("decrypt-test-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(dir.join("0")).unwrap();
+        let input = dir.join("0/report.docx");
+        std::fs::write(&input, b"encrypted").unwrap();
+        // Stands in for msoffcrypto-tool: accepts one password, copying the file
+        let tool = dir.join("msoffcrypto-tool");
+        std::fs::write(&tool, "#!/bin/sh\n[ \"$2\" = right ] || { echo 'msoffcrypto.exceptions.InvalidKeyError' >&2; exit 1; }\ncp \"$3\" \"$4\"\n").unwrap();
+        std::fs::set_permissions(&tool, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let tool = tool.to_str();
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let timeout = Duration::from_secs(10);
+        let secret = |p: &str| Secret(p.to_string());
+
+        let decrypted = decrypt(tool, &input, Some(&secret("right")), deadline, timeout).await.unwrap();
+        assert_eq!(decrypted, dir.join("0/decrypted/report.docx"));
+        assert_eq!(std::fs::read(&decrypted).unwrap(), b"encrypted");
+        let wrong = decrypt(tool, &input, Some(&secret("wrong")), deadline, timeout).await.unwrap_err();
+        let missing = decrypt(tool, &input, None, deadline, timeout).await.unwrap_err();
+        let unavailable = decrypt(None, &input, Some(&secret("right")), deadline, timeout).await.unwrap_err();
+        let _ = std::fs::remove_dir_all(&dir);
+        assert_eq!((wrong.status, wrong.code), (StatusCode::BAD_REQUEST, "INVALID_INPUT_PASSWORD"));
+        assert_eq!(missing.code, "PASSWORD_PROTECTED");
+        assert_eq!(unavailable.code, "DECRYPTION_UNAVAILABLE");
+    }
+}
@@ -0,0 +1,221 @@
+//! Raw DEFLATE (RFC 1951) compression and its gzip (RFC 1952) and zlib (RFC 1950) wrappings,
+//! for compressing responses.
+//!
+//! Matches are found with hash chains over the 32 KiB window, taking the longest of a bounded
+//! number of candidates, and everything goes out as one block of the fixed Huffman codes. That
+//! gives up some ratio next to zlib's dynamic codes and lazy matching, but stays short and fast.
+
+use crate::inflate::{DIST_BASE, DIST_EXTRA, LENGTH_BASE, LENGTH_EXTRA};
+
+const WINDOW: usize = 32 * 1024;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+const HASH_BITS: u32 = 15;
+/// Candidates looked at for each position; more finds longer matches, slower.
+const MAX_CHAIN: usize = 32;
+const NONE: usize = usize::MAX;
+
+/// Collects bits least significant first, as DEFLATE (and Brotli) packs them.
+pub struct BitWriter {
+    out: Vec<u8>,
+    bits: u64,
+    count: u32,
+}
+
+impl BitWriter {
+    pub fn new(capacity: usize) -> Self {
+        BitWriter { out: Vec::with_capacity(capacity), bits: 0, count: 0 }
+    }
+
+    /// Writes the low `count` bits of `value`, at most 32.
+    pub fn write(&mut self, value: u32, count: u32) {
+        self.bits |= (value as u64) << self.count;
+        self.count += count;
+        while self.count >= 8 {
+            self.out.push(self.bits as u8);
+            self.bits >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    /// Writes a Huffman code, which goes most significant bit first.
+    pub fn write_code(&mut self, code: u32, len: u32) {
+        self.write(code.reverse_bits() >> (32 - len), len);
+    }
+
+    pub fn finish(mut self) -> Vec<u8> {
+        if self.count > 0 {
+            self.out.push(self.bits as u8);
+        }
+        self.out
+    }
+}
+
+/// Writes literal/length symbol `symbol` in the fixed code.
+fn write_symbol(writer: &mut BitWriter, symbol: u32) {
+    match symbol {
+        0..=143 => writer.write_code(0x30 + symbol, 8),
+        144..=255 => writer.write_code(0x190 + symbol - 144, 9),
+        256..=279 => writer.write_code(symbol - 256, 7),
+        _ => writer.write_code(0xC0 + symbol - 280, 8),
+    }
+}
+
+fn write_match(writer: &mut BitWriter, len: usize, dist: usize) {
+    let code = LENGTH_BASE.partition_point(|&base| base as usize <= len) - 1;
+    write_symbol(writer, 257 + code as u32);
+    writer.write((len - LENGTH_BASE[code] as usize) as u32, LENGTH_EXTRA[code] as u32);
+    let code = DIST_BASE.partition_point(|&base| base as usize <= dist) - 1;
+    writer.write_code(code as u32, 5);
+    writer.write((dist - DIST_BASE[code] as usize) as u32, DIST_EXTRA[code] as u32);
+}
+
+fn hash(data: &[u8]) -> usize {
+    let key = u32::from_le_bytes([data[0], data[1], data[2], 0]);
+    (key.wrapping_mul(0x9E37_79B1) >> (32 - HASH_BITS)) as usize
+}
+
+/// For each hash of three bytes, the positions they were last seen at, newest first.
+struct Chains {
+    /// The newest position of each hash.
+    head: Vec<usize>,
+    /// The position before each one in the window with the same hash.
+    prev: Vec<usize>,
+}
+
+impl Chains {
+    fn insert(&mut self, data: &[u8], pos: usize) {
+        if pos + MIN_MATCH <= data.len() {
+            let h = hash(&data[pos..]);
+            let window = self.prev.len();
+            self.prev[pos % window] = self.head[h];
+            self.head[h] = pos;
+        }
+    }
+}
+
+/// One step of an LZ77 parse.
+pub enum Token {
+    Literal(u8),
+    /// `len` bytes copied from `dist` bytes back.
+    Copy { len: usize, dist: usize },
+}
+
+/// Parses `data` into literals and copies of at least three bytes, at most `max_match` long and
+/// `window` back, passing each to `emit` in order.
+pub fn lz77(data: &[u8], window: usize, max_match: usize, mut emit: impl FnMut(Token)) {
+    let mut chains = Chains { head: vec![NONE; 1 << HASH_BITS], prev: vec![NONE; window] };
+    let mut pos = 0;
+    while pos < data.len() {
+        let max_len = max_match.min(data.len() - pos);
+        let (mut best_len, mut best_dist) = (0, 0);
+        if max_len >= MIN_MATCH {
+            let mut candidate = chains.head[hash(&data[pos..])];
+            for _ in 0..MAX_CHAIN {
+                if candidate == NONE || pos - candidate > window {
+                    break;
+                }
+                let len = data[candidate..].iter().zip(&data[pos..pos + max_len]).take_while(|(a, b)| a == b).count();
+                if len > best_len {
+                    (best_len, best_dist) = (len, pos - candidate);
+                    if len == max_len {
+                        break;
+                    }
+                }
+                let next = chains.prev[candidate % window];
+                // The slot was reused by a newer position: the chain ends here
+                if next == NONE || next >= candidate {
+                    break;
+                }
+                candidate = next;
+            }
+        }
+
+        if best_len >= MIN_MATCH {
+            emit(Token::Copy { len: best_len, dist: best_dist });
+            for p in pos..pos + best_len {
+                chains.insert(data, p);
+            }
+            pos += best_len;
+        } else {
+            emit(Token::Literal(data[pos]));
+            chains.insert(data, pos);
+            pos += 1;
+        }
+    }
+}
+
+/// Compresses `data` into a raw DEFLATE stream.
+pub fn deflate(data: &[u8]) -> Vec<u8> {
+    let mut writer = BitWriter::new(data.len() / 2);
+    // Final block, fixed codes
+    writer.write(1, 1);
+    writer.write(1, 2);
+    lz77(data, WINDOW, MAX_MATCH, |token| match token {
+        Token::Literal(byte) => write_symbol(&mut writer, byte as u32),
+        Token::Copy { len, dist } => write_match(&mut writer, len, dist),
+    });
+    write_symbol(&mut writer, 256);
+    writer.finish()
+}
+
+/// Compresses `data` into a gzip file.
+pub fn gzip(data: &[u8]) -> Vec<u8> {
+    // No name or timestamp; the OS is "unknown"
+    let mut out = vec![0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 0xff];
+    out.extend_from_slice(&deflate(data));
+    out.extend_from_slice(&crate::zip::crc32(data).to_le_bytes());
+    out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    out
+}
+
+/// Compresses `data` into a zlib stream, which is what HTTP's `deflate` coding means.
+pub fn zlib(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![0x78, 0x01];
+    out.extend_from_slice(&deflate(data));
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+    out
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    // The sums cannot overflow within this many bytes
+    for chunk in data.chunks(5552) {
+        for &byte in chunk {
+            a += byte as u32;
+            b += a;
+        }
+        a %= 65521;
+        b %= 65521;
+    }
+    (b << 16) | a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::inflate::{gunzip, inflate};
+
+    #[test]
+    fn test_roundtrip() {
+        // Pseudo-random bytes do not compress; the long runs and repeats do, overlapping too
+        let mut state = 1u32;
+        let noise: Vec<u8> = (0..70_000)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                (state >> 16) as u8
+            })
+            .collect();
+        let text = b"All work and no play makes Jack a dull boy.\n".repeat(2_000);
+        let inputs: [&[u8]; 6] = [b"", b"a", b"abcabcabcabcabc", &[0; 1000], &noise, &text];
+        for input in inputs {
+            assert_eq!(inflate(&deflate(input), usize::MAX).unwrap(), input);
+            assert_eq!(gunzip(&gzip(input), usize::MAX).unwrap(), input);
+            let zlib = zlib(input);
+            assert_eq!(u16::from_be_bytes([zlib[0], zlib[1]]) % 31, 0, "zlib header check bits");
+            assert_eq!(inflate(&zlib[2..zlib.len() - 4], usize::MAX).unwrap(), input);
+        }
+        assert!(deflate(&text).len() < text.len() / 20);
+        assert_eq!(adler32(b"Wikipedia"), 0x11E6_0398);
+    }
+}
@@ -0,0 +1,326 @@
+//! Detection of an uploaded file's real type from its content, before LibreOffice sees it.
+//!
+//! Office formats are containers, so the leading magic bytes alone only say "zip" or "OLE2". For
+//! those the entry names (ZIP central directory) or stream names (OLE2 root directory) decide
+//! between e.g. `docx` and `xlsx`. Text formats have no magic; they are told apart by content
+//! (HTML) or, for CSV and Markdown, by the declared extension. A password-protected Office Open
+//! XML file is an OLE2 container too; it is taken to be the package its extension names, since
+//! nothing else can be told without the password.
+
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+
+use crate::zip;
+
+/// Input types accepted when `ALLOWED_INPUT_TYPES` is not set.
+pub const DEFAULT_ALLOWED_TYPES: &[&str] = &[
+    "docx", "doc", "xlsx", "xls", "pptx", "ppt", "odt", "ods", "odp", "rtf", "csv", "html", "md",
+];
+
+/// Upload extensions accepted when `ALLOWED_EXTENSIONS` is not set: every name the accepted
+/// types (and PDF) commonly go by, plus zip archives holding one of them (see `archive`), so that
+/// by default only the content check narrows things down.
+pub const DEFAULT_ALLOWED_EXTENSIONS: &[&str] = &[
+    "docx", "docm", "dotx", "dotm", "doc", "dot", "xlsx", "xlsm", "xltx", "xltm", "xls", "pptx", "pptm", "potx", "potm",
+    "ppt", "odt", "ott", "ods", "ots", "odp", "otp", "rtf", "csv", "txt", "html", "htm", "xml", "md", "markdown", "pdf",
+    "zip",
+];
+
+/// Extensions of Office Open XML formats that may carry VBA macros. Uploads with one of these
+/// whose content is such a package are refused unless `ALLOW_MACROS` is set.
+pub const MACRO_ENABLED_EXTENSIONS: &[&str] = &["docm", "dotm", "xlsm", "xltm", "pptm", "potm"];
+
+const HEAD_LEN: usize = 8192;
+const OLE2_MAGIC: &[u8] = &[0xd0, 0xcf, 0x11, 0xe0, 0xa1, 0xb1, 0x1a, 0xe1];
+/// Larger central directories are not read; such an archive is reported as plain `zip`.
+const MAX_CENTRAL_DIRECTORY: u64 = 4 * 1024 * 1024;
+
+/// Detects the type of the file at `path`. `declared_extension` only breaks ties between text formats.
+pub async fn detect_file(path: &Path, declared_extension: &str) -> io::Result<&'static str> {
+    let path = path.to_path_buf();
+    let declared_extension = declared_extension.to_ascii_lowercase();
+    tokio::task::spawn_blocking(move || detect(&mut std::fs::File::open(path)?, &declared_extension))
+        .await
+        .map_err(io::Error::other)?
+}
+
+pub fn detect<R: Read + Seek>(file: &mut R, declared_extension: &str) -> io::Result<&'static str> {
+    let mut head = Vec::with_capacity(HEAD_LEN);
+    file.by_ref().take(HEAD_LEN as u64).read_to_end(&mut head)?;
+
+    if head.starts_with(b"PK\x03\x04") {
+        return detect_zip(file, &head);
+    }
+    if head.starts_with(OLE2_MAGIC) {
+        return detect_ole2(file, &head, declared_extension);
+    }
+
+    let magic: &[(&[u8], &str)] = &[
+        (b"{\\rtf", "rtf"),
+        (b"%PDF-", "pdf"),
+        (b"\x89PNG\r\n\x1a\n", "png"),
+        (b"\xff\xd8\xff", "jpg"),
+        (b"GIF8", "gif"),
+        (b"\x1f\x8b", "gz"),
+        (b"\x7fELF", "elf"),
+        (b"MZ", "exe"),
+    ];
+    if let Some((_, kind)) = magic.iter().find(|(prefix, _)| head.starts_with(prefix)) {
+        return Ok(kind);
+    }
+
+    if head.contains(&0) {
+        return Ok("unknown");
+    }
+    let text = String::from_utf8_lossy(head.strip_prefix(b"\xef\xbb\xbf").unwrap_or(&head)).to_ascii_lowercase();
+    let text = text.trim_start();
+    // READMEs often quote HTML, so a declared Markdown file stays Markdown
+    if matches!(declared_extension, "md" | "markdown") {
+        return Ok("md");
+    }
+    if text.starts_with("<!doctype html") || text.starts_with("<html") || text.contains("<html") {
+        return Ok("html");
+    }
+    if text.starts_with("<?xml") {
+        return Ok("xml");
+    }
+    Ok(if declared_extension == "csv" { "csv" } else { "txt" })
+}
+
+fn detect_zip<R: Read + Seek>(file: &mut R, head: &[u8]) -> io::Result<&'static str> {
+    // ODF packages store an uncompressed `mimetype` entry first, so its content sits at offset 38
+    if head.get(30..38) == Some(b"mimetype") {
+        let mimetype = &head[38..head.len().min(38 + 64)];
+        let odf: &[(&[u8], &str)] = &[
+            (b"application/vnd.oasis.opendocument.text", "odt"),
+            (b"application/vnd.oasis.opendocument.spreadsheet", "ods"),
+            (b"application/vnd.oasis.opendocument.presentation", "odp"),
+            (b"application/vnd.oasis.opendocument.graphics", "odg"),
+        ];
+        if let Some((_, kind)) = odf.iter().find(|(prefix, _)| mimetype.starts_with(prefix)) {
+            return Ok(kind);
+        }
+    }
+
+    let len = file.seek(SeekFrom::End(0))?;
+    let tail_len = len.min(zip::MAX_END_OF_CENTRAL_DIR_SEARCH as u64);
+    let mut tail = vec![0; tail_len as usize];
+    file.seek(SeekFrom::Start(len - tail_len))?;
+    file.read_exact(&mut tail)?;
+
+    let Some((offset, size)) = zip::find_central_directory(&tail) else {
+        return Ok("zip");
+    };
+    if size > MAX_CENTRAL_DIRECTORY || offset.saturating_add(size) > len {
+        return Ok("zip");
+    }
+    let mut central = vec![0; size as usize];
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(&mut central)?;
+
+    let names = zip::central_directory_names(&central);
+    let has = |name: &str| names.iter().any(|n| n == name);
+    Ok(if has("word/document.xml") {
+        "docx"
+    } else if has("xl/workbook.xml") {
+        "xlsx"
+    } else if has("ppt/presentation.xml") {
+        "pptx"
+    } else {
+        "zip"
+    })
+}
+
+/// Looks for the main stream in the OLE2 (Compound File) directory.
+fn detect_ole2<R: Read + Seek>(file: &mut R, head: &[u8], declared_extension: &str) -> io::Result<&'static str> {
+    let Some(names) = ole2_names(file, head)? else {
+        return Ok("ole2");
+    };
+    let has = |name: &str| names.iter().any(|n| n == name);
+    if has("EncryptionInfo") && has("EncryptedPackage") {
+        return Ok(match declared_extension {
+            "xlsx" | "xlsm" | "xltx" | "xltm" => "xlsx",
+            "pptx" | "pptm" | "potx" | "potm" => "pptx",
+            _ => "docx",
+        });
+    }
+    Ok(if has("WordDocument") {
+        "doc"
+    } else if has("Workbook") || has("Book") {
+        "xls"
+    } else if has("PowerPoint Document") {
+        "ppt"
+    } else {
+        "ole2"
+    })
+}
+
+/// The stream names in the first sector of an OLE2 file's directory, or `None` if it cannot be read.
+fn ole2_names<R: Read + Seek>(file: &mut R, head: &[u8]) -> io::Result<Option<Vec<String>>> {
+    let (Some(shift), Some(first_dir_sector)) = (
+        head.get(0x1e..0x20).map(|b| u16::from_le_bytes([b[0], b[1]])),
+        head.get(0x30..0x34).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]])),
+    ) else {
+        return Ok(None);
+    };
+    if shift != 9 && shift != 12 {
+        return Ok(None);
+    }
+    let sector_size = 1u64 << shift;
+    let mut sector = vec![0; sector_size as usize];
+    // Sector n starts after the one-sector header
+    file.seek(SeekFrom::Start((first_dir_sector as u64 + 1) * sector_size))?;
+    if file.read_exact(&mut sector).is_err() {
+        return Ok(None);
+    }
+
+    // 128-byte entries; the name is UTF-16LE in the first 64 bytes, its byte length at 0x40
+    Ok(Some(
+        sector
+            .chunks_exact(128)
+            .map(|entry| {
+                let len = (u16::from_le_bytes([entry[0x40], entry[0x41]]) as usize).min(64);
+                let units: Vec<u16> = entry[..len].chunks_exact(2).map(|c| u16::from_le_bytes([c[0], c[1]])).collect();
+                String::from_utf16_lossy(&units).trim_end_matches('\0').to_string()
+            })
+            .collect(),
+    ))
+}
+
+/// Whether the file at `path` is a password-protected Office Open XML package.
+pub async fn is_encrypted_package(path: &Path) -> io::Result<bool> {
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        let mut file = std::fs::File::open(path)?;
+        let mut head = Vec::with_capacity(512);
+        file.by_ref().take(512).read_to_end(&mut head)?;
+        if !head.starts_with(OLE2_MAGIC) {
+            return Ok(false);
+        }
+        let names = ole2_names(&mut file, &head)?.unwrap_or_default();
+        Ok(names.iter().any(|n| n == "EncryptionInfo") && names.iter().any(|n| n == "EncryptedPackage"))
+    })
+    .await
+    .map_err(io::Error::other)?
+}
+
+/// The MIME type of a detected `kind`.
+pub fn mime_type(kind: &str) -> &'static str {
+    match kind {
+        "docx" => "application/vnd.openxmlformats-officedocument.wordprocessingml.document",
+        "xlsx" => "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+        "pptx" => "application/vnd.openxmlformats-officedocument.presentationml.presentation",
+        "doc" => "application/msword",
+        "xls" => "application/vnd.ms-excel",
+        "ppt" => "application/vnd.ms-powerpoint",
+        "odt" => "application/vnd.oasis.opendocument.text",
+        "ods" => "application/vnd.oasis.opendocument.spreadsheet",
+        "odp" => "application/vnd.oasis.opendocument.presentation",
+        "odg" => "application/vnd.oasis.opendocument.graphics",
+        "rtf" => "application/rtf",
+        "pdf" => "application/pdf",
+        "html" => "text/html",
+        "md" => "text/markdown",
+        "csv" => "text/csv",
+        "xml" => "application/xml",
+        "txt" => "text/plain",
+        "zip" => "application/zip",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Parses `ALLOWED_INPUT_TYPES` (comma-separated), falling back to [`DEFAULT_ALLOWED_TYPES`].
+pub fn parse_allowed_types(raw: Option<&str>) -> Vec<String> {
+    parse_list(raw, DEFAULT_ALLOWED_TYPES)
+}
+
+/// Parses `ALLOWED_EXTENSIONS`; a leading dot is optional.
+pub fn parse_allowed_extensions(raw: Option<&str>) -> Vec<String> {
+    parse_list(raw, DEFAULT_ALLOWED_EXTENSIONS)
+        .into_iter()
+        .map(|ext| ext.trim_start_matches('.').to_string())
+        .collect()
+}
+
+fn parse_list(raw: Option<&str>, default: &[&str]) -> Vec<String> {
+    match raw {
+        Some(list) => list
+            .split(',')
+            .map(|t| t.trim().to_ascii_lowercase())
+            .filter(|t| !t.is_empty())
+            .collect(),
+        None => default.iter().map(|t| t.to_string()).collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn detect_bytes(bytes: Vec<u8>, ext: &str) -> &'static str {
+        detect(&mut Cursor::new(bytes), ext).unwrap()
+    }
+
+    #[test]
+    fn test_detect_containers() {
+        let mut docx = zip::ZipWriter::new();
+        docx.add("[Content_Types].xml", b"<Types/>");
+        docx.add("word/document.xml", b"<w:document/>");
+        assert_eq!(detect_bytes(docx.finish(), "pdf"), "docx");
+
+        let mut odt = zip::ZipWriter::new();
+        odt.add("mimetype", b"application/vnd.oasis.opendocument.text");
+        assert_eq!(detect_bytes(odt.finish(), "docx"), "odt");
+
+        let mut other = zip::ZipWriter::new();
+        other.add("payload.exe", b"MZ");
+        assert_eq!(detect_bytes(other.finish(), "docx"), "zip");
+
+        // Minimal compound file: 512-byte sectors, directory in sector 0
+        let mut ole = vec![0u8; 1024];
+        ole[..8].copy_from_slice(OLE2_MAGIC);
+        ole[0x1e] = 9;
+        let name: Vec<u8> = "WordDocument\0".encode_utf16().flat_map(u16::to_le_bytes).collect();
+        ole[512 + 128..512 + 128 + name.len()].copy_from_slice(&name);
+        ole[512 + 128 + 0x40] = name.len() as u8;
+        assert_eq!(detect_bytes(ole, "xls"), "doc");
+    }
+
+    #[tokio::test]
+    async fn test_detect_encrypted_package() {
+        let mut ole = vec![0u8; 1024];
+        ole[..8].copy_from_slice(OLE2_MAGIC);
+        ole[0x1e] = 9;
+        for (i, name) in ["EncryptionInfo\0", "EncryptedPackage\0"].iter().enumerate() {
+            let name: Vec<u8> = name.encode_utf16().flat_map(u16::to_le_bytes).collect();
+            let entry = 512 + 128 * (i + 1);
+            ole[entry..entry + name.len()].copy_from_slice(&name);
+            ole[entry + 0x40] = name.len() as u8;
+        }
+        // Only the extension tells which package is inside
+        assert_eq!(detect_bytes(ole.clone(), "xlsx"), "xlsx");
+        assert_eq!(detect_bytes(ole.clone(), "docx"), "docx");
+
+        let path = std::env::temp_dir().join(format!("encrypted-{}.docx", std::process::id()));
+        std::fs::write(&path, &ole).unwrap();
+        let encrypted = is_encrypted_package(&path).await.unwrap();
+        std::fs::write(&path, b"PK\x03\x04").unwrap();
+        let plain = is_encrypted_package(&path).await.unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert!(encrypted);
+        assert!(!plain);
+    }
+
+    #[test]
+    fn test_detect_text_and_magic() {
+        assert_eq!(detect_bytes(b"{\\rtf1\\ansi hello}".to_vec(), "doc"), "rtf");
+        assert_eq!(detect_bytes(b"\xef\xbb\xbf<!DOCTYPE html><p>hi".to_vec(), "docx"), "html");
+        assert_eq!(detect_bytes(b"a,b\n1,2\n".to_vec(), "csv"), "csv");
+        assert_eq!(detect_bytes(b"a,b\n1,2\n".to_vec(), "docx"), "txt");
+        assert_eq!(detect_bytes(b"# Title\n\nText".to_vec(), "markdown"), "md");
+        assert_eq!(detect_bytes(b"# Title\n\n<html>".to_vec(), "md"), "md");
+        assert_eq!(detect_bytes(b"MZ\x90\x00".to_vec(), "docx"), "exe");
+        assert_eq!(detect_bytes(b"%PDF-1.7".to_vec(), "docx"), "pdf");
+    }
+}
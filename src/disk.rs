@@ -0,0 +1,111 @@
+//! Free disk space checks. LibreOffice does not report a full disk; it just writes a truncated
+//! or empty PDF, so conversions refuse to start when the temp filesystem is nearly full. Also
+//! what kind of storage a work directory is on.
+
+use axum::http::StatusCode;
+use std::ffi::CString;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use tracing::{error, warn};
+
+use crate::error::ConversionError;
+
+pub const DEFAULT_MIN_FREE_BYTES: u64 = 512 * 1024 * 1024;
+
+/// Bytes available to unprivileged users on the filesystem containing `path`.
+pub fn available_bytes(path: &Path) -> io::Result<u64> {
+    let c_path = CString::new(path.as_os_str().as_bytes()).map_err(io::Error::other)?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    // SAFETY: `c_path` is NUL-terminated and `stat` is a valid, writable statvfs
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+/// `tmpfs` if `path` is on a memory-backed filesystem, otherwise `disk`; for labelling metrics.
+pub fn storage_kind(path: &Path) -> &'static str {
+    let Ok(c_path) = CString::new(path.as_os_str().as_bytes()) else {
+        return "disk";
+    };
+    let mut stat: libc::statfs = unsafe { std::mem::zeroed() };
+    // SAFETY: `c_path` is NUL-terminated and `stat` is a valid, writable statfs
+    if unsafe { libc::statfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return "disk";
+    }
+    // `f_type` and the magic constants have different integer types across targets
+    #[allow(clippy::unnecessary_cast)]
+    let is_tmpfs = stat.f_type as i64 == libc::TMPFS_MAGIC as i64;
+    if is_tmpfs { "tmpfs" } else { "disk" }
+}
+
+/// Fails with 503 if fewer than `min_free` bytes are available under `path`.
+pub fn ensure_free_space(path: &Path, min_free: u64) -> Result<(), ConversionError> {
+    let available = available_bytes(path).map_err(|e| {
+        error!("Failed to check free disk space: {}", e);
+        ConversionError::internal()
+    })?;
+    if available >= min_free {
+        return Ok(());
+    }
+    warn!("Only {} bytes free in {:?}, need {}", available, path, min_free);
+    Err(ConversionError::new(
+        StatusCode::SERVICE_UNAVAILABLE,
+        "INSUFFICIENT_DISK_SPACE",
+        "Not enough free disk space to convert right now",
+    ))
+}
+
+/// Creates `dir` if needed and checks that this process may write in it, without writing
+/// anything, so that concurrent callers do not trip over each other.
+pub fn check_writable(dir: &Path) -> io::Result<()> {
+    std::fs::create_dir_all(dir)?;
+    let c_path = CString::new(dir.as_os_str().as_bytes()).map_err(io::Error::other)?;
+    // SAFETY: `c_path` is NUL-terminated
+    if unsafe { libc::access(c_path.as_ptr(), libc::W_OK) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Creates `dir` if needed and checks that files can be written in it, by creating and deleting
+/// a probe file.
+pub fn ensure_writable(dir: &Path) -> io::Result<()> {
+    std::fs::create_dir_all(dir)?;
+    let probe = dir.join(format!(".write-probe-{}", std::process::id()));
+    std::fs::write(&probe, b"")?;
+    std::fs::remove_file(&probe)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ensure_free_space() {
+        assert!(available_bytes(Path::new("/tmp")).unwrap() > 0);
+        assert!(ensure_free_space(Path::new("/tmp"), 0).is_ok());
+        assert_eq!(ensure_free_space(Path::new("/tmp"), u64::MAX).unwrap_err().code, "INSUFFICIENT_DISK_SPACE");
+    }
+
+    #[test]
+    fn test_ensure_writable() {
+        let dir = std::env::temp_dir().join(format!("writable-{}/nested", std::process::id()));
+        ensure_writable(&dir).unwrap();
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+        let _ = std::fs::remove_dir_all(dir.parent().unwrap());
+
+        // A regular file is in the way of the directory
+        assert!(ensure_writable(Path::new("/proc/self/status/dir")).is_err());
+        assert!(check_writable(Path::new("/proc/self/status/dir")).is_err());
+        assert!(check_writable(&std::env::temp_dir()).is_ok());
+    }
+
+    #[test]
+    fn test_storage_kind() {
+        assert_eq!(storage_kind(Path::new("/dev/shm")), "tmpfs");
+        assert_eq!(storage_kind(Path::new("/proc")), "disk");
+        assert_eq!(storage_kind(Path::new("/nonexistent")), "disk");
+    }
+}
@@ -0,0 +1,296 @@
+//! One-time download links for `POST /convert?response=url`.
+//!
+//! The converted file is parked on disk and the caller gets `/download/<token>`, where the token
+//! is `base64url(id || expiry || HMAC-SHA256(id || expiry))`. The HMAC key is random per process,
+//! so links do not survive a restart. A link works once: the file is deleted as it is served.
+//!
+//! `POST /sign-download/{job_id}` hands out links to the result of an async job the same way,
+//! so that whoever only needs the PDF does not need the API key. Those tokens are
+//! `base64url(token id || job id || expiry || HMAC-SHA256(...))`: they name the job, never a
+//! path, and stay valid for five minutes. The job keeps its result, so the token id is recorded
+//! when it is used, until it expires, to make it work once.
+
+use axum::{
+    body::{Body, Bytes},
+    extract::{Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde_json::json;
+use std::collections::HashMap;
+use std::io::{self, Read};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::fs;
+use tokio::io::AsyncReadExt;
+use tracing::{error, info};
+use uuid::Uuid;
+
+use crate::crypto::{base64_decode, base64url_encode, constant_time_eq, hmac_sha256};
+use crate::error::ConversionError;
+use crate::{file_body_response, file_response, AppState, ConvertedFile, Disposition};
+
+pub const DEFAULT_TOKEN_TTL_SECS: u64 = 900;
+/// How long a link from `POST /sign-download/{job_id}` works.
+pub const JOB_TOKEN_TTL_SECS: u64 = 300;
+/// id (16 bytes) + expiry (8) + MAC (32)
+const TOKEN_LEN: usize = 56;
+/// token id (16 bytes) + job id (16) + expiry (8) + MAC (32)
+const JOB_TOKEN_LEN: usize = 72;
+const READ_CHUNK: usize = 64 * 1024;
+
+struct Download {
+    path: PathBuf,
+    filename: String,
+    content_type: &'static str,
+    headers: Vec<(&'static str, String)>,
+    expires_at: u64,
+}
+
+pub struct DownloadStore {
+    dir: PathBuf,
+    key: [u8; 32],
+    ttl: Duration,
+    downloads: Mutex<HashMap<Uuid, Download>>,
+    /// Ids of used job links, with their expiry.
+    used_job_tokens: Mutex<HashMap<Uuid, u64>>,
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+impl DownloadStore {
+    pub fn new(dir: PathBuf, ttl: Duration) -> io::Result<Self> {
+        let mut key = [0u8; 32];
+        std::fs::File::open("/dev/urandom")?.read_exact(&mut key)?;
+        Ok(Self {
+            dir,
+            key,
+            ttl,
+            downloads: Mutex::new(HashMap::new()),
+            used_job_tokens: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    /// Parks `converted` on disk and returns the token that downloads it.
+    pub async fn store(&self, id: Uuid, converted: &ConvertedFile) -> io::Result<String> {
+        fs::create_dir_all(&self.dir).await?;
+        let path = self.dir.join(id.to_string());
+        fs::write(&path, &converted.content).await?;
+
+        let expires_at = now() + self.ttl.as_secs();
+        self.downloads.lock().unwrap().insert(
+            id,
+            Download {
+                path,
+                filename: converted.filename.clone(),
+                content_type: converted.content_type,
+                headers: converted.headers.clone(),
+                expires_at,
+            },
+        );
+        Ok(self.sign(id, expires_at))
+    }
+
+    fn sign(&self, id: Uuid, expires_at: u64) -> String {
+        let mut token = id.as_bytes().to_vec();
+        token.extend_from_slice(&expires_at.to_be_bytes());
+        let mac = hmac_sha256(&self.key, &token);
+        token.extend_from_slice(&mac);
+        base64url_encode(&token)
+    }
+
+    /// Checks the token's MAC and expiry and returns the download id.
+    fn verify(&self, token: &str, now: u64) -> Result<Uuid, ConversionError> {
+        let invalid = || ConversionError::new(StatusCode::FORBIDDEN, "INVALID_DOWNLOAD_TOKEN", "Invalid download token");
+        let bytes = base64_decode(token).filter(|b| b.len() == TOKEN_LEN).ok_or_else(invalid)?;
+        let (signed, mac) = bytes.split_at(24);
+        if !constant_time_eq(&hmac_sha256(&self.key, signed), mac) {
+            return Err(invalid());
+        }
+        let expires_at = u64::from_be_bytes(signed[16..24].try_into().unwrap());
+        if expires_at <= now {
+            return Err(ConversionError::new(StatusCode::GONE, "DOWNLOAD_EXPIRED", "Download link has expired"));
+        }
+        Ok(Uuid::from_slice(&signed[..16]).unwrap())
+    }
+
+    /// Returns a link token for the result of job `job_id`.
+    fn sign_job(&self, job_id: Uuid, expires_at: u64) -> String {
+        let mut token = Uuid::new_v4().as_bytes().to_vec();
+        token.extend_from_slice(job_id.as_bytes());
+        token.extend_from_slice(&expires_at.to_be_bytes());
+        let mac = hmac_sha256(&self.key, &token);
+        token.extend_from_slice(&mac);
+        base64url_encode(&token)
+    }
+
+    /// Checks a job link token and uses it up, returning the job id; `None` if `token` is not a
+    /// job link at all.
+    fn redeem_job(&self, token: &str, now: u64) -> Option<Result<Uuid, ConversionError>> {
+        let bytes = base64_decode(token).filter(|b| b.len() == JOB_TOKEN_LEN)?;
+        let (signed, mac) = bytes.split_at(40);
+        if !constant_time_eq(&hmac_sha256(&self.key, signed), mac) {
+            return Some(Err(ConversionError::new(StatusCode::FORBIDDEN, "INVALID_DOWNLOAD_TOKEN", "Invalid download token")));
+        }
+        let expires_at = u64::from_be_bytes(signed[32..40].try_into().unwrap());
+        if expires_at <= now {
+            return Some(Err(ConversionError::new(StatusCode::GONE, "DOWNLOAD_EXPIRED", "Download link has expired")));
+        }
+        let token_id = Uuid::from_slice(&signed[..16]).unwrap();
+        let mut used = self.used_job_tokens.lock().unwrap();
+        used.retain(|_, &mut expiry| expiry > now);
+        if used.insert(token_id, expires_at).is_some() {
+            return Some(Err(ConversionError::new(StatusCode::NOT_FOUND, "DOWNLOAD_NOT_FOUND", "Download was already used")));
+        }
+        Some(Ok(Uuid::from_slice(&signed[16..32]).unwrap()))
+    }
+
+    fn take(&self, id: Uuid) -> Option<Download> {
+        self.downloads.lock().unwrap().remove(&id)
+    }
+
+    fn evict_expired(&self, now: u64) -> Vec<PathBuf> {
+        let mut downloads = self.downloads.lock().unwrap();
+        let expired: Vec<Uuid> = downloads.iter().filter(|(_, d)| d.expires_at <= now).map(|(id, _)| *id).collect();
+        expired.into_iter().filter_map(|id| downloads.remove(&id).map(|d| d.path)).collect()
+    }
+}
+
+/// Periodically deletes files whose links expired without being used.
+pub async fn run_eviction(state: Arc<AppState>) {
+    let mut interval = tokio::time::interval(state.downloads.ttl.min(Duration::from_secs(60)));
+    loop {
+        interval.tick().await;
+        let evicted = state.downloads.evict_expired(now());
+        if !evicted.is_empty() {
+            info!("Removed {} expired downloads", evicted.len());
+        }
+        for path in evicted {
+            let _ = fs::remove_file(&path).await;
+        }
+    }
+}
+
+/// `POST /sign-download/{job_id}`: returns a single-use link to the result of a finished job.
+pub async fn sign(State(state): State<Arc<AppState>>, Path(job_id): Path<Uuid>) -> Response {
+    if let Err(e) = state.jobs.result(job_id) {
+        return e.into_response();
+    }
+    let token = state.downloads.sign_job(job_id, now() + JOB_TOKEN_TTL_SECS);
+    info!("Signed a download link for job {}", job_id);
+    Json(json!({ "url": format!("/download/{}", token), "expires_in": JOB_TOKEN_TTL_SECS })).into_response()
+}
+
+pub async fn download(State(state): State<Arc<AppState>>, Path(token): Path<String>) -> Response {
+    if let Some(redeemed) = state.downloads.redeem_job(&token, now()) {
+        return match redeemed.and_then(|job_id| state.jobs.result(job_id)) {
+            Ok(converted) => file_response(&converted, Disposition::Attachment),
+            Err(e) => e.into_response(),
+        };
+    }
+    let id = match state.downloads.verify(&token, now()) {
+        Ok(id) => id,
+        Err(e) => return e.into_response(),
+    };
+    let Some(download) = state.downloads.take(id) else {
+        return ConversionError::new(StatusCode::NOT_FOUND, "DOWNLOAD_NOT_FOUND", "Download was already used")
+            .into_response();
+    };
+
+    let file = match fs::File::open(&download.path).await {
+        Ok(file) => file,
+        Err(e) => {
+            error!("Failed to open download {}: {}", id, e);
+            return ConversionError::internal().into_response();
+        }
+    };
+    // The open handle keeps the data readable; unlinking now means nothing is left behind even
+    // if the client disconnects halfway
+    let _ = fs::remove_file(&download.path).await;
+
+    let stream = futures_util::stream::unfold(Some(file), |file| async move {
+        let mut file = file?;
+        let mut buf = vec![0; READ_CHUNK];
+        match file.read(&mut buf).await {
+            Ok(0) => None,
+            Ok(n) => {
+                buf.truncate(n);
+                Some((Ok(Bytes::from(buf)), Some(file)))
+            }
+            Err(e) => Some((Err(e), None)),
+        }
+    });
+
+    file_body_response(
+        &download.filename,
+        download.content_type,
+        Disposition::Attachment,
+        &download.headers,
+        Body::from_stream(stream),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_roundtrip() {
+        let store = DownloadStore::new(PathBuf::from("/tmp/convert/downloads-test"), Duration::from_secs(60)).unwrap();
+        let id = Uuid::new_v4();
+        let token = store.sign(id, 1_000);
+        assert_eq!(store.verify(&token, 999).unwrap(), id);
+        assert_eq!(store.verify(&token, 1_000).unwrap_err().code, "DOWNLOAD_EXPIRED");
+
+        // Pushing the expiry out invalidates the MAC
+        let mut bytes = base64_decode(&token).unwrap();
+        bytes[23] ^= 1;
+        assert_eq!(store.verify(&base64url_encode(&bytes), 999).unwrap_err().code, "INVALID_DOWNLOAD_TOKEN");
+        assert_eq!(store.verify("short", 999).unwrap_err().code, "INVALID_DOWNLOAD_TOKEN");
+        assert!(store.redeem_job(&token, 999).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_job_link_works_once() {
+        let state = Arc::new(crate::tests::test_state());
+        let job_id = state.jobs.submit(Uuid::new_v4(), PathBuf::from("/tmp/convert/signed"));
+        let response = sign(State(state.clone()), Path(job_id)).await;
+        assert_eq!(response.status(), StatusCode::CONFLICT, "unfinished jobs have nothing to download");
+
+        state.jobs.complete(
+            job_id,
+            Ok(ConvertedFile {
+                filename: "a.pdf".to_string(),
+                content_type: "application/pdf",
+                content: b"%PDF-1.7".to_vec(),
+                headers: Vec::new(),
+            }),
+        );
+        let response = sign(State(state.clone()), Path(job_id)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_slice(&axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap()).unwrap();
+        assert_eq!(body["expires_in"], JOB_TOKEN_TTL_SECS);
+        let token = body["url"].as_str().unwrap().strip_prefix("/download/").unwrap().to_string();
+
+        let response = download(State(state.clone()), Path(token.clone())).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap(), &b"%PDF-1.7"[..]);
+        let response = download(State(state.clone()), Path(token)).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        // Each signing makes a separate link
+        let other = state.downloads.sign_job(job_id, now() + 60);
+        assert_eq!(download(State(state.clone()), Path(other)).await.status(), StatusCode::OK);
+
+        let expired = state.downloads.sign_job(job_id, now() - 1);
+        assert_eq!(download(State(state), Path(expired)).await.status(), StatusCode::GONE);
+    }
+}
@@ -0,0 +1,79 @@
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::ser::{Serialize, SerializeStruct, Serializer};
+use serde_json::Value;
+
+/// Error returned to API clients as a JSON body of the form
+/// `{"code": "NO_FILE", "message": "No file uploaded"}`.
+///
+/// `code` is a stable machine-readable identifier; `message` is prose and may change.
+/// Some errors carry extra machine-readable fields next to those two.
+#[derive(Clone, Debug)]
+pub struct ConversionError {
+    pub status: StatusCode,
+    pub code: &'static str,
+    pub message: String,
+    pub details: Vec<(&'static str, Value)>,
+}
+
+impl ConversionError {
+    pub fn new(status: StatusCode, code: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            code,
+            message: message.into(),
+            details: Vec::new(),
+        }
+    }
+
+    /// Adds a top-level `key` field to the JSON body.
+    pub fn with_detail(mut self, key: &'static str, value: impl Into<Value>) -> Self {
+        self.details.push((key, value.into()));
+        self
+    }
+
+    pub fn internal() -> Self {
+        Self::new(StatusCode::INTERNAL_SERVER_ERROR, "INTERNAL_ERROR", "Internal Error")
+    }
+}
+
+impl Serialize for ConversionError {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut s = serializer.serialize_struct("ConversionError", 2 + self.details.len())?;
+        s.serialize_field("code", self.code)?;
+        s.serialize_field("message", &self.message)?;
+        for (key, value) in &self.details {
+            s.serialize_field(key, value)?;
+        }
+        s.end()
+    }
+}
+
+impl IntoResponse for ConversionError {
+    fn into_response(self) -> Response {
+        (self.status, Json(self)).into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_serializes_code_and_message() {
+        let err = ConversionError::new(StatusCode::BAD_REQUEST, "NO_FILE", "No file uploaded");
+        assert_eq!(
+            serde_json::to_string(&err).unwrap(),
+            r#"{"code":"NO_FILE","message":"No file uploaded"}"#
+        );
+
+        let err = err.with_detail("limit", 5);
+        assert_eq!(
+            serde_json::to_string(&err).unwrap(),
+            r#"{"code":"NO_FILE","message":"No file uploaded","limit":5}"#
+        );
+    }
+}
@@ -0,0 +1,100 @@
+//! Responses for requests no route handles: unknown paths (404) and known paths with the wrong
+//! method (405). Axum answers these with an empty body; here API clients get the usual JSON error
+//! and browsers the upload page, so they have somewhere to go.
+
+use axum::{
+    extract::State,
+    http::{header, HeaderMap, Method, StatusCode, Uri},
+    response::{Html, IntoResponse, Response},
+};
+use std::sync::Arc;
+
+use crate::error::ConversionError;
+use crate::info::ServerInfo;
+use crate::AppState;
+
+/// Whether the client asked for a web page rather than JSON: it accepts HTML and does not
+/// mention JSON, as browsers do when navigating.
+fn wants_html(headers: &HeaderMap) -> bool {
+    let accept = headers.get(header::ACCEPT).and_then(|v| v.to_str().ok()).unwrap_or_default();
+    accept.contains("text/html") && !accept.contains("application/json")
+}
+
+async fn error_page(state: &AppState, headers: &HeaderMap, error: ConversionError) -> Response {
+    if wants_html(headers) {
+        let page = ServerInfo::collect(state).await.render_index();
+        return (error.status, Html(page)).into_response();
+    }
+    error.into_response()
+}
+
+/// The router's fallback.
+pub async fn not_found(State(state): State<Arc<AppState>>, uri: Uri, headers: HeaderMap) -> Response {
+    let path = uri.path().to_string();
+    let error = ConversionError::new(StatusCode::NOT_FOUND, "NOT_FOUND", format!("No such endpoint: {}", path)).with_detail("path", path);
+    error_page(&state, &headers, error).await
+}
+
+/// The fallback for routes that exist but not for the request's method. Axum adds the `Allow`
+/// header to whatever this returns.
+pub async fn method_not_allowed(State(state): State<Arc<AppState>>, method: Method, uri: Uri, headers: HeaderMap) -> Response {
+    let path = uri.path().to_string();
+    let error = ConversionError::new(StatusCode::METHOD_NOT_ALLOWED, "METHOD_NOT_ALLOWED", format!("{} is not supported on {}", method, path))
+        .with_detail("path", path);
+    error_page(&state, &headers, error).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::test_state;
+    use axum::{body::Body, extract::Request, routing::post, Router};
+    use serde_json::{json, Value};
+    use tower::ServiceExt;
+
+    async fn send(accept: &str, method: &str, uri: &str) -> Response {
+        let state = Arc::new(test_state());
+        let app = Router::new()
+            .route("/convert", post(|| async { "converted" }))
+            .fallback(not_found)
+            .method_not_allowed_fallback(method_not_allowed)
+            .with_state(state);
+        let request = Request::builder().method(method).uri(uri).header(header::ACCEPT, accept).body(Body::empty()).unwrap();
+        app.oneshot(request).await.unwrap()
+    }
+
+    async fn json_body(response: Response) -> Value {
+        serde_json::from_slice(&axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_not_found() {
+        let response = send("*/*", "GET", "/unknown").await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "application/json");
+        assert_eq!(json_body(response).await, json!({"code": "NOT_FOUND", "message": "No such endpoint: /unknown", "path": "/unknown"}));
+
+        let response = send("text/html,application/xhtml+xml,*/*;q=0.8", "GET", "/unknown").await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "text/html; charset=utf-8");
+    }
+
+    #[tokio::test]
+    async fn test_method_not_allowed() {
+        let response = send("application/json", "GET", "/convert").await;
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(response.headers()[header::ALLOW], "POST");
+        assert_eq!(
+            json_body(response).await,
+            json!({"code": "METHOD_NOT_ALLOWED", "message": "GET is not supported on /convert", "path": "/convert"})
+        );
+
+        let response = send("text/html", "DELETE", "/convert").await;
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(response.headers()[header::ALLOW], "POST");
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "text/html; charset=utf-8");
+
+        // Requests a handler takes are unaffected
+        assert_eq!(send("*/*", "POST", "/convert").await.status(), StatusCode::OK);
+    }
+}
@@ -0,0 +1,275 @@
+//! PDF export filter settings beyond the basic form fields: the `options` JSON object (e.g.
+//! `{"page_range": "1-3,5", "dpi": 150, "compress_images": true}`), the `pages` selection and
+//! the page watermark.
+
+use axum::http::StatusCode;
+use serde_json::{json, Map, Value};
+
+use crate::error::ConversionError;
+
+pub const SUPPORTED_KEYS: &[&str] = &["page_range", "dpi", "compress_images", "pdfa_level"];
+const MAX_DPI: u64 = 2400;
+/// Keeps `1-4000000000` from expanding into a huge list.
+pub const MAX_PAGE: u32 = 100_000;
+const MAX_WATERMARK_LEN: usize = 64;
+const DEFAULT_WATERMARK_COLOR: u32 = 0xFF0000;
+const DEFAULT_WATERMARK_ANGLE: i32 = 45;
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ExportOptions {
+    /// Pages to export, in LibreOffice's syntax (`1-3,5`).
+    pub page_range: Option<String>,
+    /// Images are downsampled to at most this resolution.
+    pub dpi: Option<u32>,
+    /// `true` re-encodes images as JPEG; `false` keeps them lossless.
+    pub compress_images: Option<bool>,
+    /// PDF/A level; an alternative to the `pdfa` field.
+    pub pdfa_level: Option<String>,
+}
+
+impl ExportOptions {
+    pub fn parse(raw: &str) -> Result<Self, ConversionError> {
+        let object = match serde_json::from_str(raw) {
+            Ok(Value::Object(object)) => object,
+            _ => return Err(invalid("options must be a JSON object".to_string())),
+        };
+
+        let unknown: Vec<&str> = object.keys().map(String::as_str).filter(|k| !SUPPORTED_KEYS.contains(k)).collect();
+        if !unknown.is_empty() {
+            return Err(ConversionError::new(
+                StatusCode::BAD_REQUEST,
+                "UNKNOWN_EXPORT_OPTION",
+                format!("Unknown options {}; supported: {}", unknown.join(", "), SUPPORTED_KEYS.join(", ")),
+            )
+            .with_detail("supported_keys", SUPPORTED_KEYS));
+        }
+
+        // `null` means the same as leaving the key out
+        let get = |key: &str| object.get(key).filter(|v| !v.is_null());
+        let mut opts = ExportOptions::default();
+        if let Some(value) = get("page_range") {
+            let range = value.as_str().map(str::trim).filter(|r| is_page_range(r));
+            opts.page_range = Some(range.ok_or_else(|| invalid("page_range must look like \"1-3,5\"".to_string()))?.to_string());
+        }
+        if let Some(value) = get("dpi") {
+            let dpi = value.as_u64().filter(|dpi| (1..=MAX_DPI).contains(dpi));
+            opts.dpi = Some(dpi.ok_or_else(|| invalid(format!("dpi must be an integer from 1 to {}", MAX_DPI)))? as u32);
+        }
+        if let Some(value) = get("compress_images") {
+            opts.compress_images = Some(value.as_bool().ok_or_else(|| invalid("compress_images must be a boolean".to_string()))?);
+        }
+        if let Some(value) = get("pdfa_level") {
+            let level = value.as_str().ok_or_else(|| invalid("pdfa_level must be a string".to_string()))?;
+            opts.pdfa_level = Some(level.to_string());
+        }
+        Ok(opts)
+    }
+
+    /// Adds the matching export filter properties to `data`.
+    pub fn add_filter_data(&self, data: &mut Map<String, Value>) {
+        if let Some(ref range) = self.page_range {
+            data.insert("PageRange".into(), json!({ "type": "string", "value": range }));
+        }
+        if let Some(dpi) = self.dpi {
+            data.insert("ReduceImageResolution".into(), json!({ "type": "boolean", "value": "true" }));
+            data.insert("MaxImageResolution".into(), json!({ "type": "long", "value": dpi.to_string() }));
+        }
+        if let Some(compress) = self.compress_images {
+            let lossless = (!compress).to_string();
+            data.insert("UseLosslessCompression".into(), json!({ "type": "boolean", "value": lossless }));
+        }
+    }
+}
+
+/// Text stamped across every page by LibreOffice's PDF export.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Watermark {
+    pub text: String,
+    /// `0xRRGGBB`
+    pub color: u32,
+    /// Counter-clockwise, in degrees.
+    pub angle: i32,
+}
+
+impl Watermark {
+    /// Validates the `watermark`, `watermark_color` (`#RRGGBB`) and `watermark_angle` fields.
+    pub fn parse(text: &str, color: Option<&str>, angle: Option<&str>) -> Result<Self, ConversionError> {
+        let invalid = |message: String| ConversionError::new(StatusCode::BAD_REQUEST, "INVALID_WATERMARK", message);
+        if text.chars().count() > MAX_WATERMARK_LEN {
+            return Err(invalid(format!("watermark must be at most {} characters", MAX_WATERMARK_LEN)));
+        }
+        if text.chars().any(char::is_control) {
+            return Err(invalid("watermark must not contain control characters".to_string()));
+        }
+        let color = match color.map(str::trim) {
+            None => DEFAULT_WATERMARK_COLOR,
+            Some(hex) => hex
+                .strip_prefix('#')
+                .filter(|h| h.len() == 6)
+                .and_then(|h| u32::from_str_radix(h, 16).ok())
+                .ok_or_else(|| invalid(format!("watermark_color must be #RRGGBB, not {:?}", hex)))?,
+        };
+        let angle = match angle.map(str::trim) {
+            None => DEFAULT_WATERMARK_ANGLE,
+            Some(raw) => raw
+                .parse()
+                .ok()
+                .filter(|a: &i32| (-360..=360).contains(a))
+                .ok_or_else(|| invalid(format!("watermark_angle must be whole degrees from -360 to 360, not {:?}", raw)))?,
+        };
+        Ok(Self {
+            text: text.to_string(),
+            color,
+            angle,
+        })
+    }
+
+    pub fn add_filter_data(&self, data: &mut Map<String, Value>) {
+        data.insert("Watermark".into(), json!({ "type": "string", "value": self.text }));
+        data.insert("WatermarkColor".into(), json!({ "type": "long", "value": self.color.to_string() }));
+        // LibreOffice takes tenths of a degree
+        data.insert("WatermarkRotateAngle".into(), json!({ "type": "long", "value": (self.angle * 10).to_string() }));
+    }
+}
+
+/// Parses a `pages` selection like `1-5,8,10-12` into sorted, deduplicated 1-based page numbers.
+pub fn parse_pages(raw: &str) -> Result<Vec<u32>, ConversionError> {
+    let invalid = |message: String| ConversionError::new(StatusCode::BAD_REQUEST, "INVALID_PAGE_RANGE", message);
+    let page = |part: &str| match part.trim().parse::<u32>() {
+        Ok(n) if (1..=MAX_PAGE).contains(&n) => Ok(n),
+        _ => Err(invalid(format!("Invalid page {:?} in pages; expected a number from 1 to {}", part.trim(), MAX_PAGE))),
+    };
+
+    let mut pages = Vec::new();
+    for part in raw.split(',') {
+        if part.trim().is_empty() {
+            return Err(invalid(format!("Invalid pages {:?}; expected a list like \"1-5,8,10-12\"", raw)));
+        }
+        match part.split_once('-') {
+            Some((first, last)) => {
+                let (first, last) = (page(first)?, page(last)?);
+                if first > last {
+                    return Err(invalid(format!("Invalid range {:?} in pages: it ends before it starts", part.trim())));
+                }
+                pages.extend(first..=last);
+            }
+            None => pages.push(page(part)?),
+        }
+    }
+    pages.sort_unstable();
+    pages.dedup();
+    Ok(pages)
+}
+
+/// Formats sorted page numbers back into LibreOffice's `PageRange` syntax, e.g. `1-5,8,10-12`.
+pub fn format_pages(pages: &[u32]) -> String {
+    let mut ranges: Vec<(u32, u32)> = Vec::new();
+    for &page in pages {
+        match ranges.last_mut() {
+            Some((_, last)) if *last + 1 == page => *last = page,
+            _ => ranges.push((page, page)),
+        }
+    }
+    ranges
+        .iter()
+        .map(|&(first, last)| if first == last { first.to_string() } else { format!("{}-{}", first, last) })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Counts the page objects in a PDF written by LibreOffice, which does not use object streams,
+/// so every `/Type/Page` dictionary appears in the file as plain text.
+pub fn count_pdf_pages(pdf: &[u8]) -> usize {
+    let mut count = 0;
+    for (i, _) in pdf.windows(5).enumerate().filter(|(_, w)| *w == b"/Type") {
+        let rest = &pdf[i + 5..];
+        let rest = &rest[rest.iter().take_while(|b| b.is_ascii_whitespace()).count()..];
+        // `/Pages` is the page tree node, not a page
+        if rest.starts_with(b"/Page") && !rest[5..].first().is_some_and(|b| b.is_ascii_alphanumeric()) {
+            count += 1;
+        }
+    }
+    count
+}
+
+/// Width and height in points of the first `/MediaBox` in a PDF written by LibreOffice.
+pub fn pdf_page_size(pdf: &[u8]) -> Option<(f64, f64)> {
+    let start = pdf.windows(9).position(|w| w == b"/MediaBox")? + 9;
+    let rest = &pdf[start..];
+    let open = rest.iter().position(|&b| b == b'[')?;
+    let close = open + rest[open..].iter().position(|&b| b == b']')?;
+    let numbers: Vec<f64> = std::str::from_utf8(&rest[open + 1..close])
+        .ok()?
+        .split_ascii_whitespace()
+        .map(str::parse)
+        .collect::<Result<_, _>>()
+        .ok()?;
+    let [x0, y0, x1, y1] = numbers[..] else {
+        return None;
+    };
+    let (width, height) = ((x1 - x0).abs(), (y1 - y0).abs());
+    (width > 0.0 && height > 0.0).then_some((width, height))
+}
+
+fn invalid(message: String) -> ConversionError {
+    ConversionError::new(StatusCode::BAD_REQUEST, "INVALID_EXPORT_OPTIONS", message)
+}
+
+/// Page numbers and ranges separated by commas, e.g. `1-3,5` or `7-`.
+fn is_page_range(range: &str) -> bool {
+    range.bytes().any(|b| b.is_ascii_digit()) && range.bytes().all(|b| b.is_ascii_digit() || b"-, ".contains(&b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let opts = ExportOptions::parse(r#"{"page_range": "1-3, 5", "dpi": 150, "compress_images": false, "pdfa_level": null}"#).unwrap();
+        assert_eq!(opts.page_range.as_deref(), Some("1-3, 5"));
+        assert_eq!(opts.dpi, Some(150));
+        let mut data = Map::new();
+        opts.add_filter_data(&mut data);
+        assert_eq!(data["MaxImageResolution"]["value"], "150");
+        assert_eq!(data["UseLosslessCompression"]["value"], "true");
+
+        let err = ExportOptions::parse(r#"{"dpi": 300, "quality": 90}"#).unwrap_err();
+        assert_eq!(err.code, "UNKNOWN_EXPORT_OPTION");
+        assert!(err.message.contains("quality"));
+        assert_eq!(ExportOptions::parse(r#"{"dpi": "300"}"#).unwrap_err().code, "INVALID_EXPORT_OPTIONS");
+        assert_eq!(ExportOptions::parse(r#"{"page_range": "1;rm"}"#).unwrap_err().code, "INVALID_EXPORT_OPTIONS");
+        assert_eq!(ExportOptions::parse("[1]").unwrap_err().code, "INVALID_EXPORT_OPTIONS");
+    }
+
+    #[test]
+    fn test_pages() {
+        assert_eq!(parse_pages("10-12, 8,1-5,3").unwrap(), vec![1, 2, 3, 4, 5, 8, 10, 11, 12]);
+        assert_eq!(format_pages(&parse_pages("10-12, 8,1-5,3").unwrap()), "1-5,8,10-12");
+        for bad in ["", "1,,2", "0", "5-3", "1-", "a", "1-200000"] {
+            assert_eq!(parse_pages(bad).unwrap_err().code, "INVALID_PAGE_RANGE", "{:?}", bad);
+        }
+
+        let pdf = b"<</Type/Pages/Count 2>> <</Type/Page/Parent 2 0 R>> <</Type /Page\n/Contents 5 0 R>>";
+        assert_eq!(count_pdf_pages(pdf), 2);
+
+        assert_eq!(pdf_page_size(b"<</Type/Page/MediaBox[0 0 595.304 841.89]>>"), Some((595.304, 841.89)));
+        assert_eq!(pdf_page_size(b"<</Type/Page/MediaBox [0 0 0 842]>>"), None);
+        assert_eq!(pdf_page_size(b"<</Type/Page>>"), None);
+    }
+
+    #[test]
+    fn test_watermark() {
+        let watermark = Watermark::parse("DRAFT", None, None).unwrap();
+        assert_eq!((watermark.color, watermark.angle), (0xFF0000, 45));
+        let mut data = Map::new();
+        Watermark::parse("CONFIDENTIAL", Some("#00ff7f"), Some("-30")).unwrap().add_filter_data(&mut data);
+        assert_eq!(data["WatermarkColor"]["value"], "65407");
+        assert_eq!(data["WatermarkRotateAngle"]["value"], "-300");
+
+        assert!(Watermark::parse(&"x".repeat(65), None, None).is_err());
+        assert!(Watermark::parse("a\0b", None, None).is_err());
+        assert!(Watermark::parse("DRAFT", Some("red"), None).is_err());
+        assert!(Watermark::parse("DRAFT", None, Some("45.5")).is_err());
+    }
+}
@@ -0,0 +1,248 @@
+//! The import formats the installed LibreOffice has filters for, listed by `GET /info`.
+//!
+//! `soffice` has no command-line option that prints its filters, so they are read from the
+//! configuration registry it loads them from: the `.xcd` files in `share/registry` of the
+//! installation. Each import filter (a `Filters` node flagged `IMPORT`) names a type, and the
+//! type (a `Types` node) carries the file extensions. Read once at startup.
+
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use crate::health;
+
+/// Filters for Word formats older than Word 97, which LibreOffice imports with noticeably less
+/// fidelity (lost layout, fields and embedded objects).
+const LOW_RELIABILITY_FILTERS: &[&str] = &["MS Word 95", "MS Word 95 Vorlage", "MS WinWord 6.0", "MS WinWord 5"];
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct FormatInfo {
+    /// The filter name, as passed to `--infilter`.
+    pub name: String,
+    pub extensions: Vec<String>,
+    pub description: String,
+    /// `Some("low")` for formats known to convert poorly.
+    pub reliability: Option<&'static str>,
+}
+
+impl FormatInfo {
+    pub fn to_json(&self) -> Value {
+        let mut value = json!({
+            "name": self.name,
+            "extensions": self.extensions,
+            "description": self.description,
+        });
+        if let Some(reliability) = self.reliability {
+            value["reliability"] = json!(reliability);
+        }
+        value
+    }
+}
+
+/// The import formats of the LibreOffice that `binary` starts, found by following it (through
+/// `PATH` and symlinks) to the installation's `program` directory.
+pub async fn load(binary: &str) -> Result<Vec<FormatInfo>, String> {
+    let binary = health::find_executable(binary).await?;
+    tokio::task::spawn_blocking(move || {
+        let registry = registry_dir(&binary)?;
+        let mut files = Vec::new();
+        for entry in std::fs::read_dir(&registry).map_err(|e| format!("{}: {}", registry.display(), e))? {
+            let path = entry.map_err(|e| format!("{}: {}", registry.display(), e))?.path();
+            if path.extension().is_some_and(|e| e == "xcd") {
+                let data = std::fs::read(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+                files.push(String::from_utf8_lossy(&data).into_owned());
+            }
+        }
+        Ok(parse_registry(&files))
+    })
+    .await
+    .map_err(|e| format!("reading the filter registry panicked: {}", e))?
+}
+
+/// `share/registry` of the installation `binary` belongs to (`<root>/program/soffice`).
+fn registry_dir(binary: &Path) -> Result<PathBuf, String> {
+    let resolved = std::fs::canonicalize(binary).map_err(|e| format!("{}: {}", binary.display(), e))?;
+    resolved
+        .parent()
+        .and_then(Path::parent)
+        .map(|root| root.join("share").join("registry"))
+        .filter(|dir| dir.is_dir())
+        .ok_or_else(|| format!("no filter registry next to {}", resolved.display()))
+}
+
+/// The import filters defined across the registry files `xcds`, sorted by name.
+pub fn parse_registry(xcds: &[String]) -> Vec<FormatInfo> {
+    let mut filters = Vec::new();
+    let mut types = HashMap::new();
+    for xcd in xcds {
+        for (name, body) in leaf_nodes(xcd) {
+            if let Some(extensions) = prop(body, "Extensions") {
+                types.insert(name, (extensions, prop(body, "UIName")));
+            } else if let Some(flags) = prop(body, "Flags")
+                && flags.split_whitespace().any(|flag| flag == "IMPORT")
+                && let Some(type_name) = prop(body, "Type")
+            {
+                filters.push((name, type_name, prop(body, "UIName")));
+            }
+        }
+    }
+
+    let mut formats: Vec<FormatInfo> = filters
+        .into_iter()
+        .map(|(name, type_name, ui_name)| {
+            let (extensions, type_ui_name) = types.get(type_name.as_str()).cloned().unwrap_or_default();
+            FormatInfo {
+                extensions: extensions.split_whitespace().filter(|&e| e != "*").map(str::to_string).collect(),
+                description: ui_name.or(type_ui_name).unwrap_or_else(|| name.to_string()),
+                reliability: LOW_RELIABILITY_FILTERS.contains(&name).then_some("low"),
+                name: name.to_string(),
+            }
+        })
+        .collect();
+    formats.sort_by(|a, b| a.name.cmp(&b.name));
+    formats.dedup_by(|a, b| a.name == b.name);
+    formats
+}
+
+/// Each `<node>` with its content up to the next node tag: the properties of a leaf node, or
+/// nothing for one that only holds other nodes.
+fn leaf_nodes(xml: &str) -> Vec<(&str, &str)> {
+    let mut nodes = Vec::new();
+    let mut rest = xml;
+    while let Some(start) = rest.find("<node ") {
+        let Some(tag_len) = rest[start..].find('>') else {
+            break;
+        };
+        let tag = &rest[start..start + tag_len];
+        rest = &rest[start + tag_len + 1..];
+        let Some(name) = attribute(tag, "oor:name") else {
+            continue;
+        };
+        let end = [rest.find("<node "), rest.find("</node>")].into_iter().flatten().min().unwrap_or(rest.len());
+        nodes.push((name, if tag.ends_with('/') { "" } else { &rest[..end] }));
+    }
+    nodes
+}
+
+fn attribute<'a>(tag: &'a str, name: &str) -> Option<&'a str> {
+    let start = tag.find(&format!(" {}=\"", name))? + name.len() + 3;
+    let len = tag[start..].find('"')?;
+    Some(&tag[start..start + len])
+}
+
+/// The value of the `<prop>` called `name` in a node's `body`: the `en-US` one if it is localised,
+/// otherwise the first. `None` if the property is missing or has no value.
+fn prop(body: &str, name: &str) -> Option<String> {
+    let open = format!("<prop oor:name=\"{}\"", name);
+    let start = body.find(&open)? + open.len();
+    let tag_len = body[start..].find('>')?;
+    if body[..start + tag_len].ends_with('/') {
+        return None;
+    }
+    let content = &body[start + tag_len + 1..];
+    let content = &content[..content.find("</prop>")?];
+
+    let mut values = Vec::new();
+    let mut rest = content;
+    while let Some(at) = rest.find("<value") {
+        let tag_len = rest[at..].find('>')?;
+        let tag = &rest[at..at + tag_len];
+        rest = &rest[at + tag_len + 1..];
+        if tag.ends_with('/') {
+            continue;
+        }
+        let len = rest.find("</value>")?;
+        values.push((attribute(tag, "xml:lang"), unescape(&rest[..len])));
+        rest = &rest[len..];
+    }
+    let index = values.iter().position(|(lang, _)| *lang == Some("en-US")).unwrap_or(0);
+    (index < values.len()).then(|| values.swap_remove(index).1)
+}
+
+/// `text` with its entity and character references replaced; unknown ones are kept as they are.
+fn unescape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(at) = rest.find('&') {
+        out.push_str(&rest[..at]);
+        rest = &rest[at..];
+        let reference = rest.find(';').map(|end| &rest[1..end]);
+        let decoded = match reference {
+            Some("lt") => Some('<'),
+            Some("gt") => Some('>'),
+            Some("amp") => Some('&'),
+            Some("quot") => Some('"'),
+            Some("apos") => Some('\''),
+            Some(r) if r.starts_with("#x") => u32::from_str_radix(&r[2..], 16).ok().and_then(char::from_u32),
+            Some(r) if r.starts_with('#') => r[1..].parse().ok().and_then(char::from_u32),
+            _ => None,
+        };
+        match (decoded, reference) {
+            (Some(c), Some(reference)) => {
+                out.push(c);
+                rest = &rest[reference.len() + 2..];
+            }
+            _ => {
+                out.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    const WRITER_XCD: &str = r#"<?xml version="1.0"?>
+<oor:data xmlns:oor="http://openoffice.org/2001/registry">
+<oor:component-data oor:package="org.openoffice.TypeDetection" oor:name="Types"><node oor:name="Types">
+<node oor:name="writer_MS_Word_97" oor:op="replace"><prop oor:name="DetectService"><value>com.sun.star.comp.filters.MSWordDetect</value></prop><prop oor:name="Extensions"><value>doc dot</value></prop><prop oor:name="UIName"><value>Word 97&#x2013;2003</value></prop></node>
+<node oor:name="writer_MS_Word_95" oor:op="replace"><prop oor:name="Extensions"><value>doc</value></prop></node>
+<node oor:name="writer_web_HTML" oor:op="replace"><prop oor:name="Extensions"><value>html htm</value></prop><prop oor:name="UIName"/></node>
+</node></oor:component-data>
+<oor:component-data oor:package="org.openoffice.TypeDetection" oor:name="Filter"><node oor:name="Filters">
+<node oor:name="MS Word 97" oor:op="replace"><prop oor:name="Flags"><value>IMPORT EXPORT ALIEN PREFERRED</value></prop><prop oor:name="Type"><value>writer_MS_Word_97</value></prop><prop oor:name="UIName"><value xml:lang="de">Word 97&#x2013;2003 (Deutsch)</value><value xml:lang="en-US">Word 97&#x2013;2003 &amp; templates</value></prop></node>
+<node oor:name="MS Word 95" oor:op="replace"><prop oor:name="Flags"><value>IMPORT ALIEN</value></prop><prop oor:name="Type"><value>writer_MS_Word_95</value></prop></node>
+<node oor:name="writer_pdf_Export" oor:op="replace"><prop oor:name="Flags"><value>EXPORT ALIEN</value></prop><prop oor:name="Type"><value>pdf_Portable_Document_Format</value></prop></node>
+<node oor:name="HTML (StarWriter)" oor:op="replace"><prop oor:name="Flags"><value>IMPORT EXPORT</value></prop><prop oor:name="Type"><value>writer_web_HTML</value></prop></node>
+</node></oor:component-data>
+</oor:data>"#;
+
+    #[test]
+    fn test_parse_registry() {
+        let formats = parse_registry(&[WRITER_XCD.to_string()]);
+        let names: Vec<&str> = formats.iter().map(|f| f.name.as_str()).collect();
+        // Export-only filters are left out
+        assert_eq!(names, ["HTML (StarWriter)", "MS Word 95", "MS Word 97"]);
+        assert_eq!(formats[0].extensions, ["html", "htm"]);
+        assert_eq!(formats[0].description, "HTML (StarWriter)");
+        assert_eq!(formats[2].description, "Word 97\u{2013}2003 & templates");
+        assert_eq!(formats[2].reliability, None);
+        assert_eq!(
+            formats[1].to_json(),
+            json!({"name": "MS Word 95", "extensions": ["doc"], "description": "MS Word 95", "reliability": "low"})
+        );
+    }
+
+    #[tokio::test]
+    async fn test_load() {
+        let root = std::env::temp_dir().join(format!("filters-test-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(root.join("program")).unwrap();
+        std::fs::create_dir_all(root.join("share/registry")).unwrap();
+        let soffice = root.join("program/soffice");
+        std::fs::write(&soffice, b"#!/bin/sh\n").unwrap();
+        std::fs::set_permissions(&soffice, std::fs::Permissions::from_mode(0o755)).unwrap();
+        std::fs::write(root.join("share/registry/writer.xcd"), WRITER_XCD).unwrap();
+        std::fs::write(root.join("share/registry/README"), "not a registry file").unwrap();
+
+        let formats = load(soffice.to_str().unwrap()).await;
+        let missing = load("/nonexistent/soffice").await;
+        let _ = std::fs::remove_dir_all(&root);
+        assert_eq!(formats.unwrap().len(), 3);
+        assert!(missing.is_err());
+    }
+}
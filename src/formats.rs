@@ -0,0 +1,229 @@
+use axum::http::StatusCode;
+
+use crate::error::ConversionError;
+
+/// An output format accepted by the `format` field, passed verbatim to `--convert-to`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OutputFormat {
+    pub extension: &'static str,
+    pub content_type: &'static str,
+}
+
+impl OutputFormat {
+    /// Formats that LibreOffice writes as one file per page, returned to the caller as a zip.
+    pub fn is_paged(&self) -> bool {
+        self.extension == "png"
+    }
+}
+
+pub const PDF: OutputFormat = OutputFormat {
+    extension: "pdf",
+    content_type: "application/pdf",
+};
+
+pub const PNG: OutputFormat = OutputFormat {
+    extension: "png",
+    content_type: "image/png",
+};
+
+/// Not selectable with `format`; `/thumbnail` and `/render` write it.
+pub const JPG: OutputFormat = OutputFormat {
+    extension: "jpg",
+    content_type: "image/jpeg",
+};
+
+pub const OUTPUT_FORMATS: &[OutputFormat] = &[
+    PDF,
+    OutputFormat { extension: "html", content_type: "text/html" },
+    OutputFormat { extension: "txt", content_type: "text/plain" },
+    OutputFormat { extension: "rtf", content_type: "application/rtf" },
+    OutputFormat { extension: "csv", content_type: "text/csv" },
+    PNG,
+    OutputFormat {
+        extension: "docx",
+        content_type: "application/vnd.openxmlformats-officedocument.wordprocessingml.document",
+    },
+    OutputFormat {
+        extension: "xlsx",
+        content_type: "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+    },
+    OutputFormat {
+        extension: "pptx",
+        content_type: "application/vnd.openxmlformats-officedocument.presentationml.presentation",
+    },
+    OutputFormat { extension: "odt", content_type: "application/vnd.oasis.opendocument.text" },
+    OutputFormat { extension: "ods", content_type: "application/vnd.oasis.opendocument.spreadsheet" },
+    OutputFormat { extension: "odp", content_type: "application/vnd.oasis.opendocument.presentation" },
+];
+
+pub fn output_format(name: &str) -> Option<OutputFormat> {
+    let name = name.trim().to_ascii_lowercase();
+    OUTPUT_FORMATS.iter().copied().find(|f| f.extension == name)
+}
+
+pub fn output_format_names() -> Vec<&'static str> {
+    OUTPUT_FORMATS.iter().map(|f| f.extension).collect()
+}
+
+/// LibreOffice's `pdf` or `jpg` export filter for an input file, chosen by its extension.
+///
+/// `--convert-to pdf` picks this on its own, but passing export options requires naming it.
+pub fn export_filter(output_extension: &str, input_extension: &str) -> String {
+    let module = match input_extension.to_ascii_lowercase().as_str() {
+        "xlsx" | "xlsm" | "xls" | "ods" | "csv" => "calc",
+        "pptx" | "ppsx" | "ppt" | "pps" | "odp" => "impress",
+        // LibreOffice opens PDFs in Draw
+        "odg" | "vsd" | "vsdx" | "pdf" => "draw",
+        _ => "writer",
+    };
+    format!("{}_{}_Export", module, output_extension)
+}
+
+/// PDF/A conformance levels accepted by the `pdfa` field, with LibreOffice's `SelectPdfVersion`.
+pub const PDFA_LEVELS: &[(&str, u8)] = &[("1b", 1), ("2b", 2), ("3b", 3)];
+
+pub fn pdfa_level(name: &str) -> Option<(&'static str, u8)> {
+    let name = name.trim().to_ascii_lowercase();
+    PDFA_LEVELS.iter().copied().find(|(level, _)| *level == name)
+}
+
+/// LibreOffice's CSV import filter, given to it as `--infilter=<filter>:<options>`.
+pub const CSV_IMPORT_FILTER: &str = "Text - txt - csv (StarCalc)";
+
+/// Encodings accepted by `csv_encoding`, with LibreOffice's number for each (`rtl_TextEncoding`).
+pub const CSV_ENCODINGS: &[(&str, u16)] = &[
+    ("UTF-8", 76),
+    ("UTF-16", 65535),
+    ("US-ASCII", 11),
+    ("ISO-8859-1", 12),
+    ("ISO-8859-2", 13),
+    ("ISO-8859-15", 22),
+    ("WINDOWS-1250", 33),
+    ("WINDOWS-1251", 34),
+    ("WINDOWS-1252", 1),
+];
+
+/// How CSV inputs are read. Left to itself LibreOffice guesses the separator, and often gets
+/// semicolon-separated European files wrong.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CsvImport {
+    pub delimiter: char,
+    /// One of the `CSV_ENCODINGS` numbers.
+    pub encoding: u16,
+}
+
+impl Default for CsvImport {
+    fn default() -> Self {
+        Self { delimiter: ',', encoding: 76 }
+    }
+}
+
+impl CsvImport {
+    /// Reads the `csv_delimiter` and `csv_encoding` fields. The delimiter is taken as is, so that a
+    /// space or a tab can be given.
+    pub fn parse(delimiter: Option<&str>, encoding: Option<&str>) -> Result<Self, ConversionError> {
+        let mut csv = CsvImport::default();
+        if let Some(raw) = delimiter.filter(|d| !d.is_empty()) {
+            let mut chars = raw.chars();
+            csv.delimiter = match (chars.next(), chars.next()) {
+                (Some(c), None) if !matches!(c, '"' | '\n' | '\r') => c,
+                _ => {
+                    return Err(ConversionError::new(
+                        StatusCode::BAD_REQUEST,
+                        "INVALID_CSV_DELIMITER",
+                        format!("csv_delimiter must be a single character other than a quote or line break, not {:?}", raw),
+                    ));
+                }
+            };
+        }
+        if let Some(raw) = encoding.map(str::trim).filter(|e| !e.is_empty()) {
+            let names: Vec<&str> = CSV_ENCODINGS.iter().map(|(name, _)| *name).collect();
+            csv.encoding = CSV_ENCODINGS
+                .iter()
+                .find(|(name, _)| name.eq_ignore_ascii_case(raw))
+                .map(|&(_, number)| number)
+                .ok_or_else(|| {
+                    ConversionError::new(
+                        StatusCode::BAD_REQUEST,
+                        "UNSUPPORTED_CSV_ENCODING",
+                        format!("Unsupported csv_encoding {:?}; valid options: {}", raw, names.join(", ")),
+                    )
+                })?;
+        }
+        Ok(csv)
+    }
+
+    /// The filter options: field separator, text delimiter (`"`), encoding and first line, the
+    /// first two as character codes.
+    pub fn filter_options(&self) -> String {
+        format!("{},34,{},1", self.delimiter as u32, self.encoding)
+    }
+
+    /// The `--infilter` value.
+    pub fn infilter(&self) -> String {
+        format!("{}:{}", CSV_IMPORT_FILTER, self.filter_options())
+    }
+}
+
+/// A page size for `paper_size`, in portrait, in the hundredths of a millimetre LibreOffice
+/// measures pages in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PaperSize {
+    pub name: &'static str,
+    pub width: u32,
+    pub height: u32,
+}
+
+pub const PAPER_SIZES: &[PaperSize] = &[
+    PaperSize { name: "A3", width: 29700, height: 42000 },
+    PaperSize { name: "A4", width: 21000, height: 29700 },
+    PaperSize { name: "A5", width: 14800, height: 21000 },
+    PaperSize { name: "Letter", width: 21590, height: 27940 },
+    PaperSize { name: "Legal", width: 21590, height: 35560 },
+    PaperSize { name: "Tabloid", width: 27940, height: 43180 },
+];
+
+pub fn paper_size(name: &str) -> Option<PaperSize> {
+    let name = name.trim();
+    PAPER_SIZES.iter().copied().find(|size| size.name.eq_ignore_ascii_case(name))
+}
+
+impl PaperSize {
+    /// The `<width>x<height>` argument of `uno_convert.py`.
+    pub fn arg(&self) -> String {
+        format!("{}x{}", self.width, self.height)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_output_format_lookup() {
+        assert_eq!(output_format("pdf"), Some(PDF));
+        assert_eq!(output_format(" PNG ").map(|f| f.extension), Some("png"));
+        assert_eq!(output_format("exe"), None);
+        assert!(output_format("png").unwrap().is_paged());
+        assert!(!PDF.is_paged());
+        assert_eq!(export_filter("pdf", "XLSX"), "calc_pdf_Export");
+        assert_eq!(export_filter("pdf", "docx"), "writer_pdf_Export");
+        assert_eq!(export_filter("jpg", "pdf"), "draw_jpg_Export");
+        assert_eq!(pdfa_level("2B"), Some(("2b", 2)));
+        assert_eq!(pdfa_level("2a"), None);
+        assert_eq!(paper_size(" letter").map(|s| s.arg()), Some("21590x27940".to_string()));
+        assert_eq!(paper_size("B5"), None);
+    }
+
+    #[test]
+    fn test_csv_import() {
+        assert_eq!(CsvImport::parse(None, None).unwrap().infilter(), "Text - txt - csv (StarCalc):44,34,76,1");
+        let csv = CsvImport::parse(Some(";"), Some(" windows-1252 ")).unwrap();
+        assert_eq!(csv.filter_options(), "59,34,1,1");
+        assert_eq!(CsvImport::parse(Some("\t"), None).unwrap().filter_options(), "9,34,76,1");
+        for bad in [";;", "\"", "\n"] {
+            assert_eq!(CsvImport::parse(Some(bad), None).unwrap_err().code, "INVALID_CSV_DELIMITER");
+        }
+        assert_eq!(CsvImport::parse(None, Some("EBCDIC")).unwrap_err().code, "UNSUPPORTED_CSV_ENCODING");
+    }
+}
@@ -0,0 +1,421 @@
+//! `POST /office2pdf.ConvertService/Convert`: the conversion as a gRPC-Web call, for integrations
+//! built on Protocol Buffers (`proto/office2pdf.proto`). The server speaks HTTP/1.1 only, so this
+//! is gRPC-Web, which clients such as grpc-web, Connect or tonic-web reach directly; plain gRPC
+//! clients need a translating proxy in front.
+//!
+//! The body is a single length-prefixed message frame; the reply is a message frame followed by
+//! a trailer frame carrying `grpc-status`. Errors are reported in the trailers alone, with the
+//! usual error code in `x-error-code`.
+
+use axum::{
+    body::Bytes,
+    extract::{ConnectInfo, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Extension,
+};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use tokio::fs;
+use tracing::{error, info_span, Instrument};
+
+use crate::audit::Requester;
+use crate::crypto;
+use crate::error::ConversionError;
+use crate::telemetry::RequestId;
+use crate::{accept_file, check_extension, convert_all, create_file_dir, disk, file_too_large, sanitize_filename};
+use crate::{AppState, ConvertOptions, ConvertedFile};
+
+pub const CONVERT_PATH: &str = "/office2pdf.ConvertService/Convert";
+const CONTENT_TYPE: &str = "application/grpc-web+proto";
+/// Room for the filename and options on top of the file, when sizing the body limit.
+const MESSAGE_OVERHEAD: usize = 64 * 1024;
+
+/// The body limit for this route: `MAX_BODY_SIZE` applies to the file in the message.
+pub fn body_limit(max_body_size: usize) -> usize {
+    max_body_size + MESSAGE_OVERHEAD
+}
+
+/// gRPC status codes used here.
+const OK: u8 = 0;
+const INVALID_ARGUMENT: u8 = 3;
+const DEADLINE_EXCEEDED: u8 = 4;
+const RESOURCE_EXHAUSTED: u8 = 8;
+const UNIMPLEMENTED: u8 = 12;
+const INTERNAL: u8 = 13;
+const UNAVAILABLE: u8 = 14;
+const UNAUTHENTICATED: u8 = 16;
+
+/// The gRPC status for an error the HTTP endpoints would answer with `status`.
+fn grpc_status(status: StatusCode) -> u8 {
+    match status {
+        StatusCode::BAD_REQUEST | StatusCode::UNSUPPORTED_MEDIA_TYPE => INVALID_ARGUMENT,
+        StatusCode::UNAUTHORIZED => UNAUTHENTICATED,
+        StatusCode::PAYLOAD_TOO_LARGE | StatusCode::TOO_MANY_REQUESTS | StatusCode::INSUFFICIENT_STORAGE => RESOURCE_EXHAUSTED,
+        StatusCode::SERVICE_UNAVAILABLE => UNAVAILABLE,
+        StatusCode::GATEWAY_TIMEOUT => DEADLINE_EXCEEDED,
+        _ => INTERNAL,
+    }
+}
+
+/// A decoded `ConvertRequest`.
+#[derive(Debug, Default)]
+struct ConvertRequest {
+    filename: String,
+    content: Vec<u8>,
+    options: HashMap<String, String>,
+}
+
+impl ConvertRequest {
+    fn decode(message: &[u8]) -> Result<Self, String> {
+        let mut request = ConvertRequest::default();
+        for field in Fields::new(message) {
+            match field? {
+                (1, Value::Bytes(name)) => request.filename = string(name)?,
+                (2, Value::Bytes(content)) => request.content = content.to_vec(),
+                (3, Value::Bytes(entry)) => {
+                    let (mut key, mut value) = (String::new(), String::new());
+                    for field in Fields::new(entry) {
+                        match field? {
+                            (1, Value::Bytes(k)) => key = string(k)?,
+                            (2, Value::Bytes(v)) => value = string(v)?,
+                            _ => {}
+                        }
+                    }
+                    request.options.insert(key, value);
+                }
+                // Unknown fields are skipped, as protobuf requires
+                _ => {}
+            }
+        }
+        Ok(request)
+    }
+}
+
+/// Encodes a `ConvertResponse`.
+fn encode_response(converted: &ConvertedFile) -> Vec<u8> {
+    let mut out = Vec::with_capacity(converted.content.len() + 256);
+    put_bytes(&mut out, 1, &converted.content);
+    put_bytes(&mut out, 2, converted.filename.as_bytes());
+    put_bytes(&mut out, 3, converted.content_type.as_bytes());
+    for (name, value) in &converted.headers {
+        let mut entry = Vec::new();
+        put_bytes(&mut entry, 1, name.to_ascii_lowercase().as_bytes());
+        put_bytes(&mut entry, 2, value.as_bytes());
+        put_bytes(&mut out, 4, &entry);
+    }
+    out
+}
+
+fn string(bytes: &[u8]) -> Result<String, String> {
+    String::from_utf8(bytes.to_vec()).map_err(|_| "string field is not valid UTF-8".to_string())
+}
+
+/// A field value, by wire type. Only length-delimited fields are used by these messages.
+#[derive(Debug, PartialEq)]
+enum Value<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+    Fixed,
+}
+
+/// The (field number, value) pairs of a protobuf message.
+struct Fields<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Fields<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    fn varint(&mut self) -> Result<u64, String> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = *self.buf.get(self.pos).ok_or("truncated varint")?;
+            self.pos += 1;
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err("varint is too long".to_string())
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        let end = self.pos.checked_add(len).filter(|&end| end <= self.buf.len()).ok_or("truncated field")?;
+        let bytes = &self.buf[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn field(&mut self) -> Result<(u64, Value<'a>), String> {
+        let key = self.varint()?;
+        let value = match key & 7 {
+            0 => Value::Varint(self.varint()?),
+            1 => self.take(8).map(|_| Value::Fixed)?,
+            2 => {
+                let len = usize::try_from(self.varint()?).map_err(|_| "field is too long")?;
+                Value::Bytes(self.take(len)?)
+            }
+            5 => self.take(4).map(|_| Value::Fixed)?,
+            wire_type => return Err(format!("unsupported wire type {}", wire_type)),
+        };
+        Ok((key >> 3, value))
+    }
+}
+
+impl<'a> Iterator for Fields<'a> {
+    type Item = Result<(u64, Value<'a>), String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.pos >= self.buf.len() {
+            return None;
+        }
+        let field = self.field();
+        if field.is_err() {
+            // Nothing after a malformed field can be trusted
+            self.pos = self.buf.len();
+        }
+        Some(field)
+    }
+}
+
+fn put_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+/// Appends a length-delimited field.
+fn put_bytes(out: &mut Vec<u8>, field: u64, data: &[u8]) {
+    put_varint(out, (field << 3) | 2);
+    put_varint(out, data.len() as u64);
+    out.extend_from_slice(data);
+}
+
+/// The message in a request body: one uncompressed data frame.
+fn unframe(body: &[u8]) -> Result<&[u8], (u8, String)> {
+    let invalid = |message: &str| (INVALID_ARGUMENT, message.to_string());
+    let (&flags, rest) = body.split_first().ok_or_else(|| invalid("empty request body"))?;
+    if flags & 1 != 0 {
+        return Err((UNIMPLEMENTED, "compressed messages are not supported".to_string()));
+    }
+    let len = rest.get(..4).map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]) as usize).ok_or_else(|| invalid("truncated frame"))?;
+    if flags != 0 || rest.len() - 4 != len {
+        return Err(invalid("the body must be exactly one message frame"));
+    }
+    Ok(&rest[4..])
+}
+
+fn frame(flags: u8, payload: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(payload.len() + 5);
+    out.push(flags);
+    out.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    out.extend_from_slice(payload);
+    out
+}
+
+/// `grpc-message` is percent-encoded outside printable ASCII.
+fn percent_encode(message: &str) -> String {
+    message
+        .bytes()
+        .map(|b| match b {
+            b' '..=b'~' if b != b'%' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// A gRPC-Web reply: the message, if any, then the trailers.
+fn grpc_response(message: Option<Vec<u8>>, status: u8, grpc_message: &str, error_code: Option<&str>) -> Response {
+    let mut trailers = format!("grpc-status:{}\r\n", status);
+    if !grpc_message.is_empty() {
+        trailers.push_str(&format!("grpc-message:{}\r\n", percent_encode(grpc_message)));
+    }
+    if let Some(code) = error_code {
+        trailers.push_str(&format!("x-error-code:{}\r\n", code));
+    }
+    let mut body = message.map(|m| frame(0, &m)).unwrap_or_default();
+    body.extend(frame(0x80, trailers.as_bytes()));
+    ([(header::CONTENT_TYPE, CONTENT_TYPE)], body).into_response()
+}
+
+fn error_response(e: &ConversionError) -> Response {
+    grpc_response(None, grpc_status(e.status), &e.message, Some(e.code))
+}
+
+pub async fn convert(
+    State(state): State<Arc<AppState>>,
+    Extension(RequestId(request_id)): Extension<RequestId>,
+    peer: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let content_type = headers.get(header::CONTENT_TYPE).and_then(|v| v.to_str().ok()).unwrap_or_default();
+    if !matches!(content_type, "application/grpc-web" | "application/grpc-web+proto") {
+        return ConversionError::new(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "UNSUPPORTED_CONTENT_TYPE",
+            "Expected application/grpc-web+proto; the grpc-web-text encoding is not supported",
+        )
+        .into_response();
+    }
+    let request = match unframe(&body).and_then(|m| ConvertRequest::decode(m).map_err(|e| (INVALID_ARGUMENT, e))) {
+        Ok(request) => request,
+        Err((status, message)) => return grpc_response(None, status, &message, None),
+    };
+    if state.draining.load(Ordering::SeqCst) {
+        return grpc_response(None, UNAVAILABLE, "Server is shutting down", Some("SHUTTING_DOWN"));
+    }
+    let Some(slot) = state.try_conversion_slot() else {
+        return grpc_response(None, RESOURCE_EXHAUSTED, "Too many conversions in progress", Some("SERVER_BUSY"));
+    };
+    let _active_dir = state.active_dirs.track(request_id);
+    let work_dir = state.request_dir(request_id);
+    if let Err(e) = fs::create_dir_all(&work_dir).await {
+        error!("Failed to create work dir: {}", e);
+        return error_response(&ConversionError::internal());
+    }
+
+    let result = async {
+        disk::ensure_free_space(&work_dir, state.min_free_bytes)?;
+        let opts = ConvertOptions::from_request(&HashMap::new(), &request.options)?;
+        let filename = if request.filename.is_empty() { "document".to_string() } else { sanitize_filename(&request.filename) };
+        check_extension(&filename, &state.allowed_extensions)?;
+        let size = request.content.len() as u64;
+        if size > state.max_body_size {
+            return Err(file_too_large("Uploaded", size, state.max_body_size));
+        }
+        let path = create_file_dir(&work_dir, 0).await?.join(&filename);
+        fs::write(&path, &request.content)
+            .instrument(info_span!("disk_write", file.name = %filename))
+            .await
+            .map_err(|e| {
+                error!("Failed to write upload: {}", e);
+                ConversionError::internal()
+            })?;
+        let file = accept_file(path, size, Some(crypto::sha256(&request.content)), &state, Some(&state.allowed_input_types)).await?;
+        convert_all(&state, Requester::new(&state, request_id, &headers, peer), &work_dir, &[file], &opts).await
+    }
+    .await;
+    drop(slot);
+    let _ = fs::remove_dir_all(&work_dir).instrument(info_span!("cleanup")).await;
+
+    match result {
+        Ok(converted) => grpc_response(Some(encode_response(&converted)), OK, "", None),
+        Err(e) => error_response(&e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::service::MockConversionService;
+    use axum::{body::Body, http::Request, routing::post, Router};
+    use tower::ServiceExt;
+    use uuid::Uuid;
+
+    /// Splits a reply into its message (if any) and its trailers.
+    fn parse_reply(body: &[u8]) -> (Option<Vec<u8>>, String) {
+        let (mut message, mut trailers) = (None, String::new());
+        let mut rest = body;
+        while let [flags, a, b, c, d, tail @ ..] = rest {
+            let len = u32::from_be_bytes([*a, *b, *c, *d]) as usize;
+            if flags & 0x80 != 0 {
+                trailers = String::from_utf8(tail[..len].to_vec()).unwrap();
+            } else {
+                message = Some(tail[..len].to_vec());
+            }
+            rest = &tail[len..];
+        }
+        (message, trailers)
+    }
+
+    fn request_message(filename: &str, content: &[u8], options: &[(&str, &str)]) -> Vec<u8> {
+        let mut message = Vec::new();
+        put_bytes(&mut message, 1, filename.as_bytes());
+        put_bytes(&mut message, 2, content);
+        for (key, value) in options {
+            let mut entry = Vec::new();
+            put_bytes(&mut entry, 1, key.as_bytes());
+            put_bytes(&mut entry, 2, value.as_bytes());
+            put_bytes(&mut message, 3, &entry);
+        }
+        message
+    }
+
+    async fn call(body: Vec<u8>) -> (Option<Vec<u8>>, String) {
+        let state = AppState {
+            converter: Arc::new(MockConversionService),
+            ..crate::tests::test_state()
+        };
+        let app = Router::new()
+            .route(CONVERT_PATH, post(convert))
+            .layer(Extension(RequestId(Uuid::new_v4())))
+            .with_state(Arc::new(state));
+        let request = Request::builder()
+            .method("POST")
+            .uri(CONVERT_PATH)
+            .header(header::CONTENT_TYPE, CONTENT_TYPE)
+            .body(Body::from(body))
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], CONTENT_TYPE);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        parse_reply(&body)
+    }
+
+    #[test]
+    fn test_decode_request() {
+        let mut message = request_message("report.odt", &[0, 1, 2], &[("format", "docx")]);
+        // An unknown varint field on the end
+        put_varint(&mut message, 9 << 3);
+        put_varint(&mut message, 300);
+
+        let request = ConvertRequest::decode(unframe(&frame(0, &message)).unwrap()).unwrap();
+        assert_eq!(request.filename, "report.odt");
+        assert_eq!(request.content, [0, 1, 2]);
+        assert_eq!(request.options["format"], "docx");
+
+        assert!(ConvertRequest::decode(&message[..message.len() - 1]).is_err());
+        assert_eq!(unframe(&frame(1, &message)).unwrap_err().0, UNIMPLEMENTED);
+        assert_eq!(unframe(&[0, 0, 0, 0, 9, 1]).unwrap_err().0, INVALID_ARGUMENT);
+        assert_eq!(percent_encode("100% ok\n"), "100%25 ok%0A");
+    }
+
+    #[tokio::test]
+    async fn test_convert() {
+        let (message, trailers) = call(frame(0, &request_message("notes.md", b"# Hi", &[]))).await;
+        assert_eq!(trailers, "grpc-status:0\r\n");
+        let message = message.unwrap();
+        let fields: Vec<_> = Fields::new(&message).map(Result::unwrap).collect();
+        assert_eq!(fields[0], (1, Value::Bytes(b"# Hi")));
+        assert_eq!(fields[1], (2, Value::Bytes(b"notes.pdf")));
+        assert_eq!(fields[2], (3, Value::Bytes(b"application/pdf")));
+        let metadata: HashMap<String, String> = fields[3..]
+            .iter()
+            .map(|(_, entry)| {
+                let Value::Bytes(entry) = entry else { panic!("metadata entry is not length-delimited") };
+                let entry: Vec<_> = Fields::new(entry).map(Result::unwrap).collect();
+                let [(1, Value::Bytes(k)), (2, Value::Bytes(v))] = entry.as_slice() else { panic!("bad metadata entry") };
+                (string(k).unwrap(), string(v).unwrap())
+            })
+            .collect();
+        assert_eq!(metadata["x-conversion-backend"], "mock");
+
+        let (message, trailers) = call(frame(0, &request_message("notes.md", b"# Hi", &[("format", "bogus")]))).await;
+        assert!(message.is_none());
+        assert!(trailers.starts_with("grpc-status:3\r\n"));
+        assert!(trailers.ends_with("x-error-code:UNSUPPORTED_FORMAT\r\n"));
+
+        let (_, trailers) = call(b"\0\0".to_vec()).await;
+        assert_eq!(trailers, "grpc-status:3\r\ngrpc-message:truncated frame\r\n");
+    }
+}
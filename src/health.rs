@@ -0,0 +1,248 @@
+//! `GET /health`: reports whether LibreOffice can actually be run.
+//!
+//! `GET /ready`: the readiness probe, which also checks the work directory, free disk space and
+//! whether a conversion slot is free, so that a full or broken instance is taken out of rotation
+//! without being restarted.
+
+use axum::{
+    extract::State,
+    http::{Method, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde_json::{json, Map, Value};
+use std::io::ErrorKind;
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::fs;
+use tokio::process::Command;
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+use tracing::warn;
+
+use crate::{disk, AppState};
+
+/// Probes closer together than this share one `--version` run.
+const CACHE_TTL: Duration = Duration::from_secs(30);
+const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Readiness probes closer together than this get the same answer.
+const READY_CACHE_TTL: Duration = Duration::from_secs(10);
+/// How long `/ready` waits for LibreOffice before counting it as failed.
+const READY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The outcome of each readiness check, by name.
+type Checks = Vec<(&'static str, Result<(), String>)>;
+
+/// Cached result of running `<binary> --version`.
+pub struct LibreOfficeProbe {
+    binary: String,
+    last: Mutex<Option<(Instant, Result<String, String>)>>,
+}
+
+impl LibreOfficeProbe {
+    pub fn new(binary: &str) -> Self {
+        Self {
+            binary: binary.to_string(),
+            last: Mutex::new(None),
+        }
+    }
+
+    /// The LibreOffice version, or why it could not be determined.
+    pub async fn version(&self) -> Result<String, String> {
+        // Holding the lock across the probe keeps concurrent health checks from each spawning one
+        let mut last = self.last.lock().await;
+        if let Some((at, ref result)) = *last
+            && at.elapsed() < CACHE_TTL
+        {
+            return result.clone();
+        }
+        let result = self.probe().await;
+        *last = Some((Instant::now(), result.clone()));
+        result
+    }
+
+    async fn probe(&self) -> Result<String, String> {
+        let run = Command::new(&self.binary)
+            .arg("--version")
+            .stdin(Stdio::null())
+            .kill_on_drop(true)
+            .output();
+        let out = match tokio::time::timeout(PROBE_TIMEOUT, run).await {
+            Ok(Ok(out)) => out,
+            Ok(Err(e)) if e.kind() == ErrorKind::NotFound => return Err("libreoffice not found".to_string()),
+            Ok(Err(e)) => return Err(format!("failed to run libreoffice: {}", e)),
+            Err(_) => return Err("libreoffice --version timed out".to_string()),
+        };
+        if !out.status.success() {
+            return Err(format!("libreoffice --version exited with {}", out.status));
+        }
+        parse_version(&String::from_utf8_lossy(&out.stdout))
+            .ok_or_else(|| "unexpected libreoffice --version output".to_string())
+    }
+}
+
+/// Resolves `binary` the way spawning it will: as a path if it contains a `/`, otherwise through
+/// `PATH`. Fails unless it names an executable file.
+pub async fn find_executable(binary: &str) -> Result<PathBuf, String> {
+    if binary.contains('/') {
+        return match fs::metadata(binary).await {
+            Ok(meta) if meta.is_file() && meta.permissions().mode() & 0o111 != 0 => Ok(PathBuf::from(binary)),
+            Ok(_) => Err(format!("{} is not an executable file", binary)),
+            Err(e) => Err(format!("{}: {}", binary, e)),
+        };
+    }
+    let path = std::env::var_os("PATH").unwrap_or_default();
+    for candidate in std::env::split_paths(&path).map(|dir| dir.join(binary)) {
+        if let Ok(meta) = fs::metadata(&candidate).await
+            && meta.is_file()
+            && meta.permissions().mode() & 0o111 != 0
+        {
+            return Ok(candidate);
+        }
+    }
+    Err(format!("{} not found in PATH", binary))
+}
+
+/// Extracts `7.6.4.1` from output like `LibreOffice 7.6.4.1 e19e193f88cd6c0525a17fb7a176ed8e6a3e2aa1`.
+fn parse_version(stdout: &str) -> Option<String> {
+    stdout
+        .split_whitespace()
+        .find(|word| word.starts_with(|c: char| c.is_ascii_digit()) && word.contains('.'))
+        .map(str::to_string)
+}
+
+pub async fn health(State(state): State<Arc<AppState>>, method: Method) -> Response {
+    state.metrics.health_checks.inc();
+    let (status, body) = match state.libreoffice.version().await {
+        Ok(version) => (StatusCode::OK, json!({"status": "ok", "libreoffice_version": version})),
+        Err(e) => {
+            warn!("Health check failed: {}", e);
+            (StatusCode::SERVICE_UNAVAILABLE, json!({"status": "degraded", "error": e}))
+        }
+    };
+    if method == Method::HEAD {
+        return status.into_response();
+    }
+    (status, Json(body)).into_response()
+}
+
+/// Cached result of the readiness checks.
+#[derive(Default)]
+pub struct Readiness {
+    last: Mutex<Option<(Instant, Checks)>>,
+}
+
+impl Readiness {
+    async fn checks(&self, state: &Arc<AppState>) -> Checks {
+        let mut last = self.last.lock().await;
+        if let Some((at, ref checks)) = *last
+            && at.elapsed() < READY_CACHE_TTL
+        {
+            return checks.clone();
+        }
+        let checks = run_checks(state).await;
+        *last = Some((Instant::now(), checks.clone()));
+        checks
+    }
+}
+
+async fn run_checks(state: &Arc<AppState>) -> Checks {
+    // In its own task, so that a slow first run still finishes and is cached for the next probe
+    let probe_state = state.clone();
+    let probe = tokio::spawn(async move { probe_state.libreoffice.version().await });
+    let work_dir = disk::ensure_writable(&state.work_dir).map_err(|e| format!("{} is not writable: {}", state.work_dir.display(), e));
+    let disk_space = match disk::available_bytes(&state.work_dir) {
+        Ok(available) if available >= state.min_free_bytes => Ok(()),
+        Ok(available) => Err(format!("{} bytes free, MIN_FREE_BYTES is {}", available, state.min_free_bytes)),
+        Err(e) => Err(format!("failed to check free space: {}", e)),
+    };
+    let conversion_slots = match state.conversion_slots.available_permits() {
+        0 => Err(format!("all {} conversion slots are in use", state.max_concurrent_conversions)),
+        _ => Ok(()),
+    };
+    let libreoffice = match tokio::time::timeout(READY_TIMEOUT, probe).await {
+        Ok(Ok(version)) => version.map(|_| ()),
+        Ok(Err(e)) => Err(format!("libreoffice check failed: {}", e)),
+        Err(_) => Err("libreoffice --version did not answer in time".to_string()),
+    };
+    vec![
+        ("work_dir", work_dir),
+        ("libreoffice", libreoffice),
+        ("disk_space", disk_space),
+        ("conversion_slots", conversion_slots),
+    ]
+}
+
+/// `{"status": "ready" | "not_ready", "checks": {...}, "failed": [...]}`, with each check either
+/// `"ok"` or the reason it failed.
+fn readiness_body(checks: &Checks) -> (bool, Value) {
+    let failed: Vec<&str> = checks.iter().filter(|(_, result)| result.is_err()).map(|(name, _)| *name).collect();
+    let details: Map<String, Value> = checks
+        .iter()
+        .map(|(name, result)| (name.to_string(), json!(result.as_ref().err().map_or("ok", String::as_str))))
+        .collect();
+    let ready = failed.is_empty();
+    let status = if ready { "ready" } else { "not_ready" };
+    (ready, json!({"status": status, "checks": details, "failed": failed}))
+}
+
+pub async fn ready(State(state): State<Arc<AppState>>) -> Response {
+    let checks = state.readiness.checks(&state).await;
+    let (ready, body) = readiness_body(&checks);
+    if !ready {
+        warn!("Readiness check failed: {}", body["failed"]);
+        return (StatusCode::SERVICE_UNAVAILABLE, Json(body)).into_response();
+    }
+    Json(body).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_version() {
+        assert_eq!(
+            parse_version("LibreOffice 7.6.4.1 e19e193f88cd6c0525a17fb7a176ed8e6a3e2aa1\n").as_deref(),
+            Some("7.6.4.1")
+        );
+        assert_eq!(parse_version("LibreOffice 24.2.0.3 420(Build:3)").as_deref(), Some("24.2.0.3"));
+        assert_eq!(parse_version("usage: soffice"), None);
+    }
+
+    #[tokio::test]
+    async fn test_missing_binary() {
+        let probe = LibreOfficeProbe::new("/nonexistent/libreoffice");
+        assert_eq!(probe.version().await.unwrap_err(), "libreoffice not found");
+    }
+
+    #[test]
+    fn test_readiness_body() {
+        let (ready, body) = readiness_body(&vec![("work_dir", Ok(())), ("libreoffice", Ok(()))]);
+        assert!(ready);
+        assert_eq!(body, json!({"status": "ready", "checks": {"work_dir": "ok", "libreoffice": "ok"}, "failed": []}));
+
+        let (ready, body) = readiness_body(&vec![("work_dir", Ok(())), ("libreoffice", Err("libreoffice not found".to_string()))]);
+        assert!(!ready);
+        assert_eq!(body["status"], "not_ready");
+        assert_eq!(body["checks"]["libreoffice"], "libreoffice not found");
+        assert_eq!(body["failed"], json!(["libreoffice"]));
+    }
+
+    #[tokio::test]
+    async fn test_find_executable() {
+        assert_eq!(find_executable("/bin/sh").await, Ok(PathBuf::from("/bin/sh")));
+        assert!(find_executable("sh").await.unwrap().ends_with("sh"));
+        let plain = std::env::temp_dir().join(format!("not-executable-{}", std::process::id()));
+        fs::write(&plain, b"").await.unwrap();
+        fs::set_permissions(&plain, std::fs::Permissions::from_mode(0o644)).await.unwrap();
+        let result = find_executable(plain.to_str().unwrap()).await;
+        let _ = fs::remove_file(&plain).await;
+        assert!(result.unwrap_err().contains("not an executable"));
+        assert!(find_executable("/nonexistent/soffice").await.is_err());
+        assert!(find_executable("no-such-office-binary").await.is_err());
+    }
+}
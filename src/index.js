@@ -0,0 +1,209 @@
+const dropZone = document.getElementById('dropZone');
+const fileInput = document.getElementById('fileInput');
+const fileInfo = document.getElementById('fileInfo');
+const dropText = document.getElementById('dropText');
+const submitBtn = document.getElementById('submitBtn');
+const convertForm = document.getElementById('convertForm');
+const statusMessage = document.getElementById('statusMessage');
+const btnText = document.getElementById('btnText');
+const progress = document.getElementById('progress');
+const progressBar = document.getElementById('progressBar');
+
+// The button only works once a file is chosen; without this script the browser requires one instead
+submitBtn.disabled = true;
+
+// Drag & Drop effects
+['dragenter', 'dragover', 'dragleave', 'drop'].forEach(eventName => {
+    dropZone.addEventListener(eventName, preventDefaults, false);
+});
+
+function preventDefaults(e) {
+    e.preventDefault();
+    e.stopPropagation();
+}
+
+['dragenter', 'dragover'].forEach(eventName => {
+    dropZone.addEventListener(eventName, highlight, false);
+});
+
+['dragleave', 'drop'].forEach(eventName => {
+    dropZone.addEventListener(eventName, unhighlight, false);
+});
+
+function highlight(e) {
+    dropZone.classList.add('dragover');
+}
+
+function unhighlight(e) {
+    dropZone.classList.remove('dragover');
+}
+
+dropZone.addEventListener('drop', handleDrop, false);
+
+function handleDrop(e) {
+    const dt = e.dataTransfer;
+    const files = dt.files;
+    handleFiles(files);
+}
+
+fileInput.addEventListener('change', function() {
+    handleFiles(this.files);
+});
+
+function handleFiles(files) {
+    if (files.length > 0) {
+        const file = files[0];
+
+        // Update UI
+        fileInfo.textContent = file.name;
+        fileInfo.style.display = 'block';
+        dropText.style.display = 'none';
+        submitBtn.disabled = false;
+
+        // Update input if came from drop
+        if (fileInput.files !== files) {
+            fileInput.files = files;
+        }
+
+        resetStatus();
+        const invalid = validateFile(file);
+        if (invalid) {
+            showStatus(`Error: ${invalid}`, 'error');
+            submitBtn.disabled = true;
+        }
+    }
+}
+
+function resetStatus() {
+    statusMessage.className = 'status';
+    statusMessage.textContent = '';
+}
+
+// The error for a file the server would refuse, checked before spending time on the upload
+function validateFile(file) {
+    const maxBytes = Number(convertForm.dataset.maxBytes);
+    if (maxBytes && file.size > maxBytes) {
+        return `${file.name} is ${formatSize(file.size)}; the limit is ${formatSize(maxBytes)}.`;
+    }
+    const allowed = fileInput.accept.split(',').map(ext => ext.trim().toLowerCase()).filter(Boolean);
+    const dot = file.name.lastIndexOf('.');
+    const extension = dot === -1 ? '' : file.name.slice(dot).toLowerCase();
+    if (allowed.length > 0 && !allowed.includes(extension)) {
+        return `${extension || 'Files without an extension'} is not accepted; use ${allowed.join(', ')}.`;
+    }
+    return null;
+}
+
+function formatSize(bytes) {
+    if (bytes >= 1024 * 1024) return `${(bytes / (1024 * 1024)).toFixed(1)} MB`;
+    if (bytes >= 1024) return `${(bytes / 1024).toFixed(1)} KB`;
+    return `${bytes} bytes`;
+}
+
+convertForm.addEventListener('submit', function(e) {
+    // Without this script the form posts to /ui/convert itself
+    e.preventDefault();
+
+    const file = fileInput.files[0];
+    if (!file) return;
+
+    resetStatus();
+    const invalid = validateFile(file);
+    if (invalid) {
+        showStatus(`Error: ${invalid}`, 'error');
+        return;
+    }
+    setLoading(true);
+
+    const formData = new FormData();
+    formData.append('file', file);
+
+    // XMLHttpRequest rather than fetch, which cannot report upload progress
+    const xhr = new XMLHttpRequest();
+    xhr.open('POST', convertForm.action);
+    xhr.responseType = 'blob';
+
+    xhr.upload.onprogress = function(e) {
+        if (e.lengthComputable) {
+            setProgress(e.loaded / e.total);
+            btnText.textContent = `Uploading... ${Math.round((e.loaded / e.total) * 100)}%`;
+        }
+    };
+    xhr.upload.onload = function() {
+        progress.classList.add('converting');
+        btnText.textContent = 'Converting...';
+    };
+
+    xhr.onload = async function() {
+        setLoading(false);
+        if (xhr.status >= 200 && xhr.status < 300) {
+            download(xhr.response, filenameFrom(xhr.getResponseHeader('content-disposition'), file.name));
+            const elapsed = xhr.getResponseHeader('x-conversion-time-ms');
+            const timing = elapsed ? ` Converted in ${(Number(elapsed) / 1000).toFixed(1)}s.` : '';
+            showStatus(`Conversion successful! Downloading...${timing}`, 'success');
+        } else {
+            const text = await xhr.response.text();
+            let message = text;
+            try {
+                message = JSON.parse(text).message || text;
+            } catch (_) {
+                // Not a JSON error body (e.g. a proxy error page)
+            }
+            showStatus(`Error: ${message || xhr.statusText}`, 'error');
+        }
+    };
+    xhr.onerror = function() {
+        setLoading(false);
+        showStatus('Network error occurred.', 'error');
+    };
+
+    xhr.send(formData);
+});
+
+function filenameFrom(disposition, uploaded) {
+    if (disposition && disposition.indexOf('filename=') !== -1) {
+        const matches = /filename[^;=\n]*=((['"]).*?\2|[^;\n]*)/.exec(disposition);
+        if (matches != null && matches[1]) {
+            return matches[1].replace(/['"]/g, '');
+        }
+    }
+    // fallback: replace extension
+    return uploaded.replace(/\.[^/.]+$/, "") + ".pdf";
+}
+
+function download(blob, filename) {
+    const url = window.URL.createObjectURL(blob);
+    const a = document.createElement('a');
+    a.style.display = 'none';
+    a.href = url;
+    a.download = filename;
+    document.body.appendChild(a);
+    a.click();
+    a.remove();
+    window.URL.revokeObjectURL(url);
+}
+
+function setProgress(fraction) {
+    progressBar.style.width = `${Math.round(fraction * 100)}%`;
+}
+
+function setLoading(isLoading) {
+    if (isLoading) {
+        submitBtn.classList.add('loading');
+        submitBtn.disabled = true;
+        btnText.textContent = 'Uploading...';
+        setProgress(0);
+        progress.hidden = false;
+    } else {
+        progress.hidden = true;
+        progress.classList.remove('converting');
+        submitBtn.classList.remove('loading');
+        submitBtn.disabled = false;
+        btnText.textContent = 'Convert to PDF';
+    }
+}
+
+function showStatus(msg, type) {
+    statusMessage.textContent = msg;
+    statusMessage.className = `status visible ${type}`;
+}
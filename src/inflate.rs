@@ -0,0 +1,373 @@
+//! Raw DEFLATE (RFC 1951) decompression, for the entries of office documents and the streams of
+//! PDFs, and the gzip (RFC 1952) wrapping of it that compressed request bodies come in.
+//!
+//! Input is read through a 64-bit bit buffer and codes are decoded with a table indexed by the
+//! next bits, as zlib's `inflate` does, without its second-level tables: a table covers the
+//! longest code, at most 2^15 entries. Output goes through an [`Output`] window, so a request body
+//! is decompressed from a reader as it arrives, holding no more than the 32 KiB it refers back to.
+
+use std::io::{ErrorKind, Read};
+
+use crate::window::{DecodeError, Output, Sink};
+use crate::zip::crc32_update;
+
+pub const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258,
+];
+pub const LENGTH_EXTRA: [u8; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+pub const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145,
+    8193, 12289, 16385, 24577,
+];
+pub const DIST_EXTRA: [u8; 30] = [0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13];
+/// Order in which a dynamic block lists the code length code lengths.
+const CODE_LENGTH_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+const MAX_BITS: usize = 15;
+
+const GZIP_FHCRC: u8 = 0x02;
+const GZIP_FEXTRA: u8 = 0x04;
+const GZIP_FNAME: u8 = 0x08;
+const GZIP_FCOMMENT: u8 = 0x10;
+const WINDOW: usize = 32 * 1024;
+
+/// Decompresses a single-member gzip file, failing with `TooLarge` rather than producing more
+/// than `max_len` bytes. Request bodies go through [`gunzip_from`] instead, as they arrive.
+#[cfg(test)]
+pub fn gunzip(data: &[u8], max_len: usize) -> Result<Vec<u8>, DecodeError> {
+    let mut out = Vec::new();
+    gunzip_from(data, max_len, &mut |chunk| {
+        out.extend_from_slice(chunk);
+        Ok(())
+    })?;
+    Ok(out)
+}
+
+/// Decompresses a single-member gzip file read from `input`, handing the output to `sink` as it
+/// goes. What was handed on before a failure is to be thrown away.
+pub fn gunzip_from(input: impl Read, max_len: usize, sink: Sink) -> Result<(), DecodeError> {
+    let mut input = Bits::new(input);
+    let header = [input.bits(8)?, input.bits(8)?, input.bits(8)?, input.bits(8)?];
+    let [0x1f, 0x8b, 8, flags] = header else {
+        return Err(DecodeError::Corrupt);
+    };
+    let flags = flags as u8;
+    // Modification time, extra flags, operating system
+    for _ in 0..6 {
+        input.bits(8)?;
+    }
+    if flags & GZIP_FEXTRA != 0 {
+        for _ in 0..input.bits(16)? {
+            input.bits(8)?;
+        }
+    }
+    for flag in [GZIP_FNAME, GZIP_FCOMMENT] {
+        if flags & flag != 0 {
+            while input.bits(8)? != 0 {}
+        }
+    }
+    if flags & GZIP_FHCRC != 0 {
+        input.bits(16)?;
+    }
+
+    // The CRC-32 and length of the uncompressed data follow the DEFLATE stream
+    let (mut crc, mut len) = (0, 0u32);
+    let mut checked = |chunk: &[u8]| {
+        crc = crc32_update(crc, chunk);
+        len = len.wrapping_add(chunk.len() as u32);
+        sink(chunk)
+    };
+    let mut out = Output::new(WINDOW, max_len, &mut checked);
+    blocks(&mut input, &mut out)?;
+    out.finish()?;
+    input.align();
+    if input.bits(32)? != crc || input.bits(32)? != len || !input.at_end()? {
+        return Err(DecodeError::Corrupt);
+    }
+    Ok(())
+}
+
+/// Decompresses `data`, failing with `TooLarge` rather than producing more than `max_len` bytes.
+pub fn inflate(data: &[u8], max_len: usize) -> Result<Vec<u8>, DecodeError> {
+    let mut out = Vec::new();
+    let mut sink = |chunk: &[u8]| {
+        out.extend_from_slice(chunk);
+        Ok(())
+    };
+    let mut output = Output::new(WINDOW, max_len, &mut sink);
+    blocks(&mut Bits::new(data), &mut output)?;
+    output.finish()?;
+    Ok(out)
+}
+
+fn blocks<R: Read>(input: &mut Bits<R>, out: &mut Output) -> Result<(), DecodeError> {
+    loop {
+        let last = input.bits(1)? == 1;
+        match input.bits(2)? {
+            0 => stored(input, out)?,
+            1 => {
+                let (lengths, distances) = fixed_tables();
+                codes(input, out, &lengths, &distances)?;
+            }
+            2 => {
+                let (lengths, distances) = dynamic_tables(input)?;
+                codes(input, out, &lengths, &distances)?;
+            }
+            _ => return Err(DecodeError::Corrupt),
+        }
+        if last {
+            return Ok(());
+        }
+    }
+}
+
+struct Bits<R> {
+    input: R,
+    buf: Vec<u8>,
+    start: usize,
+    end: usize,
+    /// Bits read ahead of use; DEFLATE packs bits starting from each byte's least significant one.
+    bits: u64,
+    count: u32,
+}
+
+impl<R: Read> Bits<R> {
+    fn new(input: R) -> Self {
+        Bits { input, buf: vec![0; 16 * 1024], start: 0, end: 0, bits: 0, count: 0 }
+    }
+
+    /// Reads more of the input into `buf` once it is used up, returning whether there was any.
+    fn fill(&mut self) -> Result<bool, DecodeError> {
+        if self.start < self.end {
+            return Ok(true);
+        }
+        loop {
+            match self.input.read(&mut self.buf) {
+                Ok(n) => {
+                    (self.start, self.end) = (0, n);
+                    return Ok(n > 0);
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(_) => return Err(DecodeError::Corrupt),
+            }
+        }
+    }
+
+    /// Tops the bit buffer up to at least 57 bits, or to what is left of the input.
+    fn refill(&mut self) -> Result<(), DecodeError> {
+        while self.count <= 56 && self.fill()? {
+            self.bits |= (self.buf[self.start] as u64) << self.count;
+            self.start += 1;
+            self.count += 8;
+        }
+        Ok(())
+    }
+
+    fn bits(&mut self, n: u32) -> Result<u32, DecodeError> {
+        if self.count < n {
+            self.refill()?;
+            if self.count < n {
+                return Err(DecodeError::Corrupt);
+            }
+        }
+        let value = (self.bits & ((1 << n) - 1)) as u32;
+        self.bits >>= n;
+        self.count -= n;
+        Ok(value)
+    }
+
+    fn decode(&mut self, table: &Huffman) -> Result<u16, DecodeError> {
+        if self.count < MAX_BITS as u32 {
+            self.refill()?;
+        }
+        let entry = table.entries[(self.bits & table.mask) as usize];
+        let len = (entry & 0xF) as u32;
+        // Unused entries of an incomplete code have no length
+        if len == 0 || len > self.count {
+            return Err(DecodeError::Corrupt);
+        }
+        self.bits >>= len;
+        self.count -= len;
+        Ok(entry >> 4)
+    }
+
+    /// Skips to the next byte boundary.
+    fn align(&mut self) {
+        let skip = self.count % 8;
+        self.bits >>= skip;
+        self.count -= skip;
+    }
+
+    /// Copies `n` bytes from the input to `out`, once aligned.
+    fn copy(&mut self, mut n: usize, out: &mut Output) -> Result<(), DecodeError> {
+        while n > 0 && self.count > 0 {
+            out.push(self.bits(8)? as u8)?;
+            n -= 1;
+        }
+        while n > 0 {
+            if !self.fill()? {
+                return Err(DecodeError::Corrupt);
+            }
+            let take = n.min(self.end - self.start);
+            out.extend(&self.buf[self.start..self.start + take])?;
+            self.start += take;
+            n -= take;
+        }
+        Ok(())
+    }
+
+    /// Whether nothing is left of the input past whole bytes read, once aligned.
+    fn at_end(&mut self) -> Result<bool, DecodeError> {
+        Ok(self.count == 0 && !self.fill()?)
+    }
+}
+
+/// A canonical Huffman code as a table indexed by as many of the next bits as its longest code
+/// has, each entry holding a symbol in its upper bits and its code's length in the lowest four.
+struct Huffman {
+    entries: Vec<u16>,
+    mask: u64,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Result<Self, DecodeError> {
+        let mut counts = [0u16; MAX_BITS + 1];
+        for &len in lengths {
+            counts[len as usize] += 1;
+        }
+        counts[0] = 0;
+        // More codes of some length than the shorter ones leave room for
+        let mut left = 1i32;
+        for &count in &counts[1..] {
+            left = (left << 1) - count as i32;
+            if left < 0 {
+                return Err(DecodeError::Corrupt);
+            }
+        }
+
+        let mut next = [0u32; MAX_BITS + 1];
+        for len in 1..MAX_BITS {
+            next[len + 1] = (next[len] + counts[len] as u32) << 1;
+        }
+        let max = lengths.iter().copied().max().unwrap_or(0).max(1);
+        let mut entries = vec![0u16; 1 << max];
+        for (symbol, &len) in lengths.iter().enumerate().filter(|(_, len)| **len != 0) {
+            let code = next[len as usize];
+            next[len as usize] += 1;
+            // Codes are packed most significant bit first, unlike everything else, and fill every
+            // entry whose index ends in them
+            let mut index = (code.reverse_bits() >> (32 - len)) as usize;
+            while index < entries.len() {
+                entries[index] = (symbol as u16) << 4 | len as u16;
+                index += 1 << len;
+            }
+        }
+        Ok(Self { entries, mask: (1 << max) - 1 })
+    }
+}
+
+fn stored<R: Read>(input: &mut Bits<R>, out: &mut Output) -> Result<(), DecodeError> {
+    input.align();
+    let len = input.bits(16)?;
+    if len != !input.bits(16)? & 0xFFFF {
+        return Err(DecodeError::Corrupt);
+    }
+    input.copy(len as usize, out)
+}
+
+fn fixed_tables() -> (Huffman, Huffman) {
+    let mut lengths = [0u8; 288];
+    lengths[..144].fill(8);
+    lengths[144..256].fill(9);
+    lengths[256..280].fill(7);
+    lengths[280..].fill(8);
+    let lengths = Huffman::new(&lengths).expect("fixed code is valid");
+    let distances = Huffman::new(&[5; 30]).expect("fixed code is valid");
+    (lengths, distances)
+}
+
+fn dynamic_tables<R: Read>(input: &mut Bits<R>) -> Result<(Huffman, Huffman), DecodeError> {
+    let literal_count = input.bits(5)? as usize + 257;
+    let distance_count = input.bits(5)? as usize + 1;
+    let code_length_count = input.bits(4)? as usize + 4;
+    if literal_count > 286 || distance_count > 30 {
+        return Err(DecodeError::Corrupt);
+    }
+
+    let mut code_lengths = [0u8; 19];
+    for &i in &CODE_LENGTH_ORDER[..code_length_count] {
+        code_lengths[i] = input.bits(3)? as u8;
+    }
+    let code_length_table = Huffman::new(&code_lengths)?;
+
+    let mut lengths = vec![0u8; literal_count + distance_count];
+    let mut i = 0;
+    while i < lengths.len() {
+        let symbol = input.decode(&code_length_table)?;
+        let (value, repeat) = match symbol {
+            0..=15 => (symbol as u8, 1),
+            16 => (*lengths[..i].last().ok_or(DecodeError::Corrupt)?, 3 + input.bits(2)?),
+            17 => (0, 3 + input.bits(3)?),
+            _ => (0, 11 + input.bits(7)?),
+        };
+        let end = i + repeat as usize;
+        lengths.get_mut(i..end).ok_or(DecodeError::Corrupt)?.fill(value);
+        i = end;
+    }
+    if lengths[256] == 0 {
+        // No end-of-block code
+        return Err(DecodeError::Corrupt);
+    }
+    Ok((Huffman::new(&lengths[..literal_count])?, Huffman::new(&lengths[literal_count..])?))
+}
+
+fn codes<R: Read>(input: &mut Bits<R>, out: &mut Output, lengths: &Huffman, distances: &Huffman) -> Result<(), DecodeError> {
+    loop {
+        let symbol = input.decode(lengths)? as usize;
+        if symbol < 256 {
+            out.push(symbol as u8)?;
+            continue;
+        }
+        if symbol == 256 {
+            return Ok(());
+        }
+
+        let index = symbol - 257;
+        let len = *LENGTH_BASE.get(index).ok_or(DecodeError::Corrupt)? as usize + input.bits(LENGTH_EXTRA[index] as u32)? as usize;
+        let index = input.decode(distances)? as usize;
+        let dist = *DIST_BASE.get(index).ok_or(DecodeError::Corrupt)? as usize + input.bits(DIST_EXTRA[index] as u32)? as usize;
+        out.copy(dist, len)?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_inflate() {
+        // `printf 'hello hello hello hello\n' | gzip -9`, without the gzip header and trailer
+        let fixed = [0xcb, 0x48, 0xcd, 0xc9, 0xc9, 0x57, 0xc8, 0x40, 0x27, 0xb9, 0x00];
+        assert_eq!(inflate(&fixed, 1024).unwrap(), b"hello hello hello hello\n");
+        assert_eq!(inflate(&fixed, 10), Err(DecodeError::TooLarge));
+        assert_eq!(inflate(&fixed[..5], 1024), Err(DecodeError::Corrupt));
+
+        let stored = [0x01, 0x03, 0x00, 0xfc, 0xff, b'a', b'b', b'c'];
+        assert_eq!(inflate(&stored, 1024).unwrap(), b"abc");
+    }
+
+    #[test]
+    fn test_gunzip() {
+        // `printf 'hello hello hello hello\n' > hi.txt; gzip -9 hi.txt`, which records the name
+        let mut gzip = vec![0x1f, 0x8b, 0x08, 0x08, 0, 0, 0, 0, 0x02, 0x03, b'h', b'i', b'.', b't', b'x', b't', 0];
+        gzip.extend_from_slice(&[0xcb, 0x48, 0xcd, 0xc9, 0xc9, 0x57, 0xc8, 0x40, 0x27, 0xb9, 0x00]);
+        gzip.extend_from_slice(&crate::zip::crc32(b"hello hello hello hello\n").to_le_bytes());
+        gzip.extend_from_slice(&24u32.to_le_bytes());
+        assert_eq!(gunzip(&gzip, 1024).unwrap(), b"hello hello hello hello\n");
+        assert_eq!(gunzip(&gzip, 10), Err(DecodeError::TooLarge));
+
+        let last = gzip.len() - 1;
+        gzip[last] ^= 1;
+        assert_eq!(gunzip(&gzip, 1024), Err(DecodeError::Corrupt));
+        assert_eq!(gunzip(b"plain text", 1024), Err(DecodeError::Corrupt));
+    }
+}
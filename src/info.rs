@@ -0,0 +1,150 @@
+//! What a running instance is configured with, for operators checking a deployment: served as
+//! JSON by `GET /config` and shown at the foot of the upload page. `GET /info` adds what the
+//! installed LibreOffice can import.
+
+use axum::{extract::State, http::header, response::{Html, IntoResponse}, Json};
+use serde_json::{json, Value};
+use std::sync::Arc;
+
+use crate::filters::FormatInfo;
+use crate::{admin, markdown, AppState};
+
+const INDEX_TEMPLATE: &str = include_str!("index.html");
+// Served separately so the page works under `Content-Security-Policy: default-src 'self'`
+const INDEX_CSS: &str = include_str!("index.css");
+const INDEX_JS: &str = include_str!("index.js");
+
+pub struct ServerInfo {
+    pub version: &'static str,
+    /// The settings that switched on an optional feature, as on `/admin/status`.
+    pub features: Vec<&'static str>,
+    pub max_body_size: u64,
+    pub allowed_extensions: Vec<String>,
+    /// Cached like the health check; `None` if it cannot be determined.
+    pub libreoffice_version: Option<String>,
+}
+
+impl ServerInfo {
+    pub async fn collect(state: &AppState) -> Self {
+        Self {
+            version: env!("CARGO_PKG_VERSION"),
+            features: admin::enabled_features(state),
+            max_body_size: state.max_body_size,
+            allowed_extensions: state.allowed_extensions.clone(),
+            libreoffice_version: state.libreoffice.version().await.ok(),
+        }
+    }
+
+    fn to_json(&self) -> Value {
+        json!({
+            "version": self.version,
+            "features": self.features,
+            "max_body_size": self.max_body_size,
+            "allowed_extensions": self.allowed_extensions,
+            "libreoffice_version": self.libreoffice_version,
+        })
+    }
+
+    /// The upload page with `{{name}}` placeholders filled in.
+    pub fn render_index(&self) -> String {
+        let list = |items: &[&str]| if items.is_empty() { "none".to_string() } else { items.join(", ") };
+        let extensions: Vec<&str> = self.allowed_extensions.iter().map(String::as_str).collect();
+        let accept: Vec<String> = extensions.iter().map(|e| format!(".{}", e)).collect();
+        [
+            ("version", self.version.to_string()),
+            ("libreoffice_version", self.libreoffice_version.clone().unwrap_or_else(|| "unknown".to_string())),
+            ("max_body_size", format_bytes(self.max_body_size)),
+            ("max_body_bytes", self.max_body_size.to_string()),
+            ("features", list(&self.features)),
+            ("allowed_extensions", list(&extensions)),
+            ("accept", accept.join(",")),
+        ]
+        .iter()
+        .fold(INDEX_TEMPLATE.to_string(), |page, (name, value)| {
+            page.replace(&format!("{{{{{}}}}}", name), &markdown::escape(value))
+        })
+    }
+}
+
+/// `1048576` as `1 MB`; sizes that are not a whole number of KB or MB stay in bytes.
+fn format_bytes(bytes: u64) -> String {
+    match bytes {
+        0 => "0 bytes".to_string(),
+        b if b.is_multiple_of(1024 * 1024) => format!("{} MB", b / (1024 * 1024)),
+        b if b.is_multiple_of(1024) => format!("{} KB", b / 1024),
+        b => format!("{} bytes", b),
+    }
+}
+
+pub async fn index(State(state): State<Arc<AppState>>) -> Html<String> {
+    Html(ServerInfo::collect(&state).await.render_index())
+}
+
+pub async fn stylesheet() -> impl IntoResponse {
+    ([(header::CONTENT_TYPE, "text/css; charset=utf-8")], INDEX_CSS)
+}
+
+pub async fn script() -> impl IntoResponse {
+    ([(header::CONTENT_TYPE, "text/javascript; charset=utf-8")], INDEX_JS)
+}
+
+pub async fn config(State(state): State<Arc<AppState>>) -> Json<Value> {
+    Json(ServerInfo::collect(&state).await.to_json())
+}
+
+pub async fn info(State(state): State<Arc<AppState>>) -> Json<Value> {
+    Json(json!({
+        "server_version": env!("CARGO_PKG_VERSION"),
+        "libreoffice_version": state.libreoffice.version().await.ok(),
+        "supported_import_formats": state.import_formats.iter().map(FormatInfo::to_json).collect::<Vec<_>>(),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_index() {
+        let info = ServerInfo {
+            version: "1.2.3",
+            features: vec!["API_KEYS", "CACHE_MAX_ENTRIES"],
+            max_body_size: 10 * 1024 * 1024,
+            allowed_extensions: vec!["docx".to_string(), "csv".to_string()],
+            libreoffice_version: Some("<7.6>".to_string()),
+        };
+        let page = info.render_index();
+        assert!(!page.contains("{{"));
+        assert!(page.contains("1.2.3"));
+        assert!(page.contains("&lt;7.6&gt;"));
+        assert!(page.contains("10 MB"));
+        assert!(page.contains("API_KEYS, CACHE_MAX_ENTRIES"));
+        assert!(page.contains(r#"accept=".docx,.csv""#));
+        assert!(page.contains(r#"data-max-bytes="10485760""#));
+        assert_eq!(info.to_json()["allowed_extensions"], json!(["docx", "csv"]));
+
+        assert_eq!(format_bytes(1536), "1536 bytes");
+        assert_eq!(format_bytes(2048), "2 KB");
+    }
+
+    #[tokio::test]
+    async fn test_info() {
+        let state = AppState {
+            libreoffice: crate::health::LibreOfficeProbe::new("/nonexistent/soffice"),
+            import_formats: vec![FormatInfo {
+                name: "MS Word 95".to_string(),
+                extensions: vec!["doc".to_string()],
+                description: "Word 6.0/95".to_string(),
+                reliability: Some("low"),
+            }],
+            ..crate::tests::test_state()
+        };
+        let Json(body) = info(State(Arc::new(state))).await;
+        assert_eq!(body["server_version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(body["libreoffice_version"], Value::Null);
+        assert_eq!(
+            body["supported_import_formats"],
+            json!([{"name": "MS Word 95", "extensions": ["doc"], "description": "Word 6.0/95", "reliability": "low"}])
+        );
+    }
+}
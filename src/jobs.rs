@@ -0,0 +1,542 @@
+use axum::{
+    body::Bytes,
+    extract::{Path, State},
+    http::StatusCode,
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+    Json,
+};
+use futures_util::stream::{self, Stream};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::fs;
+use tokio::sync::broadcast;
+use tokio::task::AbortHandle;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::error::ConversionError;
+use crate::{file_response, AppState, ConvertedFile, Disposition};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JobStatus {
+    Pending,
+    Running,
+    Done,
+    Failed,
+}
+
+impl JobStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            JobStatus::Pending => "pending",
+            JobStatus::Running => "running",
+            JobStatus::Done => "done",
+            JobStatus::Failed => "failed",
+        }
+    }
+
+    fn is_finished(self) -> bool {
+        matches!(self, JobStatus::Done | JobStatus::Failed)
+    }
+}
+
+/// Comments sent on an idle event stream, so that proxies do not time it out.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Status updates not yet read by an event stream; a slower one skips to the latest.
+const EVENT_BUFFER: usize = 8;
+
+struct Job {
+    status: JobStatus,
+    work_dir: PathBuf,
+    submitted_at: Instant,
+    finished_at: Option<Instant>,
+    /// The task converting it, until it finishes.
+    task: Option<AbortHandle>,
+    result: Option<Arc<ConvertedFile>>,
+    error: Option<ConversionError>,
+    /// Each status the job moves to, for `GET /jobs/{id}/events`.
+    events: broadcast::Sender<(JobStatus, Value)>,
+}
+
+impl Job {
+    /// The `GET /jobs/{id}` body, also sent as each event.
+    fn to_json(&self, id: Uuid) -> Value {
+        let mut body = json!({ "job_id": id, "status": self.status.as_str() });
+        if let Some(ref e) = self.error {
+            body["error"] = json!(e);
+        }
+        body
+    }
+
+    fn set_status(&mut self, id: Uuid, status: JobStatus) {
+        self.status = status;
+        // Nobody may be listening
+        let _ = self.events.send((status, self.to_json(id)));
+    }
+}
+
+/// In-memory registry of background conversions started with `POST /convert?async=true`.
+///
+/// Finished jobs keep their output in memory until they are older than the TTL. Jobs still
+/// unfinished `max_age` after they were submitted are cancelled.
+pub struct JobStore {
+    jobs: Mutex<HashMap<Uuid, Job>>,
+    ttl: Duration,
+    max_age: Duration,
+}
+
+impl JobStore {
+    pub fn new(ttl: Duration, max_age: Duration) -> Self {
+        Self {
+            jobs: Mutex::new(HashMap::new()),
+            ttl,
+            max_age,
+        }
+    }
+
+    pub fn submit(&self, id: Uuid, work_dir: PathBuf) -> Uuid {
+        let job = Job {
+            status: JobStatus::Pending,
+            work_dir,
+            submitted_at: Instant::now(),
+            finished_at: None,
+            task: None,
+            result: None,
+            error: None,
+            events: broadcast::channel(EVENT_BUFFER).0,
+        };
+        self.jobs.lock().unwrap().insert(id, job);
+        id
+    }
+
+    /// Records the task running the job, so that it can be cancelled.
+    pub fn set_task(&self, id: Uuid, task: AbortHandle) {
+        if let Some(job) = self.jobs.lock().unwrap().get_mut(&id)
+            && job.finished_at.is_none()
+        {
+            job.task = Some(task);
+        }
+    }
+
+    pub fn mark_running(&self, id: Uuid) {
+        if let Some(job) = self.jobs.lock().unwrap().get_mut(&id) {
+            job.set_status(id, JobStatus::Running);
+        }
+    }
+
+    pub fn complete(&self, id: Uuid, result: Result<ConvertedFile, ConversionError>) {
+        // A job cancelled for its age stays failed
+        if let Some(job) = self.jobs.lock().unwrap().get_mut(&id)
+            && job.finished_at.is_none()
+        {
+            job.finished_at = Some(Instant::now());
+            job.task = None;
+            match result {
+                Ok(converted) => {
+                    job.result = Some(Arc::new(converted));
+                    job.set_status(id, JobStatus::Done);
+                }
+                Err(e) => {
+                    job.error = Some(e);
+                    job.set_status(id, JobStatus::Failed);
+                }
+            }
+        }
+    }
+
+    pub fn count(&self, status: JobStatus) -> usize {
+        self.jobs.lock().unwrap().values().filter(|job| job.status == status).count()
+    }
+
+    /// The output of a finished job.
+    pub fn result(&self, id: Uuid) -> Result<Arc<ConvertedFile>, ConversionError> {
+        let jobs = self.jobs.lock().unwrap();
+        let job = jobs.get(&id).ok_or_else(job_not_found)?;
+        job.result.clone().ok_or_else(|| {
+            ConversionError::new(StatusCode::CONFLICT, "JOB_NOT_DONE", format!("Job is {}", job.status.as_str()))
+        })
+    }
+
+    /// Stops a pending or running job and drops it, returning its work directory for removal.
+    /// Finished jobs are left alone and reported by their status.
+    fn cancel(&self, id: Uuid) -> Result<PathBuf, Option<JobStatus>> {
+        let mut jobs = self.jobs.lock().unwrap();
+        let job = jobs.get(&id).ok_or(None)?;
+        if job.finished_at.is_some() {
+            return Err(Some(job.status));
+        }
+        let job = jobs.remove(&id).expect("job was just found");
+        if let Some(task) = job.task {
+            task.abort();
+        }
+        Ok(job.work_dir)
+    }
+
+    /// Stops jobs unfinished `max_age` after submission, failing them with `JOB_EXPIRED` so that
+    /// pollers learn why, and returns their ids and work directories.
+    fn cancel_overdue(&self) -> Vec<(Uuid, PathBuf)> {
+        let mut jobs = self.jobs.lock().unwrap();
+        let mut cancelled = Vec::new();
+        for (&id, job) in jobs.iter_mut() {
+            if job.finished_at.is_some() || job.submitted_at.elapsed() < self.max_age {
+                continue;
+            }
+            if let Some(task) = job.task.take() {
+                task.abort();
+            }
+            job.finished_at = Some(Instant::now());
+            job.error = Some(ConversionError::new(
+                StatusCode::REQUEST_TIMEOUT,
+                "JOB_EXPIRED",
+                format!("The job was cancelled after {}s (JOB_MAX_AGE_SECS)", self.max_age.as_secs()),
+            ));
+            job.set_status(id, JobStatus::Failed);
+            cancelled.push((id, job.work_dir.clone()));
+        }
+        cancelled
+    }
+
+    /// Drops finished jobs older than the TTL and returns their work directories for removal.
+    fn evict_expired(&self) -> Vec<PathBuf> {
+        let mut jobs = self.jobs.lock().unwrap();
+        let expired: Vec<Uuid> = jobs
+            .iter()
+            .filter(|(_, job)| job.finished_at.is_some_and(|t| t.elapsed() >= self.ttl))
+            .map(|(id, _)| *id)
+            .collect();
+        expired
+            .into_iter()
+            .filter_map(|id| jobs.remove(&id).map(|job| job.work_dir))
+            .collect()
+    }
+}
+
+/// Periodically cancels overdue jobs, evicts expired ones and removes any temp files they left
+/// behind.
+pub async fn run_eviction(state: Arc<AppState>) {
+    let period = state.jobs.ttl.min(state.jobs.max_age).min(Duration::from_secs(60));
+    let mut interval = tokio::time::interval(period);
+    loop {
+        interval.tick().await;
+        for (id, work_dir) in state.jobs.cancel_overdue() {
+            warn!("Cancelled job {} after {}s (JOB_MAX_AGE_SECS)", id, state.jobs.max_age.as_secs());
+            state.metrics.jobs_force_cancelled.inc();
+            let _ = fs::remove_dir_all(&work_dir).await;
+        }
+        let evicted = state.jobs.evict_expired();
+        if !evicted.is_empty() {
+            info!("Evicted {} expired jobs", evicted.len());
+        }
+        for dir in evicted {
+            let _ = fs::remove_dir_all(&dir).await;
+        }
+    }
+}
+
+fn job_not_found() -> ConversionError {
+    ConversionError::new(StatusCode::NOT_FOUND, "JOB_NOT_FOUND", "No such job")
+}
+
+pub async fn status(State(state): State<Arc<AppState>>, Path(id): Path<Uuid>) -> Response {
+    let jobs = state.jobs.jobs.lock().unwrap();
+    let Some(job) = jobs.get(&id) else {
+        return job_not_found().into_response();
+    };
+    Json(job.to_json(id)).into_response()
+}
+
+/// The most job ids one `POST /jobs/status` may ask about.
+pub const MAX_BATCH_STATUS_IDS: usize = 100;
+
+/// The `job_ids` of a `POST /jobs/status` body.
+fn parse_job_ids(body: &[u8]) -> Result<Vec<String>, ConversionError> {
+    let invalid = |message: String| ConversionError::new(StatusCode::BAD_REQUEST, "INVALID_JSON_BODY", message);
+    let Ok(Value::Object(body)) = serde_json::from_slice(body) else {
+        return Err(invalid("The body must be a JSON object".to_string()));
+    };
+    let ids = match body.get("job_ids") {
+        Some(Value::Array(ids)) => ids,
+        _ => return Err(invalid("job_ids must be an array of job ids".to_string())),
+    };
+    if ids.len() > MAX_BATCH_STATUS_IDS {
+        return Err(ConversionError::new(
+            StatusCode::BAD_REQUEST,
+            "TOO_MANY_JOB_IDS",
+            format!("At most {} job ids may be looked up at once", MAX_BATCH_STATUS_IDS),
+        )
+        .with_detail("max", MAX_BATCH_STATUS_IDS)
+        .with_detail("received", ids.len()));
+    }
+    ids.iter().map(|id| id.as_str().map(str::to_string).ok_or_else(|| invalid("job_ids must be an array of job ids".to_string()))).collect()
+}
+
+/// `POST /jobs/status`: the status of each of up to 100 jobs, by id, read under a single lock.
+/// Ids that are unknown, expired or not ids at all get `not_found`.
+pub async fn batch_status(State(state): State<Arc<AppState>>, body: Bytes) -> Response {
+    let ids = match parse_job_ids(&body) {
+        Ok(ids) => ids,
+        Err(e) => return e.into_response(),
+    };
+    let jobs = state.jobs.jobs.lock().unwrap();
+    let statuses: serde_json::Map<String, Value> = ids
+        .into_iter()
+        .map(|id| {
+            let job = Uuid::parse_str(&id).ok().and_then(|uuid| jobs.get(&uuid));
+            let status = match job {
+                None => json!({ "status": "not_found" }),
+                Some(job) => {
+                    let mut status = json!({ "status": job.status.as_str() });
+                    if let Some(ref result) = job.result {
+                        status["pdf_size_bytes"] = json!(result.content.len());
+                    }
+                    if let Some(ref e) = job.error {
+                        status["error"] = json!(e);
+                    }
+                    status
+                }
+            };
+            (id, status)
+        })
+        .collect();
+    Json(Value::Object(statuses)).into_response()
+}
+
+/// `GET /jobs/{id}/events`: the job's status as Server-Sent Events, starting with the current
+/// one. The stream ends after the job is done or has failed.
+pub async fn events(State(state): State<Arc<AppState>>, Path(id): Path<Uuid>) -> Response {
+    let (current, receiver) = {
+        let jobs = state.jobs.jobs.lock().unwrap();
+        let Some(job) = jobs.get(&id) else {
+            return job_not_found().into_response();
+        };
+        // Subscribed under the lock, so no status change falls between the two
+        ((job.status, job.to_json(id)), job.events.subscribe())
+    };
+    Sse::new(status_events(current, receiver))
+        .keep_alive(KeepAlive::new().interval(HEARTBEAT_INTERVAL))
+        .into_response()
+}
+
+/// `current`, then each update from `receiver`, up to and including a finished status.
+fn status_events(
+    current: (JobStatus, Value),
+    receiver: broadcast::Receiver<(JobStatus, Value)>,
+) -> impl Stream<Item = Result<Event, Infallible>> {
+    stream::unfold((Some(current), Some(receiver)), |(next, mut receiver)| async move {
+        let (status, body) = match next {
+            Some(update) => update,
+            None => loop {
+                match receiver.as_mut()?.recv().await {
+                    Ok(update) => break update,
+                    // Only the latest status matters
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    // The job was deleted or evicted
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                }
+            },
+        };
+        let event = Event::default().event(status.as_str()).data(body.to_string());
+        Some((Ok(event), (None, receiver.filter(|_| !status.is_finished()))))
+    })
+}
+
+pub async fn result(State(state): State<Arc<AppState>>, Path(id): Path<Uuid>) -> Response {
+    match state.jobs.result(id) {
+        Ok(converted) => file_response(&converted, Disposition::Attachment),
+        Err(e) => e.into_response(),
+    }
+}
+
+/// `DELETE /jobs/{id}`: cancels a pending or running job, stopping its conversion and removing
+/// its files.
+pub async fn delete(State(state): State<Arc<AppState>>, Path(id): Path<Uuid>) -> Response {
+    match state.jobs.cancel(id) {
+        Ok(work_dir) => {
+            info!("Cancelled job {}", id);
+            state.metrics.jobs_force_cancelled.inc();
+            let _ = fs::remove_dir_all(&work_dir).await;
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Err(None) => job_not_found().into_response(),
+        Err(Some(status)) => ConversionError::new(
+            StatusCode::CONFLICT,
+            "JOB_FINISHED",
+            format!("Job is {}; only pending or running jobs can be cancelled", status.as_str()),
+        )
+        .into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_job_lifecycle_and_eviction() {
+        let store = JobStore::new(Duration::ZERO, Duration::from_secs(60));
+        let id = store.submit(Uuid::new_v4(), PathBuf::from("/tmp/convert/x"));
+        assert_eq!(store.jobs.lock().unwrap()[&id].status, JobStatus::Pending);
+
+        assert_eq!(store.count(JobStatus::Pending), 1);
+
+        store.mark_running(id);
+        assert_eq!(store.count(JobStatus::Pending), 0);
+        assert!(store.evict_expired().is_empty(), "unfinished jobs are never evicted");
+
+        store.complete(
+            id,
+            Ok(ConvertedFile {
+                filename: "a.pdf".to_string(),
+                content_type: "application/pdf",
+                content: b"%PDF-1.7".to_vec(),
+                headers: Vec::new(),
+            }),
+        );
+        assert_eq!(store.jobs.lock().unwrap()[&id].status, JobStatus::Done);
+
+        let failed = store.submit(Uuid::new_v4(), PathBuf::from("/tmp/convert/y"));
+        store.complete(failed, Err(ConversionError::internal()));
+        assert_eq!(store.cancel(failed), Err(Some(JobStatus::Failed)));
+        let mut evicted = store.evict_expired();
+        evicted.sort();
+        assert_eq!(evicted, vec![PathBuf::from("/tmp/convert/x"), PathBuf::from("/tmp/convert/y")]);
+        assert!(store.jobs.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_cancel() {
+        let store = JobStore::new(Duration::from_secs(60), Duration::from_secs(60));
+        let id = store.submit(Uuid::new_v4(), PathBuf::from("/tmp/convert/c"));
+        let task = tokio::spawn(std::future::pending::<()>());
+        store.set_task(id, task.abort_handle());
+        store.mark_running(id);
+        assert_eq!(store.cancel(id), Ok(PathBuf::from("/tmp/convert/c")));
+        assert!(task.await.unwrap_err().is_cancelled());
+        assert_eq!(store.cancel(id), Err(None));
+
+        // Overdue jobs fail, and a late result does not revive them
+        let store = JobStore::new(Duration::from_secs(60), Duration::ZERO);
+        let id = store.submit(Uuid::new_v4(), PathBuf::from("/tmp/convert/d"));
+        let task = tokio::spawn(std::future::pending::<()>());
+        store.set_task(id, task.abort_handle());
+        assert_eq!(store.cancel_overdue(), vec![(id, PathBuf::from("/tmp/convert/d"))]);
+        assert!(task.await.unwrap_err().is_cancelled());
+        store.complete(id, Err(ConversionError::internal()));
+        let jobs = store.jobs.lock().unwrap();
+        assert_eq!(jobs[&id].status, JobStatus::Failed);
+        assert_eq!(jobs[&id].error.as_ref().map(|e| e.code), Some("JOB_EXPIRED"));
+        drop(jobs);
+        assert!(store.cancel_overdue().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_delete() {
+        use axum::{body::Body, extract::Request, http::Method, middleware, routing::get, Router};
+        use tower::ServiceExt;
+
+        let state = Arc::new(AppState {
+            api_keys: Some(crate::apikeys::ApiKeys::parse("client-key").unwrap()),
+            ..crate::tests::test_state()
+        });
+        // As in `main`: behind the same authentication as the job's status
+        let app = Router::new()
+            .route("/jobs/:id", get(status).delete(delete))
+            .layer(middleware::from_fn_with_state(state.clone(), crate::auth_middleware))
+            .with_state(state.clone());
+        let send = |id: Uuid, key: Option<&str>| {
+            let mut request = Request::builder().method(Method::DELETE).uri(format!("/jobs/{}", id));
+            if let Some(key) = key {
+                request = request.header(crate::apikeys::HEADER, key);
+            }
+            app.clone().oneshot(request.body(Body::empty()).unwrap())
+        };
+
+        let id = state.jobs.submit(Uuid::new_v4(), PathBuf::from("/tmp/convert/jobs-delete-test"));
+        state.jobs.mark_running(id);
+        assert_eq!(send(id, None).await.unwrap().status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(send(id, Some("client-key")).await.unwrap().status(), StatusCode::NO_CONTENT);
+        assert_eq!(send(id, Some("client-key")).await.unwrap().status(), StatusCode::NOT_FOUND);
+
+        let done = state.jobs.submit(Uuid::new_v4(), PathBuf::from("/tmp/convert/jobs-delete-test"));
+        state.jobs.complete(done, Err(ConversionError::internal()));
+        let response = send(done, Some("client-key")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert!(String::from_utf8_lossy(&body).contains("JOB_FINISHED"));
+    }
+
+    #[tokio::test]
+    async fn test_batch_status() {
+        let state = Arc::new(crate::tests::test_state());
+        let done = state.jobs.submit(Uuid::new_v4(), PathBuf::from("/tmp/convert/a"));
+        state.jobs.complete(
+            done,
+            Ok(ConvertedFile {
+                filename: "a.pdf".to_string(),
+                content_type: "application/pdf",
+                content: b"%PDF-1.7".to_vec(),
+                headers: Vec::new(),
+            }),
+        );
+        let pending = state.jobs.submit(Uuid::new_v4(), PathBuf::from("/tmp/convert/b"));
+        let unknown = Uuid::new_v4();
+        let body = json!({ "job_ids": [done, pending, unknown, "not-a-uuid"] }).to_string();
+        let response = batch_status(State(state.clone()), Bytes::from(body)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: Value = serde_json::from_slice(&axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap()).unwrap();
+        assert_eq!(
+            body,
+            json!({
+                done.to_string(): {"status": "done", "pdf_size_bytes": 8},
+                pending.to_string(): {"status": "pending"},
+                unknown.to_string(): {"status": "not_found"},
+                "not-a-uuid": {"status": "not_found"},
+            })
+        );
+
+        let too_many = json!({ "job_ids": vec![unknown; MAX_BATCH_STATUS_IDS + 1] }).to_string();
+        let error = parse_job_ids(too_many.as_bytes()).unwrap_err();
+        assert_eq!(error.code, "TOO_MANY_JOB_IDS");
+        assert_eq!(parse_job_ids(br#"{"job_ids": [1]}"#).unwrap_err().code, "INVALID_JSON_BODY");
+        assert_eq!(parse_job_ids(b"[]").unwrap_err().code, "INVALID_JSON_BODY");
+    }
+
+    #[tokio::test]
+    async fn test_events() {
+        use axum::{body::Body, extract::Request, routing::get, Router};
+        use tower::ServiceExt;
+
+        let state = Arc::new(crate::tests::test_state());
+        let app = Router::new().route("/jobs/:id/events", get(events)).with_state(state.clone());
+        let id = state.jobs.submit(Uuid::new_v4(), PathBuf::from("/tmp/convert/z"));
+        let request = Request::builder().uri(format!("/jobs/{}/events", id)).body(Body::empty()).unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.headers()["content-type"], "text/event-stream");
+        assert_eq!(response.headers()["cache-control"], "no-cache");
+
+        state.jobs.mark_running(id);
+        state.jobs.complete(id, Err(ConversionError::internal()));
+        // The stream ends with the failure
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        let events: Vec<&str> = body.lines().filter_map(|line| line.strip_prefix("event: ")).collect();
+        assert_eq!(events, ["pending", "running", "failed"]);
+        assert!(body.contains(r#""code":"INTERNAL_ERROR""#), "{}", body);
+
+        // A finished job gets just its final status
+        let request = Request::builder().uri(format!("/jobs/{}/events", id)).body(Body::empty()).unwrap();
+        let body = axum::body::to_bytes(app.clone().oneshot(request).await.unwrap().into_body(), usize::MAX).await.unwrap();
+        assert_eq!(String::from_utf8_lossy(&body).matches("event: ").count(), 1);
+
+        let request = Request::builder().uri(format!("/jobs/{}/events", Uuid::new_v4())).body(Body::empty()).unwrap();
+        assert_eq!(app.oneshot(request).await.unwrap().status(), StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,180 @@
+//! `POST /convert/json`: the same conversion as `/convert`, for clients that cannot easily send
+//! multipart forms. The file arrives base64-encoded in a JSON body, and the result goes back the
+//! same way:
+//!
+//! ```json
+//! {"filename": "report.docx", "content": "<base64>", "options": {"format": "pdf", "pages": "1-3"}}
+//! ```
+//!
+//! `options` takes the same fields as the `/convert` form; values that are not strings (such as
+//! the export `options` object) are passed on as their JSON text.
+
+use axum::{
+    body::Bytes,
+    extract::{ConnectInfo, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use tokio::fs;
+use tracing::{error, info_span, Instrument};
+
+use crate::audit::Requester;
+use crate::crypto::{self, base64_decode, base64_encode};
+use crate::error::ConversionError;
+use crate::telemetry::RequestId;
+use crate::{accept_file, busy_response, check_extension, convert_all, create_file_dir, disk, file_too_large, sanitize_filename, shutting_down_response};
+use crate::{AppState, ConvertOptions};
+
+/// Room for the filename and options on top of the encoded file, when sizing the body limit.
+const JSON_OVERHEAD: usize = 64 * 1024;
+
+/// The body limit for this route: `MAX_BODY_SIZE` applies to the decoded file, and base64 takes
+/// four bytes for every three.
+pub fn body_limit(max_body_size: usize) -> usize {
+    max_body_size.div_ceil(3) * 4 + JSON_OVERHEAD
+}
+
+/// A parsed request body.
+#[derive(Debug)]
+struct JsonUpload {
+    filename: String,
+    content: String,
+    /// The options as `/convert` form fields.
+    fields: HashMap<String, String>,
+}
+
+impl JsonUpload {
+    fn parse(body: &[u8]) -> Result<Self, ConversionError> {
+        let invalid = |message: &str| ConversionError::new(StatusCode::BAD_REQUEST, "INVALID_JSON_BODY", message.to_string());
+        let Ok(Value::Object(mut body)) = serde_json::from_slice(body) else {
+            return Err(invalid("The body must be a JSON object"));
+        };
+        let filename = match body.remove("filename") {
+            Some(Value::String(name)) => sanitize_filename(&name),
+            None | Some(Value::Null) => "document".to_string(),
+            Some(_) => return Err(invalid("filename must be a string")),
+        };
+        let content = match body.remove("content") {
+            Some(Value::String(content)) => content,
+            None | Some(Value::Null) => return Err(ConversionError::new(StatusCode::BAD_REQUEST, "NO_FILE", "No file uploaded")),
+            Some(_) => return Err(invalid("content must be a base64 string")),
+        };
+        let options = match body.remove("options") {
+            Some(Value::Object(options)) => options,
+            None | Some(Value::Null) => Map::new(),
+            Some(_) => return Err(invalid("options must be an object")),
+        };
+        let fields = options
+            .into_iter()
+            .filter_map(|(name, value)| match value {
+                Value::Null => None,
+                Value::String(s) => Some((name, s)),
+                other => Some((name, other.to_string())),
+            })
+            .collect();
+        Ok(JsonUpload { filename, content, fields })
+    }
+
+    /// Decodes the file, refusing it if it comes to more than `limit` bytes.
+    fn decode(&self, limit: u64) -> Result<Vec<u8>, ConversionError> {
+        // Line breaks are allowed, as in MIME base64
+        let content: String = self.content.chars().filter(|c| !c.is_ascii_whitespace()).collect();
+        let decoded_len = (content.trim_end_matches('=').len() as u64) * 3 / 4;
+        if decoded_len > limit {
+            return Err(file_too_large("Uploaded", decoded_len, limit));
+        }
+        base64_decode(&content)
+            .ok_or_else(|| ConversionError::new(StatusCode::BAD_REQUEST, "INVALID_BASE64", "content is not valid base64"))
+    }
+}
+
+pub async fn convert_json(
+    State(state): State<Arc<AppState>>,
+    Extension(RequestId(request_id)): Extension<RequestId>,
+    peer: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    if state.draining.load(Ordering::SeqCst) {
+        return shutting_down_response();
+    }
+    let Some(slot) = state.try_conversion_slot() else {
+        return busy_response();
+    };
+    let _active_dir = state.active_dirs.track(request_id);
+    let work_dir = state.request_dir(request_id);
+    if let Err(e) = fs::create_dir_all(&work_dir).await {
+        error!("Failed to create work dir: {}", e);
+        return ConversionError::internal().into_response();
+    }
+
+    let result = async {
+        disk::ensure_free_space(&work_dir, state.min_free_bytes)?;
+        let upload = JsonUpload::parse(&body)?;
+        let opts = ConvertOptions::from_request(&HashMap::new(), &upload.fields)?;
+        check_extension(&upload.filename, &state.allowed_extensions)?;
+        let content = upload.decode(state.max_body_size)?;
+        let path = create_file_dir(&work_dir, 0).await?.join(&upload.filename);
+        fs::write(&path, &content)
+            .instrument(info_span!("disk_write", file.name = %upload.filename))
+            .await
+            .map_err(|e| {
+                error!("Failed to write upload: {}", e);
+                ConversionError::internal()
+            })?;
+        let file = accept_file(path, content.len() as u64, Some(crypto::sha256(&content)), &state, Some(&state.allowed_input_types)).await?;
+        convert_all(&state, Requester::new(&state, request_id, &headers, peer), &work_dir, &[file], &opts).await
+    }
+    .await;
+    drop(slot);
+    let _ = fs::remove_dir_all(&work_dir).instrument(info_span!("cleanup")).await;
+
+    match result {
+        Ok(converted) => {
+            let mut response = Json(json!({
+                "filename": converted.filename,
+                "content": base64_encode(&converted.content),
+            }))
+            .into_response();
+            for (name, value) in &converted.headers {
+                if let Ok(value) = header::HeaderValue::from_str(value) {
+                    response.headers_mut().insert(*name, value);
+                }
+            }
+            response
+        }
+        Err(e) => e.into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let body = br#"{"filename": "../q3/report.docx", "content": "aGVs\nbG8=", "options": {"format": "docx", "options": {"dpi": 150}, "pdfa": null}}"#;
+        let upload = JsonUpload::parse(body).unwrap();
+        assert_eq!(upload.filename, "report.docx");
+        assert_eq!(upload.fields["format"], "docx");
+        assert_eq!(upload.fields["options"], r#"{"dpi":150}"#);
+        assert!(!upload.fields.contains_key("pdfa"));
+        assert_eq!(upload.decode(5).unwrap(), b"hello");
+
+        // The limit is on the decoded size, not the length of the base64
+        let e = upload.decode(4).unwrap_err();
+        assert_eq!((e.status, e.code), (StatusCode::PAYLOAD_TOO_LARGE, "FILE_TOO_LARGE"));
+
+        assert_eq!(JsonUpload::parse(br#"{"content": "a b!"}"#).unwrap().decode(10).unwrap_err().code, "INVALID_BASE64");
+        assert_eq!(JsonUpload::parse(br#"{"filename": "a.docx"}"#).unwrap_err().code, "NO_FILE");
+        assert_eq!(JsonUpload::parse(b"[]").unwrap_err().code, "INVALID_JSON_BODY");
+        assert_eq!(JsonUpload::parse(br#"{"content": "", "options": "pdf"}"#).unwrap_err().code, "INVALID_JSON_BODY");
+        assert_eq!(body_limit(3), 4 + JSON_OVERHEAD);
+    }
+}
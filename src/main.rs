@@ -1,254 +1,3325 @@
 use axum::{
-    extract::{DefaultBodyLimit, Multipart, Request},
-    http::{header, StatusCode},
+    body::{Body, Bytes},
+    extract::{ConnectInfo, DefaultBodyLimit, Multipart, Query, Request, State},
+    http::{header, HeaderMap, HeaderName, HeaderValue, Method, StatusCode},
     middleware::{self, Next},
-    response::{Html, IntoResponse, Response},
-    routing::{get, post},
-    Router,
+    response::{IntoResponse, Response},
+    routing::{get, head, post},
+    Extension, Json, Router,
 };
-use std::env;
-use std::path::PathBuf;
+use futures_util::{Stream, StreamExt};
+use serde_json::{json, Map, Value};
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::process::{Output, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::fs;
-use tokio::io::AsyncWriteExt;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::process::Command;
-use tracing::{error, info};
+use tokio::sync::{Semaphore, SemaphorePermit};
+use tokio::time::{timeout_at, Instant};
+use tower_http::cors::{AllowOrigin, CorsLayer};
+use tracing::{debug, error, info, info_span, warn, Instrument};
 use uuid::Uuid;
 
-#[derive(Clone)]
-struct AppState {
-    api_key: Option<String>,
+mod admin;
+mod apikeys;
+mod archive;
+mod audit;
+mod backend;
+mod breaker;
+mod brotli;
+mod cache;
+mod callback;
+mod cleanup;
+mod compression;
+mod config;
+mod crypto;
+mod decompress;
+mod deflate;
+mod decrypt;
+mod detect;
+mod disk;
+mod downloads;
+mod error;
+mod errorpages;
+mod export;
+mod filters;
+mod formats;
+mod grpc;
+mod health;
+mod inflate;
+mod info;
+mod jobs;
+mod jsonconvert;
+mod jwt;
+mod markdown;
+mod merge;
+mod meta;
+mod metrics;
+mod notes;
+mod outbound;
+mod pagecount;
+mod pagesize;
+mod pdf;
+mod pool;
+mod properties;
+mod ratelimit;
+mod render;
+mod s3;
+mod security;
+mod service;
+mod shutdown;
+mod split;
+mod telemetry;
+mod templates;
+mod thumbnail;
+mod uploadtokens;
+mod useragents;
+mod window;
+mod zip;
+mod zstd;
+
+use apikeys::ApiKeys;
+use audit::{AuditLog, Requester};
+use breaker::CircuitBreaker;
+use cache::{Flight, ResultCache};
+use crypto::Sha256;
+use cleanup::ActiveDirs;
+use config::Config;
+use error::ConversionError;
+use export::{ExportOptions, Watermark};
+use filters::FormatInfo;
+use formats::{CsvImport, OutputFormat, PaperSize};
+use downloads::DownloadStore;
+use health::{LibreOfficeProbe, Readiness};
+use jobs::JobStore;
+use jwt::{JwtError, JwtVerifier};
+use metrics::{GaugeGuard, Metrics};
+use outbound::{AllowedNetworks, DownloadError};
+use pool::LibreOfficePool;
+use properties::DocumentProperties;
+use service::{ConversionService, LaunchFlags, LibreOfficeService};
+use ratelimit::{ClientIdentity, RateLimiter};
+use s3::S3Config;
+use telemetry::RequestId;
+use uploadtokens::{UploadGrant, UploadTokens};
+use thumbnail::JpegExport;
+use zip::ZipWriter;
+
+/// `Retry-After` sent when every conversion slot is taken.
+const BUSY_RETRY_AFTER_SECS: u64 = 5;
+/// Longest PDF password LibreOffice's export filter accepts.
+const MAX_PDF_PASSWORD_LEN: usize = 32;
+/// Reported on every `/convert` response: milliseconds from the start of the request, and the
+/// input and output sizes once known. The output need not be a PDF despite the name.
+const CONVERSION_TIME_HEADER: &str = "x-conversion-time-ms";
+const FILE_SIZE_HEADER: &str = "x-file-size-bytes";
+const PDF_SIZE_HEADER: &str = "x-pdf-size-bytes";
+/// Whether a PDF output is linearized for fast web view.
+const LINEARIZED_HEADER: &str = "X-Pdf-Linearized";
+/// Sent when `doc_*` fields were set on the PDF.
+const PROPERTIES_HEADER: &str = "X-Pdf-Metadata-Applied";
+/// How many pages `normalize_page_size` changed.
+const PAGES_NORMALIZED_HEADER: &str = "X-Pages-Normalized";
+/// The format a template upload was instantiated to before it was converted.
+const INTERMEDIATE_FORMAT_HEADER: &str = "X-Intermediate-Format";
+/// The upload limits `HEAD /convert` reports.
+const MAX_BODY_BYTES_HEADER: &str = "x-max-body-bytes";
+const ALLOWED_EXTENSIONS_HEADER: &str = "x-allowed-extensions";
+const MAX_FILES_HEADER: &str = "x-max-files-per-request";
+const AUTH_REQUIRED_HEADER: &str = "x-auth-required";
+const ASYNC_SUPPORTED_HEADER: &str = "x-async-supported";
+
+pub struct AppState {
+    api_keys: Option<ApiKeys>,
+    admin_api_key: Option<String>,
+    jwt: Option<JwtVerifier>,
+    conversion_timeout: Duration,
+    jobs: JobStore,
+    downloads: DownloadStore,
+    upload_tokens: UploadTokens,
+    max_files_per_request: usize,
+    /// Detected input types (see `detect`) that may be converted.
+    allowed_input_types: Vec<String>,
+    /// Extensions a file to convert may be uploaded with (`ALLOWED_EXTENSIONS`), checked before
+    /// anything is written; the content check against `allowed_input_types` still follows.
+    allowed_extensions: Vec<String>,
+    /// Extensions of formats that can carry macros, refused (when the content matches) unless
+    /// `allow_macros` is set.
+    macro_enabled_extensions: HashSet<String>,
+    allow_macros: bool,
+    /// `MAX_BODY_SIZE`, which also caps each uploaded file as it streams in.
+    max_body_size: u64,
+    /// Maximum upload size per input type, on top of the overall `MAX_BODY_SIZE`.
+    file_size_limits: HashMap<String, u64>,
+    /// Conversions are refused while less than this is free on the temp filesystem.
+    min_free_bytes: u64,
+    /// One permit per request allowed to convert at the same time (`MAX_CONCURRENT_CONVERSIONS`).
+    conversion_slots: Semaphore,
+    max_concurrent_conversions: usize,
+    /// Set once shutdown has begun; new conversions are refused.
+    draining: AtomicBool,
+    /// Allow `http://` callback URLs (development only).
+    allow_http_callbacks: bool,
+    /// Limits for inputs fetched from a `url` form field.
+    download_timeout: Duration,
+    max_download_bytes: u64,
+    /// Largest converted output accepted, checked before it is read into memory.
+    max_output_bytes: u64,
+    /// Largest `/render` output, all its pages together.
+    max_render_bytes: u64,
+    /// Allow `http://` input URLs (development only).
+    allow_http_downloads: bool,
+    /// Internal networks that `url` inputs and callbacks may reach (`OUTBOUND_ALLOWED_NETWORKS`).
+    outbound_allowed_networks: AllowedNetworks,
+    /// Bucket that successful conversions are copied to, if configured.
+    s3: Option<S3Config>,
+    /// Runs the conversions themselves.
+    converter: Arc<dyn ConversionService>,
+    libreoffice: LibreOfficeProbe,
+    /// Refuses conversions while LibreOffice keeps failing (`CB_*`).
+    circuit_breaker: CircuitBreaker,
+    /// What the installed LibreOffice can import, read at startup (see `filters`).
+    import_formats: Vec<FormatInfo>,
+    /// Last result of the `/ready` checks.
+    readiness: Readiness,
+    metrics: Metrics,
+    metrics_api_key: Option<String>,
+    /// `WORK_DIR`: parent of the per-request work directories, unless `runtime_dir` is usable.
+    work_dir: PathBuf,
+    /// `$XDG_RUNTIME_DIR/office2pdf`, if set.
+    runtime_dir: Option<PathBuf>,
+    /// Work directories of requests in progress, which the cleanup scan must not touch.
+    active_dirs: ActiveDirs,
+    started_at: Instant,
+    rate_limiter: Option<RateLimiter>,
+    /// Limits `POST /jobs/status` on its own (`JOB_STATUS_RPS`), so that job ids cannot be
+    /// probed in bulk.
+    job_status_limiter: RateLimiter,
+    /// Recent conversion results; `None` when `CACHE_MAX_ENTRIES` is 0.
+    cache: Option<ResultCache>,
+    /// `CACHE_CONTROL_MAX_AGE`: how long shared caches may keep results served from `cache`;
+    /// 0 leaves `Cache-Control` out.
+    cache_control_max_age: u64,
+    /// Reverse proxies in front of the service (`TRUSTED_PROXY_COUNT`).
+    trusted_proxy_count: usize,
+    /// Every conversion attempt is recorded here when `AUDIT_LOG_FILE` is set.
+    audit_log: Option<AuditLog>,
 }
 
 #[tokio::main]
 async fn main() {
-    tracing_subscriber::fmt::init();
+    let otel_enabled = telemetry::init();
+    if otel_enabled {
+        info!("OpenTelemetry trace export enabled");
+    }
 
-    let api_key = env::var("API_KEY").ok();
-    if api_key.is_some() {
-        info!("API Key authentication enabled");
-    } else {
-        info!("No API Key set, authentication disabled");
+    let config = match Config::from_env() {
+        Ok(config) => config,
+        Err(errors) => {
+            for e in &errors {
+                error!("{}", e);
+            }
+            error!("Refusing to start: {} invalid setting(s)", errors.len());
+            std::process::exit(1);
+        }
+    };
+
+    let listen_addr = config.listen_addr;
+    info!("Listen address: {}", listen_addr);
+    match config.api_keys {
+        Some(ref keys) => info!("API Key authentication enabled with {} key(s)", keys.len()),
+        None => info!("No API Keys set, authentication disabled"),
+    }
+    if config.admin_api_key.is_some() {
+        info!("Admin API Key set");
+    }
+    if config.jwt.is_some() {
+        info!("JWT bearer authentication enabled");
+    }
+    info!("Max upload size: {} bytes", config.max_body_size);
+    info!("Conversion timeout: {}s", config.conversion_timeout.as_secs());
+    info!("Accepted input types: {}", config.allowed_input_types.join(", "));
+    info!("Accepted extensions: {}", config.allowed_extensions.join(", "));
+    info!("Max concurrent conversions: {}", config.max_concurrent_conversions);
+    if config.allow_http_callbacks {
+        info!("Plain http:// callback URLs are allowed");
+    }
+    if config.rate_limiter.is_some() {
+        info!("Per-IP rate limiting enabled");
+    }
+    if let Some(ref s3) = config.s3 {
+        info!("Uploading converted files to S3 bucket {}", s3.bucket());
+    }
+    if config.cache_max_entries > 0 {
+        info!("Caching up to {} conversion results", config.cache_max_entries);
+    }
+    if config.cache_control_max_age > 0 {
+        info!("Cached results may be kept by shared caches for {}s", config.cache_control_max_age);
+    }
+
+    match disk::ensure_writable(&config.work_dir) {
+        Ok(()) => info!("Working in {}", config.work_dir.display()),
+        Err(e) => {
+            error!("Refusing to start: WORK_DIR {} is not writable: {}", config.work_dir.display(), e);
+            std::process::exit(1);
+        }
+    }
+
+    let audit_log = match config.audit_log_file {
+        Some(ref path) => match AuditLog::open(path).await {
+            Ok(log) => {
+                info!("Recording conversions in {}", path.display());
+                Some(log)
+            }
+            Err(e) => {
+                error!("Refusing to start: cannot open AUDIT_LOG_FILE {}: {}", path.display(), e);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+
+    match health::find_executable(&config.libreoffice_path).await {
+        Ok(path) => info!("Using LibreOffice at {}", path.display()),
+        // Not fatal: it may be installed later, and /health reports it meanwhile
+        Err(e) => warn!("LibreOffice not usable, conversions will fail: {}", e),
+    }
+    let import_formats = match filters::load(&config.libreoffice_path).await {
+        Ok(formats) => {
+            info!("LibreOffice has {} import filters", formats.len());
+            formats
+        }
+        Err(e) => {
+            warn!("Could not list LibreOffice's import filters, /info will show none: {}", e);
+            Vec::new()
+        }
+    };
+    if let Some(ref chromium) = config.chromium_path {
+        match health::find_executable(chromium).await {
+            Ok(path) => info!("Converting HTML with Chromium at {}", path.display()),
+            Err(e) => warn!("Chromium not usable, HTML conversions will fail: {}", e),
+        }
+    }
+    if let Some(ref qpdf) = config.qpdf_path {
+        match health::find_executable(qpdf).await {
+            Ok(path) => info!("Linearizing PDFs with qpdf at {}", path.display()),
+            Err(e) => warn!("qpdf not usable, linearized conversions will fail: {}", e),
+        }
+    }
+    if let Some(ref msoffcrypto) = config.msoffcrypto_path {
+        match health::find_executable(msoffcrypto).await {
+            Ok(path) => info!("Decrypting password-protected documents with msoffcrypto-tool at {}", path.display()),
+            Err(e) => warn!("msoffcrypto-tool not usable, password-protected documents will fail: {}", e),
+        }
+    }
+    if let Some(ref dir) = config.cjk_font_dir {
+        let is_font = |name: &std::ffi::OsStr| {
+            let extension = Path::new(name).extension().unwrap_or_default().to_string_lossy().to_ascii_lowercase();
+            matches!(extension.as_str(), "ttf" | "otf" | "ttc")
+        };
+        let fonts = std::fs::read_dir(dir)
+            .map(|entries| entries.filter_map(Result::ok).filter(|e| is_font(&e.file_name())).count())
+            .unwrap_or(0);
+        if fonts == 0 {
+            warn!("CJK_FONT_DIR {} holds no .ttf, .otf or .ttc fonts", dir.display());
+        } else {
+            info!("Adding {} fonts from {} to LibreOffice", fonts, dir.display());
+        }
     }
+    // Markdown has a fallback, so a Pandoc that cannot be run is dropped rather than failing every conversion
+    let pandoc_path = match config.pandoc_path {
+        Some(pandoc) => match health::find_executable(&pandoc).await {
+            Ok(path) => {
+                info!("Converting Markdown with Pandoc at {}", path.display());
+                Some(pandoc)
+            }
+            Err(e) => {
+                warn!("Pandoc not usable, converting Markdown with LibreOffice: {}", e);
+                None
+            }
+        },
+        None => None,
+    };
+
+    let max_body_size = config.max_body_size;
+    let shutdown_grace = config.shutdown_grace;
+    let (stale_dir_age, cleanup_interval) = (config.stale_dir_age, config.cleanup_interval);
+    let cors = config.cors;
+    let security_headers = Arc::new(config.security_headers);
+    let blocked_user_agents = Arc::new(config.blocked_user_agents);
+    let launch_flags = LaunchFlags {
+        safe_mode: config.lo_safe_mode,
+        extra_args: config.lo_extra_args,
+    };
+    let pool = (config.lo_pool_size > 0).then(|| {
+        LibreOfficePool::start(
+            config.lo_pool_size,
+            &config.libreoffice_path,
+            "python3",
+            &config.work_dir.join("pool"),
+            &launch_flags,
+            config.cjk_font_dir.as_deref(),
+        )
+    });
 
-    let state = Arc::new(AppState { api_key });
+    let state = Arc::new(AppState {
+        api_keys: config.api_keys,
+        admin_api_key: config.admin_api_key,
+        jwt: config.jwt,
+        conversion_timeout: config.conversion_timeout,
+        jobs: JobStore::new(config.job_ttl, config.job_max_age),
+        downloads: DownloadStore::new(config.work_dir.join("downloads"), config.download_token_ttl)
+            .expect("failed to initialise download tokens"),
+        upload_tokens: UploadTokens::new().expect("failed to initialise upload tokens"),
+        max_files_per_request: config.max_files_per_request,
+        allowed_input_types: config.allowed_input_types,
+        allowed_extensions: config.allowed_extensions,
+        macro_enabled_extensions: detect::MACRO_ENABLED_EXTENSIONS.iter().map(|e| e.to_string()).collect(),
+        allow_macros: config.allow_macros,
+        max_body_size: max_body_size as u64,
+        file_size_limits: config.file_size_limits,
+        min_free_bytes: config.min_free_bytes,
+        conversion_slots: Semaphore::new(config.max_concurrent_conversions),
+        max_concurrent_conversions: config.max_concurrent_conversions,
+        draining: AtomicBool::new(false),
+        allow_http_callbacks: config.allow_http_callbacks,
+        download_timeout: config.download_timeout,
+        max_download_bytes: config.max_download_bytes,
+        max_output_bytes: config.max_output_bytes,
+        max_render_bytes: config.max_render_bytes,
+        allow_http_downloads: config.allow_http_downloads,
+        outbound_allowed_networks: config.outbound_allowed_networks,
+        s3: config.s3,
+        converter: Arc::new(LibreOfficeService {
+            libreoffice_path: config.libreoffice_path.clone(),
+            chromium_path: config.chromium_path,
+            pandoc_path,
+            qpdf_path: config.qpdf_path,
+            msoffcrypto_path: config.msoffcrypto_path,
+            pool,
+            timeout: config.conversion_timeout,
+            include_errors: config.include_libreoffice_errors,
+            flags: launch_flags,
+            retry: config.lo_retry,
+            font_dir: config.cjk_font_dir,
+        }),
+        libreoffice: LibreOfficeProbe::new(&config.libreoffice_path),
+        circuit_breaker: config.circuit_breaker,
+        import_formats,
+        readiness: Readiness::default(),
+        metrics: Metrics::default(),
+        metrics_api_key: config.metrics_api_key,
+        work_dir: config.work_dir,
+        runtime_dir: config.runtime_dir,
+        active_dirs: ActiveDirs::default(),
+        started_at: Instant::now(),
+        rate_limiter: config.rate_limiter,
+        job_status_limiter: config.job_status_limiter,
+        cache: (config.cache_max_entries > 0).then(|| ResultCache::new(config.cache_max_entries)),
+        cache_control_max_age: config.cache_control_max_age,
+        trusted_proxy_count: config.trusted_proxy_count,
+        audit_log,
+    });
 
+    tokio::spawn(jobs::run_eviction(state.clone()));
+    tokio::spawn(downloads::run_eviction(state.clone()));
+    tokio::spawn(ratelimit::run_eviction(state.clone()));
+    tokio::spawn(cache::clear_on_hangup(state.clone()));
+    tokio::spawn(cleanup::run_cleanup(state.clone(), stale_dir_age, cleanup_interval));
+
+    let rate_limited = middleware::from_fn_with_state(state.clone(), ratelimit::rate_limit);
+    // Inside authentication and rate limiting, so refused clients get nothing decompressed. Each
+    // route's body limit applies to the decompressed body
+    let decompressed_to = |limit: usize| middleware::from_fn_with_state(limit, decompress::decompress);
+    let decompressed = decompressed_to(max_body_size);
     let app = Router::new()
-        .route("/convert", post(convert))
+        .route(
+            "/convert",
+            post(convert)
+                .put(convert_body)
+                .layer(decompressed.clone())
+                .layer(rate_limited.clone())
+                .layer(middleware::from_fn(compression::compress)),
+        )
+        .route(
+            "/convert/json",
+            post(jsonconvert::convert_json)
+                .layer(DefaultBodyLimit::max(jsonconvert::body_limit(max_body_size)))
+                .layer(decompressed_to(jsonconvert::body_limit(max_body_size)))
+                .layer(rate_limited.clone()),
+        )
+        .route(
+            grpc::CONVERT_PATH,
+            post(grpc::convert)
+                .layer(DefaultBodyLimit::max(grpc::body_limit(max_body_size)))
+                .layer(decompressed_to(grpc::body_limit(max_body_size)))
+                .layer(rate_limited.clone()),
+        )
+        .route("/notes", post(notes::notes).layer(decompressed.clone()).layer(rate_limited.clone()))
+        .route("/meta", post(meta::meta).layer(decompressed.clone()).layer(rate_limited.clone()))
+        .route("/pagecount", post(pagecount::pagecount).layer(decompressed.clone()).layer(rate_limited.clone()))
+        .route("/thumbnail", post(thumbnail::thumbnail).layer(decompressed.clone()).layer(rate_limited.clone()))
+        .route("/render", post(render::render).layer(decompressed.clone()).layer(rate_limited.clone()))
+        .route("/merge", post(merge::merge).layer(decompressed.clone()).layer(rate_limited.clone()))
+        .route("/split", post(split::split).layer(decompressed.clone()).layer(rate_limited.clone()))
+        .route(
+            "/jobs/status",
+            post(jobs::batch_status).layer(middleware::from_fn_with_state(state.clone(), ratelimit::rate_limit_job_status)),
+        )
+        .route("/jobs/:id", get(jobs::status).delete(jobs::delete))
+        .route("/jobs/:id/result", get(jobs::result))
+        .route("/jobs/:id/events", get(jobs::events))
+        .route("/sign-download/:job_id", post(downloads::sign))
         .layer(middleware::from_fn_with_state(state.clone(), auth_middleware))
-        .route("/", get(index))
-        .route("/ui/convert", post(convert))
-        .route("/health", get(health).head(health))
-        .layer(DefaultBodyLimit::max(10 * 1024 * 1024)) // 10MB limit
-        .with_state(state);
+        .route("/admin/status", get(admin::status))
+        .route("/upload-token", post(uploadtokens::create))
+        .route("/convert", head(convert_head))
+        .route("/", get(info::index))
+        .route("/config", get(info::config))
+        .route("/info", get(info::info))
+        .route("/index.css", get(info::stylesheet))
+        .route("/index.js", get(info::script))
+        .route("/ui/convert", post(ui_convert).layer(rate_limited))
+        .route("/download/:token", get(downloads::download))
+        .route("/health", get(health::health).head(health::health))
+        .route("/ready", get(health::ready))
+        .route("/metrics", get(metrics::metrics))
+        .fallback(errorpages::not_found)
+        .method_not_allowed_fallback(errorpages::method_not_allowed)
+        .layer(DefaultBodyLimit::max(max_body_size))
+        .with_state(state.clone());
+    // Inside the request id and security headers layers, so refusals carry them too
+    let app = if blocked_user_agents.is_empty() {
+        app
+    } else {
+        app.layer(middleware::from_fn_with_state(blocked_user_agents, useragents::block))
+    };
+    // Inside the request id layer, so access log lines carry the request's id
+    let app = app
+        .layer(middleware::from_fn(telemetry::access_log))
+        .layer(middleware::from_fn_with_state(state.trusted_proxy_count, telemetry::request_id))
+        .layer(middleware::from_fn_with_state(security_headers, security::add_headers));
+    // Outside the auth middleware, so preflight requests (which carry no credentials) succeed
+    let app = match cors {
+        Some(cors) => {
+            info!("CORS enabled");
+            app.layer(cors)
+        }
+        None => app,
+    };
+    let app = if otel_enabled {
+        app.layer(middleware::from_fn(telemetry::trace_request))
+    } else {
+        app
+    };
 
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
+    let listener = match tokio::net::TcpListener::bind(listen_addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Refusing to start: cannot listen on {} (LISTEN_ADDR): {}", listen_addr, e);
+            std::process::exit(1);
+        }
+    };
     info!("listening on {}", listener.local_addr().unwrap());
-    axum::serve(listener, app).await.unwrap();
+    let (stop_accepting, stopped) = tokio::sync::oneshot::channel::<()>();
+    let server = tokio::spawn(
+        axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
+            .with_graceful_shutdown(async {
+                let _ = stopped.await;
+            })
+            .into_future(),
+    );
+
+    shutdown::signal_received().await;
+    info!("Shutting down; waiting up to {}s for conversions to finish", shutdown_grace.as_secs());
+    state.draining.store(true, Ordering::SeqCst);
+    let _ = stop_accepting.send(());
+    let drained = tokio::time::timeout(shutdown_grace, async {
+        let _ = server.await;
+        // Every slot back means no synchronous request or async job is converting any more
+        let _ = state.conversion_slots.acquire_many(state.max_concurrent_conversions as u32).await;
+    })
+    .await
+    .is_ok();
+    if !drained {
+        warn!("Grace period over; killing {} LibreOffice processes", shutdown::PROCESS_GROUPS.kill_all());
+    }
+    // Pool workers are idle by now, but would otherwise outlive the server
+    shutdown::PROCESS_GROUPS.kill_all();
+    for root in state.work_roots() {
+        match shutdown::remove_work_dirs(root).await {
+            Ok(removed) => info!("Removed {} work directories from {}", removed, root.display()),
+            Err(e) => error!("Failed to clean up {}: {}", root.display(), e),
+        }
+    }
+}
+
+/// Builds the layer for `CORS_ORIGINS`: `*` for any origin, or a comma-separated list of them.
+fn cors_layer(raw: &str) -> Result<CorsLayer, String> {
+    let origin = if raw.trim() == "*" {
+        AllowOrigin::any()
+    } else {
+        let origins = raw
+            .split(',')
+            .map(|o| o.trim().trim_end_matches('/'))
+            .filter(|o| !o.is_empty())
+            .map(|o| HeaderValue::from_str(o).map_err(|_| format!("Invalid origin in CORS_ORIGINS: {:?}", o)))
+            .collect::<Result<Vec<_>, _>>()?;
+        if origins.is_empty() {
+            return Err("CORS_ORIGINS must list at least one origin".to_string());
+        }
+        AllowOrigin::list(origins)
+    };
+    Ok(CorsLayer::new()
+        .allow_origin(origin)
+        .allow_methods([Method::GET, Method::HEAD, Method::POST, Method::PUT])
+        .allow_headers([HeaderName::from_static("x-api-key"), header::CONTENT_TYPE, header::AUTHORIZATION])
+        // Lets scripts read the suggested filename of the converted file and the request id
+        .expose_headers([
+            header::CONTENT_DISPOSITION,
+            header::ETAG,
+            HeaderName::from_static(telemetry::REQUEST_ID_HEADER),
+            HeaderName::from_static(CONVERSION_TIME_HEADER),
+            HeaderName::from_static(FILE_SIZE_HEADER),
+            HeaderName::from_static(PDF_SIZE_HEADER),
+            HeaderName::from_static(MAX_BODY_BYTES_HEADER),
+            HeaderName::from_static(ALLOWED_EXTENSIONS_HEADER),
+            HeaderName::from_static(MAX_FILES_HEADER),
+            HeaderName::from_static(AUTH_REQUIRED_HEADER),
+            HeaderName::from_static(ASYNC_SUPPORTED_HEADER),
+        ]))
 }
 
-async fn health() -> StatusCode {
-    StatusCode::OK
+/// One of the `MAX_CONCURRENT_CONVERSIONS` slots, released on drop.
+struct ConversionSlot<'a> {
+    _permit: SemaphorePermit<'a>,
+    _in_use: GaugeGuard<'a>,
 }
 
-async fn index() -> Html<&'static str> {
-    Html(include_str!("index.html"))
+impl AppState {
+    /// The work directory for a request. It goes in `runtime_dir` when that can be written to
+    /// and has `min_free_bytes` free, which is checked each time as the runtime directory can
+    /// disappear (e.g. when the user's session ends); otherwise in `work_dir`.
+    fn request_dir(&self, request_id: Uuid) -> PathBuf {
+        if let Some(ref runtime_dir) = self.runtime_dir {
+            match disk::check_writable(runtime_dir).and_then(|_| disk::available_bytes(runtime_dir)) {
+                Ok(free) if free >= self.min_free_bytes => return runtime_dir.join(request_id.to_string()),
+                Ok(free) => debug!("Only {} bytes free in {}, using WORK_DIR", free, runtime_dir.display()),
+                Err(e) => debug!("{} is not usable, using WORK_DIR: {}", runtime_dir.display(), e),
+            }
+        }
+        self.work_dir.join(request_id.to_string())
+    }
+
+    /// The directories request work directories are created in, as far as they exist.
+    fn work_roots(&self) -> Vec<&Path> {
+        std::iter::once(self.work_dir.as_path())
+            .chain(self.runtime_dir.as_deref().filter(|dir| dir.is_dir()))
+            .collect()
+    }
+
+    /// Takes a conversion slot if one is free right now.
+    fn try_conversion_slot(&self) -> Option<ConversionSlot<'_>> {
+        let permit = self.conversion_slots.try_acquire().ok()?;
+        Some(ConversionSlot {
+            _permit: permit,
+            _in_use: self.metrics.conversion_slots_in_use.track(),
+        })
+    }
+
+    fn is_admin_key(&self, presented: &str) -> bool {
+        self.admin_api_key.as_deref().is_some_and(|admin| apikeys::matches(admin, presented))
+    }
+
+    /// Waits for a conversion slot.
+    async fn conversion_slot(&self) -> ConversionSlot<'_> {
+        let waiting = self.metrics.conversion_slots_waiting.track();
+        let permit = self.conversion_slots.acquire().await.expect("conversion semaphore is never closed");
+        drop(waiting);
+        ConversionSlot {
+            _permit: permit,
+            _in_use: self.metrics.conversion_slots_in_use.track(),
+        }
+    }
 }
 
 async fn auth_middleware(
-    axum::extract::State(state): axum::extract::State<Arc<AppState>>,
-    req: Request,
+    State(state): State<Arc<AppState>>,
+    mut req: Request,
     next: Next,
 ) -> Response {
-    if let Some(ref key) = state.api_key {
-        if let Some(auth_header) = req.headers().get("X-Api-Key") {
-            if let Ok(value) = auth_header.to_str() {
-                if value == key {
+    // A single-use upload token stands in for credentials on /convert
+    if req.uri().path() == "/convert"
+        && let Ok(Query(query)) = Query::<HashMap<String, String>>::try_from_uri(req.uri())
+        && let Some(redeemed) = state.upload_tokens.redeem_query(&query)
+    {
+        return match redeemed {
+            Ok(grant) => {
+                req.extensions_mut().insert(grant);
+                next.run(req).await
+            }
+            Err(e) => {
+                state.metrics.auth_failures.inc();
+                e.into_response()
+            }
+        };
+    }
+
+    if state.api_keys.is_none() && state.jwt.is_none() {
+        return next.run(req).await;
+    }
+
+    // Either mechanism is sufficient on its own
+    if let Some(key) = apikeys::from_headers(req.headers())
+        && (state.api_keys.as_ref().is_some_and(|keys| keys.contains(key)) || state.is_admin_key(key))
+    {
+        let identity = ClientIdentity(apikeys::key_id(key));
+        req.extensions_mut().insert(identity);
+        return next.run(req).await;
+    }
+
+    let error = match state.jwt {
+        Some(ref verifier) => {
+            let authorization = req.headers().get(header::AUTHORIZATION).and_then(|v| v.to_str().ok());
+            match verifier.verify_header(authorization) {
+                Ok(claims) => {
+                    if let Some(sub) = claims.get("sub").and_then(Value::as_str) {
+                        req.extensions_mut().insert(ClientIdentity(sub.to_string()));
+                    }
                     return next.run(req).await;
                 }
+                // A wrong API key is more useful to report than the absent token
+                Err(JwtError::Missing) if req.headers().contains_key(apikeys::HEADER) => unauthorized(),
+                Err(e) => e.into(),
             }
         }
-        return (StatusCode::UNAUTHORIZED, "Unauthorized").into_response();
-    }
-    next.run(req).await
+        None => unauthorized(),
+    };
+    state.metrics.auth_failures.inc();
+    error.into_response()
+}
+
+fn unauthorized() -> ConversionError {
+    ConversionError::new(StatusCode::UNAUTHORIZED, "UNAUTHORIZED", "Unauthorized")
 }
 
+/// Names the uploaded file for clients behind proxies that drop the multipart filename.
+const FILENAME_HEADER: &str = "x-filename";
+/// File names are cut to this many bytes, which leaves room under the usual 255-byte filesystem
+/// limit for what is added to them later (a new extension, ` (2)`).
+const MAX_FILENAME_LEN: usize = 200;
+/// Longest extension kept when a name is cut; a longer one is cut with the rest of the name.
+const MAX_KEPT_EXTENSION_LEN: usize = 16;
 
 fn sanitize_filename(raw: &str) -> String {
-    std::path::Path::new(raw)
+    sanitized_filename(raw).unwrap_or_else(|| "document".to_string())
+}
+
+/// The last path component of `raw`, cut to `MAX_FILENAME_LEN` bytes, or `None` if nothing
+/// usable is left of it.
+fn sanitized_filename(raw: &str) -> Option<String> {
+    // `Path` only splits on `/` here, so a Windows path would otherwise survive whole
+    let raw = raw.replace('\\', "_").replace(char::is_control, "");
+    let name = Path::new(&raw).file_name().map(|f| f.to_string_lossy().to_string())?;
+    Path::new(&name)
         .file_name()
         .map(|f| f.to_string_lossy().to_string())
-        .unwrap_or_else(|| "document".to_string())
+        .filter(|n| !n.trim().is_empty())
+        .map(truncate_filename)
 }
 
-async fn convert(mut multipart: Multipart) -> Response {
-    // create a unique directory for this request
-    let request_id = Uuid::new_v4();
-    let work_dir = PathBuf::from(format!("/tmp/convert/{}", request_id));
+/// `name` cut to `MAX_FILENAME_LEN` bytes, if it is longer, by shortening the stem and marking
+/// the cut with `..` before the extension: `Quarterly report ... final..docx`.
+fn truncate_filename(name: String) -> String {
+    if name.len() <= MAX_FILENAME_LEN {
+        return name;
+    }
+    let (stem, extension) = match name.rfind('.') {
+        Some(dot) if dot > 0 && name.len() - dot - 1 <= MAX_KEPT_EXTENSION_LEN => (&name[..dot], &name[dot + 1..]),
+        _ => (name.as_str(), ""),
+    };
+    let mut end = MAX_FILENAME_LEN - 2 - extension.len();
+    while !stem.is_char_boundary(end) {
+        end -= 1;
+    }
+    let truncated = format!("{}..{}", &stem[..end], extension);
+    debug!("Truncated a file name of {} bytes to {:?}", name.len(), truncated);
+    truncated
+}
 
-    if let Err(e) = fs::create_dir_all(&work_dir).await {
-        error!("Failed to create work dir: {}", e);
-        return (StatusCode::INTERNAL_SERVER_ERROR, "Internal Error").into_response();
+/// The `output_filename` field: the name the result is sent under, which must end in
+/// `extension`, the result's own (`.pdf` unless the result is an image or a zip). It is added if
+/// missing.
+fn output_filename(raw: &str, extension: &str) -> Result<String, ConversionError> {
+    let invalid = |message: String| {
+        ConversionError::new(StatusCode::BAD_REQUEST, "INVALID_OUTPUT_FILENAME", message).with_detail("extension", extension)
+    };
+    let mut name = sanitized_filename(raw).ok_or_else(|| invalid("output_filename is empty".to_string()))?;
+    match Path::new(&name).extension().filter(|ext| !ext.is_empty()) {
+        Some(ext) if ext.eq_ignore_ascii_case(extension) => {}
+        Some(ext) => return Err(invalid(format!("output_filename must end in .{}, not .{}", extension, ext.to_string_lossy()))),
+        None => name = format!("{}.{}", name.trim_end_matches('.'), extension),
     }
+    Ok(name)
+}
 
-    // Process the upload
-    let mut file_path = PathBuf::new();
+/// The `X-Filename` header, if it is set.
+fn filename_header(headers: &HeaderMap) -> Option<String> {
+    headers.get(FILENAME_HEADER).map(|v| String::from_utf8_lossy(v.as_bytes()).into_owned())
+}
 
-    while let Ok(Some(mut field)) = multipart.next_field().await {
-        if field.name() == Some("file") {
-            let raw_filename = field.file_name().unwrap_or("document").to_string();
-            let filename = sanitize_filename(&raw_filename);
+/// The name of a multipart `file` field: its own filename, or `header` when the form has none
+/// (or the `document` fallback clients send when they have none either).
+fn upload_filename(field_filename: Option<&str>, header: Option<&str>) -> String {
+    match field_filename.and_then(sanitized_filename).filter(|name| name != "document") {
+        Some(name) => {
+            debug!("Upload named {:?} by the multipart filename", name);
+            name
+        }
+        None => match header.and_then(sanitized_filename) {
+            Some(name) => {
+                debug!("Upload named {:?} by the {} header", name, FILENAME_HEADER);
+                name
+            }
+            None => "document".to_string(),
+        },
+    }
+}
+
+/// Sizes reported with the conversion time, as far as the request got.
+#[derive(Default)]
+struct ConversionSizes {
+    /// All uploaded files together.
+    input: Option<u64>,
+    output: Option<u64>,
+}
 
-            file_path = work_dir.join(&filename);
+/// `HEAD /convert`: what an upload may be, as headers, so that clients can check a file before
+/// sending it. Needs no credentials; `X-Auth-Required` says whether the upload itself does.
+async fn convert_head(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    [
+        (MAX_BODY_BYTES_HEADER, state.max_body_size.to_string()),
+        (ALLOWED_EXTENSIONS_HEADER, state.allowed_extensions.join(",")),
+        (MAX_FILES_HEADER, state.max_files_per_request.to_string()),
+        (AUTH_REQUIRED_HEADER, (state.api_keys.is_some() || state.jwt.is_some()).to_string()),
+        // `?async=true` is always available
+        (ASYNC_SUPPORTED_HEADER, true.to_string()),
+    ]
+}
 
-            // Stream to file
-            let mut file = match fs::File::create(&file_path).await {
-                Ok(f) => f,
-                Err(e) => {
-                    error!("Failed to create file: {}", e);
-                    let _ = fs::remove_dir_all(&work_dir).await;
-                    return (StatusCode::INTERNAL_SERVER_ERROR, "Internal Error").into_response();
-                }
-            };
+/// `POST /convert`, with a multipart form.
+async fn convert(
+    State(state): State<Arc<AppState>>,
+    Extension(RequestId(request_id)): Extension<RequestId>,
+    peer: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+    grant: Option<Extension<UploadGrant>>,
+    params: Query<HashMap<String, String>>,
+    multipart: Multipart,
+) -> Response {
+    let requester = Requester::new(&state, request_id, &headers, peer);
+    let upload = UploadBody::Multipart {
+        multipart,
+        filename_header: filename_header(&headers),
+        refused_fields: &[],
+    };
+    timed_convert(state, requester, grant.map(|Extension(grant)| grant), if_none_match(&headers), params, upload).await
+}
 
-            let mut success = true;
-            loop {
-                match field.chunk().await {
-                    Ok(Some(chunk)) => {
-                        if let Err(e) = file.write_all(&chunk).await {
-                            error!("Failed to write chunk: {}", e);
-                            success = false;
-                            break;
-                        }
-                    }
-                    Ok(None) => break, // End of stream
-                    Err(e) => {
-                        error!("Failed to read chunk: {}", e);
-                        success = false;
-                        break;
-                    }
-                }
-            }
+/// Form fields `/ui/convert` refuses: they make the server fetch or post to URLs.
+const UI_REFUSED_FIELDS: &[&str] = &["url", "callback_url"];
+/// Query parameters `/ui/convert` refuses, besides any starting with `s3`: they keep results
+/// on the server or send them elsewhere.
+const UI_REFUSED_PARAMS: &[&str] = &["async", "response"];
 
-            if !success {
-                let _ = fs::remove_dir_all(&work_dir).await;
-                return (StatusCode::BAD_REQUEST, "Stream interrupted").into_response();
-            }
+/// `POST /ui/convert`: the upload page's plain form, which works without JavaScript and so
+/// without an API key. Unlike `/convert` it takes only uploaded files and the options for
+/// rendering them; whatever would have the server fetch a URL, call one back, or keep the result
+/// is refused with `FIELD_NOT_ALLOWED`.
+async fn ui_convert(
+    State(state): State<Arc<AppState>>,
+    Extension(RequestId(request_id)): Extension<RequestId>,
+    peer: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+    params: Query<HashMap<String, String>>,
+    multipart: Multipart,
+) -> Response {
+    if let Some(name) = params.keys().find(|name| UI_REFUSED_PARAMS.contains(&name.as_str()) || name.starts_with("s3")) {
+        return field_not_allowed(name).into_response();
+    }
+    let requester = Requester::new(&state, request_id, &headers, peer);
+    let upload = UploadBody::Multipart {
+        multipart,
+        filename_header: filename_header(&headers),
+        refused_fields: UI_REFUSED_FIELDS,
+    };
+    timed_convert(state, requester, None, if_none_match(&headers), params, upload).await
+}
+
+fn field_not_allowed(name: &str) -> ConversionError {
+    ConversionError::new(StatusCode::BAD_REQUEST, "FIELD_NOT_ALLOWED", format!("{} is not accepted by /ui/convert; use /convert", name))
+        .with_detail("field", name)
+}
+
+/// `PUT /convert?filename=`: the request body is the file itself, named by `filename`. Takes the
+/// options `/convert` also reads from the query string.
+async fn convert_body(
+    State(state): State<Arc<AppState>>,
+    Extension(RequestId(request_id)): Extension<RequestId>,
+    peer: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+    grant: Option<Extension<UploadGrant>>,
+    Query(mut params): Query<HashMap<String, String>>,
+    body: Body,
+) -> Response {
+    let requester = Requester::new(&state, request_id, &headers, peer);
+    // Names the upload here; the result is named after it as with a multipart upload
+    let Some(filename) = params.remove("filename").and_then(|f| sanitized_filename(&f)) else {
+        return ConversionError::new(StatusCode::BAD_REQUEST, "NO_FILENAME", "The filename query parameter is required")
+            .into_response();
+    };
+    let if_none_match = if_none_match(&headers);
+    timed_convert(state, requester, grant.map(|Extension(grant)| grant), if_none_match, Query(params), UploadBody::Raw { filename, body }).await
+}
 
-            if let Err(e) = file.flush().await {
-                 error!("Failed to flush file: {}", e);
-                 let _ = fs::remove_dir_all(&work_dir).await;
-                 return (StatusCode::INTERNAL_SERVER_ERROR, "Internal Error").into_response();
+/// How a `/convert` request carries its file.
+enum UploadBody {
+    /// `refused_fields` are form fields answered with `FIELD_NOT_ALLOWED`.
+    Multipart { multipart: Multipart, filename_header: Option<String>, refused_fields: &'static [&'static str] },
+    /// The whole request body, with the name given for it.
+    Raw { filename: String, body: Body },
+}
+
+fn if_none_match(headers: &HeaderMap) -> Option<String> {
+    headers.get(header::IF_NONE_MATCH).and_then(|v| v.to_str().ok()).map(str::to_string)
+}
+
+/// Every `/convert` response, failed ones included, reports how long the request took and the
+/// sizes known by then. A result whose `ETag` the client sent in `If-None-Match` is answered with
+/// `304 Not Modified` instead; the `ETag` is the cache key, so a cache hit never runs LibreOffice.
+async fn timed_convert(
+    state: Arc<AppState>,
+    requester: Requester,
+    grant: Option<UploadGrant>,
+    if_none_match: Option<String>,
+    params: Query<HashMap<String, String>>,
+    upload: UploadBody,
+) -> Response {
+    let started = Instant::now();
+    let mut sizes = ConversionSizes::default();
+    let mut response = handle_convert(State(state), requester, grant, params, upload, &mut sizes).await;
+    if let Some(ref condition) = if_none_match
+        && let Some(etag) = response.headers().get(header::ETAG).cloned()
+        && ResultCache::etag_matches(condition, etag.to_str().unwrap_or_default())
+    {
+        let mut not_modified = (StatusCode::NOT_MODIFIED, [(header::ETAG, etag)]).into_response();
+        // A 304 carries the caching headers the full response would have
+        for name in [header::CACHE_CONTROL, header::VARY] {
+            if let Some(value) = response.headers().get(&name).cloned() {
+                not_modified.headers_mut().insert(name, value);
             }
-            break;
         }
+        response = not_modified;
+    }
+    let headers = response.headers_mut();
+    headers.insert(CONVERSION_TIME_HEADER, HeaderValue::from(started.elapsed().as_millis() as u64));
+    if let Some(input) = sizes.input {
+        headers.insert(FILE_SIZE_HEADER, HeaderValue::from(input));
     }
+    if let Some(output) = sizes.output {
+        headers.insert(PDF_SIZE_HEADER, HeaderValue::from(output));
+    }
+    response
+}
 
-    if file_path.as_os_str().is_empty() {
-         let _ = fs::remove_dir_all(&work_dir).await;
-         return (StatusCode::BAD_REQUEST, "No file uploaded").into_response();
+/// `grant` is the upload token the request was authorized with, if any.
+async fn handle_convert(
+    State(state): State<Arc<AppState>>,
+    requester: Requester,
+    grant: Option<UploadGrant>,
+    Query(params): Query<HashMap<String, String>>,
+    upload: UploadBody,
+    sizes: &mut ConversionSizes,
+) -> Response {
+    let request_id = requester.request_id;
+    if state.draining.load(Ordering::SeqCst) {
+        return shutting_down_response();
+    }
+    let is_async = params.get("async").is_some_and(|v| v == "true");
+    let dry_run = params.get("dry_run").is_some_and(|v| v == "true");
+    if dry_run && is_async {
+        return dry_run_not_async().into_response();
     }
+    let response_mode = match ResponseMode::from_param(params.get("response").map(String::as_str), state.s3.is_some()) {
+        Ok(mode) => mode,
+        Err(e) => return e.into_response(),
+    };
+    let disposition = match Disposition::from_param(params.get("disposition").map(String::as_str)) {
+        Ok(disposition) => disposition,
+        Err(e) => return e.into_response(),
+    };
+    // A name with nothing left after sanitizing keeps the one derived from the input
+    let filename = params.get("filename").and_then(|f| sanitized_filename(f));
+    // Synchronous callers are turned away when busy; async jobs wait for a slot instead
+    let slot = if is_async {
+        None
+    } else {
+        match state.try_conversion_slot() {
+            Some(slot) => Some(slot),
+            None => return busy_response(),
+        }
+    };
 
-    // Convert
-    info!("Converting file: {:?}", file_path);
-    let start_time = std::time::Instant::now();
+    // create a unique directory for this request
+    let active_dir = state.active_dirs.track(request_id);
+    let work_dir = state.request_dir(request_id);
 
-    // UserInstallation is set to a temp dir to avoid conflicts and permission issues
-    let user_installation = format!("-env:UserInstallation=file://{}/user", work_dir.display());
-
-    // Optimized flags for faster startup
-    let output = Command::new("libreoffice")
-        .arg("--headless")
-        .arg("--nodefault")
-        .arg("--nofirststartwizard")
-        .arg("--nolockcheck")
-        .arg("--nologo")
-        .arg("--norestore")
-        .arg("--convert-to")
-        .arg("pdf")
-        .arg("--outdir")
-        .arg(&work_dir)
-        .arg(&user_installation)
-        .arg(&file_path)
-        .output()
-        .await;
+    if let Err(e) = fs::create_dir_all(&work_dir).await {
+        error!("Failed to create work dir: {}", e);
+        return ConversionError::internal().into_response();
+    }
+    if let Err(e) = disk::ensure_free_space(&work_dir, state.min_free_bytes) {
+        let _ = fs::remove_dir_all(&work_dir).await;
+        return e.into_response();
+    }
 
-    match output {
-        Ok(out) => {
-            let duration = start_time.elapsed();
-            info!("Conversion finished in {:?}", duration);
-            if !out.status.success() {
-                error!("LibreOffice failed: stderr: {}", String::from_utf8_lossy(&out.stderr));
-                let _ = fs::remove_dir_all(&work_dir).await;
-                return (StatusCode::INTERNAL_SERVER_ERROR, "Conversion failed").into_response();
+    let received = match upload {
+        UploadBody::Multipart { mut multipart, filename_header, refused_fields } => {
+            receive_form(&mut multipart, filename_header.as_deref(), &work_dir, &state, Some(&state.allowed_input_types), refused_fields)
+                .instrument(info_span!("multipart"))
+                .await
+        }
+        UploadBody::Raw { filename, body } => receive_body(body, &filename, &work_dir, &state).instrument(info_span!("body")).await,
+    };
+    let received = received.and_then(|upload| {
+        if let Some(ref grant) = grant {
+            for file in &upload.files {
+                grant.check_filename(&file.path.file_name().unwrap_or_default().to_string_lossy())?;
             }
         }
+        Ok(upload)
+    });
+    let upload = match received {
+        Ok(upload) => upload,
         Err(e) => {
-            error!("Failed to run LibreOffice: {}", e);
             let _ = fs::remove_dir_all(&work_dir).await;
-            return (StatusCode::INTERNAL_SERVER_ERROR, "Conversion execution failed").into_response();
+            return e.into_response();
         }
-    }
+    };
 
-    // Find the PDF file
-    // LibreOffice creates a file with the same base name and .pdf extension
-    let mut found_pdf_path: Option<PathBuf> = None;
-    let mut pdf_filename_output = String::from("output.pdf");
+    sizes.input = Some(upload.files.iter().map(|f| f.size).sum());
 
-    if let Ok(mut entries) = fs::read_dir(&work_dir).await {
-        while let Ok(Some(entry)) = entries.next_entry().await {
-            let path = entry.path();
-            if path.extension().map_or(false, |e| e == "pdf") {
-                found_pdf_path = Some(path.clone());
-                if let Some(name) = path.file_name() {
-                    pdf_filename_output = name.to_string_lossy().to_string();
-                }
-                break;
-            }
+    let callback_url = upload.fields.get("callback_url").filter(|u| !u.is_empty()).cloned();
+    // The upload itself may have used up the remaining space
+    let checked = disk::ensure_free_space(&work_dir, state.min_free_bytes)
+        .and_then(|_| match callback_url {
+            Some(_) if dry_run => Err(dry_run_not_async()),
+            Some(ref url) => callback::validate_url(url, state.allow_http_callbacks),
+            None => Ok(()),
+        })
+        .and_then(|_| ConvertOptions::from_request(&params, &upload.fields))
+        .and_then(|opts| {
+            let extension = if upload.files.len() > 1 || opts.format.is_paged() { "zip" } else { opts.format.extension };
+            let name = upload.fields.get("output_filename").filter(|n| !n.is_empty()).map(|n| output_filename(n, extension));
+            Ok((opts, name.transpose()?))
+        });
+    // Resolving the callback's host takes a lookup, so it is checked last
+    let checked = match (checked, &callback_url) {
+        (Ok(checked), Some(url)) => callback::check_address(url, &state.outbound_allowed_networks).await.map(|_| checked),
+        (checked, _) => checked,
+    };
+    let (opts, filename) = match checked {
+        Ok((opts, output_filename)) => (opts, output_filename.or(filename)),
+        Err(e) => {
+            let _ = fs::remove_dir_all(&work_dir).await;
+            return e.into_response();
         }
+    };
+
+    // A callback means the caller does not wait for the result either
+    if is_async || callback_url.is_some() {
+        drop(slot);
+        let job_id = state.jobs.submit(request_id, work_dir.clone());
+        let store = state.clone();
+        let task = tokio::spawn(async move {
+            let _active_dir = active_dir;
+            let _slot = state.conversion_slot().await;
+            state.jobs.mark_running(job_id);
+            let mut result = convert_all(&state, requester, &work_dir, &upload.files, &opts).await;
+            let _ = fs::remove_dir_all(&work_dir).await;
+            if let (Ok(converted), Some(filename)) = (&mut result, filename) {
+                converted.filename = filename;
+            }
+            if let Ok(ref converted) = result {
+                upload_to_s3(&state, request_id, converted).await;
+            }
+            if let Some(url) = callback_url {
+                callback::deliver(&url, &state.outbound_allowed_networks, request_id, &result).await;
+            }
+            state.jobs.complete(job_id, result);
+        });
+        store.jobs.set_task(job_id, task.abort_handle());
+        return (StatusCode::ACCEPTED, Json(json!({ "job_id": job_id }))).into_response();
     }
 
-    let pdf_content = match found_pdf_path {
-        Some(path) => match fs::read(&path).await {
-            Ok(c) => c,
+    if dry_run {
+        let response = check_conversion(&state, &work_dir, &upload.files, &opts).await;
+        drop(slot);
+        let _ = fs::remove_dir_all(&work_dir).instrument(info_span!("cleanup")).await;
+        return response;
+    }
+
+    let result = convert_all(&state, requester, &work_dir, &upload.files, &opts).await;
+    drop(slot);
+
+    // Cleanup
+    let _ = fs::remove_dir_all(&work_dir).instrument(info_span!("cleanup")).await;
+
+    let s3_uri = match result {
+        Ok(ref converted) => upload_to_s3(&state, request_id, converted).await,
+        Err(_) => None,
+    };
+    let result = result.map(|mut converted| {
+        if let Some(filename) = filename {
+            converted.filename = filename;
+        }
+        sizes.output = Some(converted.content.len() as u64);
+        converted
+    });
+
+    match (result, response_mode) {
+        (Ok(converted), ResponseMode::Binary) => file_response(&converted, disposition),
+        (Ok(converted), ResponseMode::Url) => match state.downloads.store(request_id, &converted).await {
+            Ok(token) => Json(json!({
+                "download_url": format!("/download/{}", token),
+                "expires_in": state.downloads.ttl().as_secs(),
+            }))
+            .into_response(),
             Err(e) => {
-                error!("Failed to read generated PDF: {}", e);
-                let _ = fs::remove_dir_all(&work_dir).await;
-                return (StatusCode::INTERNAL_SERVER_ERROR, "Read PDF failed").into_response();
+                error!("Failed to store download: {}", e);
+                ConversionError::internal().into_response()
             }
         },
-        None => {
-            error!("No PDF file found in output directory");
-            let _ = fs::remove_dir_all(&work_dir).await;
-            return (StatusCode::INTERNAL_SERVER_ERROR, "PDF generation failed - output not found").into_response();
+        (Ok(_), ResponseMode::S3Url) => match s3_uri {
+            Some(Ok(uri)) => Json(json!({ "s3_uri": uri })).into_response(),
+            _ => ConversionError::new(StatusCode::BAD_GATEWAY, "S3_UPLOAD_FAILED", "Upload to S3 failed").into_response(),
+        },
+        (Err(e), _) => e.into_response(),
+    }
+}
+
+fn dry_run_not_async() -> ConversionError {
+    ConversionError::new(StatusCode::BAD_REQUEST, "INVALID_DRY_RUN", "dry_run cannot be combined with async or callback_url")
+}
+
+/// `?dry_run=true`: whether LibreOffice can convert the upload, without returning the result.
+/// LibreOffice has no mode that only opens a document, so the conversion runs in full (bypassing
+/// the cache, metrics and audit log) and its output is dropped with the work directory.
+async fn check_conversion(state: &AppState, work_dir: &Path, files: &[UploadedFile], opts: &ConvertOptions) -> Response {
+    let [file] = files else {
+        return ConversionError::new(StatusCode::BAD_REQUEST, "TOO_MANY_FILES", "A dry run checks one file at a time").into_response();
+    };
+    let mut body = json!({ "valid": true, "detected_type": detect::mime_type(file.kind), "estimated_pages": null });
+    match convert_file(state, work_dir, file, opts).await {
+        Ok(converted) => {
+            if opts.format == formats::PDF {
+                let pages = export::count_pdf_pages(&converted.content);
+                body["estimated_pages"] = json!((pages > 0).then_some(pages));
+            }
+        }
+        // Nothing was tried, so nothing is known about the file
+        Err(e) if e.code == "CIRCUIT_OPEN" => return e.into_response(),
+        Err(e) => {
+            body["valid"] = json!(false);
+            body["error"] = json!(e);
+        }
+    }
+    Json(body).into_response()
+}
+
+/// Copies a successful conversion to `<bucket>/<request_id>/<filename>` when S3 is configured.
+/// Returns the object's URI, or `None` without S3.
+async fn upload_to_s3(state: &AppState, request_id: Uuid, converted: &ConvertedFile) -> Option<Result<String, String>> {
+    let s3 = state.s3.as_ref()?;
+    let key = format!("{}/{}", request_id, converted.filename);
+    let result = s3
+        .put_object(&key, converted.content_type, &converted.content)
+        .instrument(info_span!("s3_upload"))
+        .await;
+    if let Err(ref e) = result {
+        error!("Failed to upload {} to S3: {}", key, e);
+    }
+    Some(result)
+}
+
+/// How a successful synchronous conversion is returned (`?response=`).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ResponseMode {
+    /// The file itself, as the response body.
+    Binary,
+    /// JSON with a one-time `download_url` for the file.
+    Url,
+    /// JSON with the `s3_uri` the file was uploaded to.
+    S3Url,
+}
+
+impl ResponseMode {
+    fn from_param(value: Option<&str>, s3_configured: bool) -> Result<Self, ConversionError> {
+        match value.map(str::trim) {
+            None | Some("") | Some("binary") => Ok(ResponseMode::Binary),
+            Some("url") => Ok(ResponseMode::Url),
+            Some("s3_url") if s3_configured => Ok(ResponseMode::S3Url),
+            Some("s3_url") => Err(ConversionError::new(
+                StatusCode::BAD_REQUEST,
+                "S3_NOT_CONFIGURED",
+                "response=s3_url requires S3 to be configured",
+            )),
+            Some(other) => Err(ConversionError::new(
+                StatusCode::BAD_REQUEST,
+                "INVALID_RESPONSE_MODE",
+                format!("Unsupported response mode {:?}; valid options: binary, url, s3_url", other),
+            )),
+        }
+    }
+}
+
+fn shutting_down_response() -> Response {
+    let error = ConversionError::new(StatusCode::SERVICE_UNAVAILABLE, "SHUTTING_DOWN", "Server is shutting down");
+    ([(header::CONNECTION, "close")], error).into_response()
+}
+
+/// Whether browsers should show the converted file or save it (`?disposition=`).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Disposition {
+    Attachment,
+    Inline,
+}
+
+impl Disposition {
+    fn from_param(value: Option<&str>) -> Result<Self, ConversionError> {
+        match value.map(str::trim) {
+            None | Some("") | Some("attachment") => Ok(Disposition::Attachment),
+            Some("inline") => Ok(Disposition::Inline),
+            Some(other) => Err(ConversionError::new(
+                StatusCode::BAD_REQUEST,
+                "INVALID_DISPOSITION",
+                format!("Unsupported disposition {:?}; valid options: attachment, inline", other),
+            )),
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Disposition::Attachment => "attachment",
+            Disposition::Inline => "inline",
         }
+    }
+}
+
+fn busy_response() -> Response {
+    let error = ConversionError::new(
+        StatusCode::SERVICE_UNAVAILABLE,
+        "SERVER_BUSY",
+        "Too many conversions in progress, retry later",
+    );
+    ([(header::RETRY_AFTER, BUSY_RETRY_AFTER_SECS.to_string())], error).into_response()
+}
+
+/// A multipart form with its `file` fields already streamed to disk.
+struct Upload {
+    /// Uploaded files in form order; each lives in its own `<work_dir>/<index>/` directory.
+    files: Vec<UploadedFile>,
+    /// The remaining (text) form fields, by name.
+    fields: HashMap<String, String>,
+}
+
+/// Streams every `file` field of the form into `work_dir` and collects the other fields. A `url`
+/// field is fetched and added after the uploaded files. Files must be one of `allowed_types`;
+/// with `None` any type is received and the caller checks each file's `kind`. `filename_header`
+/// (see [`upload_filename`]) names files the form leaves unnamed.
+async fn receive_upload(
+    multipart: &mut Multipart,
+    filename_header: Option<&str>,
+    work_dir: &Path,
+    state: &AppState,
+    allowed_types: Option<&[String]>,
+) -> Result<Upload, ConversionError> {
+    receive_form(multipart, filename_header, work_dir, state, allowed_types, &[]).await
+}
+
+/// `receive_upload`, refusing the fields in `refused_fields` as soon as they are seen.
+async fn receive_form(
+    multipart: &mut Multipart,
+    filename_header: Option<&str>,
+    work_dir: &Path,
+    state: &AppState,
+    allowed_types: Option<&[String]>,
+    refused_fields: &[&str],
+) -> Result<Upload, ConversionError> {
+    let max_files = state.max_files_per_request;
+    let too_many_files = || {
+        ConversionError::new(
+            StatusCode::BAD_REQUEST,
+            "TOO_MANY_FILES",
+            format!("At most {} files can be converted per request", max_files),
+        )
     };
+    let mut guard = UploadGuard::new(work_dir);
+    let mut files = Vec::new();
+    let mut fields = HashMap::new();
 
-    // Cleanup
-    let _ = fs::remove_dir_all(&work_dir).await;
+    while let Ok(Some(mut field)) = multipart.next_field().await {
+        let Some(name) = field.name().map(str::to_string) else {
+            continue;
+        };
 
-    // Return
-    // Escape double quotes in filename to prevent header injection
-    let escaped_filename = pdf_filename_output.replace('"', "\\\"");
-    let headers = [
-        (header::CONTENT_TYPE, "application/pdf"),
-        (header::CONTENT_DISPOSITION, &format!("attachment; filename=\"{}\"", escaped_filename)),
-    ];
+        if refused_fields.contains(&name.as_str()) {
+            return Err(field_not_allowed(&name));
+        }
+        if name != "file" {
+            let bytes = field.bytes().await.map_err(|e| {
+                error!("Failed to read field {}: {}", name, e);
+                stream_interrupted()
+            })?;
+            let value = String::from_utf8(bytes.to_vec()).map_err(|_| {
+                ConversionError::new(StatusCode::BAD_REQUEST, "INVALID_FIELD_ENCODING", format!("Field {} is not valid UTF-8", name))
+            })?;
+            fields.insert(name, value);
+            continue;
+        }
+
+        if files.len() == max_files {
+            return Err(too_many_files());
+        }
+
+        let filename = upload_filename(field.file_name(), filename_header);
+        if allowed_types.is_some() {
+            check_extension(&filename, &state.allowed_extensions)?;
+        }
+        let path = create_file_dir(work_dir, files.len()).await?.join(&filename);
+
+        guard.start_file_limit(&path, state);
+        let (size, digest) = write_stream(&mut field, &path, &mut guard)
+            .instrument(info_span!("disk_write", file.name = %filename))
+            .await?;
+        files.push(accept_file(path, size, Some(digest), state, allowed_types).await?);
+    }
+
+    if let Some(url) = fields.get("url") {
+        if files.len() == max_files {
+            return Err(too_many_files());
+        }
+        let file_dir = create_file_dir(work_dir, files.len()).await?;
+        files.push(fetch_url(url, &file_dir, state, allowed_types).await?);
+    }
 
-    (headers, pdf_content).into_response()
+    if files.is_empty() {
+        return Err(ConversionError::new(StatusCode::BAD_REQUEST, "NO_FILE", "No file uploaded"));
+    }
+    guard.complete();
+    Ok(Upload { files, fields })
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+/// The request body as the one file of an upload, named `filename`.
+async fn receive_body(body: Body, filename: &str, work_dir: &Path, state: &AppState) -> Result<Upload, ConversionError> {
+    let mut guard = UploadGuard::new(work_dir);
+    check_extension(filename, &state.allowed_extensions)?;
+    let path = create_file_dir(work_dir, 0).await?.join(filename);
+    guard.start_file_limit(&path, state);
+    let (size, digest) = write_stream(body.into_data_stream(), &path, &mut guard)
+        .instrument(info_span!("disk_write", file.name = %filename))
+        .await?;
+    let file = accept_file(path, size, Some(digest), state, Some(&state.allowed_input_types)).await?;
+    guard.complete();
+    Ok(Upload {
+        files: vec![file],
+        fields: HashMap::new(),
+    })
+}
 
-    #[test]
-    fn test_sanitize_filename() {
-        assert_eq!(sanitize_filename("test.docx"), "test.docx");
-        assert_eq!(sanitize_filename("/tmp/test.docx"), "test.docx");
-        // Windows paths are treated as full filenames on Linux, so we skip that check or expect full string
-        // assert_eq!(sanitize_filename("C:\\Windows\\test.docx"), "test.docx");
-        // Edge cases
-        assert_eq!(sanitize_filename(""), "document");
+/// Counts the bytes of the file being received against its size limit, and removes the work dir
+/// unless the upload completes. Removal happens on drop, so it also covers a client that
+/// disconnects mid-upload: the handler future is dropped and its own cleanup never runs.
+struct UploadGuard<'a> {
+    work_dir: &'a Path,
+    /// The type the current file's limit belongs to, for the error message.
+    kind: String,
+    limit: u64,
+    received: u64,
+    completed: bool,
+}
+
+impl<'a> UploadGuard<'a> {
+    fn new(work_dir: &'a Path) -> Self {
+        Self {
+            work_dir,
+            kind: String::new(),
+            limit: u64::MAX,
+            received: 0,
+            completed: false,
+        }
+    }
+
+    fn start_file(&mut self, kind: &str, limit: u64) {
+        self.kind = kind.to_string();
+        self.limit = limit;
+        self.received = 0;
+    }
+
+    /// Starts on the file to be written to `path`. The declared extension's limit (or
+    /// MAX_BODY_SIZE) stops an oversized upload early; the detected type's limit is checked once
+    /// the content has been sniffed.
+    fn start_file_limit(&mut self, path: &Path, state: &AppState) {
+        let declared = path.extension().unwrap_or_default().to_string_lossy().to_ascii_lowercase();
+        match state.file_size_limits.get(&declared) {
+            Some(&limit) => self.start_file(&declared, limit.min(state.max_body_size)),
+            None => self.start_file("Uploaded", state.max_body_size),
+        }
+    }
+
+    /// Counts a received chunk, failing as soon as the file is over its limit.
+    fn add(&mut self, len: usize) -> Result<u64, ConversionError> {
+        self.received += len as u64;
+        if self.received > self.limit {
+            return Err(file_too_large(&self.kind, self.received, self.limit));
+        }
+        Ok(self.received)
+    }
+
+    /// Keeps the work dir: the handler is now responsible for it.
+    fn complete(mut self) {
+        self.completed = true;
+    }
+}
+
+impl Drop for UploadGuard<'_> {
+    fn drop(&mut self) {
+        if !self.completed {
+            let _ = std::fs::remove_dir_all(self.work_dir);
+        }
+    }
+}
+
+/// A directory per file keeps identically named inputs (and their outputs) apart.
+async fn create_file_dir(work_dir: &Path, index: usize) -> Result<PathBuf, ConversionError> {
+    let file_dir = work_dir.join(index.to_string());
+    fs::create_dir_all(&file_dir).await.map_err(|e| {
+        error!("Failed to create file dir: {}", e);
+        ConversionError::internal()
+    })?;
+    Ok(file_dir)
+}
+
+/// Checks a received file's content type and that type's size limit. `digest` is the SHA-256 of
+/// the content, if it was hashed on the way in.
+async fn accept_file(
+    mut path: PathBuf,
+    mut size: u64,
+    mut digest: Option<[u8; 32]>,
+    state: &AppState,
+    allowed_types: Option<&[String]>,
+) -> Result<UploadedFile, ConversionError> {
+    // A plain archive (not an office package, which is a zip too) stands in for the document in it
+    if allowed_types.is_some() && detect::detect_file(&path, "").await.is_ok_and(|kind| kind == "zip") {
+        (path, size) = archive::extract_document(&path, &state.allowed_extensions, state.max_body_size).await?;
+        digest = None;
+    }
+    let detected = check_file_type(&path, allowed_types).await?;
+    check_macros(&path, detected, state)?;
+    if let Some(&limit) = state.file_size_limits.get(detected)
+        && size > limit
+    {
+        return Err(file_too_large(detected, size, limit));
+    }
+    Ok(UploadedFile { path, size, kind: detected, digest })
+}
+
+/// Refuses an Office Open XML package uploaded under a macro-enabled extension (`.docm` and the
+/// like) unless `ALLOW_MACROS` is set. Both must match: a `.docx` is not refused, nor is a text
+/// file named `.docm`.
+fn check_macros(path: &Path, detected: &str, state: &AppState) -> Result<(), ConversionError> {
+    let extension = path.extension().unwrap_or_default().to_string_lossy().to_ascii_lowercase();
+    if !state.macro_enabled_extensions.contains(&extension) || !matches!(detected, "docx" | "xlsx" | "pptx") {
+        return Ok(());
+    }
+    if state.allow_macros {
+        warn!("Accepted macro-enabled upload {:?}", path);
+        return Ok(());
+    }
+    info!("Rejected macro-enabled upload {:?}", path);
+    Err(ConversionError::new(
+        StatusCode::UNSUPPORTED_MEDIA_TYPE,
+        "MACRO_ENABLED_FORMAT_REJECTED",
+        format!("Macro-enabled .{} files are not accepted", extension),
+    )
+    .with_detail("extension", extension))
+}
+
+/// Downloads `url` into `file_dir`, naming the file after the last segment of the URL path.
+async fn fetch_url(url: &str, file_dir: &Path, state: &AppState, allowed_types: Option<&[String]>) -> Result<UploadedFile, ConversionError> {
+    if !outbound::is_allowed_url(url, state.allow_http_downloads) {
+        let expected = if state.allow_http_downloads { "an http:// or https://" } else { "an https://" };
+        return Err(ConversionError::new(StatusCode::BAD_REQUEST, "INVALID_URL", format!("url must be {} URL", expected)));
+    }
+
+    let filename = filename_from_url(url);
+    if allowed_types.is_some() {
+        check_extension(&filename, &state.allowed_extensions)?;
+    }
+    let path = file_dir.join(&filename);
+    let limit = state.max_download_bytes;
+    let size = outbound::download(url, &path, limit, state.download_timeout, state.allow_http_downloads, &state.outbound_allowed_networks)
+        .instrument(info_span!("url_download", file.name = %filename))
+        .await
+        .map_err(|e| {
+            info!("Failed to download {}: {:?}", url, e);
+            match e {
+                DownloadError::TooLarge => ConversionError::new(
+                    StatusCode::PAYLOAD_TOO_LARGE,
+                    "FILE_TOO_LARGE",
+                    format!("Downloaded files are limited to {} bytes", limit),
+                )
+                .with_detail("limit", limit),
+                DownloadError::Status(status) => ConversionError::new(
+                    StatusCode::BAD_GATEWAY,
+                    "URL_FETCH_FAILED",
+                    format!("Fetching url returned HTTP {}", status),
+                )
+                .with_detail("upstream_status", status),
+                DownloadError::Refused(reason) => {
+                    ConversionError::new(StatusCode::BAD_REQUEST, "URL_NOT_ALLOWED", format!("url is not allowed: {}", reason))
+                }
+                DownloadError::Failed(_) => {
+                    ConversionError::new(StatusCode::BAD_GATEWAY, "URL_FETCH_FAILED", "Failed to fetch url")
+                }
+            }
+        })?;
+    accept_file(path, size, None, state, allowed_types).await
+}
+
+/// The sanitized, percent-decoded last path segment of `url`, or `document` if there is none.
+fn filename_from_url(url: &str) -> String {
+    let rest = url.split_once("://").map_or(url, |(_, rest)| rest);
+    let rest = rest.split(['?', '#']).next().unwrap_or_default();
+    let segment = rest.split_once('/').map_or("", |(_, path)| path.rsplit('/').next().unwrap_or_default());
+    sanitize_filename(&percent_decode(segment))
+}
+
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes.get(i + 1..i + 3).and_then(|h| std::str::from_utf8(h).ok());
+        match (bytes[i], hex.and_then(|h| u8::from_str_radix(h, 16).ok())) {
+            (b'%', Some(b)) => {
+                out.push(b);
+                i += 3;
+            }
+            (b, _) => {
+                out.push(b);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// Streams an upload (a multipart field or a request body) to a new file at `path`, returning the
+/// number of bytes written. Gives up as soon as it grows past the limit `guard` was started with.
+async fn write_stream<E: std::fmt::Display>(
+    mut chunks: impl Stream<Item = Result<Bytes, E>> + Unpin,
+    path: &Path,
+    guard: &mut UploadGuard<'_>,
+) -> Result<(u64, [u8; 32]), ConversionError> {
+    let mut file = fs::File::create(path).await.map_err(|e| {
+        error!("Failed to create file: {}", e);
+        ConversionError::internal()
+    })?;
+
+    let mut size = 0u64;
+    let mut hasher = Sha256::new();
+    loop {
+        match chunks.next().await.transpose() {
+            Ok(Some(chunk)) => {
+                size = guard.add(chunk.len())?;
+                hasher.update(&chunk);
+                if let Err(e) = file.write_all(&chunk).await {
+                    error!("Failed to write chunk: {}", e);
+                    return Err(stream_interrupted());
+                }
+            }
+            Ok(None) => break, // End of stream
+            Err(e) => {
+                error!("Failed to read chunk: {}", e);
+                return Err(stream_interrupted());
+            }
+        }
+    }
+
+    file.flush().await.map_err(|e| {
+        error!("Failed to flush file: {}", e);
+        ConversionError::internal()
+    })?;
+    Ok((size, hasher.finish()))
+}
+
+fn file_too_large(kind: &str, size: u64, limit: u64) -> ConversionError {
+    ConversionError::new(
+        StatusCode::PAYLOAD_TOO_LARGE,
+        "FILE_TOO_LARGE",
+        format!("{} files are limited to {} bytes; received {}", kind, limit, size),
+    )
+    .with_detail("size", size)
+    .with_detail("limit", limit)
+}
+
+/// Rejects a file named with an extension outside `allowed`. Names without an extension are left
+/// to the content check.
+fn check_extension(filename: &str, allowed: &[String]) -> Result<(), ConversionError> {
+    let Some(extension) = Path::new(filename).extension().map(|e| e.to_string_lossy().to_ascii_lowercase()) else {
+        return Ok(());
+    };
+    if allowed.contains(&extension) {
+        return Ok(());
+    }
+    info!("Rejected upload with extension {:?}", extension);
+    Err(ConversionError::new(
+        StatusCode::UNSUPPORTED_MEDIA_TYPE,
+        "UNSUPPORTED_EXTENSION",
+        format!("Files with extension {:?} are not accepted", extension),
+    )
+    .with_detail("extension", extension)
+    .with_detail("allowed_extensions", allowed.to_vec()))
+}
+
+/// Rejects files whose content is not one of the `allowed` types, whatever their extension says.
+/// Returns the detected type.
+async fn check_file_type(path: &Path, allowed: Option<&[String]>) -> Result<&'static str, ConversionError> {
+    let declared = path.extension().unwrap_or_default().to_string_lossy();
+    let detected = detect::detect_file(path, &declared).await.map_err(|e| {
+        error!("Failed to inspect upload: {}", e);
+        ConversionError::internal()
+    })?;
+    let Some(allowed) = allowed else {
+        return Ok(detected);
+    };
+    if allowed.iter().any(|t| t == detected) {
+        return Ok(detected);
+    }
+    info!("Rejected upload detected as {}", detected);
+    Err(ConversionError::new(
+        StatusCode::UNSUPPORTED_MEDIA_TYPE,
+        "UNSUPPORTED_FILE_TYPE",
+        format!("Detected file type {:?} is not accepted", detected),
+    )
+    .with_detail("detected_type", detected)
+    .with_detail("accepted_types", allowed.to_vec()))
+}
+
+pub struct UploadedFile {
+    pub path: PathBuf,
+    pub size: u64,
+    /// The type detected from the content.
+    pub kind: &'static str,
+    /// The SHA-256 of the content, when it was hashed as it was received.
+    pub digest: Option<[u8; 32]>,
+}
+
+fn stream_interrupted() -> ConversionError {
+    ConversionError::new(StatusCode::BAD_REQUEST, "STREAM_INTERRUPTED", "Stream interrupted")
+}
+
+/// Per-request conversion settings, taken from form fields or (as a fallback) query parameters.
+#[derive(Clone, Debug)]
+pub struct ConvertOptions {
+    pub format: OutputFormat,
+    /// Password required to open the resulting PDF.
+    pub password: Option<Secret>,
+    /// Password that opens an encrypted input (see `decrypt`).
+    pub input_password: Option<Secret>,
+    /// PDF/A conformance level (e.g. `2b`) and the matching `SelectPdfVersion`.
+    pub pdfa: Option<(&'static str, u8)>,
+    /// Further export filter settings from the `options` JSON field.
+    pub export: ExportOptions,
+    /// Sorted 1-based page numbers from the `pages` field.
+    pub pages: Option<Vec<u32>>,
+    pub watermark: Option<Watermark>,
+    /// JPEG export settings; only set for `/thumbnail` renders.
+    pub jpeg: Option<JpegExport>,
+    /// How CSV inputs are read.
+    pub csv: CsvImport,
+    /// Page size every page is set to; `None` keeps the document's own.
+    pub paper_size: Option<PaperSize>,
+    /// Page size the pages of the PDF are scaled to afterwards.
+    pub normalize_page_size: Option<PaperSize>,
+    /// Linearize the PDF for fast web view.
+    pub linearize: bool,
+    /// Title, author, subject and keywords set on the PDF.
+    pub properties: DocumentProperties,
+}
+
+/// A string that is never printed, so it cannot leak through `{:?}` in logs.
+#[derive(Clone)]
+pub struct Secret(String);
+
+impl std::fmt::Debug for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Secret(***)")
+    }
+}
+
+impl Default for ConvertOptions {
+    fn default() -> Self {
+        Self {
+            format: formats::PDF,
+            password: None,
+            input_password: None,
+            pdfa: None,
+            export: ExportOptions::default(),
+            pages: None,
+            watermark: None,
+            jpeg: None,
+            csv: CsvImport::default(),
+            paper_size: None,
+            normalize_page_size: None,
+            linearize: false,
+            properties: DocumentProperties::default(),
+        }
+    }
+}
+
+impl ConvertOptions {
+    fn from_request(params: &HashMap<String, String>, fields: &HashMap<String, String>) -> Result<Self, ConversionError> {
+        let lookup = |name: &str| fields.get(name).or_else(|| params.get(name)).map(String::as_str);
+
+        let mut opts = ConvertOptions::default();
+        if let Some(format) = lookup("format") {
+            opts.format = formats::output_format(format).ok_or_else(|| {
+                ConversionError::new(
+                    StatusCode::BAD_REQUEST,
+                    "UNSUPPORTED_FORMAT",
+                    format!(
+                        "Unsupported output format {:?}; valid options: {}",
+                        format,
+                        formats::output_format_names().join(", ")
+                    ),
+                )
+            })?;
+        }
+
+        // Only read from the form: a query string ends up in access logs
+        if let Some(password) = fields.get("password").filter(|p| !p.is_empty()) {
+            if password.chars().count() > MAX_PDF_PASSWORD_LEN {
+                return Err(ConversionError::new(
+                    StatusCode::BAD_REQUEST,
+                    "PASSWORD_TOO_LONG",
+                    format!("password must be at most {} characters", MAX_PDF_PASSWORD_LEN),
+                ));
+            }
+            opts.password = Some(Secret(password.clone()));
+        }
+        opts.input_password = fields.get("input_password").filter(|p| !p.is_empty()).map(|p| Secret(p.clone()));
+
+        if let Some(text) = fields.get("watermark").filter(|w| !w.is_empty()) {
+            let field = |name: &str| fields.get(name).map(String::as_str).filter(|v| !v.is_empty());
+            opts.watermark = Some(Watermark::parse(text, field("watermark_color"), field("watermark_angle"))?);
+        }
+
+        opts.csv = CsvImport::parse(lookup("csv_delimiter"), lookup("csv_encoding"))?;
+
+        for field in ["paper_size", "normalize_page_size"] {
+            let Some(name) = lookup(field).filter(|p| !p.trim().is_empty()) else {
+                continue;
+            };
+            let size = formats::paper_size(name).ok_or_else(|| {
+                let names: Vec<&str> = formats::PAPER_SIZES.iter().map(|size| size.name).collect();
+                ConversionError::new(
+                    StatusCode::BAD_REQUEST,
+                    "UNSUPPORTED_PAPER_SIZE",
+                    format!("Unsupported {} {:?}; valid options: {}", field, name, names.join(", ")),
+                )
+            })?;
+            match field {
+                "paper_size" => opts.paper_size = Some(size),
+                _ => opts.normalize_page_size = Some(size),
+            }
+        }
+
+        if let Some(raw) = fields.get("options").filter(|o| !o.trim().is_empty()) {
+            opts.export = ExportOptions::parse(raw)?;
+        }
+
+        if let Some(raw) = lookup("pages").filter(|p| !p.trim().is_empty()) {
+            if opts.export.page_range.is_some() {
+                return Err(ConversionError::new(
+                    StatusCode::BAD_REQUEST,
+                    "PDF_OPTION_CONFLICT",
+                    "pages and options.page_range cannot both be given",
+                ));
+            }
+            opts.pages = Some(export::parse_pages(raw)?);
+        }
+
+        let pdfa = lookup("pdfa").filter(|l| !l.is_empty());
+        if pdfa.is_some() && opts.export.pdfa_level.is_some() {
+            return Err(ConversionError::new(
+                StatusCode::BAD_REQUEST,
+                "PDF_OPTION_CONFLICT",
+                "pdfa and options.pdfa_level cannot both be given",
+            ));
+        }
+        if let Some(level) = pdfa.or(opts.export.pdfa_level.as_deref()) {
+            opts.pdfa = Some(formats::pdfa_level(level).ok_or_else(|| {
+                let levels: Vec<&str> = formats::PDFA_LEVELS.iter().map(|(name, _)| *name).collect();
+                ConversionError::new(
+                    StatusCode::BAD_REQUEST,
+                    "UNSUPPORTED_PDFA_LEVEL",
+                    format!("Unsupported pdfa level {:?}; valid options: {}", level, levels.join(", ")),
+                )
+            })?);
+        }
+
+        // PDF/A forbids encryption; LibreOffice would silently drop one of the two
+        if opts.pdfa.is_some() && opts.password.is_some() {
+            return Err(ConversionError::new(
+                StatusCode::BAD_REQUEST,
+                "PDF_OPTION_CONFLICT",
+                "pdfa and password cannot be combined",
+            ));
+        }
+
+        opts.linearize = lookup("linearize").is_some_and(|v| v == "true");
+
+        opts.properties = DocumentProperties::from_fields(lookup);
+        // The properties are added to the finished file, which cannot be parsed once encrypted
+        if !opts.properties.is_empty() && opts.password.is_some() {
+            return Err(ConversionError::new(
+                StatusCode::BAD_REQUEST,
+                "PDF_OPTION_CONFLICT",
+                "doc_title, doc_author, doc_subject and doc_keywords cannot be combined with password",
+            ));
+        }
+
+        // Both rewrite the finished file: an encrypted one cannot be parsed, and the incremental
+        // update would undo the linearization
+        if opts.normalize_page_size.is_some() && (opts.password.is_some() || opts.linearize) {
+            return Err(ConversionError::new(
+                StatusCode::BAD_REQUEST,
+                "PDF_OPTION_CONFLICT",
+                "normalize_page_size cannot be combined with password or linearize",
+            ));
+        }
+
+        if opts.format != formats::PDF
+            && (!opts.pdf_filter_data().is_empty() || opts.linearize || !opts.properties.is_empty() || opts.normalize_page_size.is_some())
+        {
+            return Err(ConversionError::new(
+                StatusCode::BAD_REQUEST,
+                "PDF_OPTION_REQUIRES_PDF",
+                format!("PDF export options cannot be used with format {:?}", opts.format.extension),
+            ));
+        }
+        Ok(opts)
+    }
+
+    /// PDF export filter options, in the JSON form LibreOffice accepts after `--convert-to pdf:<filter>:`.
+    fn pdf_filter_data(&self) -> Map<String, Value> {
+        let mut data = Map::new();
+        if let Some(Secret(ref password)) = self.password {
+            data.insert("EncryptFile".into(), json!({ "type": "boolean", "value": "true" }));
+            data.insert("DocumentOpenPassword".into(), json!({ "type": "string", "value": password }));
+        }
+        if let Some((_, version)) = self.pdfa {
+            data.insert("SelectPdfVersion".into(), json!({ "type": "long", "value": version.to_string() }));
+        }
+        self.export.add_filter_data(&mut data);
+        if let Some(ref pages) = self.pages {
+            data.insert("PageRange".into(), json!({ "type": "string", "value": export::format_pages(pages) }));
+        }
+        if let Some(ref watermark) = self.watermark {
+            watermark.add_filter_data(&mut data);
+        }
+        data
+    }
+}
+
+/// A finished conversion: the output bytes and how to present them.
+pub struct ConvertedFile {
+    pub filename: String,
+    pub content_type: &'static str,
+    pub content: Vec<u8>,
+    /// Extra response headers describing the output (e.g. the applied PDF/A level).
+    pub headers: Vec<(&'static str, String)>,
+}
+
+/// Converts every uploaded file. A single file is returned as-is; several are zipped together.
+async fn convert_all(
+    state: &AppState,
+    requester: Requester,
+    work_dir: &Path,
+    files: &[UploadedFile],
+    opts: &ConvertOptions,
+) -> Result<ConvertedFile, ConversionError> {
+    if let [file] = files {
+        return run_audited_conversion(state, requester, work_dir, file, opts).await;
+    }
+
+    let mut archive = ZipWriter::new();
+    let mut names = HashSet::new();
+    for file in files {
+        let converted = run_audited_conversion(state, requester, work_dir, file, opts).await?;
+        archive.add(&unique_name(&mut names, &converted.filename), &converted.content);
+    }
+    Ok(ConvertedFile {
+        filename: format!("converted_{}.zip", requester.request_id),
+        content_type: "application/zip",
+        content: archive.finish(),
+        headers: output_headers(opts),
+    })
+}
+
+/// LibreOffice skips requested pages that do not exist rather than failing, and exports the
+/// whole document when none of them do, so compare the output against the request.
+fn check_page_count(pages: &[u32], pdf: &[u8]) -> Result<(), ConversionError> {
+    let exported = export::count_pdf_pages(pdf);
+    if exported == pages.len() {
+        return Ok(());
+    }
+    // The pages are sorted, so the exported ones are a prefix of the request
+    let missing = pages.get(exported).copied().unwrap_or(pages[0]);
+    Err(ConversionError::new(
+        StatusCode::BAD_REQUEST,
+        "PAGE_OUT_OF_RANGE",
+        format!("Page {} is beyond the end of the document", missing),
+    )
+    .with_detail("page", missing))
+}
+
+/// Response headers that confirm which options were applied.
+fn output_headers(opts: &ConvertOptions) -> Vec<(&'static str, String)> {
+    let mut headers = Vec::new();
+    if let Some((level, _)) = opts.pdfa {
+        headers.push(("Content-X-Pdfa-Level", level.to_string()));
+    }
+    if !opts.properties.is_empty() {
+        headers.push((PROPERTIES_HEADER, "true".to_string()));
+    }
+    headers
+}
+
+/// Returns `name`, or `name (2)`, `name (3)`... if it was already taken.
+fn unique_name(taken: &mut HashSet<String>, name: &str) -> String {
+    let path = Path::new(name);
+    let stem = path.file_stem().map(|s| s.to_string_lossy().to_string()).unwrap_or_default();
+    let ext = path.extension().map(|e| format!(".{}", e.to_string_lossy())).unwrap_or_default();
+
+    let mut candidate = name.to_string();
+    let mut n = 2;
+    while !taken.insert(candidate.clone()) {
+        candidate = format!("{} ({}){}", stem, n, ext);
+        n += 1;
+    }
+    candidate
+}
+
+/// `run_conversion`, recorded in the audit log.
+async fn run_audited_conversion(
+    state: &AppState,
+    requester: Requester,
+    work_dir: &Path,
+    file: &UploadedFile,
+    opts: &ConvertOptions,
+) -> Result<ConvertedFile, ConversionError> {
+    let start_time = Instant::now();
+    let result = run_conversion(state, work_dir, file, opts).await;
+    audit::record(state, requester, file, opts.format.extension, start_time.elapsed(), result).await
+}
+
+/// Converts a single uploaded file, recording metrics for it. Results are served from and
+/// stored in the cache when there is one, with their cache key as `ETag`; a conversion identical
+/// to one under way waits for its result. With `CACHE_CONTROL_MAX_AGE` set, results also carry
+/// `Cache-Control` (see `cache_control`).
+async fn run_conversion(
+    state: &AppState,
+    work_dir: &Path,
+    file: &UploadedFile,
+    opts: &ConvertOptions,
+) -> Result<ConvertedFile, ConversionError> {
+    state.metrics.upload_bytes.observe(file.size as f64);
+
+    let cached = match state.cache {
+        Some(ref cache) if ResultCache::is_cacheable(opts) => {
+            let digest = match file.digest {
+                Some(digest) => Ok(digest),
+                None => ResultCache::digest(&file.path).await,
+            };
+            match digest {
+                Ok(digest) => Some((cache, ResultCache::key(&digest, opts))),
+                Err(e) => {
+                    warn!("Failed to hash upload for the result cache: {}", e);
+                    None
+                }
+            }
+        }
+        _ => None,
+    };
+    let mut lead = None;
+    if let Some((cache, key)) = cached {
+        let stem = file.path.file_stem().unwrap_or_default().to_string_lossy();
+        let output_name = format!("{}.{}", stem, opts.format.extension);
+        if let Some(hit) = cache.get(&key, output_name.clone()) {
+            info!("Serving {:?} from the result cache", file.path);
+            state.metrics.cache_hits.inc();
+            if file.digest.is_some() {
+                state.metrics.cache_skip_disk.inc();
+            }
+            return Ok(cache_control(state, hit, true));
+        }
+        state.metrics.cache_misses.inc();
+        match cache.flight(key) {
+            Flight::Lead(l) => lead = Some(l),
+            Flight::Follow(outcome) => {
+                info!("Waiting for an identical conversion of {:?}", file.path);
+                match ResultCache::wait(outcome).await {
+                    Some(Ok(())) => {
+                        // Unless it was evicted in the meantime
+                        if let Some(hit) = cache.get(&key, output_name) {
+                            state.metrics.conversions_deduplicated.inc();
+                            return Ok(cache_control(state, hit, true));
+                        }
+                    }
+                    Some(Err(e)) => {
+                        state.metrics.conversions_deduplicated.inc();
+                        return Err(e);
+                    }
+                    // The request converting it went away; convert it here instead
+                    None => {}
+                }
+            }
+        }
+    }
+
+    let _active = state.metrics.active_conversions.track();
+    let start_time = std::time::Instant::now();
+
+    let mut result = convert_file(state, work_dir, file, opts).await;
+
+    match result {
+        Ok(ref mut converted) => {
+            state.metrics.conversions.inc("success");
+            state.metrics.conversion_duration.observe(disk::storage_kind(work_dir), start_time.elapsed().as_secs_f64());
+            if let Some((cache, key)) = cached {
+                // Kept with the output, so hits carry it too
+                converted.headers.push(("etag", ResultCache::etag(&key)));
+                cache.insert(key, converted);
+            }
+        }
+        Err(_) => state.metrics.conversions.inc("failure"),
+    }
+    if let Some(lead) = lead {
+        lead.finish(result.as_ref().map(|_| ()).map_err(ConversionError::clone));
+    }
+    result.map(|converted| cache_control(state, converted, false))
+}
+
+/// `converted` with the caching headers of `CACHE_CONTROL_MAX_AGE`, if set: a result served
+/// from the cache may be kept by CDNs and browsers for that long, while one converted afresh
+/// (which may not be cacheable at all) is not kept. Either varies with `Accept-Encoding`, as
+/// `/convert` responses may be compressed.
+fn cache_control(state: &AppState, mut converted: ConvertedFile, from_cache: bool) -> ConvertedFile {
+    if state.cache_control_max_age == 0 {
+        return converted;
+    }
+    let value = if from_cache { format!("public, max-age={}", state.cache_control_max_age) } else { "no-store".to_string() };
+    converted.headers.push(("cache-control", value));
+    converted.headers.push(("vary", "accept-encoding".to_string()));
+    converted
+}
+
+/// Converts `file` next to itself with `state.converter`, sharing the LibreOffice profile in
+/// `work_dir`, and reads the result back. The caller owns cleanup of `work_dir`.
+async fn convert_file(
+    state: &AppState,
+    work_dir: &Path,
+    file: &UploadedFile,
+    opts: &ConvertOptions,
+) -> Result<ConvertedFile, ConversionError> {
+    // Templates go through their base format first, unless that is what was asked for anyway
+    if let Some(base) = templates::base_format(&file.path)
+        && base != opts.format
+    {
+        let document = templates::instantiate(state, work_dir, file, opts, base).await?;
+        let mut converted = Box::pin(convert_file(state, work_dir, &document, opts)).await?;
+        converted.headers.push((INTERMEDIATE_FORMAT_HEADER, base.extension.to_string()));
+        return Ok(converted);
+    }
+    let file_path = file.path.as_path();
+    let lo_span = info_span!(
+        "libreoffice",
+        file.extension = file_path.extension().unwrap_or_default().to_string_lossy().as_ref(),
+        file.size_bytes = file.size,
+        process.exit_code = tracing::field::Empty,
+        stderr.length = tracing::field::Empty,
+    );
+    // Both the conversion and reading its output must finish within the timeout
+    let deadline = Instant::now() + state.conversion_timeout;
+    // Dropped unrecorded if this request goes away mid-conversion
+    let permit = state.circuit_breaker.check()?;
+    let result = state.converter.convert(file_path, work_dir, opts).instrument(lo_span).await;
+    permit.record(&result);
+    let out_dir = result?;
+
+    // Find the output files
+    // LibreOffice creates files with the same base name and the target extension
+    let mut outputs = Vec::new();
+    if let Ok(mut entries) = fs::read_dir(&out_dir).await {
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            let path = entry.path();
+            if path.extension().is_some_and(|e| e == opts.format.extension) {
+                outputs.push(path);
+            }
+        }
+    }
+    outputs.sort();
+
+    if outputs.is_empty() {
+        error!("No {} file found in output directory", opts.format.extension);
+        return Err(ConversionError::new(StatusCode::INTERNAL_SERVER_ERROR, "PDF_NOT_FOUND", "Conversion failed - output not found"));
+    }
+    check_output_size(&outputs, state.max_output_bytes).await?;
+
+    match timeout_at(deadline, read_outputs(&outputs, opts.format, file_path))
+        .instrument(info_span!("read_output"))
+        .await
+    {
+        Ok(Ok(mut converted)) => {
+            if let Some(ref pages) = opts.pages {
+                check_page_count(pages, &converted.content)?;
+            }
+            converted.headers = output_headers(opts);
+            if let Some(size) = opts.normalize_page_size {
+                let normalized = pagesize::normalize_output(&mut converted, size).await?;
+                converted.headers.push((PAGES_NORMALIZED_HEADER, normalized.to_string()));
+            }
+            if opts.format == formats::PDF {
+                converted.headers.push((LINEARIZED_HEADER, pdf::is_linearized(&converted.content).to_string()));
+            }
+            converted.headers.push(("X-Conversion-Backend", state.converter.backend_label(file_path, opts)));
+            Ok(converted)
+        }
+        Ok(Err(e)) => {
+            error!("Failed to read generated output: {}", e);
+            Err(ConversionError::new(StatusCode::INTERNAL_SERVER_ERROR, "PDF_READ_FAILED", "Read PDF failed"))
+        }
+        Err(_) => {
+            error!("Reading generated output timed out");
+            Err(conversion_timeout_error(state.conversion_timeout))
+        }
+    }
+}
+
+/// Rejects outputs adding up to more than `limit` bytes, going by their size on disk so that a
+/// huge one is never loaded.
+async fn check_output_size(outputs: &[PathBuf], limit: u64) -> Result<(), ConversionError> {
+    let mut size = 0;
+    for path in outputs {
+        size += fs::metadata(path).await.map_err(|e| {
+            error!("Failed to stat generated output: {}", e);
+            ConversionError::new(StatusCode::INTERNAL_SERVER_ERROR, "PDF_READ_FAILED", "Read PDF failed")
+        })?
+        .len();
+    }
+    if size > limit {
+        error!("Conversion output of {} bytes exceeds MAX_OUTPUT_BYTES ({})", size, limit);
+        return Err(ConversionError::new(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "OUTPUT_TOO_LARGE",
+            format!("The converted output is {} bytes, more than the limit of {}", size, limit),
+        )
+        .with_detail("size", size)
+        .with_detail("limit", limit));
+    }
+    Ok(())
+}
+
+/// Loads the converted file, or zips all of them up for paged formats.
+async fn read_outputs(outputs: &[PathBuf], format: OutputFormat, input: &Path) -> std::io::Result<ConvertedFile> {
+    let file_name = |path: &Path| path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+
+    if !format.is_paged() {
+        return Ok(ConvertedFile {
+            filename: file_name(&outputs[0]),
+            content_type: format.content_type,
+            content: fs::read(&outputs[0]).await?,
+            headers: Vec::new(),
+        });
+    }
+
+    let mut archive = ZipWriter::new();
+    for path in outputs {
+        archive.add(&file_name(path), &fs::read(path).await?);
+    }
+    let stem = input.file_stem().map(|s| s.to_string_lossy().to_string()).unwrap_or_else(|| "document".to_string());
+    Ok(ConvertedFile {
+        filename: format!("{}.zip", stem),
+        content_type: "application/zip",
+        content: archive.finish(),
+        headers: Vec::new(),
+    })
+}
+
+fn file_response(converted: &ConvertedFile, disposition: Disposition) -> Response {
+    file_body_response(
+        &converted.filename,
+        converted.content_type,
+        disposition,
+        &converted.headers,
+        Body::from(converted.content.clone()),
+    )
+}
+
+/// A file response with a `Content-Disposition` naming it, and any extra headers.
+pub fn file_body_response(
+    filename: &str,
+    content_type: &str,
+    disposition: Disposition,
+    extra: &[(&'static str, String)],
+    body: Body,
+) -> Response {
+    let headers = [
+        (header::CONTENT_TYPE, content_type),
+        (header::CONTENT_DISPOSITION, &content_disposition(disposition, filename)),
+    ];
+
+    let mut response = (headers, body).into_response();
+    for (name, value) in extra {
+        if let Ok(value) = header::HeaderValue::from_str(value) {
+            response.headers_mut().insert(*name, value);
+        }
+    }
+    response
+}
+
+/// `inline` or `attachment` with the filename, plus its UTF-8 form (RFC 6266) when it is not
+/// plain ASCII, since header values cannot carry it directly.
+fn content_disposition(disposition: Disposition, filename: &str) -> String {
+    // Escape double quotes in filename to prevent header injection
+    let ascii: String = filename
+        .chars()
+        .map(|c| if c.is_ascii() && !c.is_ascii_control() { c } else { '_' })
+        .collect::<String>()
+        .replace('"', "\\\"");
+    let mut value = format!("{}; filename=\"{}\"", disposition.as_str(), ascii);
+    if !filename.is_ascii() {
+        let encoded: String = filename
+            .bytes()
+            .map(|b| match b {
+                b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'.' | b'-' | b'_' | b'~' => (b as char).to_string(),
+                _ => format!("%{:02X}", b),
+            })
+            .collect();
+        value.push_str(&format!("; filename*=UTF-8''{}", encoded));
+    }
+    value
+}
+
+fn conversion_timeout_error(limit: Duration) -> ConversionError {
+    ConversionError::new(
+        StatusCode::GATEWAY_TIMEOUT,
+        "CONVERSION_TIMEOUT",
+        format!("Conversion did not finish within {}s", limit.as_secs()),
+    )
+}
+
+/// Runs `command` to completion, capturing stdout and stderr.
+///
+/// Returns `Ok(None)` if the process was still running at `deadline`; in that case it is killed.
+async fn run_until(mut command: Command, deadline: Instant) -> std::io::Result<Option<Output>> {
+    let mut child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        // Own process group, so a timeout also takes down the soffice.bin grandchild
+        .process_group(0)
+        .spawn()?;
+    // Dropped with the task when a job is cancelled, which kills the whole group
+    let mut group = shutdown::PROCESS_GROUPS.track(child.id());
+
+    // Drain the pipes concurrently so a chatty process cannot block on a full pipe buffer
+    let mut stdout_pipe = child.stdout.take();
+    let mut stderr_pipe = child.stderr.take();
+    let stdout_task = tokio::spawn(async move {
+        let mut buf = Vec::new();
+        if let Some(pipe) = stdout_pipe.as_mut() {
+            let _ = pipe.read_to_end(&mut buf).await;
+        }
+        buf
+    });
+    let stderr_task = tokio::spawn(async move {
+        let mut buf = Vec::new();
+        if let Some(pipe) = stderr_pipe.as_mut() {
+            let _ = pipe.read_to_end(&mut buf).await;
+        }
+        buf
+    });
+
+    match timeout_at(deadline, child.wait()).await {
+        Ok(status) => {
+            let status = status?;
+            group.reaped();
+            Ok(Some(Output {
+                status,
+                stdout: stdout_task.await.unwrap_or_default(),
+                stderr: stderr_task.await.unwrap_or_default(),
+            }))
+        }
+        Err(_) => {
+            group.kill();
+            if child.kill().await.is_ok() {
+                group.reaped();
+            }
+            stdout_task.abort();
+            stderr_task.abort();
+            Ok(None)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use service::MockConversionService;
+
+    pub(crate) fn test_state() -> AppState {
+        AppState {
+            api_keys: None,
+            admin_api_key: None,
+            jwt: None,
+            conversion_timeout: Duration::from_secs(60),
+            jobs: JobStore::new(Duration::from_secs(60), Duration::from_secs(60)),
+            downloads: DownloadStore::new(PathBuf::from("/tmp/convert/downloads-test"), Duration::from_secs(60)).unwrap(),
+            upload_tokens: UploadTokens::new().unwrap(),
+            max_files_per_request: config::DEFAULT_MAX_FILES_PER_REQUEST,
+            allowed_input_types: detect::parse_allowed_types(None),
+            allowed_extensions: detect::parse_allowed_extensions(None),
+            macro_enabled_extensions: detect::MACRO_ENABLED_EXTENSIONS.iter().map(|e| e.to_string()).collect(),
+            allow_macros: false,
+            max_body_size: config::DEFAULT_MAX_BODY_SIZE as u64,
+            file_size_limits: HashMap::new(),
+            min_free_bytes: 0,
+            conversion_slots: Semaphore::new(config::DEFAULT_MAX_CONCURRENT_CONVERSIONS),
+            max_concurrent_conversions: config::DEFAULT_MAX_CONCURRENT_CONVERSIONS,
+            draining: AtomicBool::new(false),
+            allow_http_callbacks: false,
+            download_timeout: Duration::from_secs(config::DEFAULT_DOWNLOAD_TIMEOUT_SECS),
+            max_download_bytes: config::DEFAULT_MAX_BODY_SIZE as u64,
+            max_output_bytes: config::DEFAULT_MAX_OUTPUT_BYTES,
+            max_render_bytes: config::DEFAULT_MAX_RENDER_BYTES,
+            allow_http_downloads: false,
+            outbound_allowed_networks: AllowedNetworks::default(),
+            s3: None,
+            converter: Arc::new(LibreOfficeService {
+                libreoffice_path: config::DEFAULT_LIBREOFFICE_PATH.to_string(),
+                chromium_path: None,
+                pandoc_path: None,
+                qpdf_path: None,
+                msoffcrypto_path: None,
+                pool: None,
+                timeout: Duration::from_secs(60),
+                include_errors: false,
+                flags: LaunchFlags::default(),
+                retry: service::RetryPolicy::default(),
+                font_dir: None,
+            }),
+            libreoffice: LibreOfficeProbe::new(config::DEFAULT_LIBREOFFICE_PATH),
+            circuit_breaker: CircuitBreaker::new(
+                breaker::DEFAULT_FAILURE_THRESHOLD,
+                Duration::from_secs(breaker::DEFAULT_WINDOW_SECS),
+                Duration::from_secs(breaker::DEFAULT_RESET_SECS),
+            ),
+            import_formats: Vec::new(),
+            readiness: Readiness::default(),
+            metrics: Metrics::default(),
+            metrics_api_key: None,
+            work_dir: PathBuf::from("/tmp/convert"),
+            runtime_dir: None,
+            active_dirs: ActiveDirs::default(),
+            started_at: Instant::now(),
+            rate_limiter: None,
+            job_status_limiter: RateLimiter::new(10, 2.0, 0),
+            cache: None,
+            cache_control_max_age: 0,
+            trusted_proxy_count: 0,
+            audit_log: None,
+        }
+    }
+
+    /// Converts `content` (saved as `name`) with a real LibreOffice.
+    async fn convert_sample(name: &str, content: &[u8], opts: &ConvertOptions) -> Result<ConvertedFile, ConversionError> {
+        let work_dir = PathBuf::from(format!("/tmp/convert/test-{}", Uuid::new_v4()));
+        let path = work_dir.join("0").join(name);
+        fs::create_dir_all(path.parent().unwrap()).await.unwrap();
+        fs::write(&path, content).await.unwrap();
+        let kind = detect::detect_file(&path, "").await.unwrap();
+        let file = UploadedFile { path, size: content.len() as u64, kind, digest: None };
+        let result = convert_file(&test_state(), &work_dir, &file, opts).await;
+        let _ = fs::remove_dir_all(&work_dir).await;
+        result
+    }
+
+    #[test]
+    fn test_sanitize_filename() {
+        assert_eq!(sanitize_filename("test.docx"), "test.docx");
+        assert_eq!(sanitize_filename("/tmp/test.docx"), "test.docx");
+        // Backslashes are not separators on Linux; they are neutralised instead
+        assert_eq!(sanitize_filename("C:\\Users\\test\\file.docx"), "C:_Users_test_file.docx");
+        assert_eq!(sanitize_filename("..\\..\\etc\\passwd"), ".._.._etc_passwd");
+        assert_eq!(sanitize_filename("dir/sub\\file.docx"), "sub_file.docx");
+        assert_eq!(sanitize_filename(".."), "document");
+        // Edge cases
+        assert_eq!(sanitize_filename(""), "document");
+        assert_eq!(sanitized_filename("../"), None);
+        assert_eq!(sanitized_filename(" \r\n "), None);
+        assert_eq!(sanitized_filename("a\r\nSet-Cookie: x.pdf").as_deref(), Some("aSet-Cookie: x.pdf"));
+        // Long names are cut before the extension
+        let long = sanitize_filename(&format!("{}.docx", "a".repeat(1000)));
+        assert!(long.len() <= MAX_FILENAME_LEN);
+        assert!(long.ends_with("a..docx"), "{}", long);
+        let long = sanitize_filename(&format!("{}.docx", "é".repeat(150)));
+        assert!(long.len() <= MAX_FILENAME_LEN && long.ends_with("é..docx"), "{}", long);
+        assert_eq!(sanitize_filename(&"b".repeat(300)).len(), MAX_FILENAME_LEN);
+    }
+
+    #[test]
+    fn test_output_filename() {
+        assert_eq!(output_filename("INV-2024-0042", "pdf").unwrap(), "INV-2024-0042.pdf");
+        assert_eq!(output_filename("../Invoice\0 42.PDF", "pdf").unwrap(), "Invoice 42.PDF");
+        assert_eq!(output_filename("report.", "pdf").unwrap(), "report.pdf");
+        assert_eq!(output_filename("pages", "zip").unwrap(), "pages.zip");
+        for bad in ["invoice.docx", "../", " "] {
+            let err = output_filename(bad, "pdf").unwrap_err();
+            assert_eq!(err.code, "INVALID_OUTPUT_FILENAME", "{:?}", bad);
+        }
+        assert_eq!(output_filename(&format!("{}.pdf", "x".repeat(252)), "pdf").unwrap().len(), MAX_FILENAME_LEN);
+    }
+
+    #[test]
+    fn test_upload_filename() {
+        assert_eq!(upload_filename(Some("a.docx"), Some("b.xlsx")), "a.docx");
+        assert_eq!(upload_filename(None, Some("../b.xlsx")), "b.xlsx");
+        assert_eq!(upload_filename(Some("document"), Some("b.xlsx")), "b.xlsx");
+        assert_eq!(upload_filename(Some(".."), Some("b.xlsx")), "b.xlsx");
+        assert_eq!(upload_filename(None, Some("  ")), "document");
+        assert_eq!(upload_filename(None, None), "document");
+        let long = format!("{}.docx", "a".repeat(MAX_FILENAME_LEN));
+        assert!(upload_filename(None, Some(&long)).ends_with("a..docx"));
+        assert!(upload_filename(Some(&long), None).ends_with("a..docx"));
+    }
+
+    #[test]
+    fn test_content_disposition() {
+        assert_eq!(Disposition::from_param(None).unwrap(), Disposition::Attachment);
+        assert_eq!(Disposition::from_param(Some("inline")).unwrap(), Disposition::Inline);
+        assert_eq!(Disposition::from_param(Some("open")).unwrap_err().code, "INVALID_DISPOSITION");
+
+        assert_eq!(content_disposition(Disposition::Inline, "a \"b\".pdf"), "inline; filename=\"a \\\"b\\\".pdf\"");
+        assert_eq!(
+            content_disposition(Disposition::Attachment, "Bericht ü.pdf"),
+            "attachment; filename=\"Bericht _.pdf\"; filename*=UTF-8''Bericht%20%C3%BC.pdf"
+        );
+    }
+
+    #[test]
+    fn test_filename_from_url() {
+        assert_eq!(filename_from_url("https://example.com/files/Q3%20report.docx?sig=abc#top"), "Q3 report.docx");
+        assert_eq!(filename_from_url("https://example.com/a/..%2F..%2Fetc%2Fpasswd"), "passwd");
+        assert_eq!(filename_from_url("https://example.com/dir/"), "document");
+        assert_eq!(filename_from_url("https://example.com"), "document");
+        assert_eq!(filename_from_url("https://example.com/100%"), "100%");
+    }
+
+    #[test]
+    fn test_check_extension() {
+        let allowed = detect::parse_allowed_extensions(Some(".DOCX, xlsx,pptx"));
+        assert_eq!(allowed, ["docx", "xlsx", "pptx"]);
+        assert!(check_extension("Report.DOCX", &allowed).is_ok());
+        assert!(check_extension("document", &allowed).is_ok());
+        let e = check_extension("legacy.doc", &allowed).unwrap_err();
+        assert_eq!((e.status, e.code), (StatusCode::UNSUPPORTED_MEDIA_TYPE, "UNSUPPORTED_EXTENSION"));
+        assert_eq!(e.details, [("extension", json!("doc")), ("allowed_extensions", json!(["docx", "xlsx", "pptx"]))]);
+        assert!(check_extension("a.doc", &detect::parse_allowed_extensions(None)).is_ok());
+    }
+
+    #[test]
+    fn test_unique_name() {
+        let mut taken = HashSet::new();
+        assert_eq!(unique_name(&mut taken, "report.pdf"), "report.pdf");
+        assert_eq!(unique_name(&mut taken, "report.pdf"), "report (2).pdf");
+        assert_eq!(unique_name(&mut taken, "report.pdf"), "report (3).pdf");
+        assert_eq!(unique_name(&mut taken, "other.pdf"), "other.pdf");
+    }
+
+    #[test]
+    fn test_convert_options_password() {
+        let fields = HashMap::from([("password".to_string(), "s3cret".to_string())]);
+        let opts = ConvertOptions::from_request(&HashMap::new(), &fields).unwrap();
+        assert!(!format!("{:?}", opts).contains("s3cret"));
+        assert_eq!(opts.pdf_filter_data()["DocumentOpenPassword"]["value"], "s3cret");
+
+        let fields = HashMap::from([("password".to_string(), "x".repeat(33))]);
+        assert_eq!(ConvertOptions::from_request(&HashMap::new(), &fields).unwrap_err().code, "PASSWORD_TOO_LONG");
+
+        // Passwords in the query string are ignored, as they would end up in access logs
+        let params = HashMap::from([("password".to_string(), "s3cret".to_string())]);
+        assert!(ConvertOptions::from_request(&params, &HashMap::new()).unwrap().password.is_none());
+    }
+
+    #[test]
+    fn test_convert_options_export() {
+        let fields = HashMap::from([("options".to_string(), r#"{"page_range": "2", "pdfa_level": "2b"}"#.to_string())]);
+        let opts = ConvertOptions::from_request(&HashMap::new(), &fields).unwrap();
+        assert_eq!(opts.pdfa, Some(("2b", 2)));
+        assert_eq!(opts.pdf_filter_data()["PageRange"]["value"], "2");
+
+        let params = HashMap::from([("pdfa".to_string(), "1b".to_string())]);
+        assert_eq!(ConvertOptions::from_request(&params, &fields).unwrap_err().code, "PDF_OPTION_CONFLICT");
+        let params = HashMap::from([("format".to_string(), "docx".to_string())]);
+        assert_eq!(ConvertOptions::from_request(&params, &fields).unwrap_err().code, "PDF_OPTION_REQUIRES_PDF");
+
+        let params = HashMap::from([("pages".to_string(), "3,1-2".to_string())]);
+        assert_eq!(ConvertOptions::from_request(&params, &fields).unwrap_err().code, "PDF_OPTION_CONFLICT");
+        let opts = ConvertOptions::from_request(&params, &HashMap::new()).unwrap();
+        assert_eq!(opts.pdf_filter_data()["PageRange"]["value"], "1-3");
+        assert_eq!(check_page_count(&[1, 2, 3], b"/Type/Page /Type/Page").unwrap_err().code, "PAGE_OUT_OF_RANGE");
+    }
+
+    #[test]
+    fn test_convert_options_paper_size() {
+        assert_eq!(ConvertOptions::from_request(&HashMap::new(), &HashMap::new()).unwrap().paper_size, None);
+        let params = HashMap::from([("paper_size".to_string(), "legal".to_string())]);
+        let opts = ConvertOptions::from_request(&params, &HashMap::new()).unwrap();
+        assert_eq!(opts.paper_size.map(|size| size.name), Some("Legal"));
+
+        let fields = HashMap::from([("paper_size".to_string(), "B4".to_string())]);
+        assert_eq!(ConvertOptions::from_request(&HashMap::new(), &fields).unwrap_err().code, "UNSUPPORTED_PAPER_SIZE");
+
+        let fields = HashMap::from([("normalize_page_size".to_string(), "a4".to_string())]);
+        let opts = ConvertOptions::from_request(&HashMap::new(), &fields).unwrap();
+        assert_eq!((opts.paper_size, opts.normalize_page_size.map(|size| size.name)), (None, Some("A4")));
+        let fields = HashMap::from([("normalize_page_size".to_string(), "B4".to_string())]);
+        assert_eq!(ConvertOptions::from_request(&HashMap::new(), &fields).unwrap_err().code, "UNSUPPORTED_PAPER_SIZE");
+        let fields = HashMap::from([("normalize_page_size".to_string(), "A4".to_string()), ("linearize".to_string(), "true".to_string())]);
+        assert_eq!(ConvertOptions::from_request(&HashMap::new(), &fields).unwrap_err().code, "PDF_OPTION_CONFLICT");
+    }
+
+    #[tokio::test]
+    #[ignore = "requires LibreOffice"]
+    async fn test_pdfa_output() {
+        let opts = ConvertOptions {
+            pdfa: formats::pdfa_level("2b"),
+            ..Default::default()
+        };
+        let converted = convert_sample("note.txt", b"Archived text", &opts).await.unwrap();
+        assert!(converted.content.starts_with(b"%PDF-1."));
+        let text = String::from_utf8_lossy(&converted.content);
+        assert!(text.contains("<pdfaid:part>2</pdfaid:part>"));
+        assert!(text.contains("<pdfaid:conformance>B</pdfaid:conformance>"));
+        assert_eq!(
+            converted.headers,
+            [
+                ("Content-X-Pdfa-Level", "2b".to_string()),
+                (LINEARIZED_HEADER, "false".to_string()),
+                ("X-Conversion-Backend", "libreoffice".to_string())
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_linearize_requires_qpdf() {
+        let fields = HashMap::from([("linearize".to_string(), "true".to_string())]);
+        let opts = ConvertOptions::from_request(&HashMap::new(), &fields).unwrap();
+        assert!(opts.linearize);
+        let result = convert_sample("note.txt", b"Text", &opts).await;
+        assert_eq!(result.err().map(|e| e.code), Some("LINEARIZE_UNAVAILABLE"));
+
+        let params = HashMap::from([("format".to_string(), "docx".to_string())]);
+        assert_eq!(ConvertOptions::from_request(&params, &fields).unwrap_err().code, "PDF_OPTION_REQUIRES_PDF");
+    }
+
+    #[test]
+    fn test_document_properties_options() {
+        let mut fields = HashMap::from([("doc_title".to_string(), "Q3".to_string())]);
+        let opts = ConvertOptions::from_request(&HashMap::new(), &fields).unwrap();
+        assert!(!opts.properties.is_empty());
+        assert_eq!(output_headers(&opts), [(PROPERTIES_HEADER, "true".to_string())]);
+
+        let params = HashMap::from([("format".to_string(), "docx".to_string())]);
+        assert_eq!(ConvertOptions::from_request(&params, &fields).unwrap_err().code, "PDF_OPTION_REQUIRES_PDF");
+        fields.insert("password".to_string(), "secret".to_string());
+        assert_eq!(ConvertOptions::from_request(&HashMap::new(), &fields).unwrap_err().code, "PDF_OPTION_CONFLICT");
+    }
+
+    #[tokio::test]
+    #[ignore = "requires LibreOffice and qpdf"]
+    async fn test_linearized_output() {
+        let work_dir = PathBuf::from(format!("/tmp/convert/test-{}", Uuid::new_v4()));
+        let path = work_dir.join("0").join("note.txt");
+        fs::create_dir_all(path.parent().unwrap()).await.unwrap();
+        fs::write(&path, b"Fast web view").await.unwrap();
+        let file = UploadedFile { path, size: 13, kind: "txt", digest: None };
+        let state = AppState {
+            converter: Arc::new(LibreOfficeService {
+                libreoffice_path: config::DEFAULT_LIBREOFFICE_PATH.to_string(),
+                chromium_path: None,
+                pandoc_path: None,
+                qpdf_path: Some("qpdf".to_string()),
+                msoffcrypto_path: None,
+                pool: None,
+                timeout: Duration::from_secs(60),
+                include_errors: false,
+                flags: LaunchFlags::default(),
+                retry: service::RetryPolicy::default(),
+                font_dir: None,
+            }),
+            ..test_state()
+        };
+        let opts = ConvertOptions { linearize: true, ..Default::default() };
+        let converted = convert_file(&state, &work_dir, &file, &opts).await;
+        let _ = fs::remove_dir_all(&work_dir).await;
+        let converted = converted.unwrap();
+        let head = String::from_utf8_lossy(&converted.content[..1024]);
+        assert!(head.contains("/Linearized 1"));
+        assert!(converted.headers.contains(&(LINEARIZED_HEADER, "true".to_string())));
+    }
+
+    #[tokio::test]
+    async fn test_cors_preflight() {
+        use tower::ServiceExt;
+
+        let cors = cors_layer("https://app.example.com/, https://other.example.com").unwrap();
+        let app = Router::new().route("/convert", post(|| async {})).layer(cors);
+        let preflight = |origin: &str| {
+            Request::builder()
+                .method(Method::OPTIONS)
+                .uri("/convert")
+                .header(header::ORIGIN, origin)
+                .header(header::ACCESS_CONTROL_REQUEST_METHOD, "POST")
+                .header(header::ACCESS_CONTROL_REQUEST_HEADERS, "x-api-key")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let response = app.clone().oneshot(preflight("https://app.example.com")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN], "https://app.example.com");
+        assert!(response.headers()[header::ACCESS_CONTROL_ALLOW_HEADERS].to_str().unwrap().contains("x-api-key"));
+
+        let response = app.oneshot(preflight("https://evil.example.com")).await.unwrap();
+        assert!(response.headers().get(header::ACCESS_CONTROL_ALLOW_ORIGIN).is_none());
+
+        assert!(cors_layer(" , ").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_convert_reports_time_and_sizes_on_error() {
+        use tower::ServiceExt;
+
+        let app = Router::new()
+            .route("/convert", post(convert))
+            .layer(Extension(RequestId(Uuid::new_v4())))
+            .with_state(Arc::new(test_state()));
+        let body = "--b\r\nContent-Disposition: form-data; name=\"file\"; filename=\"a.md\"\r\n\r\nhello\r\n\
+                    --b\r\nContent-Disposition: form-data; name=\"format\"\r\n\r\nbogus\r\n--b--\r\n";
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri("/convert")
+            .header(header::CONTENT_TYPE, "multipart/form-data; boundary=b")
+            .body(Body::from(body))
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert!(response.headers()[CONVERSION_TIME_HEADER].to_str().unwrap().parse::<u64>().is_ok());
+        assert_eq!(response.headers()[FILE_SIZE_HEADER], "5");
+        assert!(response.headers().get(PDF_SIZE_HEADER).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_convert_with_mock_service() {
+        use tower::ServiceExt;
+
+        let state = AppState {
+            converter: Arc::new(MockConversionService),
+            ..test_state()
+        };
+        let app = Router::new()
+            .route("/convert", post(convert))
+            .layer(Extension(RequestId(Uuid::new_v4())))
+            .with_state(Arc::new(state));
+        let body = "--b\r\nContent-Disposition: form-data; name=\"file\"; filename=\"notes.md\"\r\n\r\n# Hi\r\n--b--\r\n";
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri("/convert")
+            .header(header::CONTENT_TYPE, "multipart/form-data; boundary=b")
+            .body(Body::from(body))
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["x-conversion-backend"], "mock");
+        assert_eq!(response.headers()[PDF_SIZE_HEADER], "4");
+        assert!(response.headers()[header::CONTENT_DISPOSITION].to_str().unwrap().contains("notes.pdf"));
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(&body[..], b"# Hi");
+    }
+
+    #[tokio::test]
+    async fn test_convert_dry_run() {
+        use tower::ServiceExt;
+
+        let state = AppState {
+            converter: Arc::new(MockConversionService),
+            ..test_state()
+        };
+        let app = Router::new()
+            .route("/convert", post(convert).put(convert_body))
+            .layer(Extension(RequestId(Uuid::new_v4())))
+            .with_state(Arc::new(state));
+        let request = |uri: &str| {
+            Request::builder().method(Method::PUT).uri(uri).body(Body::from(Bytes::from_static(b"# Hi"))).unwrap()
+        };
+        let response = app.clone().oneshot(request("/convert?filename=notes.md&dry_run=true")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "application/json");
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body, json!({"valid": true, "detected_type": "text/markdown", "estimated_pages": null}));
+
+        let response = app.oneshot(request("/convert?filename=notes.md&dry_run=true&async=true")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_convert_raw_body() {
+        use tower::ServiceExt;
+
+        let state = AppState {
+            converter: Arc::new(MockConversionService),
+            ..test_state()
+        };
+        let app = Router::new()
+            .route("/convert", post(convert).put(convert_body))
+            .layer(Extension(RequestId(Uuid::new_v4())))
+            .with_state(Arc::new(state));
+        let request = Request::builder()
+            .method(Method::PUT)
+            .uri("/convert?filename=../notes.md&disposition=inline")
+            .header(header::CONTENT_TYPE, "application/octet-stream")
+            .body(Body::from(Bytes::from_static(b"# Hi")))
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[FILE_SIZE_HEADER], "4");
+        assert_eq!(response.headers()[header::CONTENT_DISPOSITION], "inline; filename=\"notes.pdf\"");
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(&body[..], b"# Hi");
+
+        let request = Request::builder().method(Method::PUT).uri("/convert").body(Body::from("# Hi")).unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let request = Request::builder().method(Method::PUT).uri("/convert?filename=run.exe").body(Body::from("MZ")).unwrap();
+        assert_eq!(app.oneshot(request).await.unwrap().status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+
+    #[tokio::test]
+    async fn test_convert_head() {
+        use tower::ServiceExt;
+
+        let state = Arc::new(AppState {
+            api_keys: Some(ApiKeys::parse("client-key").unwrap()),
+            allowed_extensions: vec!["docx".to_string(), "xlsx".to_string()],
+            max_body_size: 1024,
+            ..test_state()
+        });
+        // As in `main`: the POST behind authentication, the HEAD outside it
+        let app = Router::new()
+            .route("/convert", post(convert))
+            .layer(middleware::from_fn_with_state(state.clone(), auth_middleware))
+            .route("/convert", head(convert_head))
+            .with_state(state);
+        let request = |method: Method| Request::builder().method(method).uri("/convert").body(Body::empty()).unwrap();
+
+        let response = app.clone().oneshot(request(Method::HEAD)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let headers = response.headers();
+        assert_eq!(headers[MAX_BODY_BYTES_HEADER], "1024");
+        assert_eq!(headers[ALLOWED_EXTENSIONS_HEADER], "docx,xlsx");
+        assert_eq!(headers[MAX_FILES_HEADER], config::DEFAULT_MAX_FILES_PER_REQUEST.to_string());
+        assert_eq!(headers[AUTH_REQUIRED_HEADER], "true");
+        assert_eq!(headers[ASYNC_SUPPORTED_HEADER], "true");
+        assert!(axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap().is_empty());
+
+        assert_eq!(app.oneshot(request(Method::POST)).await.unwrap().status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_ui_convert_refuses_server_side_options() {
+        use tower::ServiceExt;
+
+        let state = Arc::new(AppState {
+            api_keys: Some(ApiKeys::parse("client-key").unwrap()),
+            converter: Arc::new(MockConversionService),
+            ..test_state()
+        });
+        // As in `main`: the upload page's form outside authentication
+        let app = Router::new()
+            .route("/convert", post(convert))
+            .layer(middleware::from_fn_with_state(state.clone(), auth_middleware))
+            .route("/ui/convert", post(ui_convert))
+            .layer(Extension(RequestId(Uuid::new_v4())))
+            .with_state(state);
+        let send = |uri: &str, field: &str, value: &str| {
+            let body = format!("--b\r\nContent-Disposition: form-data; name=\"{}\"\r\n\r\n{}\r\n--b--\r\n", field, value);
+            let request = Request::builder()
+                .method(Method::POST)
+                .uri(uri)
+                .header(header::CONTENT_TYPE, "multipart/form-data; boundary=b")
+                .body(Body::from(body))
+                .unwrap();
+            app.clone().oneshot(request)
+        };
+        let refused = |response: Response| async move {
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+            let body: serde_json::Value = serde_json::from_slice(&axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap()).unwrap();
+            assert_eq!(body["code"], "FIELD_NOT_ALLOWED");
+            body["field"].as_str().unwrap().to_string()
+        };
+
+        assert_eq!(send("/convert", "url", "http://169.254.169.254/").await.unwrap().status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(refused(send("/ui/convert", "url", "http://169.254.169.254/").await.unwrap()).await, "url");
+        assert_eq!(refused(send("/ui/convert", "callback_url", "https://example.com/").await.unwrap()).await, "callback_url");
+        assert_eq!(refused(send("/ui/convert?async=true", "format", "pdf").await.unwrap()).await, "async");
+        assert_eq!(refused(send("/ui/convert?response=url", "format", "pdf").await.unwrap()).await, "response");
+        // Rendering options alone are fine, and only lack a file
+        let response = send("/ui/convert", "format", "pdf").await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert!(String::from_utf8_lossy(&body).contains("NO_FILE"));
+    }
+
+    #[tokio::test]
+    async fn test_macro_enabled_upload() {
+        use tower::ServiceExt;
+
+        let mut docm = zip::ZipWriter::new();
+        docm.add("[Content_Types].xml", b"<Types/>");
+        docm.add("word/document.xml", b"<w:document/>");
+        docm.add("word/vbaProject.bin", b"\xd0\xcf\x11\xe0");
+        let docm = docm.finish();
+        let send = |state: AppState, filename: &'static str| {
+            let app = Router::new()
+                .route("/convert", post(convert))
+                .layer(Extension(RequestId(Uuid::new_v4())))
+                .with_state(Arc::new(state));
+            let mut body = format!("--b\r\nContent-Disposition: form-data; name=\"file\"; filename=\"{}\"\r\n\r\n", filename).into_bytes();
+            body.extend_from_slice(&docm);
+            body.extend_from_slice(b"\r\n--b--\r\n");
+            let request = Request::builder()
+                .method(Method::POST)
+                .uri("/convert")
+                .header(header::CONTENT_TYPE, "multipart/form-data; boundary=b")
+                .body(Body::from(body))
+                .unwrap();
+            app.oneshot(request)
+        };
+        let mock = || AppState {
+            converter: Arc::new(MockConversionService),
+            ..test_state()
+        };
+
+        let response = send(mock(), "invoice.docm").await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        let body: Value = serde_json::from_slice(&axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap()).unwrap();
+        assert_eq!(body["code"], "MACRO_ENABLED_FORMAT_REJECTED");
+        assert_eq!(body["extension"], "docm");
+
+        // The extension decides: the same package uploaded as a .docx converts
+        assert_eq!(send(mock(), "invoice.docx").await.unwrap().status(), StatusCode::OK);
+        let allowed = AppState { allow_macros: true, ..mock() };
+        assert_eq!(send(allowed, "invoice.docm").await.unwrap().status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn test_request_dir() {
+        let runtime_dir = std::env::temp_dir().join(format!("runtime-{}/office2pdf", Uuid::new_v4()));
+        let id = Uuid::new_v4();
+        let state = AppState {
+            runtime_dir: Some(runtime_dir.clone()),
+            min_free_bytes: 0,
+            ..test_state()
+        };
+        assert_eq!(state.request_dir(id), runtime_dir.join(id.to_string()));
+        assert_eq!(state.work_roots(), [Path::new("/tmp/convert"), &runtime_dir]);
+        let _ = std::fs::remove_dir_all(runtime_dir.parent().unwrap());
+
+        // Too little space, or not a directory that can be created: back to WORK_DIR
+        let state = AppState { min_free_bytes: u64::MAX, ..state };
+        assert_eq!(state.request_dir(id), PathBuf::from("/tmp/convert").join(id.to_string()));
+        let state = AppState {
+            runtime_dir: Some(PathBuf::from("/proc/self/status/office2pdf")),
+            min_free_bytes: 0,
+            ..state
+        };
+        assert_eq!(state.request_dir(id), PathBuf::from("/tmp/convert").join(id.to_string()));
+        assert_eq!(state.work_roots(), [Path::new("/tmp/convert")]);
+    }
+
+    #[tokio::test]
+    async fn test_upload_token() {
+        use tower::ServiceExt;
+
+        let state = Arc::new(AppState {
+            api_keys: Some(ApiKeys::parse("client-key").unwrap()),
+            admin_api_key: Some("admin-secret".to_string()),
+            converter: Arc::new(MockConversionService),
+            ..test_state()
+        });
+        let app = Router::new()
+            .route("/convert", post(convert))
+            .layer(middleware::from_fn_with_state(state.clone(), auth_middleware))
+            .route("/upload-token", post(uploadtokens::create))
+            .layer(Extension(RequestId(Uuid::new_v4())))
+            .with_state(state);
+        let issue = |key: &str| {
+            Request::builder()
+                .method(Method::POST)
+                .uri("/upload-token")
+                .header(apikeys::HEADER, key)
+                .body(Body::from(r#"{"ttl_secs": 300, "allowed_filename": "*.md", "subject": "client@example.com"}"#))
+                .unwrap()
+        };
+        assert_eq!(app.clone().oneshot(issue("client-key")).await.unwrap().status(), StatusCode::UNAUTHORIZED);
+        let response = app.clone().oneshot(issue("admin-secret")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: Value = serde_json::from_slice(&axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap()).unwrap();
+        let upload_url = body["upload_url"].as_str().unwrap().to_string();
+
+        let upload = |uri: &str, filename: &str| {
+            let form = format!("--b\r\nContent-Disposition: form-data; name=\"file\"; filename=\"{}\"\r\n\r\n# Hi\r\n--b--\r\n", filename);
+            Request::builder()
+                .method(Method::POST)
+                .uri(uri)
+                .header(header::CONTENT_TYPE, "multipart/form-data; boundary=b")
+                .body(Body::from(form))
+                .unwrap()
+        };
+        // No API key needed with the token, but only once
+        assert_eq!(app.clone().oneshot(upload(&upload_url, "notes.md")).await.unwrap().status(), StatusCode::OK);
+        assert_eq!(app.clone().oneshot(upload(&upload_url, "notes.md")).await.unwrap().status(), StatusCode::GONE);
+        assert_eq!(app.clone().oneshot(upload("/convert", "notes.md")).await.unwrap().status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(app.clone().oneshot(upload("/convert?token=forged", "notes.md")).await.unwrap().status(), StatusCode::FORBIDDEN);
+
+        // A name outside the token's pattern is refused
+        let response = app.clone().oneshot(issue("admin-secret")).await.unwrap();
+        let body: Value = serde_json::from_slice(&axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap()).unwrap();
+        let response = app.oneshot(upload(body["upload_url"].as_str().unwrap(), "notes.csv")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn test_convert_filename_header() {
+        use tower::ServiceExt;
+
+        let state = AppState {
+            converter: Arc::new(MockConversionService),
+            ..test_state()
+        };
+        let app = Router::new()
+            .route("/convert", post(convert))
+            .layer(Extension(RequestId(Uuid::new_v4())))
+            .with_state(Arc::new(state));
+        let form = |filename: &str| {
+            format!("--b\r\nContent-Disposition: form-data; name=\"file\"{}\r\n\r\n# Hi\r\n--b--\r\n", filename)
+        };
+        let request = |body: String, header: &str| {
+            Request::builder()
+                .method(Method::POST)
+                .uri("/convert")
+                .header(header::CONTENT_TYPE, "multipart/form-data; boundary=b")
+                .header(FILENAME_HEADER, header)
+                .body(Body::from(body))
+                .unwrap()
+        };
+        // A field without a filename takes the header's, and with it the Markdown extension
+        let response = app.clone().oneshot(request(form(""), "notes.md")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_DISPOSITION], "attachment; filename=\"notes.pdf\"");
+        let response = app.clone().oneshot(request(form("; filename=\"document\""), "notes.md")).await.unwrap();
+        assert_eq!(response.headers()[header::CONTENT_DISPOSITION], "attachment; filename=\"notes.pdf\"");
+        // The header is checked like a form filename
+        let response = app.oneshot(request(form(""), "run.exe")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+
+    #[tokio::test]
+    async fn test_convert_all_with_mock_service() {
+        let work_dir = PathBuf::from(format!("/tmp/convert/test-{}", Uuid::new_v4()));
+        let mut files = Vec::new();
+        for i in 0..2 {
+            let path = work_dir.join(i.to_string()).join("a.docx");
+            fs::create_dir_all(path.parent().unwrap()).await.unwrap();
+            fs::write(&path, b"12345").await.unwrap();
+            files.push(UploadedFile { path, size: 5, kind: "docx", digest: None });
+        }
+        let state = AppState {
+            converter: Arc::new(MockConversionService),
+            ..test_state()
+        };
+        let requester = Requester { request_id: Uuid::nil(), client_ip: None };
+        let opts = ConvertOptions::default();
+
+        let converted = convert_all(&state, requester, &work_dir, &files, &opts).await.unwrap();
+        assert_eq!(converted.filename, format!("converted_{}.zip", Uuid::nil()));
+        let archive = zip::ZipReader::new(&converted.content).unwrap();
+        assert_eq!(archive.read("a.pdf", 5).unwrap(), b"12345");
+        assert_eq!(archive.read("a (2).pdf", 5).unwrap(), b"12345");
+
+        let small = AppState { max_output_bytes: 4, ..state };
+        let result = convert_all(&small, requester, &work_dir, &files[..1], &opts).await;
+        assert_eq!(result.err().map(|e| e.code), Some("OUTPUT_TOO_LARGE"));
+        let _ = fs::remove_dir_all(&work_dir).await;
+    }
+
+    #[tokio::test]
+    async fn test_cache_hit_skips_disk() {
+        let work_dir = PathBuf::from(format!("/tmp/convert/test-{}", Uuid::new_v4()));
+        let path = work_dir.join("0").join("a.docx");
+        fs::create_dir_all(path.parent().unwrap()).await.unwrap();
+        fs::write(&path, b"12345").await.unwrap();
+        let state = AppState {
+            converter: Arc::new(MockConversionService),
+            cache: Some(ResultCache::new(4)),
+            cache_control_max_age: 3600,
+            ..test_state()
+        };
+        let opts = ConvertOptions::default();
+        let file = UploadedFile { path: path.clone(), size: 5, kind: "docx", digest: Some(crypto::sha256(b"12345")) };
+        let fresh = run_conversion(&state, &work_dir, &file, &opts).await.unwrap();
+        assert_eq!(fresh.content, b"12345");
+        assert!(fresh.headers.contains(&("cache-control", "no-store".to_string())));
+
+        // The retry is answered from its hash, with the upload no longer there to read
+        let _ = fs::remove_dir_all(&work_dir).await;
+        let hit = run_conversion(&state, &work_dir, &file, &opts).await.unwrap();
+        assert_eq!((hit.filename.as_str(), hit.content.as_slice()), ("a.pdf", &b"12345"[..]));
+        // Only the hit may be kept by a CDN, under the same ETag
+        let cache_control: Vec<_> = hit.headers.iter().filter(|(name, _)| *name == "cache-control").collect();
+        assert_eq!(cache_control, [&("cache-control", "public, max-age=3600".to_string())]);
+        assert!(hit.headers.contains(&("vary", "accept-encoding".to_string())));
+        assert_eq!(hit.headers.iter().find(|(name, _)| *name == "etag"), fresh.headers.iter().find(|(name, _)| *name == "etag"));
+        assert_eq!(state.metrics.cache_hits.get(), 1);
+        assert_eq!(state.metrics.cache_skip_disk.get(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_identical_conversions_run_once() {
+        /// Converts slowly, counting its runs, and fails inputs containing "bad".
+        struct SlowService(std::sync::atomic::AtomicUsize);
+
+        #[axum::async_trait]
+        impl ConversionService for SlowService {
+            async fn convert(&self, input: &Path, work_dir: &Path, opts: &ConvertOptions) -> Result<PathBuf, ConversionError> {
+                self.0.fetch_add(1, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                if fs::read(input).await.unwrap_or_default().starts_with(b"bad") {
+                    return Err(ConversionError::new(StatusCode::BAD_REQUEST, "CONVERSION_FAILED", "bad"));
+                }
+                MockConversionService.convert(input, work_dir, opts).await
+            }
+
+            fn backend_label(&self, _input: &Path, _opts: &ConvertOptions) -> String {
+                "slow".to_string()
+            }
+        }
+
+        let service = Arc::new(SlowService(std::sync::atomic::AtomicUsize::new(0)));
+        let state = AppState {
+            converter: service.clone(),
+            cache: Some(ResultCache::new(4)),
+            ..test_state()
+        };
+        let opts = ConvertOptions::default();
+        let upload = |content: &'static [u8]| async move {
+            let work_dir = PathBuf::from(format!("/tmp/convert/test-{}", Uuid::new_v4()));
+            let path = work_dir.join("0").join("a.docx");
+            fs::create_dir_all(path.parent().unwrap()).await.unwrap();
+            fs::write(&path, content).await.unwrap();
+            let file = UploadedFile { path, size: content.len() as u64, kind: "docx", digest: Some(crypto::sha256(content)) };
+            (work_dir, file)
+        };
+
+        // Three browser retries of one upload convert it once
+        let uploads = [upload(b"same").await, upload(b"same").await, upload(b"same").await];
+        let results = futures_util::future::join_all(uploads.iter().map(|(dir, file)| run_conversion(&state, dir, file, &opts))).await;
+        assert!(results.iter().all(|r| r.as_ref().is_ok_and(|c| c.content == b"same")));
+        assert_eq!(service.0.load(Ordering::SeqCst), 1);
+        assert_eq!(state.metrics.conversions_deduplicated.get(), 2);
+
+        // The waiting ones get the first one's error
+        let uploads = [upload(b"bad").await, upload(b"bad").await];
+        let results = futures_util::future::join_all(uploads.iter().map(|(dir, file)| run_conversion(&state, dir, file, &opts))).await;
+        assert!(results.iter().all(|r| r.as_ref().is_err_and(|e| e.code == "CONVERSION_FAILED")));
+        assert_eq!(service.0.load(Ordering::SeqCst), 2);
+        for (dir, _) in uploads {
+            let _ = fs::remove_dir_all(dir).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_conditional_convert() {
+        use tower::ServiceExt;
+
+        let app = Router::new()
+            .route("/convert", post(convert))
+            .layer(Extension(RequestId(Uuid::new_v4())))
+            .with_state(Arc::new(AppState {
+                converter: Arc::new(MockConversionService),
+                cache: Some(ResultCache::new(4)),
+                ..test_state()
+            }));
+        let mut docx = zip::ZipWriter::new();
+        docx.add("[Content_Types].xml", b"<Types/>");
+        docx.add("word/document.xml", b"<w:document/>");
+        let docx = docx.finish();
+        let send = |if_none_match: Option<&str>| {
+            let mut request = Request::builder()
+                .method(Method::POST)
+                .uri("/convert")
+                .header(header::CONTENT_TYPE, "multipart/form-data; boundary=b");
+            if let Some(etag) = if_none_match {
+                request = request.header(header::IF_NONE_MATCH, etag);
+            }
+            let mut body = b"--b\r\nContent-Disposition: form-data; name=\"file\"; filename=\"a.docx\"\r\n\r\n".to_vec();
+            body.extend_from_slice(&docx);
+            body.extend_from_slice(b"\r\n--b--\r\n");
+            app.clone().oneshot(request.body(Body::from(body)).unwrap())
+        };
+
+        let first = send(None).await.unwrap();
+        assert_eq!(first.status(), StatusCode::OK);
+        let etag = first.headers()[header::ETAG].to_str().unwrap().to_string();
+        assert_eq!(etag.len(), 66);
+
+        let second = send(Some(&etag)).await.unwrap();
+        assert_eq!(second.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(second.headers()[header::ETAG], etag.as_str());
+        assert!(axum::body::to_bytes(second.into_body(), usize::MAX).await.unwrap().is_empty());
+
+        // A stale tag gets the output again, with the same tag
+        let stale = send(Some("\"0000\"")).await.unwrap();
+        assert_eq!(stale.status(), StatusCode::OK);
+        assert_eq!(stale.headers()[header::ETAG], etag.as_str());
+    }
+
+    #[tokio::test]
+    async fn test_compressed_convert() {
+        use tower::ServiceExt;
+
+        let app = Router::new()
+            .route("/convert", post(convert).layer(middleware::from_fn(compression::compress)))
+            .layer(Extension(RequestId(Uuid::new_v4())))
+            .with_state(Arc::new(AppState {
+                converter: Arc::new(MockConversionService),
+                cache: Some(ResultCache::new(4)),
+                ..test_state()
+            }));
+        // The mock converter returns the upload, a stored zip with a lot of text
+        let mut docx = zip::ZipWriter::new();
+        docx.add("[Content_Types].xml", b"<Types/>");
+        docx.add("word/document.xml", "<w:p><w:r><w:t>Quarterly revenue grew in every region.</w:t></w:r></w:p>".repeat(500).as_bytes());
+        let docx = docx.finish();
+        let send = |accept_encoding: &str| {
+            let mut body = b"--b\r\nContent-Disposition: form-data; name=\"file\"; filename=\"a.docx\"\r\n\r\n".to_vec();
+            body.extend_from_slice(&docx);
+            body.extend_from_slice(b"\r\n--b--\r\n");
+            let request = Request::builder()
+                .method(Method::POST)
+                .uri("/convert")
+                .header(header::CONTENT_TYPE, "multipart/form-data; boundary=b")
+                .header(header::ACCEPT_ENCODING, accept_encoding)
+                .body(Body::from(body))
+                .unwrap();
+            app.clone().oneshot(request)
+        };
+
+        let response = send("gzip, deflate").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_ENCODING], "gzip");
+        assert_eq!(response.headers()[header::VARY], "accept-encoding");
+        assert!(response.headers()[header::ETAG].to_str().unwrap().starts_with("W/\""));
+        let gzipped = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert!(gzipped.len() < docx.len() / 10);
+        assert_eq!(inflate::gunzip(&gzipped, usize::MAX).unwrap(), docx);
+
+        // Brotli is preferred when offered alongside
+        let response = send("br, gzip, deflate").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_ENCODING], "br");
+        assert!(response.headers()[header::ETAG].to_str().unwrap().starts_with("W/\""));
+        let compressed = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(compressed, brotli::compress(&docx));
+        assert!(compressed.len() < gzipped.len());
+
+        let response = send("compress").await.unwrap();
+        assert!(response.headers().get(header::CONTENT_ENCODING).is_none());
+        assert_eq!(axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap(), docx);
+    }
+
+    #[tokio::test]
+    async fn test_admin_status() {
+        use tower::ServiceExt;
+
+        let state = AppState {
+            admin_api_key: Some("admin-secret".to_string()),
+            libreoffice: LibreOfficeProbe::new("/nonexistent/soffice"),
+            ..test_state()
+        };
+        let app = Router::new().route("/admin/status", get(admin::status)).with_state(Arc::new(state));
+        let request = |key: &str| Request::builder().uri("/admin/status").header("x-api-key", key).body(Body::empty()).unwrap();
+
+        let response = app.clone().oneshot(request("wrong")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let response = app.oneshot(request("admin-secret")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let status: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(status["conversion_slots"]["max"], config::DEFAULT_MAX_CONCURRENT_CONVERSIONS);
+        assert_eq!(status["conversions"]["success"], 0);
+        assert_eq!(status["cache"], Value::Null);
+        assert_eq!(status["circuit_breaker"], json!({"state": "closed", "failure_count": 0}));
+        assert_eq!(status["libreoffice_version"], Value::Null);
+        assert_eq!(status["features"], json!([]));
+    }
+
+    #[tokio::test]
+    async fn test_conversion_slots() {
+        let state = test_state();
+        let slots: Vec<_> = (0..config::DEFAULT_MAX_CONCURRENT_CONVERSIONS).map(|_| state.try_conversion_slot().unwrap()).collect();
+        assert!(state.try_conversion_slot().is_none());
+        assert_eq!(state.metrics.conversion_slots_in_use.get(), config::DEFAULT_MAX_CONCURRENT_CONVERSIONS as i64);
+        assert_eq!(busy_response().headers()[header::RETRY_AFTER], "5");
+
+        drop(slots);
+        assert_eq!(state.metrics.conversion_slots_in_use.get(), 0);
+        let _slot = state.conversion_slot().await;
+        assert_eq!(state.metrics.conversion_slots_waiting.get(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_download_link_is_single_use() {
+        let state = Arc::new(test_state());
+        let converted = ConvertedFile {
+            filename: "a.pdf".to_string(),
+            content_type: "application/pdf",
+            content: b"%PDF-1.7 test".to_vec(),
+            headers: vec![("Content-X-Pdfa-Level", "2b".to_string())],
+        };
+        let token = state.downloads.store(Uuid::new_v4(), &converted).await.unwrap();
+
+        let response = downloads::download(State(state.clone()), axum::extract::Path(token.clone())).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["Content-X-Pdfa-Level"], "2b");
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(&body[..], b"%PDF-1.7 test");
+
+        let again = downloads::download(State(state), axum::extract::Path(token)).await;
+        assert_eq!(again.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_check_output_size() {
+        let dir = std::env::temp_dir().join(format!("output-size-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let outputs = [dir.join("a-1.png"), dir.join("a-2.png")];
+        for path in &outputs {
+            std::fs::write(path, b"12345").unwrap();
+        }
+        assert!(check_output_size(&outputs, 10).await.is_ok());
+        let e = check_output_size(&outputs, 9).await.unwrap_err();
+        assert_eq!((e.status, e.code), (StatusCode::PAYLOAD_TOO_LARGE, "OUTPUT_TOO_LARGE"));
+        assert_eq!(e.details, [("size", json!(10)), ("limit", json!(9))]);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_upload_guard() {
+        let work_dir = std::env::temp_dir().join(format!("upload-guard-{}", std::process::id()));
+        std::fs::create_dir_all(work_dir.join("0")).unwrap();
+
+        let mut guard = UploadGuard::new(&work_dir);
+        guard.start_file("docx", 10);
+        assert_eq!(guard.add(6).unwrap(), 6);
+        let e = guard.add(6).unwrap_err();
+        assert_eq!((e.status, e.code), (StatusCode::PAYLOAD_TOO_LARGE, "FILE_TOO_LARGE"));
+        // The count starts over for the next file
+        guard.start_file("Uploaded", 10);
+        assert_eq!(guard.add(10).unwrap(), 10);
+        guard.complete();
+        assert!(work_dir.exists());
+
+        // Dropped before completing, as on an error or a disconnect
+        drop(UploadGuard::new(&work_dir));
+        assert!(!work_dir.exists());
+    }
+
+    #[tokio::test]
+    async fn test_run_until_kills_on_deadline() {
+        let mut command = Command::new("sleep");
+        command.arg("5");
+        let started = Instant::now();
+        let result = run_until(command, Instant::now() + Duration::from_millis(100)).await.unwrap();
+        assert!(result.is_none());
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[tokio::test]
+    async fn test_run_until_abort_kills_group() {
+        let pid_file = PathBuf::from(format!("/tmp/convert/run-until-{}.pid", Uuid::new_v4()));
+        fs::create_dir_all(pid_file.parent().unwrap()).await.unwrap();
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(format!("sleep 30 & echo $! > {}; wait", pid_file.display()));
+        let task = tokio::spawn(run_until(command, Instant::now() + Duration::from_secs(60)));
+
+        let mut pid = None;
+        for _ in 0..100 {
+            if let Ok(written) = fs::read_to_string(&pid_file).await
+                && let Ok(p) = written.trim().parse::<u32>()
+            {
+                pid = Some(p);
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        let pid = pid.expect("the shell did not start sleep");
+        let _ = fs::remove_file(&pid_file).await;
+
+        // As a cancelled job's task is
+        task.abort();
+        assert!(task.await.unwrap_err().is_cancelled());
+        // Gone, or a zombie waiting for init to reap it
+        let alive = || std::fs::read_to_string(format!("/proc/{}/stat", pid)).is_ok_and(|stat| !stat.contains(") Z "));
+        for _ in 0..100 {
+            if !alive() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("the grandchild outlived its aborted task");
     }
 }
@@ -0,0 +1,426 @@
+//! Markdown to HTML, for Markdown inputs when Pandoc is not configured: LibreOffice would show
+//! the Markdown source as plain text, but converts the HTML like any other web page.
+//!
+//! Covers what READMEs mostly use: ATX headings, paragraphs, fenced code, block quotes, nested
+//! lists, rules, GitHub-style tables, and inline code, emphasis, strikethrough, links, images and
+//! autolinks. Raw HTML is escaped and shown as text rather than passed through.
+
+use std::io;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+
+/// Output formats a Markdown input can be converted to: those of a text document.
+pub const OUTPUTS: &[&str] = &["pdf", "docx", "odt", "rtf", "html", "txt", "png"];
+
+const STYLE: &str = "body{font-family:sans-serif;line-height:1.4}\
+pre,code{font-family:monospace}pre{background:#f4f4f4;padding:6px}\
+blockquote{border-left:3px solid #ccc;margin-left:0;padding-left:10px;color:#555}\
+table{border-collapse:collapse}th,td{border:1px solid #999;padding:3px 6px}";
+
+/// Renders `markdown` as a complete HTML document.
+pub fn to_html(markdown: &str) -> String {
+    let mut out = format!("<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><style>{}</style></head><body>\n", STYLE);
+    let lines: Vec<&str> = markdown.lines().collect();
+    render_blocks(&lines, false, &mut out);
+    out.push_str("</body></html>\n");
+    out
+}
+
+/// Renders the Markdown file at `path` to an HTML file next to it and returns that file's path.
+pub async fn render_file(path: &Path) -> io::Result<PathBuf> {
+    let markdown = fs::read(path).await?;
+    let html = to_html(String::from_utf8_lossy(&markdown).trim_start_matches('\u{feff}'));
+    let html_path = path.with_extension("html");
+    fs::write(&html_path, html).await?;
+    Ok(html_path)
+}
+
+/// Renders block elements. In a `tight` list item, paragraphs are not wrapped in `<p>`.
+fn render_blocks(lines: &[&str], tight: bool, out: &mut String) {
+    let mut i = 0;
+    while i < lines.len() {
+        let line = lines[i].trim_start();
+        if line.is_empty() {
+            i += 1;
+        } else if let Some(fence) = fence(line) {
+            let end = (i + 1..lines.len()).find(|&j| lines[j].trim_start().starts_with(fence)).unwrap_or(lines.len());
+            out.push_str("<pre><code>");
+            for code in &lines[i + 1..end] {
+                out.push_str(&escape(code));
+                out.push('\n');
+            }
+            out.push_str("</code></pre>\n");
+            i = end + 1;
+        } else if let Some((level, text)) = heading(line) {
+            out.push_str(&format!("<h{}>{}</h{}>\n", level, inline(text), level));
+            i += 1;
+        } else if is_rule(line) {
+            out.push_str("<hr>\n");
+            i += 1;
+        } else if line.starts_with('>') {
+            let end = (i..lines.len()).find(|&j| !lines[j].trim_start().starts_with('>')).unwrap_or(lines.len());
+            let quoted: Vec<&str> = lines[i..end]
+                .iter()
+                .map(|l| {
+                    let l = &l.trim_start()[1..];
+                    l.strip_prefix(' ').unwrap_or(l)
+                })
+                .collect();
+            out.push_str("<blockquote>\n");
+            render_blocks(&quoted, false, out);
+            out.push_str("</blockquote>\n");
+            i = end;
+        } else if let Some((ordered, _, _)) = list_item(line) {
+            i = render_list(lines, i, ordered, out);
+        } else if is_table(lines, i) {
+            i = render_table(lines, i, out);
+        } else {
+            let end = (i..lines.len()).find(|&j| j > i && interrupts_paragraph(lines[j])).unwrap_or(lines.len());
+            let text: Vec<&str> = lines[i..end].iter().map(|l| l.trim()).collect();
+            let text = inline(&text.join("\n"));
+            if tight {
+                out.push_str(&text);
+                out.push('\n');
+            } else {
+                out.push_str(&format!("<p>{}</p>\n", text));
+            }
+            i = end;
+        }
+    }
+}
+
+/// Whether `line` ends a paragraph: a blank line or the start of another block.
+fn interrupts_paragraph(line: &str) -> bool {
+    let line = line.trim_start();
+    line.is_empty() || fence(line).is_some() || heading(line).is_some() || is_rule(line) || line.starts_with('>') || list_item(line).is_some()
+}
+
+/// The closing marker of a code fence opened by `line`.
+fn fence(line: &str) -> Option<&'static str> {
+    if line.starts_with("```") {
+        Some("```")
+    } else if line.starts_with("~~~") {
+        Some("~~~")
+    } else {
+        None
+    }
+}
+
+fn heading(line: &str) -> Option<(usize, &str)> {
+    let level = line.bytes().take_while(|&b| b == b'#').count();
+    let rest = &line[level..];
+    if !(1..=6).contains(&level) || !(rest.is_empty() || rest.starts_with([' ', '\t'])) {
+        return None;
+    }
+    Some((level, rest.trim().trim_end_matches('#').trim_end()))
+}
+
+fn is_rule(line: &str) -> bool {
+    let marks: Vec<char> = line.chars().filter(|c| !c.is_whitespace()).collect();
+    marks.len() >= 3 && matches!(marks[0], '-' | '*' | '_') && marks.iter().all(|&c| c == marks[0])
+}
+
+/// For a list item line: whether it is ordered, the width of its marker and the item text.
+fn list_item(line: &str) -> Option<(bool, usize, &str)> {
+    let digits = line.bytes().take_while(u8::is_ascii_digit).count();
+    let (ordered, marker) = match line.as_bytes().get(digits) {
+        Some(b'.' | b')') if (1..=9).contains(&digits) => (true, digits + 1),
+        Some(b'-' | b'*' | b'+') if digits == 0 => (false, 1),
+        _ => return None,
+    };
+    let rest = &line[marker..];
+    let text = rest.trim_start();
+    if !rest.starts_with([' ', '\t']) && !rest.is_empty() {
+        return None;
+    }
+    Some((ordered, line.len() - text.len(), text))
+}
+
+fn indent(line: &str) -> usize {
+    line.chars().take_while(|c| c.is_whitespace()).map(|c| if c == '\t' { 4 } else { 1 }).sum()
+}
+
+/// Renders the list starting at `lines[start]`; returns the index after it. Lines indented past
+/// an item's marker belong to that item and are rendered as its own blocks, nested lists included.
+fn render_list(lines: &[&str], start: usize, ordered: bool, out: &mut String) -> usize {
+    let list_indent = indent(lines[start]);
+    out.push_str(if ordered { "<ol>\n" } else { "<ul>\n" });
+    let mut i = start;
+    while i < lines.len() {
+        let Some((item_ordered, width, text)) = list_item(lines[i].trim_start()) else {
+            break;
+        };
+        if item_ordered != ordered || indent(lines[i]) != list_indent {
+            break;
+        }
+        let content_indent = list_indent + width;
+        let mut item = vec![text];
+        i += 1;
+        while i < lines.len() {
+            let line = lines[i];
+            let blank = line.trim().is_empty();
+            let next_indented = lines.get(i + 1).is_some_and(|next| indent(next) >= content_indent.min(list_indent + 2));
+            if blank && !next_indented {
+                break;
+            }
+            if !blank && indent(line) < list_indent + 2 {
+                // A lazy continuation of the item's paragraph, unless a new block starts
+                if interrupts_paragraph(line) || item.last().is_some_and(|l| l.trim().is_empty()) {
+                    break;
+                }
+            }
+            item.push(strip_indent(line, content_indent));
+            i += 1;
+        }
+        out.push_str("<li>");
+        render_blocks(&item, true, out);
+        out.push_str("</li>\n");
+        // A blank line between two items of the same list
+        if i + 1 < lines.len() && lines[i].trim().is_empty() && list_item(lines[i + 1].trim_start()).is_some() && indent(lines[i + 1]) == list_indent {
+            i += 1;
+        }
+    }
+    out.push_str(if ordered { "</ol>\n" } else { "</ul>\n" });
+    i
+}
+
+/// Removes up to `width` columns of leading whitespace.
+fn strip_indent(line: &str, width: usize) -> &str {
+    let mut columns = 0;
+    for (at, c) in line.char_indices() {
+        if columns >= width || !c.is_whitespace() {
+            return &line[at..];
+        }
+        columns += if c == '\t' { 4 } else { 1 };
+    }
+    ""
+}
+
+fn is_table(lines: &[&str], at: usize) -> bool {
+    let Some(separator) = lines.get(at + 1) else {
+        return false;
+    };
+    let separator = separator.trim();
+    lines[at].contains('|')
+        && separator.contains('-')
+        && separator.contains(['|', '-'])
+        && separator.chars().all(|c| matches!(c, '|' | '-' | ':' | ' ' | '\t'))
+        && split_row(lines[at]).len() == split_row(separator).len()
+}
+
+/// The cells of a table row. `\|` is a pipe inside a cell, even within code.
+fn split_row(line: &str) -> Vec<String> {
+    let line = line.trim();
+    let line = line.strip_prefix('|').unwrap_or(line);
+    let line = if line.ends_with('|') && !line.ends_with("\\|") { &line[..line.len() - 1] } else { line };
+    let mut cells = vec![String::new()];
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if chars.peek() == Some(&'|') => cells.last_mut().unwrap().push(chars.next().unwrap()),
+            '|' => cells.push(String::new()),
+            c => cells.last_mut().unwrap().push(c),
+        }
+    }
+    cells.iter().map(|cell| cell.trim().to_string()).collect()
+}
+
+fn render_table(lines: &[&str], start: usize, out: &mut String) -> usize {
+    let aligns: Vec<&str> = split_row(lines[start + 1])
+        .iter()
+        .map(|cell| match (cell.starts_with(':'), cell.ends_with(':')) {
+            (true, true) => " style=\"text-align:center\"",
+            (false, true) => " style=\"text-align:right\"",
+            _ => "",
+        })
+        .collect();
+    let row = |line: &str, tag: &str, out: &mut String| {
+        out.push_str("<tr>");
+        let cells = split_row(line);
+        for (n, align) in aligns.iter().enumerate() {
+            let cell = cells.get(n).map(String::as_str).unwrap_or_default();
+            out.push_str(&format!("<{}{}>{}</{}>", tag, align, inline(cell), tag));
+        }
+        out.push_str("</tr>\n");
+    };
+
+    out.push_str("<table>\n<thead>");
+    row(lines[start], "th", out);
+    out.push_str("</thead>\n<tbody>\n");
+    let mut i = start + 2;
+    while i < lines.len() && lines[i].contains('|') && !lines[i].trim().is_empty() {
+        row(lines[i], "td", out);
+        i += 1;
+    }
+    out.push_str("</tbody>\n</table>\n");
+    i
+}
+
+/// Renders inline markup in `text`.
+fn inline(text: &str) -> String {
+    let mut out = String::new();
+    let mut rest = text;
+    while let Some(c) = rest.chars().next() {
+        if let Some((html, len)) = inline_element(rest) {
+            out.push_str(&html);
+            rest = &rest[len..];
+            continue;
+        }
+        // A backslash escapes the punctuation after it
+        if c == '\\'
+            && let Some(next) = rest[1..].chars().next().filter(char::is_ascii_punctuation)
+        {
+            out.push_str(&escape(&next.to_string()));
+            rest = &rest[2..];
+            continue;
+        }
+        // Run through a backtick run or emphasis marker that did not match, so it is not retried
+        let len = if matches!(c, '`' | '*' | '_' | '~') {
+            rest.len() - rest.trim_start_matches(c).len()
+        } else {
+            c.len_utf8()
+        };
+        out.push_str(&escape(&rest[..len]));
+        rest = &rest[len..];
+    }
+    out
+}
+
+/// The element at the start of `text`, if one starts there, with the length of its source.
+fn inline_element(text: &str) -> Option<(String, usize)> {
+    if text.starts_with('`') {
+        let ticks = text.len() - text.trim_start_matches('`').len();
+        let fence = &text[..ticks];
+        let end = text[ticks..].find(fence)? + ticks;
+        let code = text[ticks..end].trim();
+        return Some((format!("<code>{}</code>", escape(code)), end + ticks));
+    }
+    if let Some(rest) = text.strip_prefix("![") {
+        let (alt, url, len) = link_parts(rest)?;
+        let html = format!("<img src=\"{}\" alt=\"{}\">", escape(&safe_url(url)), escape(alt));
+        return Some((html, len + 2));
+    }
+    if let Some(rest) = text.strip_prefix('[') {
+        let (label, url, len) = link_parts(rest)?;
+        return Some((format!("<a href=\"{}\">{}</a>", escape(&safe_url(url)), inline(label)), len + 1));
+    }
+    if let Some(rest) = text.strip_prefix('<') {
+        let end = rest.find('>')?;
+        let url = &rest[..end];
+        if !(url.starts_with("http://") || url.starts_with("https://")) || url.contains(char::is_whitespace) {
+            return None;
+        }
+        return Some((format!("<a href=\"{}\">{}</a>", escape(url), escape(url)), end + 2));
+    }
+    for (marker, tag) in [("**", "strong"), ("__", "strong"), ("~~", "del"), ("*", "em"), ("_", "em")] {
+        let Some(rest) = text.strip_prefix(marker) else {
+            continue;
+        };
+        if rest.starts_with(char::is_whitespace) || rest.starts_with(marker) {
+            continue;
+        }
+        let end = closing_marker(rest, marker)?;
+        return Some((format!("<{}>{}</{}>", tag, inline(&rest[..end]), tag), end + 2 * marker.len()));
+    }
+    None
+}
+
+/// Where the `marker` closing an emphasis span in `text` starts.
+fn closing_marker(text: &str, marker: &str) -> Option<usize> {
+    let mut from = 0;
+    while let Some(found) = text[from..].find(marker) {
+        let at = from + found;
+        let before = text[..at].chars().next_back();
+        let after = text[at + marker.len()..].chars().next();
+        // `snake_case_names` are not emphasis
+        let intraword = marker.starts_with('_') && after.is_some_and(char::is_alphanumeric);
+        let doubled = marker.len() == 1 && after.is_some_and(|c| marker.starts_with(c));
+        if at > 0 && !before.is_some_and(char::is_whitespace) && !intraword && !doubled {
+            return Some(at);
+        }
+        from = at + marker.len();
+        if doubled {
+            from += 1;
+        }
+    }
+    None
+}
+
+/// Splits `label](url "title")` into the label and URL, with the length consumed.
+fn link_parts(text: &str) -> Option<(&str, &str, usize)> {
+    let mut depth = 0;
+    let close = text.char_indices().find_map(|(at, c)| {
+        match c {
+            '[' => depth += 1,
+            ']' if depth == 0 => return Some(at),
+            ']' => depth -= 1,
+            _ => {}
+        }
+        None
+    })?;
+    let target = text[close + 1..].strip_prefix('(')?;
+    let end = target.find(')')?;
+    let url = target[..end].split_whitespace().next().unwrap_or_default();
+    Some((&text[..close], url.trim_start_matches('<').trim_end_matches('>'), close + 2 + end + 1))
+}
+
+/// Drops `javascript:` and other scripting URLs.
+fn safe_url(url: &str) -> String {
+    let scheme = url.split_once(':').map(|(scheme, _)| scheme.to_ascii_lowercase());
+    match scheme.as_deref() {
+        Some("javascript" | "vbscript" | "data") => String::new(),
+        _ => url.to_string(),
+    }
+}
+
+/// Escapes `text` for HTML content and double-quoted attribute values.
+pub fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn body(markdown: &str) -> String {
+        let html = to_html(markdown);
+        let start = html.find("<body>\n").unwrap() + 7;
+        html[start..html.len() - "</body></html>\n".len()].to_string()
+    }
+
+    #[test]
+    fn test_blocks() {
+        let md = "# Title #\n\nSome *text*\nwrapped.\n\n```rust\nlet a = 1 < 2;\n```\n\n> quoted\n\n---\n";
+        assert_eq!(
+            body(md),
+            "<h1>Title</h1>\n<p>Some <em>text</em>\nwrapped.</p>\n<pre><code>let a = 1 &lt; 2;\n</code></pre>\n\
+             <blockquote>\n<p>quoted</p>\n</blockquote>\n<hr>\n"
+        );
+
+        let md = "- one\n- two\n  - nested\n3. not a sibling\n\n1. first\n2. second\n";
+        assert_eq!(
+            body(md),
+            "<ul>\n<li>one\n</li>\n<li>two\n<ul>\n<li>nested\n</li>\n</ul>\n</li>\n</ul>\n\
+             <ol>\n<li>not a sibling\n</li>\n<li>first\n</li>\n<li>second\n</li>\n</ol>\n"
+        );
+
+        let md = "| Key | Value |\n|:----|------:|\n| `a\\|b` | 1 |\n| b | 2 |\n";
+        let html = body(md);
+        assert!(html.starts_with("<table>\n<thead><tr><th>Key</th><th style=\"text-align:right\">Value</th></tr>"));
+        assert!(html.contains("<tr><td><code>a|b</code></td><td style=\"text-align:right\">1</td></tr>"));
+        assert!(html.contains("<tr><td>b</td><td style=\"text-align:right\">2</td></tr>"));
+    }
+
+    #[test]
+    fn test_inline() {
+        assert_eq!(inline("**bold** and _em_ and ~~gone~~"), "<strong>bold</strong> and <em>em</em> and <del>gone</del>");
+        assert_eq!(inline("snake_case_name and 2 * 3 * 4"), "snake_case_name and 2 * 3 * 4");
+        assert_eq!(inline("`<b>` \\*literal\\*"), "<code>&lt;b&gt;</code> *literal*");
+        assert_eq!(
+            inline("[the **docs**](https://example.com/a \"Title\") ![logo](logo.png)"),
+            "<a href=\"https://example.com/a\">the <strong>docs</strong></a> <img src=\"logo.png\" alt=\"logo\">"
+        );
+        assert_eq!(inline("[x](javascript:alert(1))"), "<a href=\"\">x</a>)");
+        assert_eq!(inline("<https://example.com> <b>raw</b>"), "<a href=\"https://example.com\">https://example.com</a> &lt;b&gt;raw&lt;/b&gt;");
+    }
+}
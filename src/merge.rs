@@ -0,0 +1,393 @@
+//! `POST /merge`: concatenates uploaded PDFs into one, in upload order.
+//!
+//! The pages of each document are copied with everything they reference, renumbered, under a
+//! new page tree. Whatever hangs off the old catalogs instead (named destinations, form fields,
+//! the structure tree, the documents' own outlines) is left behind. `/split` writes its parts
+//! the same way.
+
+use axum::{
+    extract::{Multipart, State},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Extension,
+};
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use tokio::fs;
+use tracing::{error, info, info_span, Instrument};
+
+use crate::error::ConversionError;
+use crate::pdf::{self, Dict, Document, Object};
+use crate::telemetry::RequestId;
+use crate::{busy_response, disk, file_response, filename_header, receive_upload, shutting_down_response};
+use crate::{AppState, ConvertedFile, Disposition};
+
+/// Page attributes a page can inherit from its ancestors in the page tree.
+const INHERITED: &[&str] = &["Resources", "MediaBox", "CropBox", "Rotate"];
+
+pub async fn merge(
+    State(state): State<Arc<AppState>>,
+    Extension(RequestId(request_id)): Extension<RequestId>,
+    headers: HeaderMap,
+    mut multipart: Multipart,
+) -> Response {
+    if state.draining.load(Ordering::SeqCst) {
+        return shutting_down_response();
+    }
+    let Some(slot) = state.try_conversion_slot() else {
+        return busy_response();
+    };
+    let _active_dir = state.active_dirs.track(request_id);
+    let work_dir = state.request_dir(request_id);
+    if let Err(e) = fs::create_dir_all(&work_dir).await {
+        error!("Failed to create work dir: {}", e);
+        return ConversionError::internal().into_response();
+    }
+
+    let result = async {
+        disk::ensure_free_space(&work_dir, state.min_free_bytes)?;
+        // Any type is received so that non-PDFs get the same 400 as broken PDFs
+        let upload = receive_upload(&mut multipart, filename_header(&headers).as_deref(), &work_dir, &state, None)
+            .instrument(info_span!("multipart"))
+            .await?;
+        if upload.files.len() < 2 {
+            return Err(ConversionError::new(StatusCode::BAD_REQUEST, "TOO_FEW_FILES", "Merging needs at least two PDF files"));
+        }
+        let bookmarks = match upload.fields.get("bookmarks").map(|v| v.trim()) {
+            None | Some("" | "false") => false,
+            Some("true") => true,
+            Some(other) => {
+                return Err(ConversionError::new(
+                    StatusCode::BAD_REQUEST,
+                    "INVALID_BOOKMARKS",
+                    format!("bookmarks must be true or false, not {:?}", other),
+                ));
+            }
+        };
+
+        let mut documents = Vec::new();
+        for file in &upload.files {
+            let name = file.path.file_name().unwrap_or_default().to_string_lossy().to_string();
+            if file.kind != "pdf" {
+                return Err(invalid_pdf(&name, &format!("detected as {}", file.kind)));
+            }
+            let content = fs::read(&file.path).await.map_err(|e| {
+                error!("Failed to read upload: {}", e);
+                ConversionError::internal()
+            })?;
+            documents.push((name, content));
+        }
+        tokio::task::spawn_blocking(move || merge_documents(&documents, bookmarks))
+            .instrument(info_span!("merge"))
+            .await
+            .map_err(|e| {
+                error!("PDF merge panicked: {}", e);
+                ConversionError::internal()
+            })?
+    }
+    .await;
+    drop(slot);
+    let _ = fs::remove_dir_all(&work_dir).instrument(info_span!("cleanup")).await;
+
+    match result {
+        Ok(merged) => {
+            let converted = ConvertedFile {
+                filename: "merged.pdf".to_string(),
+                content_type: "application/pdf",
+                content: merged,
+                headers: Vec::new(),
+            };
+            file_response(&converted, Disposition::Attachment)
+        }
+        Err(e) => e.into_response(),
+    }
+}
+
+pub fn invalid_pdf(name: &str, reason: &str) -> ConversionError {
+    ConversionError::new(StatusCode::BAD_REQUEST, "INVALID_PDF", format!("{} is not a valid PDF: {}", name, reason)).with_detail("file", name)
+}
+
+/// Merges `(filename, content)` pairs, outlining each document under its filename if `bookmarks`.
+fn merge_documents(documents: &[(String, Vec<u8>)], bookmarks: bool) -> Result<Vec<u8>, ConversionError> {
+    let mut merger = Merger::new();
+    for (name, content) in documents {
+        let mut document = Document::parse(content).map_err(|e| invalid_pdf(name, &e))?;
+        merger
+            .append(&mut document, bookmarks.then_some(name.as_str()))
+            .map_err(|e| invalid_pdf(name, &e))?;
+    }
+    info!("Merged {} PDFs into {} pages", documents.len(), merger.pages.len());
+    Ok(merger.finish())
+}
+
+/// Writes the pages of several documents out as one PDF.
+pub struct Merger {
+    out: Vec<u8>,
+    /// Byte offset of each object written so far, by object number.
+    offsets: Vec<usize>,
+    next_id: u32,
+    pages: Vec<u32>,
+    /// Outline entries: a title and the page it points to.
+    outline: Vec<(String, u32)>,
+}
+
+/// Object numbers of the page tree root and the catalog, which are written last.
+const PAGES_ID: u32 = 1;
+const CATALOG_ID: u32 = 2;
+
+impl Merger {
+    pub fn new() -> Self {
+        Merger {
+            // The binary comment tells transfer tools the file is not text
+            out: b"%PDF-1.7\n%\xe2\xe3\xcf\xd3\n".to_vec(),
+            offsets: vec![0; 3],
+            next_id: 3,
+            pages: Vec::new(),
+            outline: Vec::new(),
+        }
+    }
+
+    /// Appends the pages of `document`, with an outline entry at its first page if `title` is set.
+    pub fn append(&mut self, document: &mut Document, title: Option<&str>) -> Result<(), String> {
+        let pages = pages(document)?;
+        self.append_pages(document, pages, title)
+    }
+
+    /// Appends `pages`, as returned by `pages(document)` or a selection of them.
+    pub fn append_pages(&mut self, document: &mut Document, pages: Vec<Page>, title: Option<&str>) -> Result<(), String> {
+        // Pages are numbered up front so that links between them (and annotations' /P) resolve
+        let mut renumbered = HashMap::new();
+        let mut pending = Vec::new();
+        for (old, _) in &pages {
+            if let Some(old) = old {
+                renumbered.insert(*old, self.next_id);
+            }
+            self.pages.push(self.next_id);
+            self.next_id += 1;
+        }
+        let first_new = self.pages.len() - pages.len();
+        if let Some(title) = title {
+            self.outline.push((title.to_string(), self.pages[first_new]));
+        }
+        for (n, (_, mut page)) in pages.into_iter().enumerate() {
+            page.iter_mut().for_each(|value| self.renumber(value, &mut renumbered, &mut pending));
+            page.set("Parent", Object::Ref(PAGES_ID, 0));
+            self.write_object(self.pages[first_new + n], &Object::Dict(page));
+        }
+        while let Some((old, new)) = pending.pop() {
+            let mut object = document.get(old)?;
+            self.renumber(&mut object, &mut renumbered, &mut pending);
+            self.write_object(new, &object);
+        }
+        Ok(())
+    }
+
+    /// Rewrites the references in `object` to the merged file's numbering, queueing objects not
+    /// seen before in `pending` as `(old, new)` numbers.
+    fn renumber(&mut self, object: &mut Object, renumbered: &mut HashMap<u32, u32>, pending: &mut Vec<(u32, u32)>) {
+        match object {
+            Object::Ref(old, generation) => {
+                let new = *renumbered.entry(*old).or_insert_with(|| {
+                    let new = self.next_id;
+                    self.next_id += 1;
+                    pending.push((*old, new));
+                    new
+                });
+                *old = new;
+                *generation = 0;
+            }
+            Object::Array(items) => items.iter_mut().for_each(|item| self.renumber(item, renumbered, pending)),
+            Object::Dict(dict) | Object::Stream(dict, _) => dict.iter_mut().for_each(|value| self.renumber(value, renumbered, pending)),
+            _ => {}
+        }
+    }
+
+    fn write_object(&mut self, id: u32, object: &Object) {
+        let id = id as usize;
+        if self.offsets.len() <= id {
+            self.offsets.resize(id + 1, 0);
+        }
+        self.offsets[id] = self.out.len();
+        self.out.extend_from_slice(format!("{} 0 obj\n", id).as_bytes());
+        object.write(&mut self.out);
+        self.out.extend_from_slice(b"\nendobj\n");
+    }
+
+    /// Writes the page tree, outline and catalog, and returns the finished file.
+    pub fn finish(mut self) -> Vec<u8> {
+        let kids = self.pages.iter().map(|&id| Object::Ref(id, 0)).collect();
+        let tree = Dict::from([("Type", Object::name("Pages")), ("Kids", Object::Array(kids)), ("Count", Object::int(self.pages.len()))]);
+        self.write_object(PAGES_ID, &Object::Dict(tree));
+
+        let mut catalog = Dict::from([("Type", Object::name("Catalog")), ("Pages", Object::Ref(PAGES_ID, 0))]);
+        if !self.outline.is_empty() {
+            let outlines_id = self.next_id;
+            let first = outlines_id + 1;
+            let last = outlines_id + self.outline.len() as u32;
+            self.next_id = last + 1;
+            let outlines = Dict::from([
+                ("Type", Object::name("Outlines")),
+                ("First", Object::Ref(first, 0)),
+                ("Last", Object::Ref(last, 0)),
+                ("Count", Object::int(self.outline.len())),
+            ]);
+            self.write_object(outlines_id, &Object::Dict(outlines));
+            for (n, (title, page)) in std::mem::take(&mut self.outline).into_iter().enumerate() {
+                let id = first + n as u32;
+                let mut item = Dict::from([
+                    ("Title", Object::String(pdf::text_string(&title))),
+                    ("Parent", Object::Ref(outlines_id, 0)),
+                    ("Dest", Object::Array(vec![Object::Ref(page, 0), Object::name("Fit")])),
+                ]);
+                if id > first {
+                    item.set("Prev", Object::Ref(id - 1, 0));
+                }
+                if id < last {
+                    item.set("Next", Object::Ref(id + 1, 0));
+                }
+                self.write_object(id, &Object::Dict(item));
+            }
+            catalog.set("Outlines", Object::Ref(outlines_id, 0));
+            catalog.set("PageMode", Object::name("UseOutlines"));
+        }
+        self.write_object(CATALOG_ID, &Object::Dict(catalog));
+
+        let xref = self.out.len();
+        let size = self.offsets.len();
+        self.out.extend_from_slice(format!("xref\n0 {}\n0000000000 65535 f \n", size).as_bytes());
+        for offset in &self.offsets[1..] {
+            self.out.extend_from_slice(format!("{:010} 00000 n \n", offset).as_bytes());
+        }
+        let trailer = Dict::from([("Size", Object::int(size)), ("Root", Object::Ref(CATALOG_ID, 0))]);
+        self.out.extend_from_slice(b"trailer\n");
+        Object::Dict(trailer).write(&mut self.out);
+        self.out.extend_from_slice(format!("\nstartxref\n{}\n%%EOF\n", xref).as_bytes());
+        self.out
+    }
+}
+
+/// A page of a source document: its object number (unless it was a direct object) and its
+/// dictionary, with the attributes it inherits filled in and its `/Parent` dropped.
+pub type Page = (Option<u32>, Dict);
+
+/// The pages of `document`, in order.
+pub fn pages(document: &mut Document) -> Result<Vec<Page>, String> {
+    let root = document.trailer.get("Root").cloned().unwrap_or(Object::Null);
+    let catalog = document.resolve(&root)?;
+    let tree = catalog.as_dict().and_then(|c| c.get("Pages")).cloned().ok_or("no page tree")?;
+    let mut pages = Vec::new();
+    collect_pages(document, &tree, &Dict::default(), &mut HashSet::new(), &mut pages, 0)?;
+    if pages.is_empty() {
+        return Err("the document has no pages".to_string());
+    }
+    Ok(pages)
+}
+
+/// Walks the page tree from `node`, collecting each page.
+fn collect_pages(
+    document: &mut Document,
+    node: &Object,
+    inherited: &Dict,
+    visited: &mut HashSet<u32>,
+    pages: &mut Vec<Page>,
+    depth: usize,
+) -> Result<(), String> {
+    if depth > pdf::MAX_DEPTH {
+        return Err("page tree nested too deeply".to_string());
+    }
+    let id = match node {
+        Object::Ref(id, _) if !visited.insert(*id) => return Err("page tree contains a cycle".to_string()),
+        Object::Ref(id, _) => Some(*id),
+        _ => None,
+    };
+    let Object::Dict(mut dict) = document.resolve(node)? else {
+        return Err("page tree node is not a dictionary".to_string());
+    };
+    dict.remove("Parent");
+
+    let mut inherited = inherited.clone();
+    for key in INHERITED {
+        match dict.get(key) {
+            Some(value) => inherited.set(key, value.clone()),
+            None => {
+                if let Some(value) = inherited.get(key) {
+                    dict.set(key, value.clone());
+                }
+            }
+        }
+    }
+    match dict.get("Kids") {
+        // Only intermediate nodes have kids; a leaf without /Type is taken to be a page
+        Some(kids) if !dict.get("Type").is_some_and(|t| t.is_name("Page")) => {
+            let Object::Array(kids) = document.resolve(kids)? else {
+                return Err("/Kids is not an array".to_string());
+            };
+            for kid in &kids {
+                collect_pages(document, kid, &inherited, visited, pages, depth + 1)?;
+            }
+        }
+        _ => pages.push((id, dict)),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::export;
+    use crate::pdf::tests::sample_pdf;
+
+    #[test]
+    fn test_merge() {
+        let documents = vec![
+            ("a.pdf".to_string(), sample_pdf(&["a1", "a2"])),
+            ("Bericht ü.pdf".to_string(), sample_pdf(&["b1"])),
+        ];
+        let merged = merge_documents(&documents, true).unwrap();
+        assert_eq!(export::count_pdf_pages(&merged), 3);
+
+        let mut document = Document::parse(&merged).unwrap();
+        let catalog = document.get(CATALOG_ID).unwrap();
+        let catalog = catalog.as_dict().unwrap();
+        assert!(catalog.get("PageMode").unwrap().is_name("UseOutlines"));
+        let tree = document.get(PAGES_ID).unwrap();
+        let Some(Object::Array(kids)) = tree.as_dict().unwrap().get("Kids").cloned() else {
+            panic!("no kids");
+        };
+        let contents: Vec<Vec<u8>> = kids
+            .iter()
+            .map(|kid| {
+                let page = document.resolve(kid).unwrap();
+                let page = page.as_dict().unwrap();
+                assert_eq!(page.get("Parent"), Some(&Object::Ref(PAGES_ID, 0)));
+                // Inherited from the source documents' page trees
+                assert!(page.get("MediaBox").is_some());
+                match document.resolve(page.get("Contents").unwrap()).unwrap() {
+                    Object::Stream(_, data) => data,
+                    other => panic!("not a stream: {:?}", other),
+                }
+            })
+            .collect();
+        assert!(contents[0].ends_with(b"(a1) Tj ET"));
+        assert!(contents[1].ends_with(b"(a2) Tj ET"));
+        assert!(contents[2].ends_with(b"(b1) Tj ET"));
+
+        let outlines = document.resolve(catalog.get("Outlines").unwrap()).unwrap();
+        let second = document.resolve(outlines.as_dict().unwrap().get("Last").unwrap()).unwrap();
+        let second = second.as_dict().unwrap();
+        assert_eq!(second.get("Title"), Some(&Object::String(b"<FEFF0042006500720069006300680074002000FC002E007000640066>".to_vec())));
+        assert_eq!(second.get("Dest"), Some(&Object::Array(vec![kids[2].clone(), Object::name("Fit")])));
+
+        let plain = merge_documents(&documents, false).unwrap();
+        assert!(!String::from_utf8_lossy(&plain).contains("/Outlines"));
+    }
+
+    #[test]
+    fn test_merge_rejects_invalid_documents() {
+        let documents = vec![("a.pdf".to_string(), sample_pdf(&["a1"])), ("b.pdf".to_string(), b"%PDF-1.4\ngarbage".to_vec())];
+        let e = merge_documents(&documents, false).unwrap_err();
+        assert_eq!(e.code, "INVALID_PDF");
+        assert!(e.message.starts_with("b.pdf is not a valid PDF"), "{}", e.message);
+    }
+}
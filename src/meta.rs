@@ -0,0 +1,336 @@
+//! `POST /meta`: document properties read straight from the file, without running LibreOffice.
+//!
+//! OOXML (`docProps/core.xml`, `docProps/app.xml`) and ODF (`meta.xml`) keep their properties in
+//! small XML parts; RTF, HTML and plain text carry what little they have inline. Whatever a
+//! format does not record is returned as `null`. Legacy binary formats (`doc`, `xls`, `ppt`) only
+//! report their type.
+
+use axum::{
+    extract::{Multipart, State},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use serde_json::{json, Value};
+use std::sync::Arc;
+use tokio::fs;
+use tracing::{error, info_span, Instrument};
+
+use crate::error::ConversionError;
+use crate::telemetry::RequestId;
+use crate::zip::ZipReader;
+use crate::{filename_header, receive_upload, AppState};
+
+/// Metadata parts are a few KiB; anything past this is not worth reading.
+const MAX_PART_LEN: usize = 1024 * 1024;
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Metadata {
+    pub title: Option<String>,
+    pub author: Option<String>,
+    pub last_modified: Option<String>,
+    pub page_count: Option<u64>,
+    pub word_count: Option<u64>,
+    pub language: Option<String>,
+}
+
+impl Metadata {
+    fn to_json(&self, kind: &str) -> Value {
+        json!({
+            "type": kind,
+            "title": self.title,
+            "author": self.author,
+            "last_modified": self.last_modified,
+            "page_count": self.page_count,
+            "word_count": self.word_count,
+            "language": self.language,
+        })
+    }
+}
+
+pub async fn meta(
+    State(state): State<Arc<AppState>>,
+    Extension(RequestId(request_id)): Extension<RequestId>,
+    headers: HeaderMap,
+    mut multipart: Multipart,
+) -> Response {
+    let _active_dir = state.active_dirs.track(request_id);
+    let work_dir = state.request_dir(request_id);
+    if let Err(e) = fs::create_dir_all(&work_dir).await {
+        error!("Failed to create work dir: {}", e);
+        return ConversionError::internal().into_response();
+    }
+    let result = async {
+        let upload = receive_upload(&mut multipart, filename_header(&headers).as_deref(), &work_dir, &state, Some(&state.allowed_input_types))
+            .instrument(info_span!("multipart"))
+            .await?;
+        let [ref file] = upload.files[..] else {
+            return Err(ConversionError::new(StatusCode::BAD_REQUEST, "TOO_MANY_FILES", "Metadata is read from one file at a time"));
+        };
+        let content = fs::read(&file.path).await.map_err(|e| {
+            error!("Failed to read upload: {}", e);
+            ConversionError::internal()
+        })?;
+        let kind = file.kind;
+        let metadata = tokio::task::spawn_blocking(move || extract(kind, &content)).await.map_err(|e| {
+            error!("Metadata extraction panicked: {}", e);
+            ConversionError::internal()
+        })?;
+        Ok(metadata.to_json(kind))
+    }
+    .await;
+    let _ = fs::remove_dir_all(&work_dir).await;
+
+    match result {
+        Ok(body) => Json(body).into_response(),
+        Err(e) => e.into_response(),
+    }
+}
+
+/// Reads what metadata the `kind` of document (as detected by `detect`) records.
+pub fn extract(kind: &str, content: &[u8]) -> Metadata {
+    match kind {
+        "docx" | "xlsx" | "pptx" => ZipReader::new(content).map(|zip| ooxml(&zip)).unwrap_or_default(),
+        "odt" | "ods" | "odp" | "odg" => ZipReader::new(content).map(|zip| odf(&zip)).unwrap_or_default(),
+        "rtf" => rtf(&String::from_utf8_lossy(content)),
+        "html" => html(&String::from_utf8_lossy(content)),
+        "txt" | "csv" | "md" => Metadata {
+            word_count: Some(String::from_utf8_lossy(content).split_whitespace().count() as u64),
+            ..Metadata::default()
+        },
+        _ => Metadata::default(),
+    }
+}
+
+fn ooxml(zip: &ZipReader) -> Metadata {
+    let part = |name: &str| zip.read(name, MAX_PART_LEN).map(|xml| String::from_utf8_lossy(&xml).into_owned());
+    let core = part("docProps/core.xml").unwrap_or_default();
+    let app = part("docProps/app.xml").unwrap_or_default();
+    let number = |name: &str| element_text(&app, name).and_then(|n| n.trim().parse().ok());
+    Metadata {
+        title: element_text(&core, "dc:title"),
+        author: element_text(&core, "dc:creator"),
+        last_modified: element_text(&core, "dcterms:modified"),
+        // Word counts pages, PowerPoint slides; Excel records neither
+        page_count: number("Pages").or_else(|| number("Slides")),
+        word_count: number("Words"),
+        language: element_text(&core, "dc:language"),
+    }
+}
+
+fn odf(zip: &ZipReader) -> Metadata {
+    let xml = zip.read("meta.xml", MAX_PART_LEN).map(|xml| String::from_utf8_lossy(&xml).into_owned()).unwrap_or_default();
+    let statistic = |name: &str| attribute(&xml, "meta:document-statistic", name).and_then(|n| n.parse().ok());
+    Metadata {
+        title: element_text(&xml, "dc:title"),
+        // dc:creator is whoever saved it last
+        author: element_text(&xml, "meta:initial-creator").or_else(|| element_text(&xml, "dc:creator")),
+        last_modified: element_text(&xml, "dc:date"),
+        page_count: statistic("meta:page-count"),
+        word_count: statistic("meta:word-count"),
+        language: element_text(&xml, "dc:language"),
+    }
+}
+
+fn rtf(text: &str) -> Metadata {
+    // `{\title Report}`; escapes and nested groups inside the value are not unpicked
+    let group = |name: &str| {
+        let start = text.find(&format!("{{\\{} ", name))? + name.len() + 3;
+        let value = text[start..].split('}').next()?.trim();
+        (!value.is_empty()).then(|| value.to_string())
+    };
+    let number = |name: &str| {
+        let start = text.find(&format!("\\{}", name))? + name.len() + 1;
+        text[start..].split(|c: char| !c.is_ascii_digit()).next()?.parse().ok()
+    };
+    Metadata {
+        title: group("title"),
+        author: group("author"),
+        last_modified: rtf_time(text),
+        page_count: number("nofpages"),
+        word_count: number("nofwords"),
+        language: None,
+    }
+}
+
+/// `{\revtim\yr2024\mo3\dy5\hr10\min30}` as `2024-03-05T10:30:00`.
+fn rtf_time(text: &str) -> Option<String> {
+    let start = text.find("{\\revtim")?;
+    let group = text[start..].split('}').next()?;
+    let field = |name: &str| -> Option<u32> {
+        let at = group.find(&format!("\\{}", name))? + name.len() + 1;
+        group[at..].split(|c: char| !c.is_ascii_digit()).next()?.parse().ok()
+    };
+    Some(format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:00",
+        field("yr")?,
+        field("mo")?,
+        field("dy")?,
+        field("hr").unwrap_or(0),
+        field("min").unwrap_or(0)
+    ))
+}
+
+fn html(text: &str) -> Metadata {
+    let lower = text.to_ascii_lowercase();
+    let title = lower.find("<title").and_then(|start| {
+        let content = start + lower[start..].find('>')? + 1;
+        let end = content + lower[content..].find("</title")?;
+        let title = unescape(text[content..end].trim());
+        (!title.is_empty()).then_some(title)
+    });
+    let author = lower.match_indices("<meta").find_map(|(start, _)| {
+        let tag = &text[start..start + lower[start..].find('>')?];
+        let name = attribute_value(tag, "name")?;
+        name.eq_ignore_ascii_case("author").then(|| attribute_value(tag, "content")).flatten()
+    });
+    let language = lower.find("<html").and_then(|start| {
+        let tag = &text[start..start + lower[start..].find('>')?];
+        attribute_value(tag, "lang")
+    });
+    Metadata {
+        title,
+        author,
+        language,
+        ..Metadata::default()
+    }
+}
+
+/// The text of the first `<name>` element in `xml`, unescaped and trimmed, if not empty.
+fn element_text(xml: &str, name: &str) -> Option<String> {
+    let open = format!("<{}", name);
+    let mut from = 0;
+    while let Some(found) = xml[from..].find(&open) {
+        let start = from + found + open.len();
+        from = start;
+        // Skip `<dc:titles>` when looking for `<dc:title>`
+        if !xml[start..].starts_with(['>', ' ', '\t', '\r', '\n', '/']) {
+            continue;
+        }
+        let tag_end = start + xml[start..].find('>')?;
+        if xml[..tag_end].ends_with('/') {
+            return None;
+        }
+        let end = tag_end + xml[tag_end..].find(&format!("</{}>", name))?;
+        let text = unescape(xml[tag_end + 1..end].trim());
+        return (!text.is_empty()).then_some(text);
+    }
+    None
+}
+
+/// The value of attribute `attr` on the first `<element>` in `xml`.
+fn attribute(xml: &str, element: &str, attr: &str) -> Option<String> {
+    let start = xml.find(&format!("<{}", element))?;
+    let tag = &xml[start..start + xml[start..].find('>')?];
+    attribute_value(tag, attr)
+}
+
+/// Looks up `name="value"` (or single-quoted) in the text of a start tag.
+pub fn attribute_value(tag: &str, name: &str) -> Option<String> {
+    let mut rest = tag;
+    while let Some(at) = rest.find(name) {
+        let preceded_by_space = rest[..at].ends_with(|c: char| c.is_ascii_whitespace());
+        let after = rest[at + name.len()..].trim_start();
+        rest = &rest[at + name.len()..];
+        let Some(value) = after.strip_prefix('=').map(str::trim_start) else {
+            continue;
+        };
+        let Some(quote) = value.chars().next().filter(|&q| q == '"' || q == '\'') else {
+            continue;
+        };
+        if preceded_by_space {
+            let value = &value[1..];
+            return Some(unescape(&value[..value.find(quote)?]));
+        }
+    }
+    None
+}
+
+/// Replaces the predefined XML entities and character references.
+pub fn unescape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(amp) = rest.find('&') {
+        out.push_str(&rest[..amp]);
+        rest = &rest[amp..];
+        let decoded = rest.find(';').and_then(|semi| {
+            let entity = &rest[1..semi];
+            let c = match entity {
+                "amp" => '&',
+                "lt" => '<',
+                "gt" => '>',
+                "quot" => '"',
+                "apos" => '\'',
+                _ => {
+                    let code = match entity.strip_prefix("#x").or_else(|| entity.strip_prefix("#X")) {
+                        Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+                        None => entity.strip_prefix('#')?.parse().ok()?,
+                    };
+                    char::from_u32(code)?
+                }
+            };
+            Some((c, semi + 1))
+        });
+        match decoded {
+            Some((c, len)) => {
+                out.push(c);
+                rest = &rest[len..];
+            }
+            None => {
+                out.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::zip::ZipWriter;
+
+    #[test]
+    fn test_ooxml_and_odf() {
+        let mut docx = ZipWriter::new();
+        docx.add(
+            "docProps/core.xml",
+            br#"<cp:coreProperties xmlns:dc="http://purl.org/dc/elements/1.1/"><dc:title>Q3 &amp; Q4</dc:title><dc:creator>Ada</dc:creator><dcterms:modified xsi:type="dcterms:W3CDTF">2024-05-01T09:00:00Z</dcterms:modified></cp:coreProperties>"#,
+        );
+        docx.add("docProps/app.xml", b"<Properties><Pages>12</Pages><Words>3400</Words></Properties>");
+        let meta = extract("docx", &docx.finish());
+        assert_eq!(meta.title.as_deref(), Some("Q3 & Q4"));
+        assert_eq!(meta.author.as_deref(), Some("Ada"));
+        assert_eq!(meta.last_modified.as_deref(), Some("2024-05-01T09:00:00Z"));
+        assert_eq!((meta.page_count, meta.word_count, meta.language), (Some(12), Some(3400), None));
+
+        let mut odt = ZipWriter::new();
+        odt.add(
+            "meta.xml",
+            br#"<office:meta><meta:initial-creator>Grace</meta:initial-creator><dc:creator>Linus</dc:creator><dc:language>en-GB</dc:language><meta:document-statistic meta:page-count="2" meta:word-count="410"/></office:meta>"#,
+        );
+        let meta = extract("odt", &odt.finish());
+        assert_eq!(meta.author.as_deref(), Some("Grace"));
+        assert_eq!(meta.language.as_deref(), Some("en-GB"));
+        assert_eq!((meta.title, meta.page_count, meta.word_count), (None, Some(2), Some(410)));
+
+        // Not actually a zip: nothing is known, but it is not an error either
+        assert_eq!(extract("xlsx", b"PK\x03\x04garbage"), Metadata::default());
+    }
+
+    #[test]
+    fn test_inline_formats() {
+        let meta = extract("rtf", br"{\rtf1{\info{\title Minutes}{\author Bo}{\revtim\yr2023\mo11\dy7\hr8\min5}\nofpages3\nofwords512}}");
+        assert_eq!(meta.title.as_deref(), Some("Minutes"));
+        assert_eq!(meta.last_modified.as_deref(), Some("2023-11-07T08:05:00"));
+        assert_eq!((meta.page_count, meta.word_count), (Some(3), Some(512)));
+
+        let meta = extract("html", br#"<html lang="de"><head><TITLE>Bericht</TITLE><meta name="Author" content="Jo &quot;J&quot; Li"></head>"#);
+        assert_eq!(meta.title.as_deref(), Some("Bericht"));
+        assert_eq!(meta.author.as_deref(), Some("Jo \"J\" Li"));
+        assert_eq!(meta.language.as_deref(), Some("de"));
+
+        assert_eq!(extract("csv", b"a,b\n1, 2\n").word_count, Some(3));
+    }
+}
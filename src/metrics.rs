@@ -0,0 +1,354 @@
+//! Prometheus metrics, rendered in the text exposition format at `GET /metrics`.
+
+use axum::{
+    extract::State,
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::apikeys;
+use crate::error::ConversionError;
+use crate::jobs::JobStatus;
+use crate::AppState;
+
+const DURATION_BUCKETS: &[f64] = &[0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0];
+const SIZE_BUCKETS: &[f64] = &[
+    10_240.0,
+    102_400.0,
+    1_048_576.0,
+    5_242_880.0,
+    10_485_760.0,
+    52_428_800.0,
+    104_857_600.0,
+];
+
+#[derive(Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// A counter family with a single label, e.g. `conversions_total{status="success"}`.
+pub struct LabeledCounter {
+    label: &'static str,
+    values: Mutex<BTreeMap<String, u64>>,
+}
+
+impl LabeledCounter {
+    fn new(label: &'static str) -> Self {
+        Self {
+            label,
+            values: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn inc(&self, value: &str) {
+        *self.values.lock().unwrap().entry(value.to_string()).or_insert(0) += 1;
+    }
+
+    pub fn get(&self, value: &str) -> u64 {
+        self.values.lock().unwrap().get(value).copied().unwrap_or(0)
+    }
+}
+
+#[derive(Default)]
+pub struct Gauge(AtomicI64);
+
+impl Gauge {
+    pub fn get(&self) -> i64 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Increments the gauge until the returned guard is dropped.
+    pub fn track(&self) -> GaugeGuard<'_> {
+        self.0.fetch_add(1, Ordering::Relaxed);
+        GaugeGuard(self)
+    }
+}
+
+pub struct GaugeGuard<'a>(&'a Gauge);
+
+impl Drop for GaugeGuard<'_> {
+    fn drop(&mut self) {
+        self.0 .0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+pub struct Histogram {
+    buckets: &'static [f64],
+    inner: Mutex<HistogramInner>,
+}
+
+struct HistogramInner {
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl HistogramInner {
+    fn new(buckets: &[f64]) -> Self {
+        Self {
+            counts: vec![0; buckets.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    fn observe(&mut self, buckets: &[f64], value: f64) {
+        for (i, bound) in buckets.iter().enumerate() {
+            if value <= *bound {
+                self.counts[i] += 1;
+            }
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+impl Histogram {
+    fn new(buckets: &'static [f64]) -> Self {
+        Self {
+            buckets,
+            inner: Mutex::new(HistogramInner::new(buckets)),
+        }
+    }
+
+    pub fn observe(&self, value: f64) {
+        self.inner.lock().unwrap().observe(self.buckets, value);
+    }
+}
+
+/// A histogram family with a single label, e.g. `conversion_duration_seconds{storage="tmpfs"}`.
+pub struct LabeledHistogram {
+    label: &'static str,
+    buckets: &'static [f64],
+    series: Mutex<BTreeMap<String, HistogramInner>>,
+}
+
+impl LabeledHistogram {
+    fn new(label: &'static str, buckets: &'static [f64]) -> Self {
+        Self {
+            label,
+            buckets,
+            series: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn observe(&self, value: &str, observed: f64) {
+        let mut series = self.series.lock().unwrap();
+        let inner = series.entry(value.to_string()).or_insert_with(|| HistogramInner::new(self.buckets));
+        inner.observe(self.buckets, observed);
+    }
+}
+
+pub struct Metrics {
+    pub conversions: LabeledCounter,
+    /// By the kind of storage the work directory was on (see `disk::storage_kind`).
+    pub conversion_duration: LabeledHistogram,
+    pub upload_bytes: Histogram,
+    pub active_conversions: Gauge,
+    pub conversion_slots_in_use: Gauge,
+    pub conversion_slots_waiting: Gauge,
+    pub auth_failures: Counter,
+    pub rate_limited: Counter,
+    /// By `ClientIdentity`; requests limited by IP are left out, as there is no bound on their number.
+    pub rate_limit_hits: LabeledCounter,
+    pub health_checks: Counter,
+    pub cache_hits: Counter,
+    pub cache_misses: Counter,
+    /// Cache hits found from the hash taken as the upload arrived, without reading it back.
+    pub cache_skip_disk: Counter,
+    /// Conversions answered by waiting for an identical one under way.
+    pub conversions_deduplicated: Counter,
+    /// Async jobs cancelled with `DELETE /jobs/{id}` or for exceeding `JOB_MAX_AGE_SECS`.
+    pub jobs_force_cancelled: Counter,
+    pub leaked_dirs_cleaned: Counter,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self {
+            conversions: LabeledCounter::new("status"),
+            conversion_duration: LabeledHistogram::new("storage", DURATION_BUCKETS),
+            upload_bytes: Histogram::new(SIZE_BUCKETS),
+            active_conversions: Gauge::default(),
+            conversion_slots_in_use: Gauge::default(),
+            conversion_slots_waiting: Gauge::default(),
+            auth_failures: Counter::default(),
+            rate_limited: Counter::default(),
+            rate_limit_hits: LabeledCounter::new("key_id"),
+            health_checks: Counter::default(),
+            cache_hits: Counter::default(),
+            cache_misses: Counter::default(),
+            cache_skip_disk: Counter::default(),
+            conversions_deduplicated: Counter::default(),
+            jobs_force_cancelled: Counter::default(),
+            leaked_dirs_cleaned: Counter::default(),
+        }
+    }
+}
+
+impl Metrics {
+    pub fn render(&self, queue_depth: usize) -> String {
+        let mut out = String::new();
+        write_labeled(&mut out, "conversions_total", "Conversions by outcome.", &self.conversions);
+        write_labeled_histogram(
+            &mut out,
+            "conversion_duration_seconds",
+            "Time spent converting a single file, by the storage (tmpfs or disk) it was converted on.",
+            &self.conversion_duration,
+        );
+        write_histogram(&mut out, "upload_bytes", "Size of uploaded files.", &self.upload_bytes);
+        write_gauge(&mut out, "active_conversions", "Conversions currently running.", self.active_conversions.get());
+        write_gauge(
+            &mut out,
+            "conversion_slots_in_use",
+            "Requests holding one of the MAX_CONCURRENT_CONVERSIONS slots.",
+            self.conversion_slots_in_use.get(),
+        );
+        write_gauge(
+            &mut out,
+            "conversion_slots_waiting",
+            "Async jobs waiting for a conversion slot.",
+            self.conversion_slots_waiting.get(),
+        );
+        write_gauge(&mut out, "queue_depth", "Async jobs waiting to start.", queue_depth as i64);
+        write_counter(&mut out, "auth_failures_total", "Requests rejected by authentication.", self.auth_failures.get());
+        write_counter(&mut out, "rate_limited_total", "Requests rejected by the rate limit.", self.rate_limited.get());
+        write_labeled(
+            &mut out,
+            "rate_limit_hits_total",
+            "Requests of authenticated clients rejected by the rate limit, by API key id or token subject.",
+            &self.rate_limit_hits,
+        );
+        write_counter(&mut out, "health_checks_total", "Health check requests served.", self.health_checks.get());
+        write_counter(&mut out, "cache_hit_total", "Conversions answered from the result cache.", self.cache_hits.get());
+        write_counter(&mut out, "cache_miss_total", "Cacheable conversions that were not in the result cache.", self.cache_misses.get());
+        write_counter(
+            &mut out,
+            "cache_skip_disk_total",
+            "Cache hits found from the upload's hash as it arrived, without reading the upload back.",
+            self.cache_skip_disk.get(),
+        );
+        write_counter(
+            &mut out,
+            "conversions_deduplicated_total",
+            "Conversions answered with the result of an identical one under way, without running LibreOffice.",
+            self.conversions_deduplicated.get(),
+        );
+        write_counter(
+            &mut out,
+            "jobs_force_cancelled_total",
+            "Async jobs cancelled with DELETE /jobs/{id} or for exceeding JOB_MAX_AGE_SECS.",
+            self.jobs_force_cancelled.get(),
+        );
+        write_counter(
+            &mut out,
+            "leaked_dirs_cleaned_total",
+            "Work directories left behind by a crash and removed by the cleanup scan.",
+            self.leaked_dirs_cleaned.get(),
+        );
+        out
+    }
+}
+
+fn write_counter(out: &mut String, name: &str, help: &str, value: u64) {
+    let _ = writeln!(out, "# HELP {} {}\n# TYPE {} counter\n{} {}", name, help, name, name, value);
+}
+
+fn write_gauge(out: &mut String, name: &str, help: &str, value: i64) {
+    let _ = writeln!(out, "# HELP {} {}\n# TYPE {} gauge\n{} {}", name, help, name, name, value);
+}
+
+fn write_labeled(out: &mut String, name: &str, help: &str, counter: &LabeledCounter) {
+    let _ = writeln!(out, "# HELP {} {}\n# TYPE {} counter", name, help, name);
+    for (value, count) in counter.values.lock().unwrap().iter() {
+        let _ = writeln!(out, "{}{{{}=\"{}\"}} {}", name, counter.label, escape_label(value), count);
+    }
+}
+
+fn write_histogram(out: &mut String, name: &str, help: &str, histogram: &Histogram) {
+    let _ = writeln!(out, "# HELP {} {}\n# TYPE {} histogram", name, help, name);
+    write_histogram_series(out, name, "", histogram.buckets, &histogram.inner.lock().unwrap());
+}
+
+fn write_labeled_histogram(out: &mut String, name: &str, help: &str, histogram: &LabeledHistogram) {
+    let _ = writeln!(out, "# HELP {} {}\n# TYPE {} histogram", name, help, name);
+    for (value, inner) in histogram.series.lock().unwrap().iter() {
+        let labels = format!("{}=\"{}\"", histogram.label, escape_label(value));
+        write_histogram_series(out, name, &labels, histogram.buckets, inner);
+    }
+}
+
+/// One series of a histogram; `labels` (`name="value"`, or empty) go before `le`.
+fn write_histogram_series(out: &mut String, name: &str, labels: &str, buckets: &[f64], inner: &HistogramInner) {
+    let prefix = if labels.is_empty() { String::new() } else { format!("{},", labels) };
+    for (bound, count) in buckets.iter().zip(&inner.counts) {
+        let _ = writeln!(out, "{}_bucket{{{}le=\"{}\"}} {}", name, prefix, bound, count);
+    }
+    let _ = writeln!(out, "{}_bucket{{{}le=\"+Inf\"}} {}", name, prefix, inner.count);
+    let labels = if labels.is_empty() { String::new() } else { format!("{{{}}}", labels) };
+    let _ = writeln!(out, "{}_sum{} {}", name, labels, inner.sum);
+    let _ = writeln!(out, "{}_count{} {}", name, labels, inner.count);
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+pub async fn metrics(State(state): State<Arc<AppState>>, headers: HeaderMap) -> Response {
+    // Either key will do once one of them is set
+    let presented = apikeys::from_headers(&headers).unwrap_or_default();
+    let metrics_key = state.metrics_api_key.as_deref().is_some_and(|key| apikeys::matches(key, presented));
+    if (state.metrics_api_key.is_some() || state.admin_api_key.is_some()) && !metrics_key && !state.is_admin_key(presented) {
+        state.metrics.auth_failures.inc();
+        return ConversionError::new(StatusCode::UNAUTHORIZED, "UNAUTHORIZED", "Unauthorized").into_response();
+    }
+
+    let body = state.metrics.render(state.jobs.count(JobStatus::Pending));
+    ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], body).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::default();
+        metrics.conversions.inc("success");
+        metrics.conversions.inc("success");
+        metrics.conversions.inc("failure");
+        metrics.conversion_duration.observe("tmpfs", 0.7);
+        metrics.upload_bytes.observe(2048.0);
+        let active = metrics.active_conversions.track();
+
+        let text = metrics.render(3);
+        assert!(text.contains("conversions_total{status=\"success\"} 2\n"));
+        assert!(text.contains("conversions_total{status=\"failure\"} 1\n"));
+        assert_eq!((metrics.conversions.get("success"), metrics.conversions.get("timeout")), (2, 0));
+        assert!(text.contains("conversion_duration_seconds_bucket{storage=\"tmpfs\",le=\"0.5\"} 0\n"));
+        assert!(text.contains("conversion_duration_seconds_bucket{storage=\"tmpfs\",le=\"1\"} 1\n"));
+        assert!(text.contains("conversion_duration_seconds_sum{storage=\"tmpfs\"} 0.7\n"));
+        assert!(text.contains("conversion_duration_seconds_count{storage=\"tmpfs\"} 1\n"));
+        assert!(!text.contains("storage=\"disk\""));
+        assert!(text.contains("upload_bytes_bucket{le=\"10240\"} 1\n"));
+        assert!(text.contains("upload_bytes_count 1\n"));
+        assert!(text.contains("active_conversions 1\n"));
+        assert!(text.contains("queue_depth 3\n"));
+
+        drop(active);
+        assert_eq!(metrics.active_conversions.get(), 0);
+    }
+}
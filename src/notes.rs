@@ -0,0 +1,312 @@
+//! `POST /notes`: the speaker notes of each slide of a presentation, read straight from the file
+//! without running LibreOffice.
+//!
+//! PowerPoint keeps each slide's notes in a notes slide (`ppt/notesSlides/notesSlideN.xml`),
+//! linked from the slide's relationships, with the text in the shape holding the `body`
+//! placeholder. Slides are listed in order in `ppt/presentation.xml`. Impress keeps everything in
+//! `content.xml`, a `<presentation:notes>` inside each `<draw:page>`.
+
+use axum::{
+    extract::{Multipart, State},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use serde_json::{json, Value};
+use std::sync::Arc;
+use tokio::fs;
+use tracing::{error, info_span, Instrument};
+
+use crate::error::ConversionError;
+use crate::meta::{attribute_value, unescape};
+use crate::telemetry::RequestId;
+use crate::zip::ZipReader;
+use crate::{filename_header, receive_upload, AppState};
+
+/// Larger XML parts are skipped (`content.xml` of an Impress file holds every slide).
+const MAX_PART_LEN: usize = 16 * 1024 * 1024;
+
+/// The input types notes can be read from.
+const NOTES_TYPES: &[&str] = &["pptx", "odp"];
+
+pub async fn notes(
+    State(state): State<Arc<AppState>>,
+    Extension(RequestId(request_id)): Extension<RequestId>,
+    headers: HeaderMap,
+    mut multipart: Multipart,
+) -> Response {
+    let _active_dir = state.active_dirs.track(request_id);
+    let work_dir = state.request_dir(request_id);
+    if let Err(e) = fs::create_dir_all(&work_dir).await {
+        error!("Failed to create work dir: {}", e);
+        return ConversionError::internal().into_response();
+    }
+    let accepted: Vec<String> = state.allowed_input_types.iter().filter(|t| NOTES_TYPES.contains(&t.as_str())).cloned().collect();
+    let result = async {
+        let upload = receive_upload(&mut multipart, filename_header(&headers).as_deref(), &work_dir, &state, Some(&accepted))
+            .instrument(info_span!("multipart"))
+            .await?;
+        let [ref file] = upload.files[..] else {
+            return Err(ConversionError::new(StatusCode::BAD_REQUEST, "TOO_MANY_FILES", "Notes are read from one file at a time"));
+        };
+        let content = fs::read(&file.path).await.map_err(|e| {
+            error!("Failed to read upload: {}", e);
+            ConversionError::internal()
+        })?;
+        let kind = file.kind;
+        let notes = tokio::task::spawn_blocking(move || extract(kind, &content)).await.map_err(|e| {
+            error!("Notes extraction panicked: {}", e);
+            ConversionError::internal()
+        })?;
+        let slides: Vec<Value> = notes.into_iter().enumerate().map(|(i, notes)| json!({ "slide": i + 1, "notes": notes })).collect();
+        Ok(json!({ "slides": slides }))
+    }
+    .await;
+    let _ = fs::remove_dir_all(&work_dir).await;
+
+    match result {
+        Ok(body) => Json(body).into_response(),
+        Err(e) => e.into_response(),
+    }
+}
+
+/// The notes of each slide of a `kind` presentation, in order; `None` for a slide without any.
+pub fn extract(kind: &str, content: &[u8]) -> Vec<Option<String>> {
+    let Some(zip) = ZipReader::new(content) else {
+        return Vec::new();
+    };
+    let part = |name: &str| zip.read(name, MAX_PART_LEN).map(|xml| String::from_utf8_lossy(&xml).into_owned());
+    match kind {
+        "pptx" => pptx(&part),
+        "odp" => odp(&part("content.xml").unwrap_or_default()),
+        _ => Vec::new(),
+    }
+}
+
+fn pptx(part: &dyn Fn(&str) -> Option<String>) -> Vec<Option<String>> {
+    let presentation = part("ppt/presentation.xml").unwrap_or_default();
+    let presentation_relationships = relationships(&part("ppt/_rels/presentation.xml.rels").unwrap_or_default());
+    start_tags(&presentation, "p:sldId")
+        .filter_map(|tag| {
+            let id = attribute_value(tag, "r:id")?;
+            presentation_relationships.iter().find(|(rel_id, _, _)| *rel_id == id).map(|(_, _, target)| resolve("ppt", target))
+        })
+        .map(|slide| {
+            let (dir, name) = slide.rsplit_once('/')?;
+            let slide_relationships = relationships(&part(&format!("{}/_rels/{}.rels", dir, name))?);
+            let (_, _, target) = slide_relationships.iter().find(|(_, kind, _)| kind.ends_with("/notesSlide"))?;
+            notes_slide_text(&part(&resolve(dir, target))?)
+        })
+        .collect()
+}
+
+/// The text of the body placeholder of a notes slide, a line per paragraph.
+fn notes_slide_text(xml: &str) -> Option<String> {
+    let body = elements(xml, "p:sp").into_iter().find(|shape| {
+        start_tags(shape, "p:ph").any(|ph| attribute_value(ph, "type").as_deref() == Some("body"))
+    })?;
+    let paragraphs: Vec<String> = elements(body, "a:p").into_iter().map(drawingml_text).collect();
+    non_empty(paragraphs.join("\n"))
+}
+
+/// The text runs of a DrawingML paragraph, with line breaks.
+fn drawingml_text(paragraph: &str) -> String {
+    let mut text = String::new();
+    let mut rest = paragraph;
+    while let Some(at) = rest.find('<') {
+        let Some(end) = rest[at..].find('>') else {
+            break;
+        };
+        let tag = &rest[at + 1..at + end];
+        rest = &rest[at + end + 1..];
+        if tag == "a:t" || tag.starts_with("a:t ") {
+            let close = rest.find("</a:t>").unwrap_or(rest.len());
+            text.push_str(&unescape(&rest[..close]));
+            rest = &rest[close..];
+        } else if tag.starts_with("a:br") {
+            text.push('\n');
+        }
+    }
+    text
+}
+
+fn odp(content: &str) -> Vec<Option<String>> {
+    elements(content, "draw:page")
+        .into_iter()
+        .map(|page| {
+            let notes = elements(page, "presentation:notes").into_iter().next()?;
+            let paragraphs: Vec<String> = elements(notes, "text:p").into_iter().map(odf_text).collect();
+            non_empty(paragraphs.join("\n"))
+        })
+        .collect()
+}
+
+/// The text of an ODF paragraph, with its spaces, tabs and line breaks; other markup (spans,
+/// links) only wraps text and is dropped.
+fn odf_text(paragraph: &str) -> String {
+    let mut text = String::new();
+    let mut rest = paragraph;
+    while let Some(at) = rest.find('<') {
+        text.push_str(&unescape(&rest[..at]));
+        let Some(end) = rest[at..].find('>') else {
+            rest = "";
+            break;
+        };
+        let tag = &rest[at + 1..at + end];
+        rest = &rest[at + end + 1..];
+        if tag.starts_with("text:s") && !tag.starts_with("text:span") {
+            let count = attribute_value(tag, "text:c").and_then(|c| c.parse().ok()).unwrap_or(1);
+            text.extend(std::iter::repeat_n(' ', count));
+        } else if tag.starts_with("text:tab") {
+            text.push('\t');
+        } else if tag.starts_with("text:line-break") {
+            text.push('\n');
+        }
+    }
+    text.push_str(&unescape(rest));
+    text
+}
+
+fn non_empty(text: String) -> Option<String> {
+    let trimmed = text.trim();
+    (!trimmed.is_empty()).then(|| trimmed.to_string())
+}
+
+/// The `(Id, Type, Target)` of each relationship in a `.rels` part.
+fn relationships(xml: &str) -> Vec<(String, String, String)> {
+    start_tags(xml, "Relationship")
+        .filter_map(|tag| Some((attribute_value(tag, "Id")?, attribute_value(tag, "Type")?, attribute_value(tag, "Target")?)))
+        .collect()
+}
+
+/// A relationship `target` as a part name: absolute, or relative to the source part's `dir`.
+fn resolve(dir: &str, target: &str) -> String {
+    if let Some(absolute) = target.strip_prefix('/') {
+        return absolute.to_string();
+    }
+    let mut segments: Vec<&str> = dir.split('/').filter(|s| !s.is_empty()).collect();
+    for segment in target.split('/') {
+        match segment {
+            ".." => {
+                segments.pop();
+            }
+            "." | "" => {}
+            segment => segments.push(segment),
+        }
+    }
+    segments.join("/")
+}
+
+/// The start tags (`<name ...>`, without the brackets' contents past the name) of each `name`
+/// element in `xml`.
+fn start_tags<'a>(xml: &'a str, name: &str) -> impl Iterator<Item = &'a str> {
+    let open = format!("<{}", name);
+    let mut from = 0;
+    std::iter::from_fn(move || {
+        loop {
+            let start = from + xml[from..].find(&open)?;
+            let after = start + open.len();
+            let end = after + xml[after..].find('>')?;
+            from = end + 1;
+            // Skip `<p:sldIdLst>` when looking for `<p:sldId>`
+            if xml[after..].starts_with(['>', ' ', '\t', '\r', '\n', '/']) {
+                return Some(&xml[start..end]);
+            }
+        }
+    })
+}
+
+/// The content of each `name` element in `xml` (empty for `<name/>`). Elements of the same name
+/// nested in one another are not told apart.
+fn elements<'a>(xml: &'a str, name: &str) -> Vec<&'a str> {
+    let close = format!("</{}>", name);
+    let mut found = Vec::new();
+    let mut from = 0;
+    for tag in start_tags(xml, name) {
+        // `tag` borrows from `xml`, so its end is a position in it
+        let tag_end = tag.as_ptr() as usize - xml.as_ptr() as usize + tag.len();
+        if tag_end < from {
+            continue;
+        }
+        if tag.ends_with('/') {
+            found.push("");
+            from = tag_end;
+            continue;
+        }
+        let Some(len) = xml[tag_end + 1..].find(&close) else {
+            break;
+        };
+        found.push(&xml[tag_end + 1..tag_end + 1 + len]);
+        from = tag_end + 1 + len;
+    }
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::zip::ZipWriter;
+
+    const RELS: &str = "http://schemas.openxmlformats.org/officeDocument/2006/relationships";
+
+    fn pptx_sample() -> Vec<u8> {
+        let mut zip = ZipWriter::new();
+        zip.add(
+            "ppt/presentation.xml",
+            br#"<p:presentation><p:sldIdLst><p:sldId id="257" r:id="rId3"/><p:sldId id="256" r:id="rId2"/></p:sldIdLst></p:presentation>"#,
+        );
+        zip.add(
+            "ppt/_rels/presentation.xml.rels",
+            format!(
+                r#"<Relationships><Relationship Id="rId2" Type="{0}/slide" Target="slides/slide1.xml"/><Relationship Id="rId3" Type="{0}/slide" Target="/ppt/slides/slide2.xml"/></Relationships>"#,
+                RELS
+            )
+            .as_bytes(),
+        );
+        zip.add("ppt/slides/slide1.xml", b"<p:sld/>");
+        zip.add("ppt/slides/slide2.xml", b"<p:sld/>");
+        zip.add(
+            "ppt/slides/_rels/slide2.xml.rels",
+            format!(r#"<Relationships><Relationship Id="rId2" Type="{}/notesSlide" Target="../notesSlides/notesSlide1.xml"/></Relationships>"#, RELS)
+                .as_bytes(),
+        );
+        zip.add(
+            "ppt/notesSlides/notesSlide1.xml",
+            br#"<p:notes><p:cSld><p:spTree>
+<p:sp><p:nvSpPr><p:nvPr><p:ph type="sldImg"/></p:nvPr></p:nvSpPr></p:sp>
+<p:sp><p:nvSpPr><p:nvPr><p:ph type="body" idx="1"/></p:nvPr></p:nvSpPr><p:txBody><a:p><a:r><a:t>Mention Q3 </a:t></a:r><a:r><a:rPr b="1"/><a:t>&amp; Q4</a:t></a:r></a:p><a:p><a:r><a:t>Then</a:t></a:r><a:br/><a:r><a:t>questions</a:t></a:r></a:p></p:txBody></p:sp>
+<p:sp><p:nvSpPr><p:nvPr><p:ph type="sldNum" idx="5"/></p:nvPr></p:nvSpPr><p:txBody><a:p><a:r><a:t>2</a:t></a:r></a:p></p:txBody></p:sp>
+</p:spTree></p:cSld></p:notes>"#,
+        );
+        zip.finish()
+    }
+
+    #[test]
+    fn test_pptx() {
+        // Presentation order, not file names: slide2.xml comes first
+        assert_eq!(extract("pptx", &pptx_sample()), [Some("Mention Q3 & Q4\nThen\nquestions".to_string()), None]);
+        assert!(extract("pptx", b"not a zip").is_empty());
+    }
+
+    #[test]
+    fn test_odp() {
+        let mut zip = ZipWriter::new();
+        zip.add("mimetype", b"application/vnd.oasis.opendocument.presentation");
+        zip.add(
+            "content.xml",
+            br#"<office:document-content><office:body><office:presentation>
+<draw:page draw:name="page1"><draw:frame><draw:text-box><text:p>Slide text</text:p></draw:text-box></draw:frame>
+<presentation:notes><draw:page-thumbnail draw:page-number="1"/><draw:frame presentation:class="notes"><draw:text-box><text:p>Say <text:span text:style-name="T1">hello</text:span><text:s text:c="2"/>first</text:p><text:p/><text:p>Then&lt;demo&gt;</text:p></draw:text-box></draw:frame></presentation:notes></draw:page>
+<draw:page draw:name="page2"><presentation:notes><draw:page-thumbnail draw:page-number="2"/></presentation:notes></draw:page>
+</office:presentation></office:body></office:document-content>"#,
+        );
+        assert_eq!(extract("odp", &zip.finish()), [Some("Say hello  first\n\nThen<demo>".to_string()), None]);
+    }
+
+    #[test]
+    fn test_resolve() {
+        assert_eq!(resolve("ppt", "slides/slide1.xml"), "ppt/slides/slide1.xml");
+        assert_eq!(resolve("ppt/slides", "../notesSlides/notesSlide1.xml"), "ppt/notesSlides/notesSlide1.xml");
+        assert_eq!(resolve("ppt/slides", "/ppt/notesSlides/notesSlide1.xml"), "ppt/notesSlides/notesSlide1.xml");
+    }
+}
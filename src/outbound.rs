@@ -0,0 +1,407 @@
+//! Outgoing HTTP requests, made by shelling out to `curl` (the same way conversions shell out to
+//! LibreOffice) so that HTTPS, proxies and system CA certificates work without extra crates.
+//!
+//! URLs that callers supply (`url` inputs and `callback_url`s) must not reach into the network
+//! the service runs in: their host is resolved here, refused if any of its addresses is internal
+//! (loopback, link-local such as the cloud metadata endpoint, private, unique-local or
+//! unspecified) unless `OUTBOUND_ALLOWED_NETWORKS` lists it, and the request is then pinned to the
+//! checked address with curl's `--resolve`, so a second DNS answer cannot swap it. Redirects are
+//! followed here rather than by curl, so that each hop is checked the same way.
+
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::Path;
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+/// AWS Signature Version 4 credentials, signed by curl itself.
+pub struct AwsSigV4<'a> {
+    pub region: &'a str,
+    pub service: &'a str,
+    pub access_key: &'a str,
+    pub secret_key: &'a str,
+}
+
+/// curl's exit code when the response exceeds `--max-filesize`.
+const CURL_FILESIZE_EXCEEDED: i32 = 63;
+
+/// Redirects followed by `download`.
+const MAX_REDIRECTS: usize = 5;
+
+#[derive(Debug, PartialEq)]
+pub enum DownloadError {
+    TooLarge,
+    Status(u16),
+    /// The URL, or one it redirected to, is not allowed: its scheme, or the address it resolves to.
+    Refused(String),
+    Failed(String),
+}
+
+/// Internal networks that caller-supplied URLs may reach all the same (`OUTBOUND_ALLOWED_NETWORKS`),
+/// as CIDR blocks.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AllowedNetworks(Vec<(IpAddr, u8)>);
+
+impl AllowedNetworks {
+    /// Parses a comma-separated list such as `10.1.0.0/16, 192.168.1.20, fd00::/8`; an address
+    /// without a prefix length stands for itself alone.
+    pub fn parse(raw: Option<&str>) -> Result<Self, String> {
+        let mut networks = Vec::new();
+        for item in raw.unwrap_or_default().split(',').map(str::trim).filter(|item| !item.is_empty()) {
+            let invalid = || format!("Invalid network {:?} in OUTBOUND_ALLOWED_NETWORKS: expected an address or CIDR block", item);
+            let (addr, prefix) = item.split_once('/').unwrap_or((item, ""));
+            let addr: IpAddr = addr.parse().map_err(|_| invalid())?;
+            let max = if addr.is_ipv4() { 32 } else { 128 };
+            let prefix = if prefix.is_empty() { max } else { prefix.parse().map_err(|_| invalid())? };
+            if prefix > max {
+                return Err(invalid());
+            }
+            networks.push((addr, prefix));
+        }
+        Ok(Self(networks))
+    }
+
+    fn contains(&self, ip: IpAddr) -> bool {
+        self.0.iter().any(|&(network, prefix)| match (network, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        })
+    }
+}
+
+/// Whether `ip` belongs to the deployment's own network rather than the internet.
+fn is_internal(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_internal_v4(ip),
+        IpAddr::V6(ip) => {
+            if let Some(v4) = ip.to_ipv4_mapped() {
+                return is_internal_v4(v4);
+            }
+            let first = ip.segments()[0];
+            ip.is_loopback()
+                || ip.is_unspecified()
+                // Unique local fc00::/7 and link-local fe80::/10
+                || (first & 0xfe00) == 0xfc00
+                || (first & 0xffc0) == 0xfe80
+        }
+    }
+}
+
+fn is_internal_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        || ip.is_broadcast()
+        // "This network" 0.0.0.0/8 and carrier-grade NAT 100.64.0.0/10
+        || a == 0
+        || (a == 100 && (b & 0xc0) == 64)
+}
+
+/// A URL's host with the address it was checked at.
+#[derive(Debug, PartialEq)]
+pub struct Pinned {
+    host: String,
+    port: u16,
+    addr: IpAddr,
+    /// Whether the host is the address itself, which needs no pinning.
+    literal: bool,
+}
+
+impl Pinned {
+    /// curl arguments that make it connect to the checked address.
+    fn curl_args(&self) -> Vec<String> {
+        if self.literal {
+            return Vec::new();
+        }
+        let addr = match self.addr {
+            IpAddr::V4(addr) => addr.to_string(),
+            IpAddr::V6(addr) => format!("[{}]", addr),
+        };
+        vec!["--resolve".to_string(), format!("{}:{}:{}", self.host, self.port, addr)]
+    }
+}
+
+/// The host and port of an absolute `http(s)://` URL, without IPv6 brackets.
+fn host_and_port(url: &str) -> Option<(String, u16)> {
+    let (scheme, rest) = url.split_once("://")?;
+    let default_port = if scheme.eq_ignore_ascii_case("http") { 80 } else { 443 };
+    let authority = rest.split(['/', '?', '#']).next()?;
+    let authority = authority.rsplit_once('@').map_or(authority, |(_, host)| host);
+    let (host, port) = match authority.strip_prefix('[') {
+        Some(bracketed) => {
+            let (host, after) = bracketed.split_once(']')?;
+            (host, after.strip_prefix(':'))
+        }
+        None => match authority.rsplit_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (authority, None),
+        },
+    };
+    let port = match port {
+        Some(port) => port.parse().ok()?,
+        None => default_port,
+    };
+    (!host.is_empty()).then(|| (host.to_ascii_lowercase(), port))
+}
+
+/// Resolves the host of `url` and checks every address it has, returning the one to connect to.
+/// Internal addresses are refused unless `allowed` lists them.
+pub async fn resolve(url: &str, allowed: &AllowedNetworks) -> Result<Pinned, String> {
+    let (host, port) = host_and_port(url).ok_or("the URL has no valid host")?;
+    let literal = host.parse::<IpAddr>().ok();
+    let addrs: Vec<IpAddr> = match literal {
+        Some(addr) => vec![addr],
+        None => tokio::net::lookup_host((host.as_str(), port))
+            .await
+            .map_err(|e| format!("{} cannot be resolved: {}", host, e))?
+            .map(|addr| addr.ip())
+            .collect(),
+    };
+    let Some(&addr) = addrs.first() else {
+        return Err(format!("{} has no addresses", host));
+    };
+    if let Some(internal) = addrs.iter().find(|&&addr| is_internal(addr) && !allowed.contains(addr)) {
+        return Err(format!("{} resolves to the internal address {}", host, internal));
+    }
+    Ok(Pinned { host, port, addr, literal: literal.is_some() })
+}
+
+/// Whether `url` is an absolute `https://` URL (or `http://` if `allow_http`) with a host.
+pub fn is_allowed_url(url: &str, allow_http: bool) -> bool {
+    let (scheme, rest) = url.split_once("://").unwrap_or_default();
+    let allowed = scheme.eq_ignore_ascii_case("https") || (allow_http && scheme.eq_ignore_ascii_case("http"));
+    let has_host = !rest.is_empty() && !rest.starts_with('/');
+    allowed && has_host && !url.chars().any(|c| c.is_whitespace() || c.is_control())
+}
+
+/// GETs `url` into the file at `path`, following redirects (each of which must be an allowed URL
+/// resolving to an allowed address, see [`resolve`]), and returns the number of bytes written.
+pub async fn download(
+    url: &str,
+    path: &Path,
+    max_bytes: u64,
+    timeout: Duration,
+    allow_http: bool,
+    allowed: &AllowedNetworks,
+) -> Result<u64, DownloadError> {
+    let protocols = if allow_http { "=http,https" } else { "=https" };
+    let deadline = tokio::time::Instant::now() + timeout;
+    let mut url = url.to_string();
+    let mut redirects = 0;
+    let status = loop {
+        if !is_allowed_url(&url, allow_http) {
+            return Err(DownloadError::Refused(format!("{} is not an allowed URL", url)));
+        }
+        let pinned = resolve(&url, allowed).await.map_err(DownloadError::Refused)?;
+        let remaining = deadline.saturating_duration_since(tokio::time::Instant::now());
+        let out = Command::new("curl")
+            .arg("--silent")
+            .arg("--show-error")
+            .arg("--proto")
+            .arg(protocols)
+            .arg("--max-filesize")
+            .arg(max_bytes.to_string())
+            .arg("--max-time")
+            .arg(remaining.as_secs().max(1).to_string())
+            .args(pinned.curl_args())
+            .arg("--output")
+            .arg(path)
+            .arg("--write-out")
+            .arg("%{http_code}\n%{redirect_url}")
+            .arg("--url")
+            .arg(&url)
+            .stdin(Stdio::null())
+            .kill_on_drop(true)
+            .output()
+            .await
+            .map_err(|e| DownloadError::Failed(format!("failed to run curl: {}", e)))?;
+
+        if out.status.code() == Some(CURL_FILESIZE_EXCEEDED) {
+            return Err(DownloadError::TooLarge);
+        }
+        if !out.status.success() {
+            return Err(DownloadError::Failed(String::from_utf8_lossy(&out.stderr).trim().to_string()));
+        }
+        let written = String::from_utf8_lossy(&out.stdout).to_string();
+        let (status, location) = written.split_once('\n').unwrap_or((&written, ""));
+        let status: u16 = status.trim().parse().unwrap_or_default();
+        let location = location.trim();
+        if !(300..400).contains(&status) || location.is_empty() {
+            break status;
+        }
+        if redirects == MAX_REDIRECTS {
+            return Err(DownloadError::Failed(format!("more than {} redirects", MAX_REDIRECTS)));
+        }
+        redirects += 1;
+        url = location.to_string();
+    };
+    if !(200..300).contains(&status) {
+        return Err(DownloadError::Status(status));
+    }
+    // `--max-filesize` cannot stop a response without Content-Length, so check afterwards too
+    let size = tokio::fs::metadata(path).await.map_err(|e| DownloadError::Failed(e.to_string()))?.len();
+    if size > max_bytes {
+        return Err(DownloadError::TooLarge);
+    }
+    Ok(size)
+}
+
+/// POSTs `body` to `url` and returns the HTTP status code of the response. With `pinned` (see
+/// [`resolve`]) the request goes to the address checked there.
+pub async fn post(
+    url: &str,
+    pinned: Option<&Pinned>,
+    content_type: &str,
+    headers: &[(&str, String)],
+    body: &[u8],
+    timeout: Duration,
+) -> Result<u16, String> {
+    send("POST", url, pinned, content_type, headers, None, body, timeout).await
+}
+
+/// PUTs `body` to `url`, signing the request with `auth`, and returns the HTTP status code.
+pub async fn put_signed(
+    url: &str,
+    content_type: &str,
+    headers: &[(&str, String)],
+    auth: &AwsSigV4<'_>,
+    body: &[u8],
+    timeout: Duration,
+) -> Result<u16, String> {
+    send("PUT", url, None, content_type, headers, Some(auth), body, timeout).await
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn send(
+    method: &str,
+    url: &str,
+    pinned: Option<&Pinned>,
+    content_type: &str,
+    headers: &[(&str, String)],
+    auth: Option<&AwsSigV4<'_>>,
+    body: &[u8],
+    timeout: Duration,
+) -> Result<u16, String> {
+    let mut command = Command::new("curl");
+    command
+        .arg("--silent")
+        .arg("--show-error")
+        .arg("--output")
+        .arg("/dev/null")
+        .arg("--write-out")
+        .arg("%{http_code}")
+        .arg("--max-time")
+        .arg(timeout.as_secs().max(1).to_string())
+        .arg("--request")
+        .arg(method)
+        .arg("--header")
+        .arg(format!("Content-Type: {}", content_type));
+    for (name, value) in headers {
+        command.arg("--header").arg(format!("{}: {}", name, value));
+    }
+    if let Some(auth) = auth {
+        // curl blanks the `--user` argument once parsed, so the secret does not linger in `ps`
+        command
+            .arg("--aws-sigv4")
+            .arg(format!("aws:amz:{}:{}", auth.region, auth.service))
+            .arg("--user")
+            .arg(format!("{}:{}", auth.access_key, auth.secret_key));
+    }
+    if let Some(pinned) = pinned {
+        command.args(pinned.curl_args());
+    }
+    // `--url` keeps a caller-supplied URL from ever being parsed as an option
+    command.arg("--data-binary").arg("@-").arg("--url").arg(url);
+
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| format!("failed to run curl: {}", e))?;
+
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(body).await.map_err(|e| format!("failed to send body: {}", e))?;
+    }
+    let out = child.wait_with_output().await.map_err(|e| format!("curl failed: {}", e))?;
+
+    if !out.status.success() {
+        return Err(String::from_utf8_lossy(&out.stderr).trim().to_string());
+    }
+    String::from_utf8_lossy(&out.stdout)
+        .trim()
+        .parse()
+        .map_err(|_| "unexpected curl output".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_allowed_url() {
+        assert!(is_allowed_url("https://example.com/a.docx", false));
+        assert!(!is_allowed_url("http://example.com/a.docx", false));
+        assert!(is_allowed_url("http://example.com/a.docx", true));
+        assert!(!is_allowed_url("file:///etc/passwd", true));
+        assert!(!is_allowed_url("https:///path", false));
+        assert!(!is_allowed_url("https://example.com/a b", false));
+        assert!(!is_allowed_url("-K /etc/passwd", false));
+    }
+
+    #[tokio::test]
+    async fn test_resolve() {
+        let none = AllowedNetworks::default();
+        for internal in [
+            "https://127.0.0.1/a.docx",
+            "http://169.254.169.254/latest/meta-data/",
+            "https://10.1.2.3:8443/",
+            "https://192.168.0.1/",
+            "https://172.16.0.1/",
+            "https://0.0.0.0/",
+            "https://[::1]/",
+            "https://[fd00::1]:8080/",
+            "https://[fe80::1]/",
+            "https://[::ffff:127.0.0.1]/",
+            "https://user:pw@localhost/",
+            "https://LOCALHOST:3000/hook",
+        ] {
+            let error = resolve(internal, &none).await.unwrap_err();
+            assert!(error.contains("internal address"), "{}: {}", internal, error);
+        }
+
+        let pinned = resolve("https://93.184.215.14:8443/a", &none).await.unwrap();
+        assert!(pinned.curl_args().is_empty(), "an address needs no pinning");
+        let pinned = Pinned { host: "example.com".to_string(), port: 443, addr: "2606:2800::1".parse().unwrap(), literal: false };
+        assert_eq!(pinned.curl_args(), ["--resolve", "example.com:443:[2606:2800::1]"]);
+
+        // Opting in
+        let allowed = AllowedNetworks::parse(Some("10.0.0.0/8, ::1")).unwrap();
+        assert!(resolve("https://10.1.2.3:8443/", &allowed).await.is_ok());
+        assert!(resolve("https://[::1]/", &allowed).await.is_ok());
+        assert!(resolve("https://127.0.0.1/", &allowed).await.is_err());
+        assert!(AllowedNetworks::parse(Some("10.0.0.0/33")).is_err());
+        assert!(AllowedNetworks::parse(Some("intranet")).is_err());
+        assert_eq!(AllowedNetworks::parse(None), Ok(AllowedNetworks::default()));
+    }
+
+    #[test]
+    fn test_host_and_port() {
+        assert_eq!(host_and_port("https://Example.com/a"), Some(("example.com".to_string(), 443)));
+        assert_eq!(host_and_port("http://example.com:8080?q"), Some(("example.com".to_string(), 8080)));
+        assert_eq!(host_and_port("https://u:p@[::1]:9000/"), Some(("::1".to_string(), 9000)));
+        assert_eq!(host_and_port("https:///path"), None);
+        assert_eq!(host_and_port("https://example.com:http/"), None);
+    }
+}
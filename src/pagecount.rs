@@ -0,0 +1,167 @@
+//! `POST /pagecount`: how many pages a document has, for pricing a conversion before running it.
+//!
+//! Presentations are counted from their structure (`<p:sldId>` in PPTX, `<draw:page>` in ODP and
+//! ODG), which is exact. Text documents use the page count their editor recorded on save (see
+//! `meta`), as pagination is only known once laid out. Everything else (spreadsheets, legacy
+//! binary formats, files saved without statistics) is rendered to PDF by the converter and its
+//! pages counted.
+
+use axum::{
+    extract::{Multipart, State},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use serde_json::json;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use tokio::fs;
+use tracing::{error, info_span, Instrument};
+
+use crate::error::ConversionError;
+use crate::telemetry::RequestId;
+use crate::zip::ZipReader;
+use crate::{busy_response, disk, export, meta, filename_header, receive_upload, run_conversion, shutting_down_response};
+use crate::{AppState, ConvertOptions};
+
+/// Presentation parts are rarely more than a few hundred KiB.
+const MAX_PART_LEN: usize = 16 * 1024 * 1024;
+
+/// The page count of a `kind` of document (as detected by `detect`), if it can be told without
+/// rendering it.
+pub fn count_in_document(kind: &str, content: &[u8]) -> Option<u64> {
+    let part = |name: &str| {
+        let zip = ZipReader::new(content)?;
+        zip.read(name, MAX_PART_LEN).map(|xml| String::from_utf8_lossy(&xml).into_owned())
+    };
+    match kind {
+        // The slide list, in presentation order; hidden slides are included
+        "pptx" => part("ppt/presentation.xml").map(|xml| count_elements(&xml, "p:sldId")),
+        "odp" | "odg" => part("content.xml").map(|xml| count_elements(&xml, "draw:page")),
+        "docx" | "odt" | "rtf" => meta::extract(kind, content).page_count,
+        _ => None,
+    }
+}
+
+/// How many `<name>` elements `xml` has, not counting longer names sharing the prefix.
+fn count_elements(xml: &str, name: &str) -> u64 {
+    let open = format!("<{}", name);
+    xml.match_indices(&open)
+        .filter(|&(at, _)| xml[at + open.len()..].starts_with(['>', ' ', '\t', '\r', '\n', '/']))
+        .count() as u64
+}
+
+pub async fn pagecount(
+    State(state): State<Arc<AppState>>,
+    Extension(RequestId(request_id)): Extension<RequestId>,
+    headers: HeaderMap,
+    mut multipart: Multipart,
+) -> Response {
+    if state.draining.load(Ordering::SeqCst) {
+        return shutting_down_response();
+    }
+    let Some(slot) = state.try_conversion_slot() else {
+        return busy_response();
+    };
+
+    let _active_dir = state.active_dirs.track(request_id);
+    let work_dir = state.request_dir(request_id);
+    if let Err(e) = fs::create_dir_all(&work_dir).await {
+        error!("Failed to create work dir: {}", e);
+        return ConversionError::internal().into_response();
+    }
+    let result = async {
+        disk::ensure_free_space(&work_dir, state.min_free_bytes)?;
+        let upload = receive_upload(&mut multipart, filename_header(&headers).as_deref(), &work_dir, &state, Some(&state.allowed_input_types))
+            .instrument(info_span!("multipart"))
+            .await?;
+        let [ref file] = upload.files[..] else {
+            return Err(ConversionError::new(StatusCode::BAD_REQUEST, "TOO_MANY_FILES", "Pages are counted in one file at a time"));
+        };
+        let content = fs::read(&file.path).await.map_err(|e| {
+            error!("Failed to read upload: {}", e);
+            ConversionError::internal()
+        })?;
+        let kind = file.kind;
+        let counted = tokio::task::spawn_blocking(move || count_in_document(kind, &content)).await.map_err(|e| {
+            error!("Page counting panicked: {}", e);
+            ConversionError::internal()
+        })?;
+        if let Some(count) = counted {
+            return Ok((count, "document"));
+        }
+        let pdf = run_conversion(&state, &work_dir, file, &ConvertOptions::default()).await?;
+        Ok((export::count_pdf_pages(&pdf.content) as u64, "rendered"))
+    }
+    .await;
+    drop(slot);
+    let _ = fs::remove_dir_all(&work_dir).instrument(info_span!("cleanup")).await;
+
+    match result {
+        Ok((page_count, source)) => Json(json!({ "page_count": page_count, "source": source })).into_response(),
+        Err(e) => e.into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::service::MockConversionService;
+    use crate::tests::test_state;
+    use crate::zip::ZipWriter;
+    use axum::{body::Body, extract::Request, http::header, routing::post, Router};
+    use serde_json::Value;
+    use tower::ServiceExt;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_count_in_document() {
+        let mut pptx = ZipWriter::new();
+        pptx.add(
+            "ppt/presentation.xml",
+            br#"<p:presentation><p:sldMasterIdLst><p:sldMasterId id="1" r:id="rId1"/></p:sldMasterIdLst><p:sldIdLst><p:sldId id="256" r:id="rId2"/><p:sldId id="257" r:id="rId3"/><p:sldId id="258" r:id="rId4"/></p:sldIdLst></p:presentation>"#,
+        );
+        assert_eq!(count_in_document("pptx", &pptx.finish()), Some(3));
+
+        let mut odp = ZipWriter::new();
+        odp.add(
+            "content.xml",
+            br#"<office:presentation><draw:page draw:name="a"><draw:page-thumbnail/></draw:page><draw:page draw:name="b"/></office:presentation>"#,
+        );
+        assert_eq!(count_in_document("odp", &odp.finish()), Some(2));
+
+        let mut docx = ZipWriter::new();
+        docx.add("docProps/app.xml", b"<Properties><Pages>7</Pages></Properties>");
+        assert_eq!(count_in_document("docx", &docx.finish()), Some(7));
+
+        // Saved without statistics, or never recorded: left to rendering
+        assert_eq!(count_in_document("docx", &ZipWriter::new().finish()), None);
+        assert_eq!(count_in_document("xlsx", &ZipWriter::new().finish()), None);
+        assert_eq!(count_in_document("doc", b"\xd0\xcf\x11\xe0"), None);
+    }
+
+    #[tokio::test]
+    async fn test_pagecount_renders_without_statistics() {
+        let state = AppState {
+            converter: Arc::new(MockConversionService),
+            ..test_state()
+        };
+        let app = Router::new()
+            .route("/pagecount", post(pagecount))
+            .layer(Extension(RequestId(Uuid::new_v4())))
+            .with_state(Arc::new(state));
+        // The mock "converts" by copying, so the page objects are taken from the upload itself
+        let body = "--b\r\nContent-Disposition: form-data; name=\"file\"; filename=\"notes.md\"\r\n\r\n\
+                    << /Type /Pages >> << /Type /Page >> << /Type/Page >>\r\n--b--\r\n";
+        let request = Request::builder()
+            .method("POST")
+            .uri("/pagecount")
+            .header(header::CONTENT_TYPE, "multipart/form-data; boundary=b")
+            .body(Body::from(body))
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: Value = serde_json::from_slice(&axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap()).unwrap();
+        assert_eq!(body, json!({"page_count": 2, "source": "rendered"}));
+    }
+}
@@ -0,0 +1,180 @@
+//! `normalize_page_size`: brings every page of the converted PDF to one paper size, for print
+//! workflows that choke on documents mixing, say, A4 portrait and A3 landscape pages.
+//!
+//! Pages whose `MediaBox` is already the requested size are left alone. The others get the
+//! requested size, in portrait, and their content is scaled down or up to fit and centered on it,
+//! by wrapping their content streams in `q <matrix> cm ... Q`. The new page objects are written
+//! as an incremental update. Annotation rectangles are moved with the content; other annotation
+//! geometry (quad points, ink paths) is not.
+
+use axum::http::StatusCode;
+use tracing::error;
+
+use crate::error::ConversionError;
+use crate::formats::PaperSize;
+use crate::merge;
+use crate::pdf::{Document, Object};
+use crate::ConvertedFile;
+
+/// Pages within this many points of the requested size count as that size, which absorbs the
+/// rounding of millimetre sizes to points.
+const TOLERANCE: f64 = 1.0;
+/// The size of a page without a `MediaBox`, US Letter, as the PDF specification has it.
+const DEFAULT_MEDIA_BOX: [f64; 4] = [0.0, 0.0, 612.0, 792.0];
+/// Page boxes that would no longer match the new `MediaBox`.
+const PAGE_BOXES: &[&str] = &["CropBox", "BleedBox", "TrimBox", "ArtBox"];
+
+/// The size in points of `size`, in portrait.
+fn points(size: PaperSize) -> (f64, f64) {
+    let points = |hundredths_mm: u32| hundredths_mm as f64 / 2540.0 * 72.0;
+    (points(size.width), points(size.height))
+}
+
+/// The four numbers of a rectangle, ordered lower left then upper right.
+fn rectangle(document: &mut Document, object: Option<&Object>) -> Result<Option<[f64; 4]>, String> {
+    let Some(object) = object else {
+        return Ok(None);
+    };
+    let Object::Array(items) = document.resolve(object)? else {
+        return Ok(None);
+    };
+    let numbers: Vec<f64> = items.iter().filter_map(Object::as_number).collect();
+    let [x0, y0, x1, y1] = numbers[..] else {
+        return Ok(None);
+    };
+    Ok(Some([x0.min(x1), y0.min(y1), x0.max(x1), y0.max(y1)]))
+}
+
+/// `pdf` with every page brought to `size`, and how many pages had to be changed. A PDF whose
+/// pages all have that size already comes back unchanged.
+pub fn normalize(pdf: &[u8], size: PaperSize) -> Result<(Vec<u8>, usize), String> {
+    let mut document = Document::parse(pdf)?;
+    let (width, height) = points(size);
+    let mut next_id = document.next_id();
+    let mut objects = Vec::new();
+    let mut normalized = 0;
+
+    for (id, page) in merge::pages(&mut document)? {
+        let [x0, y0, x1, y1] = rectangle(&mut document, page.get("MediaBox"))?.unwrap_or(DEFAULT_MEDIA_BOX);
+        let (page_width, page_height) = (x1 - x0, y1 - y0);
+        // The MediaBox is in the page's unrotated space, while the requested size is as shown
+        let rotated = page.get("Rotate").and_then(Object::as_int).is_some_and(|r| r.rem_euclid(180) == 90);
+        let (target_width, target_height) = if rotated { (height, width) } else { (width, height) };
+        if (page_width - target_width).abs() <= TOLERANCE && (page_height - target_height).abs() <= TOLERANCE {
+            continue;
+        }
+        if page_width <= 0.0 || page_height <= 0.0 {
+            return Err("a page has an empty MediaBox".to_string());
+        }
+        let Some(id) = id else {
+            return Err("a page is not an indirect object".to_string());
+        };
+
+        let scale = (target_width / page_width).min(target_height / page_height);
+        let dx = (target_width - page_width * scale) / 2.0 - x0 * scale;
+        let dy = (target_height - page_height * scale) / 2.0 - y0 * scale;
+
+        // The page as stored, keeping its /Parent and whatever else it inherits
+        let Object::Dict(mut dict) = document.get(id)? else {
+            return Err("a page is not a dictionary".to_string());
+        };
+        dict.set("MediaBox", Object::Array(vec![Object::int(0), Object::int(0), Object::real(target_width), Object::real(target_height)]));
+        for key in PAGE_BOXES {
+            dict.remove(key);
+        }
+
+        if let Some(contents) = dict.get("Contents").cloned() {
+            let mut streams = match document.resolve(&contents)? {
+                Object::Array(streams) => streams,
+                _ => vec![contents],
+            };
+            let (prefix, suffix) = (next_id, next_id + 1);
+            next_id += 2;
+            let matrix = [scale, 0.0, 0.0, scale, dx, dy].map(|n| format!("{}", (n * 10000.0).round() / 10000.0));
+            objects.push((prefix, Object::Stream(Default::default(), format!("q {} cm\n", matrix.join(" ")).into_bytes())));
+            objects.push((suffix, Object::Stream(Default::default(), b"\nQ\n".to_vec())));
+            streams.insert(0, Object::Ref(prefix, 0));
+            streams.push(Object::Ref(suffix, 0));
+            dict.set("Contents", Object::Array(streams));
+        }
+
+        if let Some(annots) = dict.get("Annots").cloned()
+            && let Object::Array(annots) = document.resolve(&annots)?
+        {
+            for annot in annots {
+                let Object::Ref(annot_id, _) = annot else {
+                    continue;
+                };
+                let Object::Dict(mut annot) = document.get(annot_id)? else {
+                    continue;
+                };
+                if let Some([ax0, ay0, ax1, ay1]) = rectangle(&mut document, annot.get("Rect"))? {
+                    let rect = [ax0 * scale + dx, ay0 * scale + dy, ax1 * scale + dx, ay1 * scale + dy];
+                    annot.set("Rect", Object::Array(rect.into_iter().map(Object::real).collect()));
+                    objects.push((annot_id, Object::Dict(annot)));
+                }
+            }
+        }
+
+        objects.push((id, Object::Dict(dict)));
+        normalized += 1;
+    }
+
+    if objects.is_empty() {
+        return Ok((pdf.to_vec(), 0));
+    }
+    Ok((document.append_update(&objects, &[])?, normalized))
+}
+
+/// Brings the pages of `converted`, a PDF, to `size`, returning how many were changed.
+pub async fn normalize_output(converted: &mut ConvertedFile, size: PaperSize) -> Result<usize, ConversionError> {
+    let content = std::mem::take(&mut converted.content);
+    let result = tokio::task::spawn_blocking(move || normalize(&content, size)).await.map_err(|e| {
+        error!("Normalizing page sizes panicked: {}", e);
+        ConversionError::internal()
+    })?;
+    let (content, normalized) = result.map_err(|e| {
+        error!("Failed to normalize page sizes: {}", e);
+        ConversionError::new(StatusCode::INTERNAL_SERVER_ERROR, "PAGE_NORMALIZATION_FAILED", "Normalizing the page sizes failed")
+    })?;
+    converted.content = content;
+    Ok(normalized)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::formats;
+    use crate::pdf::tests::sample_pdf;
+
+    #[test]
+    fn test_normalize() {
+        let a4 = formats::paper_size("A4").unwrap();
+        let original = sample_pdf(&["one", "two"]);
+        let (normalized, count) = normalize(&original, a4).unwrap();
+        assert_eq!(count, 2);
+        assert!(normalized.starts_with(&original), "written as an incremental update");
+
+        let mut document = Document::parse(&normalized).unwrap();
+        let pages = merge::pages(&mut document).unwrap();
+        assert_eq!(pages.len(), 2);
+        for (_, page) in &pages {
+            let [x0, y0, x1, y1] = rectangle(&mut document, page.get("MediaBox")).unwrap().unwrap();
+            assert_eq!((x0, y0, x1, y1), (0.0, 0.0, 595.276, 841.89));
+            let Some(Object::Array(contents)) = page.get("Contents") else {
+                panic!("contents not wrapped");
+            };
+            assert_eq!(contents.len(), 3);
+            let Object::Stream(_, prefix) = document.resolve(&contents[0]).unwrap() else {
+                panic!("prefix is not a stream");
+            };
+            // 200pt squares scaled to the A4 width and centered vertically
+            assert_eq!(String::from_utf8(prefix).unwrap(), "q 2.9764 0 0 2.9764 0 123.3071 cm\n");
+        }
+
+        // Already the right size
+        let (again, count) = normalize(&normalized, a4).unwrap();
+        assert_eq!((again, count), (normalized, 0));
+        assert!(normalize(b"not a pdf", a4).is_err());
+    }
+}
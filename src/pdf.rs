@@ -0,0 +1,976 @@
+//! Reading PDF objects, enough to copy pages from one document into another (see `merge`).
+//!
+//! Objects are found through the cross-reference table or stream, following `/Prev` to earlier
+//! revisions, and may sit in object streams. When the cross-reference data is missing or broken,
+//! the file is scanned for `n g obj` headers instead, as viewers do. Strings, names and numbers
+//! are kept as written, and content streams are never decoded, so copied objects come out
+//! byte-for-byte as they went in.
+
+use std::collections::{HashMap, HashSet};
+
+use crate::inflate;
+
+/// Arrays and dictionaries nested deeper than this are rejected rather than recursed into.
+pub const MAX_DEPTH: usize = 64;
+/// Decoded cross-reference and object streams larger than this are rejected.
+const MAX_DECODED_LEN: usize = 64 * 1024 * 1024;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Object {
+    Null,
+    Bool(bool),
+    /// An integer or real, as written.
+    Number(Vec<u8>),
+    /// A name without its leading `/`, as written (with any `#xx` escapes).
+    Name(Vec<u8>),
+    /// A literal or hex string, as written with its delimiters.
+    String(Vec<u8>),
+    Array(Vec<Object>),
+    Dict(Dict),
+    /// A stream's dictionary and its still-encoded data.
+    Stream(Dict, Vec<u8>),
+    Ref(u32, u16),
+}
+
+impl Object {
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            Object::Number(n) => std::str::from_utf8(n).ok()?.parse().ok(),
+            _ => None,
+        }
+    }
+
+    pub fn as_number(&self) -> Option<f64> {
+        match self {
+            Object::Number(n) => std::str::from_utf8(n).ok()?.parse().ok(),
+            _ => None,
+        }
+    }
+
+    pub fn as_dict(&self) -> Option<&Dict> {
+        match self {
+            Object::Dict(dict) | Object::Stream(dict, _) => Some(dict),
+            _ => None,
+        }
+    }
+
+    pub fn is_name(&self, name: &str) -> bool {
+        matches!(self, Object::Name(n) if n == name.as_bytes())
+    }
+
+    pub fn int(n: usize) -> Object {
+        Object::Number(n.to_string().into_bytes())
+    }
+
+    /// A real, to three decimal places.
+    pub fn real(n: f64) -> Object {
+        Object::Number(format!("{}", (n * 1000.0).round() / 1000.0).into_bytes())
+    }
+
+    pub fn name(name: &str) -> Object {
+        Object::Name(name.as_bytes().to_vec())
+    }
+
+    /// Writes the object in PDF syntax.
+    pub fn write(&self, out: &mut Vec<u8>) {
+        match self {
+            Object::Null => out.extend_from_slice(b"null"),
+            Object::Bool(b) => out.extend_from_slice(if *b { b"true" } else { b"false" }),
+            Object::Number(n) | Object::String(n) => out.extend_from_slice(n),
+            Object::Name(n) => {
+                out.push(b'/');
+                out.extend_from_slice(n);
+            }
+            Object::Array(items) => {
+                out.push(b'[');
+                for (n, item) in items.iter().enumerate() {
+                    if n > 0 {
+                        out.push(b' ');
+                    }
+                    item.write(out);
+                }
+                out.push(b']');
+            }
+            Object::Dict(dict) => dict.write(out),
+            Object::Stream(dict, data) => {
+                let mut dict = dict.clone();
+                dict.set("Length", Object::int(data.len()));
+                dict.write(out);
+                out.extend_from_slice(b"\nstream\n");
+                out.extend_from_slice(data);
+                out.extend_from_slice(b"\nendstream");
+            }
+            Object::Ref(id, generation) => out.extend_from_slice(format!("{} {} R", id, generation).as_bytes()),
+        }
+    }
+}
+
+/// A dictionary, keeping its entries in file order.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Dict(Vec<(Vec<u8>, Object)>);
+
+impl Dict {
+    pub fn get(&self, key: &str) -> Option<&Object> {
+        self.0.iter().find(|(k, _)| k == key.as_bytes()).map(|(_, v)| v)
+    }
+
+    pub fn set(&mut self, key: &str, value: Object) {
+        match self.0.iter_mut().find(|(k, _)| k == key.as_bytes()) {
+            Some(entry) => entry.1 = value,
+            None => self.0.push((key.as_bytes().to_vec(), value)),
+        }
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<Object> {
+        let at = self.0.iter().position(|(k, _)| k == key.as_bytes())?;
+        Some(self.0.remove(at).1)
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut Object> {
+        self.0.iter_mut().map(|(_, v)| v)
+    }
+
+    fn write(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(b"<<");
+        for (key, value) in &self.0 {
+            out.push(b'/');
+            out.extend_from_slice(key);
+            out.push(b' ');
+            value.write(out);
+        }
+        out.extend_from_slice(b">>");
+    }
+}
+
+impl<const N: usize> From<[(&str, Object); N]> for Dict {
+    fn from(entries: [(&str, Object); N]) -> Self {
+        Dict(entries.into_iter().map(|(k, v)| (k.as_bytes().to_vec(), v)).collect())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Location {
+    Offset(usize),
+    /// In the object stream with this number, at this index.
+    Compressed(u32, usize),
+    Free,
+}
+
+/// A parsed PDF file. Objects are read from `data` when asked for.
+pub struct Document<'a> {
+    data: &'a [u8],
+    xref: HashMap<u32, Location>,
+    pub trailer: Dict,
+    /// Decoded object streams, by object number.
+    object_streams: HashMap<u32, Vec<Object>>,
+    /// Where the newest cross-reference section starts, unless the data had to be rebuilt.
+    xref_offset: Option<usize>,
+}
+
+impl<'a> Document<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Self, String> {
+        if !data.starts_with(b"%PDF-") {
+            return Err("not a PDF file".to_string());
+        }
+        let mut doc = Document {
+            data,
+            xref: HashMap::new(),
+            trailer: Dict::default(),
+            object_streams: HashMap::new(),
+            xref_offset: None,
+        };
+        if doc.read_xref().is_err() || doc.trailer.get("Root").is_none() {
+            doc.xref_offset = None;
+            doc.reconstruct_xref()?;
+        }
+        if doc.trailer.get("Encrypt").is_some() {
+            return Err("the PDF is encrypted".to_string());
+        }
+        Ok(doc)
+    }
+
+    /// The object with number `id`; a missing object is `null`, as references to one mean.
+    pub fn get(&mut self, id: u32) -> Result<Object, String> {
+        match self.xref.get(&id).copied() {
+            Some(Location::Offset(offset)) => {
+                let (found, object) = self.object_at(offset, true)?;
+                if found != id {
+                    return Err(format!("object {} is not where the cross-reference table says", id));
+                }
+                Ok(object)
+            }
+            Some(Location::Compressed(stream, index)) => {
+                if !self.object_streams.contains_key(&stream) {
+                    let objects = self.object_stream(stream)?.into_iter().map(|(_, object)| object).collect();
+                    self.object_streams.insert(stream, objects);
+                }
+                Ok(self.object_streams[&stream].get(index).cloned().unwrap_or(Object::Null))
+            }
+            Some(Location::Free) | None => Ok(Object::Null),
+        }
+    }
+
+    /// Follows `object` if it is a reference.
+    pub fn resolve(&mut self, object: &Object) -> Result<Object, String> {
+        match object {
+            Object::Ref(id, _) => self.get(*id),
+            other => Ok(other.clone()),
+        }
+    }
+
+    /// The number a new object can be given.
+    pub fn next_id(&self) -> u32 {
+        let size = self.trailer.get("Size").and_then(Object::as_int).unwrap_or(0).clamp(0, u32::MAX as i64) as u32;
+        self.xref.keys().map(|id| id + 1).max().unwrap_or(1).max(size)
+    }
+
+    /// The file with `objects` appended as an incremental update, so that everything already in it
+    /// stays byte-for-byte as it was. An object keeping its number replaces the old one; `trailer`
+    /// entries are added to the trailer. The new cross-reference section is a table or a stream,
+    /// whichever the file already uses.
+    pub fn append_update(&self, objects: &[(u32, Object)], trailer: &[(&str, Object)]) -> Result<Vec<u8>, String> {
+        let prev = self.xref_offset.ok_or("the cross-reference data is broken")?;
+        let is_stream = self.trailer.get("Type").is_some_and(|t| t.is_name("XRef"));
+        let mut out = self.data.to_vec();
+        if !out.ends_with(b"\n") {
+            out.push(b'\n');
+        }
+        let mut offsets = Vec::new();
+        for (id, object) in objects {
+            offsets.push((*id, out.len()));
+            out.extend_from_slice(format!("{} 0 obj\n", id).as_bytes());
+            object.write(&mut out);
+            out.extend_from_slice(b"\nendobj\n");
+        }
+
+        let mut next_id = self.next_id().max(objects.iter().map(|(id, _)| id + 1).max().unwrap_or(0));
+        let xref_id = next_id;
+        let xref_offset = out.len();
+        if is_stream {
+            // The stream lists itself
+            offsets.push((xref_id, xref_offset));
+            next_id += 1;
+        }
+        offsets.sort();
+        // Runs of consecutive object numbers, as `[first count]` pairs
+        let mut runs: Vec<(u32, u32)> = Vec::new();
+        for &(id, _) in &offsets {
+            match runs.last_mut() {
+                Some((first, count)) if *first + *count == id => *count += 1,
+                _ => runs.push((id, 1)),
+            }
+        }
+
+        let mut dict = Dict::default();
+        for (key, value) in &self.trailer.0 {
+            if [&b"Root"[..], b"Info", b"ID"].contains(&key.as_slice()) {
+                dict.0.push((key.clone(), value.clone()));
+            }
+        }
+        for (key, value) in trailer {
+            dict.set(key, value.clone());
+        }
+        dict.set("Size", Object::int(next_id as usize));
+        dict.set("Prev", Object::int(prev));
+
+        if is_stream {
+            let mut entries = Vec::new();
+            for &(_, offset) in &offsets {
+                entries.push(1);
+                entries.extend_from_slice(&u32::try_from(offset).map_err(|_| "the file is too large")?.to_be_bytes());
+            }
+            dict.set("Type", Object::name("XRef"));
+            dict.set("W", Object::Array(vec![Object::int(1), Object::int(4), Object::int(0)]));
+            let index = runs.iter().flat_map(|&(first, count)| [Object::int(first as usize), Object::int(count as usize)]).collect();
+            dict.set("Index", Object::Array(index));
+            out.extend_from_slice(format!("{} 0 obj\n", xref_id).as_bytes());
+            Object::Stream(dict, entries).write(&mut out);
+            out.extend_from_slice(b"\nendobj\n");
+        } else {
+            out.extend_from_slice(b"xref\n");
+            let mut offsets = offsets.iter();
+            for (first, count) in runs {
+                out.extend_from_slice(format!("{} {}\n", first, count).as_bytes());
+                for (_, offset) in offsets.by_ref().take(count as usize) {
+                    out.extend_from_slice(format!("{:010} 00000 n \n", offset).as_bytes());
+                }
+            }
+            out.extend_from_slice(b"trailer\n");
+            Object::Dict(dict).write(&mut out);
+            out.push(b'\n');
+        }
+        out.extend_from_slice(format!("startxref\n{}\n%%EOF\n", xref_offset).as_bytes());
+        Ok(out)
+    }
+
+    /// Reads the cross-reference section `startxref` points to and the ones before it.
+    fn read_xref(&mut self) -> Result<(), String> {
+        let tail = &self.data[self.data.len().saturating_sub(1024)..];
+        let at = find_last(tail, b"startxref").ok_or("startxref not found")?;
+        let mut parser = Parser::new(tail, at + b"startxref".len());
+        let mut next = Some(parser.object(0)?.as_int().ok_or("invalid startxref")? as usize);
+        self.xref_offset = next;
+        let mut seen = HashSet::new();
+        while let Some(offset) = next.filter(|offset| seen.insert(*offset)) {
+            let trailer = self.read_xref_section(offset)?;
+            // A hybrid file keeps its compressed objects in a stream the table points to
+            if let Some(stream) = trailer.get("XRefStm").and_then(Object::as_int) {
+                self.read_xref_section(stream as usize)?;
+            }
+            next = trailer.get("Prev").and_then(Object::as_int).map(|prev| prev as usize);
+            if self.trailer.0.is_empty() {
+                self.trailer = trailer;
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads one `xref` table or cross-reference stream; entries already known are newer and kept.
+    fn read_xref_section(&mut self, offset: usize) -> Result<Dict, String> {
+        let mut parser = Parser::new(self.data, offset);
+        parser.skip_whitespace();
+        if !parser.rest().starts_with(b"xref") {
+            let (_, object) = self.object_at(offset, true)?;
+            let Object::Stream(dict, data) = object else {
+                return Err("startxref does not point to a cross-reference section".to_string());
+            };
+            let decoded = decode(&dict, &data)?;
+            self.read_xref_stream(&dict, &decoded)?;
+            return Ok(dict);
+        }
+
+        parser.pos += b"xref".len();
+        loop {
+            parser.skip_whitespace();
+            if parser.rest().starts_with(b"trailer") {
+                parser.pos += b"trailer".len();
+                return match parser.object(0)? {
+                    Object::Dict(dict) => Ok(dict),
+                    _ => Err("invalid trailer".to_string()),
+                };
+            }
+            let first = parser.int()?;
+            let count = parser.int()?;
+            for id in first..first.saturating_add(count) {
+                let entry_offset = parser.int()?;
+                parser.int()?;
+                parser.skip_whitespace();
+                let location = match parser.rest().first() {
+                    Some(b'n') => Location::Offset(entry_offset as usize),
+                    Some(b'f') => Location::Free,
+                    _ => return Err("invalid cross-reference entry".to_string()),
+                };
+                parser.pos += 1;
+                if id != 0 {
+                    self.xref.entry(id as u32).or_insert(location);
+                }
+            }
+        }
+    }
+
+    fn read_xref_stream(&mut self, dict: &Dict, data: &[u8]) -> Result<(), String> {
+        let widths: Option<Vec<usize>> = match dict.get("W") {
+            Some(Object::Array(w)) if w.len() == 3 => w.iter().map(|n| n.as_int().filter(|w| (0..=8).contains(w)).map(|w| w as usize)).collect(),
+            _ => None,
+        };
+        let widths = widths.filter(|w| w.iter().sum::<usize>() > 0).ok_or("invalid /W in cross-reference stream")?;
+        let size = dict.get("Size").and_then(Object::as_int).unwrap_or(0);
+        let index: Vec<i64> = match dict.get("Index") {
+            Some(Object::Array(index)) => index.iter().map(|n| n.as_int().unwrap_or(0)).collect(),
+            _ => vec![0, size],
+        };
+        let entry_len: usize = widths.iter().sum();
+        let mut entries = data.chunks_exact(entry_len);
+        for range in index.chunks_exact(2) {
+            for id in range[0]..range[0].saturating_add(range[1]) {
+                let Some(entry) = entries.next() else {
+                    return Ok(());
+                };
+                let field = |n: usize, default: u64| {
+                    let start: usize = widths[..n].iter().sum();
+                    let bytes = &entry[start..start + widths[n]];
+                    if bytes.is_empty() { default } else { bytes.iter().fold(0, |acc, &b| acc << 8 | b as u64) }
+                };
+                let location = match field(0, 1) {
+                    0 => Location::Free,
+                    1 => Location::Offset(field(1, 0) as usize),
+                    2 => Location::Compressed(field(1, 0) as u32, field(2, 0) as usize),
+                    // Unknown entry types are to be read as references to null
+                    _ => Location::Free,
+                };
+                if id > 0 && id <= u32::MAX as i64 {
+                    self.xref.entry(id as u32).or_insert(location);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Rebuilds the cross-reference data by scanning for `n g obj`, the last definition winning.
+    fn reconstruct_xref(&mut self) -> Result<(), String> {
+        self.xref.clear();
+        self.trailer = Dict::default();
+        let data = self.data;
+        let mut from = 0;
+        while let Some(found) = find(&data[from..], b"obj") {
+            let at = from + found;
+            from = at + 3;
+            if let Some(start) = object_header_start(data, at) {
+                let mut parser = Parser::new(data, start);
+                if let Ok(id) = parser.int() {
+                    self.xref.insert(id as u32, Location::Offset(start));
+                }
+            }
+        }
+
+        let ids: Vec<u32> = self.xref.keys().copied().collect();
+        let mut catalog = None;
+        for id in ids {
+            let Ok(object) = self.get(id) else {
+                continue;
+            };
+            let Some(dict) = object.as_dict() else {
+                continue;
+            };
+            if dict.get("Type").is_some_and(|t| t.is_name("ObjStm"))
+                && let Ok(objects) = self.object_stream(id)
+            {
+                for (index, (inner, _)) in objects.into_iter().enumerate() {
+                    self.xref.entry(inner).or_insert(Location::Compressed(id, index));
+                }
+            }
+            if dict.get("Type").is_some_and(|t| t.is_name("Catalog")) {
+                catalog = Some(id);
+            }
+        }
+
+        if let Some(at) = find_last(data, b"trailer")
+            && let Ok(Object::Dict(trailer)) = Parser::new(data, at + b"trailer".len()).object(0)
+            && trailer.get("Root").is_some()
+        {
+            self.trailer = trailer;
+        } else if let Some(catalog) = catalog {
+            self.trailer.set("Root", Object::Ref(catalog, 0));
+        } else {
+            return Err("no document catalog found".to_string());
+        }
+        Ok(())
+    }
+
+    /// Parses the `n g obj ... endobj` at `offset`. A stream's `/Length` may be a reference, which
+    /// is followed only when `resolve_length` is set.
+    fn object_at(&mut self, offset: usize, resolve_length: bool) -> Result<(u32, Object), String> {
+        if offset >= self.data.len() {
+            return Err("object offset beyond the end of the file".to_string());
+        }
+        let mut parser = Parser::new(self.data, offset);
+        let id = parser.int()?;
+        parser.int()?;
+        parser.keyword(b"obj")?;
+        let object = parser.object(0)?;
+        parser.skip_whitespace();
+        let Object::Dict(dict) = object else {
+            return Ok((id as u32, object));
+        };
+        if !parser.rest().starts_with(b"stream") {
+            return Ok((id as u32, Object::Dict(dict)));
+        }
+
+        parser.pos += b"stream".len();
+        // The data starts after CRLF or LF
+        if parser.rest().starts_with(b"\r\n") {
+            parser.pos += 2;
+        } else if parser.rest().starts_with(b"\n") || parser.rest().starts_with(b"\r") {
+            parser.pos += 1;
+        }
+        let start = parser.pos;
+        let length = match dict.get("Length") {
+            Some(Object::Ref(length_id, _)) if resolve_length => match self.xref.get(length_id).copied() {
+                Some(Location::Offset(at)) => self.object_at(at, false).ok().and_then(|(_, n)| n.as_int()),
+                _ => None,
+            },
+            Some(length) => length.as_int(),
+            None => None,
+        };
+        let data = self.data;
+        let end = length
+            .and_then(|len| start.checked_add(usize::try_from(len).ok()?))
+            .filter(|&end| end <= data.len() && Parser::new(data, end).at_keyword(b"endstream"))
+            .or_else(|| {
+                // A wrong /Length is common enough to look for the end instead
+                let found = start + find(&data[start..], b"endstream")?;
+                let mut end = found;
+                if data[..end].ends_with(b"\r\n") {
+                    end -= 2;
+                } else if data[..end].ends_with(b"\n") || data[..end].ends_with(b"\r") {
+                    end -= 1;
+                }
+                Some(end.max(start))
+            })
+            .ok_or("stream without endstream")?;
+        Ok((id as u32, Object::Stream(dict, data[start..end].to_vec())))
+    }
+
+    /// The objects in object stream `id`, with their numbers.
+    fn object_stream(&mut self, id: u32) -> Result<Vec<(u32, Object)>, String> {
+        let Some(Location::Offset(offset)) = self.xref.get(&id).copied() else {
+            return Err(format!("object stream {} not found", id));
+        };
+        let Object::Stream(dict, data) = self.object_at(offset, true)?.1 else {
+            return Err(format!("object {} is not an object stream", id));
+        };
+        let decoded = decode(&dict, &data)?;
+        let count = dict.get("N").and_then(Object::as_int).unwrap_or(0).max(0) as usize;
+        let first = dict.get("First").and_then(Object::as_int).unwrap_or(0).max(0) as usize;
+        if first > decoded.len() {
+            return Err(format!("invalid /First in object stream {}", id));
+        }
+        let mut header = Parser::new(&decoded[..first], 0);
+        let mut objects = Vec::new();
+        for _ in 0..count {
+            let (Ok(number), Ok(offset)) = (header.int(), header.int()) else {
+                break;
+            };
+            let object = Parser::new(&decoded, first.saturating_add(offset as usize).min(decoded.len())).object(0)?;
+            objects.push((number as u32, object));
+        }
+        Ok(objects)
+    }
+}
+
+/// Decodes stream data compressed with `/FlateDecode`, the only filter PDF writers use for
+/// cross-reference and object streams.
+pub fn decode(dict: &Dict, data: &[u8]) -> Result<Vec<u8>, String> {
+    let filter = match dict.get("Filter") {
+        Some(Object::Array(filters)) if filters.len() == 1 => filters.first(),
+        other => other,
+    };
+    let decoded = match filter {
+        None => return Ok(data.to_vec()),
+        Some(f) if f.is_name("FlateDecode") => {
+            // Skip the zlib header; the Adler-32 trailer is not checked
+            let deflated = data.get(2..).ok_or("truncated stream")?;
+            inflate::inflate(deflated, MAX_DECODED_LEN).map_err(|e| format!("invalid compressed stream: {:?}", e))?
+        }
+        Some(_) => return Err("unsupported stream filter".to_string()),
+    };
+    let params = dict.get("DecodeParms").and_then(|p| match p {
+        Object::Array(params) => params.first().and_then(Object::as_dict),
+        other => other.as_dict(),
+    });
+    let predictor = params.and_then(|p| p.get("Predictor")).and_then(Object::as_int).unwrap_or(1);
+    match predictor {
+        1 => Ok(decoded),
+        10..=15 => {
+            let columns = params.and_then(|p| p.get("Columns")).and_then(Object::as_int).unwrap_or(1).max(1) as usize;
+            unpredict_png(&decoded, columns)
+        }
+        _ => Err(format!("unsupported predictor {}", predictor)),
+    }
+}
+
+/// Undoes PNG row filters: each row of `columns` bytes is preceded by its filter type.
+fn unpredict_png(data: &[u8], columns: usize) -> Result<Vec<u8>, String> {
+    let mut out: Vec<u8> = Vec::with_capacity(data.len());
+    let mut previous = vec![0u8; columns];
+    for row in data.chunks(columns + 1) {
+        let (&filter, row) = row.split_first().ok_or("empty predictor row")?;
+        let mut current = vec![0u8; columns];
+        for (i, &byte) in row.iter().enumerate() {
+            let left = if i > 0 { current[i - 1] } else { 0 };
+            let up = previous[i];
+            let up_left = if i > 0 { previous[i - 1] } else { 0 };
+            current[i] = byte.wrapping_add(match filter {
+                0 => 0,
+                1 => left,
+                2 => up,
+                3 => ((left as u16 + up as u16) / 2) as u8,
+                4 => paeth(left, up, up_left),
+                _ => return Err(format!("invalid PNG predictor {}", filter)),
+            });
+        }
+        out.extend_from_slice(&current[..row.len()]);
+        previous = current;
+    }
+    Ok(out)
+}
+
+fn paeth(left: u8, up: u8, up_left: u8) -> u8 {
+    let estimate = left as i16 + up as i16 - up_left as i16;
+    let (a, b, c) = ((estimate - left as i16).abs(), (estimate - up as i16).abs(), (estimate - up_left as i16).abs());
+    if a <= b && a <= c {
+        left
+    } else if b <= c {
+        up
+    } else {
+        up_left
+    }
+}
+
+/// Where the `n g obj` whose keyword is at `at` starts, if `at` is one.
+fn object_header_start(data: &[u8], at: usize) -> Option<usize> {
+    if data.get(at + 3).is_some_and(|&b| !is_whitespace(b) && !is_delimiter(b)) {
+        return None;
+    }
+    let mut pos = at;
+    for _ in 0..2 {
+        let digits_end = data[..pos].iter().rposition(|&b| !is_whitespace(b))? + 1;
+        if digits_end == pos {
+            return None;
+        }
+        let digits_start = data[..digits_end].iter().rposition(|b| !b.is_ascii_digit()).map_or(0, |p| p + 1);
+        if digits_start == digits_end {
+            return None;
+        }
+        pos = digits_start;
+    }
+    (pos == 0 || is_whitespace(data[pos - 1])).then_some(pos)
+}
+
+/// Whether `data` is linearized ("fast web view"): its first object, which then starts within the
+/// first 1024 bytes, is a dictionary with a `/Linearized` entry.
+pub fn is_linearized(data: &[u8]) -> bool {
+    let head = &data[..data.len().min(1024)];
+    let Some(start) = find(head, b"obj") else {
+        return false;
+    };
+    let first_object = &head[start..];
+    let end = find(first_object, b"endobj").unwrap_or(first_object.len());
+    find(&first_object[..end], b"/Linearized")
+        .map(|at| first_object.get(at + b"/Linearized".len()))
+        .is_some_and(|next| next.is_none_or(|&b| is_whitespace(b) || is_delimiter(b)))
+}
+
+/// `text` as a PDF text string: UTF-16BE with a byte order mark, written in hex.
+pub fn text_string(text: &str) -> Vec<u8> {
+    let mut hex = "<FEFF".to_string();
+    for unit in text.encode_utf16() {
+        hex.push_str(&format!("{:04X}", unit));
+    }
+    hex.push('>');
+    hex.into_bytes()
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+fn find_last(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).rposition(|w| w == needle)
+}
+
+fn is_whitespace(b: u8) -> bool {
+    matches!(b, b'\0' | b'\t' | b'\n' | b'\x0c' | b'\r' | b' ')
+}
+
+fn is_delimiter(b: u8) -> bool {
+    matches!(b, b'(' | b')' | b'<' | b'>' | b'[' | b']' | b'{' | b'}' | b'/' | b'%')
+}
+
+struct Parser<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn new(data: &'a [u8], pos: usize) -> Self {
+        Parser { data, pos }
+    }
+
+    fn rest(&self) -> &'a [u8] {
+        &self.data[self.pos.min(self.data.len())..]
+    }
+
+    /// Skips whitespace and comments.
+    fn skip_whitespace(&mut self) {
+        while let Some(&b) = self.rest().first() {
+            if is_whitespace(b) {
+                self.pos += 1;
+            } else if b == b'%' {
+                self.pos += self.rest().iter().position(|&b| b == b'\n' || b == b'\r').unwrap_or(self.rest().len());
+            } else {
+                break;
+            }
+        }
+    }
+
+    /// A run of regular characters: a number or a keyword.
+    fn token(&mut self) -> &'a [u8] {
+        self.skip_whitespace();
+        let rest = self.rest();
+        let len = rest.iter().position(|&b| is_whitespace(b) || is_delimiter(b)).unwrap_or(rest.len());
+        self.pos += len;
+        &rest[..len]
+    }
+
+    fn int(&mut self) -> Result<i64, String> {
+        let token = self.token();
+        std::str::from_utf8(token)
+            .ok()
+            .and_then(|t| t.parse().ok())
+            .filter(|n| *n >= 0)
+            .ok_or_else(|| format!("expected an integer, found {:?}", String::from_utf8_lossy(token)))
+    }
+
+    fn keyword(&mut self, keyword: &[u8]) -> Result<(), String> {
+        if self.token() == keyword {
+            Ok(())
+        } else {
+            Err(format!("expected {}", String::from_utf8_lossy(keyword)))
+        }
+    }
+
+    /// Whether `keyword` comes next, without consuming it.
+    fn at_keyword(&mut self, keyword: &[u8]) -> bool {
+        self.skip_whitespace();
+        self.rest().starts_with(keyword)
+    }
+
+    fn object(&mut self, depth: usize) -> Result<Object, String> {
+        if depth > MAX_DEPTH {
+            return Err("objects nested too deeply".to_string());
+        }
+        self.skip_whitespace();
+        let rest = self.rest();
+        match rest.first() {
+            None => Err("unexpected end of file".to_string()),
+            Some(b'/') => {
+                self.pos += 1;
+                Ok(Object::Name(self.token().to_vec()))
+            }
+            Some(b'(') => {
+                let mut nesting = 0;
+                let mut i = 0;
+                while i < rest.len() {
+                    match rest[i] {
+                        b'\\' => i += 1,
+                        b'(' => nesting += 1,
+                        b')' => {
+                            nesting -= 1;
+                            if nesting == 0 {
+                                self.pos += i + 1;
+                                return Ok(Object::String(rest[..=i].to_vec()));
+                            }
+                        }
+                        _ => {}
+                    }
+                    i += 1;
+                }
+                Err("unterminated string".to_string())
+            }
+            Some(b'<') if rest.starts_with(b"<<") => {
+                self.pos += 2;
+                let mut dict = Dict::default();
+                loop {
+                    self.skip_whitespace();
+                    if self.rest().starts_with(b">>") {
+                        self.pos += 2;
+                        return Ok(Object::Dict(dict));
+                    }
+                    let Object::Name(key) = self.object(depth + 1)? else {
+                        return Err("dictionary key is not a name".to_string());
+                    };
+                    let value = self.object(depth + 1)?;
+                    dict.0.push((key, value));
+                }
+            }
+            Some(b'<') => {
+                let end = rest.iter().position(|&b| b == b'>').ok_or("unterminated hex string")?;
+                self.pos += end + 1;
+                Ok(Object::String(rest[..=end].to_vec()))
+            }
+            Some(b'[') => {
+                self.pos += 1;
+                let mut items = Vec::new();
+                loop {
+                    self.skip_whitespace();
+                    if self.rest().starts_with(b"]") {
+                        self.pos += 1;
+                        return Ok(Object::Array(items));
+                    }
+                    items.push(self.object(depth + 1)?);
+                }
+            }
+            Some(_) => {
+                let token = self.token();
+                match token {
+                    b"" => Err(format!("unexpected {:?}", rest[0] as char)),
+                    b"null" => Ok(Object::Null),
+                    b"true" => Ok(Object::Bool(true)),
+                    b"false" => Ok(Object::Bool(false)),
+                    _ if token.iter().all(u8::is_ascii_digit) => {
+                        // `n g R` is a reference
+                        let after = self.pos;
+                        if let Ok(generation) = self.int()
+                            && self.token() == b"R"
+                            && let (Some(id), Ok(generation)) = (std::str::from_utf8(token).ok().and_then(|t| t.parse().ok()), u16::try_from(generation))
+                        {
+                            return Ok(Object::Ref(id, generation));
+                        }
+                        self.pos = after;
+                        Ok(Object::Number(token.to_vec()))
+                    }
+                    _ if token.iter().all(|b| b.is_ascii_digit() || matches!(b, b'+' | b'-' | b'.')) => Ok(Object::Number(token.to_vec())),
+                    _ => Err(format!("unexpected {:?}", String::from_utf8_lossy(token))),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    /// A PDF with one page per entry of `texts`, with a classic cross-reference table.
+    pub fn sample_pdf(texts: &[&str]) -> Vec<u8> {
+        let mut objects = vec![
+            "<< /Type /Catalog /Pages 2 0 R >>".to_string(),
+            format!(
+                "<< /Type /Pages /MediaBox [0 0 200 200] /Kids [{}] /Count {} >>",
+                (0..texts.len()).map(|n| format!("{} 0 R", 3 + 2 * n)).collect::<Vec<_>>().join(" "),
+                texts.len()
+            ),
+        ];
+        for (n, text) in texts.iter().enumerate() {
+            let content = format!("BT /F1 12 Tf 20 100 Td ({}) Tj ET", text);
+            objects.push(format!("<< /Type /Page /Parent 2 0 R /Contents {} 0 R >>", 4 + 2 * n));
+            objects.push(format!("<< /Length {} >>\nstream\n{}\nendstream", content.len(), content));
+        }
+        let mut pdf = b"%PDF-1.4\n".to_vec();
+        let mut offsets = Vec::new();
+        for (n, object) in objects.iter().enumerate() {
+            offsets.push(pdf.len());
+            pdf.extend_from_slice(format!("{} 0 obj\n{}\nendobj\n", n + 1, object).as_bytes());
+        }
+        let xref = pdf.len();
+        pdf.extend_from_slice(format!("xref\n0 {}\n0000000000 65535 f \n", objects.len() + 1).as_bytes());
+        for offset in offsets {
+            pdf.extend_from_slice(format!("{:010} 00000 n \n", offset).as_bytes());
+        }
+        pdf.extend_from_slice(format!("trailer\n<< /Size {} /Root 1 0 R >>\nstartxref\n{}\n%%EOF\n", objects.len() + 1, xref).as_bytes());
+        pdf
+    }
+
+    /// `data` as a zlib stream of stored (uncompressed) DEFLATE blocks.
+    fn zlib_stored(data: &[u8]) -> Vec<u8> {
+        let mut out = vec![0x78, 0x01, 0x01];
+        out.extend_from_slice(&(data.len() as u16).to_le_bytes());
+        out.extend_from_slice(&(!(data.len() as u16)).to_le_bytes());
+        out.extend_from_slice(data);
+        out.extend_from_slice(&[0, 0, 0, 0]);
+        out
+    }
+
+    #[test]
+    fn test_parse_objects() {
+        let mut parser = Parser::new(b"<< /A [1 0 R -2.5 (a (b) \\) c) <0aF0>] /B#20C true /D << >> /E null >>", 0);
+        let Object::Dict(dict) = parser.object(0).unwrap() else {
+            panic!("not a dictionary");
+        };
+        assert_eq!(
+            dict.get("A"),
+            Some(&Object::Array(vec![
+                Object::Ref(1, 0),
+                Object::Number(b"-2.5".to_vec()),
+                Object::String(b"(a (b) \\) c)".to_vec()),
+                Object::String(b"<0aF0>".to_vec()),
+            ]))
+        );
+        assert_eq!(dict.get("B#20C"), Some(&Object::Bool(true)));
+        assert_eq!(dict.get("D"), Some(&Object::Dict(Dict::default())));
+        let mut out = Vec::new();
+        Object::Dict(dict).write(&mut out);
+        assert_eq!(out, b"<</A [1 0 R -2.5 (a (b) \\) c) <0aF0>]/B#20C true/D <<>>/E null>>");
+
+        let nested = "[".repeat(MAX_DEPTH + 2);
+        assert!(Parser::new(nested.as_bytes(), 0).object(0).is_err());
+    }
+
+    #[test]
+    fn test_document() {
+        let pdf = sample_pdf(&["one", "two"]);
+        let mut doc = Document::parse(&pdf).unwrap();
+        assert_eq!(doc.trailer.get("Root"), Some(&Object::Ref(1, 0)));
+        let Object::Stream(dict, data) = doc.get(4).unwrap() else {
+            panic!("not a stream");
+        };
+        assert_eq!(dict.get("Length").and_then(Object::as_int), Some(data.len() as i64));
+        assert_eq!(data, b"BT /F1 12 Tf 20 100 Td (one) Tj ET");
+        assert_eq!(doc.get(99).unwrap(), Object::Null);
+
+        // Without a usable cross-reference table the objects are found by scanning
+        let broken = String::from_utf8(pdf.clone()).unwrap().replace("startxref", "startxrex");
+        let mut doc = Document::parse(broken.as_bytes()).unwrap();
+        assert!(doc.get(3).unwrap().as_dict().unwrap().get("Type").unwrap().is_name("Page"));
+
+        assert!(Document::parse(b"PK\x03\x04").is_err());
+        let encrypted = String::from_utf8(pdf).unwrap().replace("/Root 1 0 R", "/Root 1 0 R /Encrypt 9 0 R");
+        assert_eq!(Document::parse(encrypted.as_bytes()).err().unwrap(), "the PDF is encrypted");
+    }
+
+    #[test]
+    fn test_is_linearized() {
+        assert!(!is_linearized(&sample_pdf(&["a"])));
+        let linearized = b"%PDF-1.7\n%\xbf\xf7\xa2\xfe\n4 0 obj\n<< /Linearized 1 /L 1234 /H [ 680 130 ] /O 7 /E 900 /N 1 /T 1100 >>\nendobj\n";
+        assert!(is_linearized(linearized));
+        assert!(!is_linearized(b"%PDF-1.7\n1 0 obj\n<< /Type /Catalog >>\nendobj\n2 0 obj\n<< /Linearized 1 >>\nendobj\n"));
+        assert!(!is_linearized(b"%PDF-1.7\n1 0 obj\n<< /LinearizedNot 1 >>\nendobj\n"));
+    }
+
+    #[test]
+    fn test_xref_and_object_streams() {
+        // Objects 1 and 2 in an object stream, object 3 plain; the xref stream uses PNG "up" rows
+        let header = "1 0 2 34 ";
+        let body = "<< /Type /Catalog /Pages 2 0 R >> << /Type /Pages /Kids [3 0 R] /Count 1 >>";
+        let objstm_data = zlib_stored(format!("{}{}", header, body).as_bytes());
+        let mut pdf = b"%PDF-1.5\n".to_vec();
+        let page_offset = pdf.len();
+        pdf.extend_from_slice(b"3 0 obj\n<< /Type /Page /Parent 2 0 R >>\nendobj\n");
+        let objstm_offset = pdf.len();
+        pdf.extend_from_slice(format!("4 0 obj\n<< /Type /ObjStm /N 2 /First {} /Filter /FlateDecode /Length {} >>\nstream\n", header.len(), objstm_data.len()).as_bytes());
+        pdf.extend_from_slice(&objstm_data);
+        pdf.extend_from_slice(b"\nendstream\nendobj\n");
+        let xref_offset = pdf.len();
+
+        // Entries for objects 1-5 with /W [1 2 1]
+        let rows: Vec<[u8; 4]> = vec![
+            [2, 0, 4, 0],
+            [2, 0, 4, 1],
+            [1, (page_offset >> 8) as u8, page_offset as u8, 0],
+            [1, (objstm_offset >> 8) as u8, objstm_offset as u8, 0],
+            [1, (xref_offset >> 8) as u8, xref_offset as u8, 0],
+        ];
+        let mut predicted = Vec::new();
+        let mut previous = [0u8; 4];
+        for row in rows {
+            predicted.push(2);
+            predicted.extend(row.iter().zip(previous).map(|(b, p)| b.wrapping_sub(p)));
+            previous = row;
+        }
+        let xref_data = zlib_stored(&predicted);
+        pdf.extend_from_slice(
+            format!(
+                "5 0 obj\n<< /Type /XRef /Size 6 /Index [1 5] /W [1 2 1] /Root 1 0 R /Filter /FlateDecode /DecodeParms << /Predictor 12 /Columns 4 >> /Length {} >>\nstream\n",
+                xref_data.len()
+            )
+            .as_bytes(),
+        );
+        pdf.extend_from_slice(&xref_data);
+        pdf.extend_from_slice(format!("\nendstream\nendobj\nstartxref\n{}\n%%EOF\n", xref_offset).as_bytes());
+
+        let mut doc = Document::parse(&pdf).unwrap();
+        assert_eq!(doc.trailer.get("Root"), Some(&Object::Ref(1, 0)));
+        let pages = doc.get(2).unwrap();
+        assert_eq!(pages.as_dict().unwrap().get("Kids"), Some(&Object::Array(vec![Object::Ref(3, 0)])));
+        assert!(doc.get(3).unwrap().as_dict().unwrap().get("Type").unwrap().is_name("Page"));
+
+        // An update to a file with a cross-reference stream gets one too
+        let updated = doc.append_update(&[(3, Object::Dict(Dict::from([("Type", Object::name("Page")), ("Rotate", Object::int(90))])))], &[]).unwrap();
+        let mut doc = Document::parse(&updated).unwrap();
+        assert!(doc.trailer.get("Type").unwrap().is_name("XRef"));
+        assert_eq!(doc.trailer.get("Prev").and_then(Object::as_int), Some(xref_offset as i64));
+        assert_eq!(doc.get(3).unwrap().as_dict().unwrap().get("Rotate"), Some(&Object::int(90)));
+        assert!(doc.get(2).unwrap().as_dict().unwrap().get("Type").unwrap().is_name("Pages"));
+    }
+}
@@ -0,0 +1,223 @@
+//! Pool of long-running LibreOffice listeners.
+//!
+//! Each slot runs `soffice --accept=pipe,name=office2pdf_<slot>;urp;` with its own profile and is
+//! restarted by a supervisor task whenever it exits. Conversions lease a slot and talk to it over
+//! the named pipe with `uno_convert.py`, so they skip LibreOffice start-up entirely.
+
+use serde_json::{Map, Value};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::process::Command;
+use tokio::sync::{Notify, OwnedSemaphorePermit, Semaphore};
+use tracing::{debug, error, info, warn};
+
+use crate::formats::PaperSize;
+use crate::service::LaunchFlags;
+use crate::{service, shutdown};
+
+pub const UNO_CONVERT_SCRIPT: &str = include_str!("uno_convert.py");
+
+/// How long to wait before restarting a worker that exited, so a broken install cannot spin.
+const RESTART_DELAY: Duration = Duration::from_secs(1);
+
+struct Slot {
+    pipe_name: String,
+    /// Signalled when a lease saw the worker misbehave (e.g. a timeout) and wants it replaced.
+    restart: Notify,
+}
+
+pub struct LibreOfficePool {
+    slots: Vec<Arc<Slot>>,
+    free: Mutex<Vec<usize>>,
+    permits: Arc<Semaphore>,
+    python: String,
+}
+
+/// Exclusive use of one pool slot; the slot returns to the pool when this is dropped.
+pub struct Lease<'a> {
+    pool: &'a LibreOfficePool,
+    index: usize,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl LibreOfficePool {
+    /// Starts `size` workers, with their profiles under `profile_root` (given the fonts in
+    /// `font_dir`, if set), with the `--safe-mode` and extra arguments in `flags`.
+    pub fn start(size: usize, soffice: &str, python: &str, profile_root: &Path, flags: &LaunchFlags, font_dir: Option<&Path>) -> Self {
+        let slots: Vec<Arc<Slot>> = (0..size)
+            .map(|i| {
+                Arc::new(Slot {
+                    pipe_name: format!("office2pdf_{}", i),
+                    restart: Notify::new(),
+                })
+            })
+            .collect();
+
+        for (i, slot) in slots.iter().enumerate() {
+            tokio::spawn(supervise(
+                slot.clone(),
+                soffice.to_string(),
+                profile_root.join(i.to_string()),
+                flags.clone(),
+                font_dir.map(Path::to_path_buf),
+            ));
+        }
+        info!("Started LibreOffice pool with {} workers", size);
+
+        Self {
+            slots,
+            free: Mutex::new((0..size).rev().collect()),
+            permits: Arc::new(Semaphore::new(size)),
+            python: python.to_string(),
+        }
+    }
+
+    /// Waits for a free slot.
+    pub async fn lease(&self) -> Lease<'_> {
+        let permit = self.permits.clone().acquire_owned().await.expect("pool semaphore is never closed");
+        // Holding a permit guarantees a free index is available
+        let index = self.free.lock().unwrap().pop().expect("permit without free slot");
+        Lease {
+            pool: self,
+            index,
+            _permit: permit,
+        }
+    }
+}
+
+impl Lease<'_> {
+    /// Builds the client command converting `input` to `output` (a file path) in `format`,
+    /// passing `filter_data` to the export filter, loading `input` with the `infilter` import
+    /// filter (`<name>:<options>`, as for `--infilter`) and setting every page to `paper_size` if
+    /// given.
+    pub fn command(
+        &self,
+        input: &Path,
+        output: &Path,
+        format: &str,
+        filter_data: &Map<String, Value>,
+        infilter: Option<&str>,
+        paper_size: Option<PaperSize>,
+    ) -> Command {
+        let mut command = Command::new(&self.pool.python);
+        command
+            .arg("-c")
+            .arg(UNO_CONVERT_SCRIPT)
+            .arg(&self.pool.slots[self.index].pipe_name)
+            .arg(input)
+            .arg(output)
+            .arg(format)
+            .arg(Value::Object(filter_data.clone()).to_string());
+        if infilter.is_some() || paper_size.is_some() {
+            command.arg(infilter.unwrap_or_default());
+        }
+        if let Some(size) = paper_size {
+            command.arg(size.arg());
+        }
+        command
+    }
+
+    /// Kills and restarts this slot's worker, e.g. after it hung on a document.
+    pub fn restart_worker(&self) {
+        self.pool.slots[self.index].restart.notify_one();
+    }
+}
+
+impl Drop for Lease<'_> {
+    fn drop(&mut self) {
+        self.pool.free.lock().unwrap().push(self.index);
+    }
+}
+
+async fn supervise(slot: Arc<Slot>, soffice: String, profile_dir: PathBuf, flags: LaunchFlags, font_dir: Option<PathBuf>) {
+    loop {
+        let accept = format!("--accept=pipe,name={};urp;StarOffice.ComponentContext", slot.pipe_name);
+        if let Err(e) = service::prepare_profile(&profile_dir, font_dir.as_deref()).await {
+            // Not starting at all beats running with macros possibly enabled
+            error!("Failed to prepare profile for LibreOffice worker {}: {}", slot.pipe_name, e);
+            tokio::time::sleep(RESTART_DELAY).await;
+            continue;
+        }
+        let mut command = Command::new(&soffice);
+        if flags.safe_mode {
+            command.arg("--safe-mode");
+        }
+        command
+            .arg("--headless")
+            .arg("--invisible")
+            .arg("--nodefault")
+            .arg("--nofirststartwizard")
+            .arg("--nolockcheck")
+            .arg("--nologo")
+            .arg("--norestore")
+            .arg(format!("-env:UserInstallation=file://{}", profile_dir.display()))
+            .arg(&accept)
+            .args(&flags.extra_args)
+            .kill_on_drop(true)
+            .process_group(0);
+        debug!("Starting LibreOffice worker {}: {:?}", slot.pipe_name, command.as_std());
+        let spawned = command.spawn();
+
+        match spawned {
+            Ok(mut child) => {
+                let mut group = shutdown::PROCESS_GROUPS.track(child.id());
+                tokio::select! {
+                    status = child.wait() => {
+                        if status.is_ok() {
+                            group.reaped();
+                        }
+                        warn!("LibreOffice worker {} exited ({:?}), restarting", slot.pipe_name, status);
+                    }
+                    _ = slot.restart.notified() => {
+                        warn!("Restarting LibreOffice worker {}", slot.pipe_name);
+                        // The whole group: a hung soffice.bin left behind would keep the pipe and
+                        // the profile, and its replacement would hand off to it and exit
+                        group.kill();
+                        if child.kill().await.is_ok() {
+                            group.reaped();
+                        }
+                    }
+                }
+            }
+            Err(e) => error!("Failed to start LibreOffice worker {}: {}", slot.pipe_name, e),
+        }
+        tokio::time::sleep(RESTART_DELAY).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_lease_hands_out_distinct_slots() {
+        // `false` exits immediately; the supervisor just keeps restarting it
+        let pool = LibreOfficePool::start(2, "false", "python3", Path::new("/tmp/convert/pool-test"), &LaunchFlags::default(), None);
+        let a = pool.lease().await;
+        let b = pool.lease().await;
+        assert_ne!(a.index, b.index);
+        assert!(tokio::time::timeout(Duration::from_millis(50), pool.lease()).await.is_err());
+
+        drop(a);
+        let c = tokio::time::timeout(Duration::from_millis(50), pool.lease()).await.unwrap();
+        assert_ne!(c.index, b.index);
+
+        let args: Vec<_> = c.command(Path::new("in.docx"), Path::new("out.pdf"), "pdf", &Map::new(), None, None)
+            .as_std()
+            .get_args()
+            .skip(2)
+            .map(|a| a.to_string_lossy().to_string())
+            .collect();
+        assert_eq!(args, [format!("office2pdf_{}", c.index), "in.docx".into(), "out.pdf".into(), "pdf".into(), "{}".into()]);
+
+        let a4 = crate::formats::paper_size("A4");
+        let args: Vec<_> = c.command(Path::new("in.docx"), Path::new("out.pdf"), "pdf", &Map::new(), None, a4)
+            .as_std()
+            .get_args()
+            .skip(7)
+            .map(|a| a.to_string_lossy().to_string())
+            .collect();
+        assert_eq!(args, ["", "21000x29700"]);
+    }
+}
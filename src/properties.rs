@@ -0,0 +1,238 @@
+//! `doc_title`, `doc_author`, `doc_subject` and `doc_keywords`: document properties set on the
+//! converted PDF. LibreOffice only exports the source document's own properties, so these are
+//! written afterwards as an incremental update: a new `/Info` dictionary and, for PDFs that carry
+//! XMP metadata (PDF/A), a copy of it with the same values, so the two keep agreeing.
+
+use axum::http::StatusCode;
+use std::path::Path;
+use tokio::fs;
+use tracing::error;
+
+use crate::error::ConversionError;
+use crate::pdf::{self, Dict, Document, Object};
+
+/// Longest value kept, in characters; the rest is cut off.
+pub const MAX_PROPERTY_LEN: usize = 255;
+
+/// The form field, `/Info` key and XMP property of each property.
+const PROPERTIES: [(&str, &str, &str); 4] = [
+    ("doc_title", "Title", "dc:title"),
+    ("doc_author", "Author", "dc:creator"),
+    ("doc_subject", "Subject", "dc:description"),
+    ("doc_keywords", "Keywords", "pdf:Keywords"),
+];
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DocumentProperties {
+    /// Values in `PROPERTIES` order.
+    values: [Option<String>; 4],
+}
+
+impl DocumentProperties {
+    /// Reads the `doc_*` fields through `lookup`. Null bytes are dropped and values cut to
+    /// `MAX_PROPERTY_LEN` characters; blank ones are ignored.
+    pub fn from_fields<'a>(mut lookup: impl FnMut(&str) -> Option<&'a str>) -> Self {
+        let values = PROPERTIES.map(|(field, _, _)| {
+            let value: String = lookup(field)?.chars().filter(|&c| c != '\0').collect();
+            let value: String = value.trim().chars().take(MAX_PROPERTY_LEN).collect();
+            (!value.is_empty()).then_some(value)
+        });
+        Self { values }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.iter().all(Option::is_none)
+    }
+
+    fn iter(&self) -> impl Iterator<Item = (&'static str, &'static str, &str)> {
+        PROPERTIES.iter().zip(&self.values).filter_map(|(&(_, key, xmp), value)| Some((key, xmp, value.as_deref()?)))
+    }
+
+    /// `pdf` with the properties set.
+    pub fn apply(&self, pdf: &[u8]) -> Result<Vec<u8>, String> {
+        let mut document = Document::parse(pdf)?;
+        let mut objects = Vec::new();
+
+        let info_ref = document.trailer.get("Info").cloned();
+        let mut info = match info_ref {
+            Some(ref info) => document.resolve(info)?.as_dict().cloned().unwrap_or_default(),
+            None => Dict::default(),
+        };
+        for (key, _, value) in self.iter() {
+            info.set(key, Object::String(pdf::text_string(value)));
+        }
+        let info_id = match info_ref {
+            Some(Object::Ref(id, _)) => id,
+            _ => document.next_id(),
+        };
+        objects.push((info_id, Object::Dict(info)));
+
+        let root = document.trailer.get("Root").cloned().unwrap_or(Object::Null);
+        let catalog = document.resolve(&root)?;
+        if let Some(&Object::Ref(metadata_id, _)) = catalog.as_dict().and_then(|catalog| catalog.get("Metadata"))
+            && let Object::Stream(mut dict, data) = document.get(metadata_id)?
+        {
+            let data = if dict.get("Filter").is_some() { pdf::decode(&dict, &data)? } else { data };
+            if let Some(xmp) = self.update_xmp(&String::from_utf8_lossy(&data)) {
+                dict.remove("Filter");
+                dict.remove("DecodeParms");
+                objects.push((metadata_id, Object::Stream(dict, xmp.into_bytes())));
+            }
+        }
+        document.append_update(&objects, &[("Info", Object::Ref(info_id, 0))])
+    }
+
+    /// The XMP packet `xmp` with the properties replaced, or `None` if it is not one.
+    fn update_xmp(&self, xmp: &str) -> Option<String> {
+        let mut xmp = xmp.to_string();
+        let mut description = String::new();
+        for (_, name, value) in self.iter() {
+            remove_elements(&mut xmp, name);
+            let value = escape_xml(value);
+            let element = match name {
+                "dc:creator" => format!("<rdf:Seq><rdf:li>{}</rdf:li></rdf:Seq>", value),
+                "dc:title" | "dc:description" => format!(r#"<rdf:Alt><rdf:li xml:lang="x-default">{}</rdf:li></rdf:Alt>"#, value),
+                _ => value,
+            };
+            description.push_str(&format!("<{0}>{1}</{0}>", name, element));
+        }
+        let end = xmp.find("</rdf:RDF>")?;
+        xmp.insert_str(
+            end,
+            &format!(
+                r#"<rdf:Description rdf:about="" xmlns:dc="http://purl.org/dc/elements/1.1/" xmlns:pdf="http://ns.adobe.com/pdf/1.3/">{}</rdf:Description>"#,
+                description
+            ),
+        );
+        Some(xmp)
+    }
+}
+
+/// Removes every `<name>` element from `xml`, leaving elements with longer names alone.
+fn remove_elements(xml: &mut String, name: &str) {
+    let open = format!("<{}", name);
+    let close = format!("</{}>", name);
+    let mut from = 0;
+    while let Some(found) = xml[from..].find(&open) {
+        let start = from + found;
+        let rest = &xml[start + open.len()..];
+        if !rest.starts_with(['>', ' ', '\t', '\r', '\n', '/']) {
+            from = start + open.len();
+            continue;
+        }
+        let Some(tag_end) = rest.find('>').map(|at| start + open.len() + at + 1) else {
+            return;
+        };
+        let end = if xml[..tag_end].ends_with("/>") {
+            tag_end
+        } else {
+            match xml[tag_end..].find(&close) {
+                Some(at) => tag_end + at + close.len(),
+                None => return,
+            }
+        };
+        xml.replace_range(start..end, "");
+        from = start;
+    }
+}
+
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+/// Sets `properties` on each PDF in `out_dir`.
+pub async fn apply_to_outputs(out_dir: &Path, properties: &DocumentProperties) -> Result<(), ConversionError> {
+    let failed = || ConversionError::new(StatusCode::INTERNAL_SERVER_ERROR, "PDF_PROPERTIES_FAILED", "Setting the PDF's properties failed");
+    let mut entries = fs::read_dir(out_dir).await.map_err(|e| {
+        error!("Failed to list output dir: {}", e);
+        ConversionError::internal()
+    })?;
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let path = entry.path();
+        if path.extension().is_none_or(|e| e != "pdf") {
+            continue;
+        }
+        let data = fs::read(&path).await.map_err(|e| {
+            error!("Failed to read output: {}", e);
+            failed()
+        })?;
+        let properties = properties.clone();
+        let updated = tokio::task::spawn_blocking(move || properties.apply(&data)).await.map_err(|e| {
+            error!("Setting PDF properties panicked: {}", e);
+            ConversionError::internal()
+        })?;
+        let updated = updated.map_err(|e| {
+            error!("Failed to set PDF properties: {}", e);
+            failed()
+        })?;
+        fs::write(&path, updated).await.map_err(|e| {
+            error!("Failed to write output: {}", e);
+            failed()
+        })?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::export;
+    use crate::pdf::tests::sample_pdf;
+    use std::collections::HashMap;
+
+    fn properties(fields: &[(&str, &str)]) -> DocumentProperties {
+        let fields: HashMap<&str, &str> = fields.iter().copied().collect();
+        DocumentProperties::from_fields(|name| fields.get(name).copied())
+    }
+
+    #[test]
+    fn test_from_fields() {
+        let long = "é".repeat(300);
+        let props = properties(&[("doc_title", " Q3\0 Report "), ("doc_author", &long), ("doc_subject", "  ")]);
+        assert_eq!(props.values[0].as_deref(), Some("Q3 Report"));
+        assert_eq!(props.values[1].as_ref().map(|a| a.chars().count()), Some(MAX_PROPERTY_LEN));
+        assert_eq!(props.values[2], None);
+        assert!(properties(&[("doc_keywords", "")]).is_empty());
+    }
+
+    #[test]
+    fn test_apply() {
+        let original = sample_pdf(&["one", "two"]);
+        let props = properties(&[("doc_title", "Q3 Report"), ("doc_keywords", "finance, 2024")]);
+        let updated = props.apply(&original).unwrap();
+        // An incremental update: the original is kept as it was
+        assert!(updated.starts_with(&original));
+
+        let mut document = Document::parse(&updated).unwrap();
+        let info = document.trailer.get("Info").cloned().unwrap();
+        let info = document.resolve(&info).unwrap();
+        assert_eq!(info.as_dict().unwrap().get("Title"), Some(&Object::String(pdf::text_string("Q3 Report"))));
+        assert!(info.as_dict().unwrap().get("Author").is_none());
+        assert_eq!(export::count_pdf_pages(&updated), 2);
+
+        // Applying again replaces the /Info object, following /Prev to the first revision
+        let updated = properties(&[("doc_author", "Ada")]).apply(&updated).unwrap();
+        let mut document = Document::parse(&updated).unwrap();
+        let info = document.trailer.get("Info").cloned().unwrap();
+        let info = document.resolve(&info).unwrap();
+        let info = info.as_dict().unwrap();
+        assert_eq!(info.get("Title"), Some(&Object::String(pdf::text_string("Q3 Report"))));
+        assert_eq!(info.get("Author"), Some(&Object::String(pdf::text_string("Ada"))));
+        assert!(document.trailer.get("Prev").and_then(Object::as_int).is_some_and(|prev| prev as usize > original.len()));
+        assert_eq!(document.get(3).unwrap().as_dict().unwrap().get("Type"), Some(&Object::name("Page")));
+
+        assert!(props.apply(b"%PDF-1.4 nothing").is_err());
+    }
+
+    #[test]
+    fn test_update_xmp() {
+        let xmp = r#"<?xpacket begin=""?><x:xmpmeta><rdf:RDF><rdf:Description rdf:about="" xmlns:dc="http://purl.org/dc/elements/1.1/"><dc:title><rdf:Alt><rdf:li xml:lang="x-default">Old</rdf:li></rdf:Alt></dc:title><dc:titles/><dc:format>application/pdf</dc:format></rdf:Description><rdf:Description rdf:about="" xmlns:pdfaid="http://www.aiim.org/pdfa/ns/id/"><pdfaid:part>2</pdfaid:part></rdf:Description></rdf:RDF></x:xmpmeta><?xpacket end="w"?>"#;
+        let updated = properties(&[("doc_title", "A <b> & c"), ("doc_author", "Ada")]).update_xmp(xmp).unwrap();
+        assert!(!updated.contains(">Old<"));
+        assert!(updated.contains("<dc:titles/>"));
+        assert!(updated.contains("<pdfaid:part>2</pdfaid:part>"));
+        assert!(updated.contains(r#"<dc:title><rdf:Alt><rdf:li xml:lang="x-default">A &lt;b&gt; &amp; c</rdf:li></rdf:Alt></dc:title>"#));
+        assert!(updated.contains("<dc:creator><rdf:Seq><rdf:li>Ada</rdf:li></rdf:Seq></dc:creator></rdf:Description></rdf:RDF>"));
+        assert_eq!(properties(&[("doc_title", "x")]).update_xmp("<x:xmpmeta/>"), None);
+    }
+}
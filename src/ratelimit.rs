@@ -0,0 +1,289 @@
+//! Per-client rate limiting for conversion requests.
+//!
+//! Each client gets a token bucket holding up to `RATE_LIMIT_BURST` requests, refilled at
+//! `RATE_LIMIT_RPS` per second. Authenticated clients are told apart by their identity (see
+//! `ClientIdentity`), so many users behind one NAT address do not share a bucket and one user
+//! does not get a fresh bucket per address; `RATE_LIMIT_KEY_<identity>` sets a different rate
+//! for one of them. Anyone else is limited by IP. Buckets that have refilled completely carry no
+//! state worth keeping and are dropped periodically.
+
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::{header, HeaderMap, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::warn;
+
+use crate::error::ConversionError;
+use crate::AppState;
+
+const EVICTION_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Who an authenticated request came from: the `apikeys::key_id` of its API key, or the `sub`
+/// of its bearer token. Added to the request by the auth middleware.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ClientIdentity(pub String);
+
+/// How fast one client may start conversions.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Rate {
+    pub per_second: f64,
+    pub burst: u32,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn full(rate: Rate, now: Instant) -> Self {
+        Self {
+            tokens: rate.burst as f64,
+            updated: now,
+        }
+    }
+
+    /// Takes a token, or returns how long until one is available.
+    fn take(&mut self, rate: Rate, now: Instant) -> Result<(), Duration> {
+        self.tokens = self.tokens_at(rate, now);
+        self.updated = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return Ok(());
+        }
+        Err(Duration::from_secs_f64((1.0 - self.tokens) / rate.per_second))
+    }
+
+    fn tokens_at(&self, rate: Rate, now: Instant) -> f64 {
+        let refilled = now.saturating_duration_since(self.updated).as_secs_f64() * rate.per_second;
+        (self.tokens + refilled).min(rate.burst as f64)
+    }
+}
+
+/// A client as the rate limiter tells them apart.
+#[derive(Clone, Debug, PartialEq)]
+enum Client {
+    Ip(IpAddr),
+    Identity(String),
+}
+
+impl std::fmt::Display for Client {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Client::Ip(ip) => write!(f, "{}", ip),
+            Client::Identity(identity) => write!(f, "client {}", identity),
+        }
+    }
+}
+
+pub struct RateLimiter {
+    rate: Rate,
+    /// `RATE_LIMIT_KEY_<identity>` rates, by identity.
+    overrides: HashMap<String, Rate>,
+    /// Reverse proxies in front of the service whose `X-Forwarded-For` entries are trusted.
+    trusted_proxies: usize,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+    identity_buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(burst: u32, per_second: f64, trusted_proxies: usize) -> Self {
+        Self {
+            rate: Rate { per_second, burst },
+            overrides: HashMap::new(),
+            trusted_proxies,
+            buckets: Mutex::new(HashMap::new()),
+            identity_buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn with_overrides(mut self, overrides: HashMap<String, Rate>) -> Self {
+        self.overrides = overrides;
+        self
+    }
+
+    fn rate_for(&self, identity: &str) -> Rate {
+        self.overrides.get(identity).copied().unwrap_or(self.rate)
+    }
+
+    /// Takes a token from `client`'s bucket, or returns how long until one is available.
+    fn check(&self, client: &Client, now: Instant) -> Result<(), Duration> {
+        match client {
+            Client::Ip(ip) => self.buckets.lock().unwrap().entry(*ip).or_insert_with(|| Bucket::full(self.rate, now)).take(self.rate, now),
+            Client::Identity(identity) => {
+                let rate = self.rate_for(identity);
+                let mut buckets = self.identity_buckets.lock().unwrap();
+                buckets.entry(identity.clone()).or_insert_with(|| Bucket::full(rate, now)).take(rate, now)
+            }
+        }
+    }
+
+    fn evict_full(&self, now: Instant) -> usize {
+        let mut buckets = self.buckets.lock().unwrap();
+        let mut identity_buckets = self.identity_buckets.lock().unwrap();
+        let before = buckets.len() + identity_buckets.len();
+        buckets.retain(|_, b| b.tokens_at(self.rate, now) < self.rate.burst as f64);
+        identity_buckets.retain(|identity, b| {
+            let rate = self.rate_for(identity);
+            b.tokens_at(rate, now) < rate.burst as f64
+        });
+        before - buckets.len() - identity_buckets.len()
+    }
+
+    /// The bucket `req` is counted against: its identity if it was authenticated, otherwise its IP.
+    fn client(&self, req: &Request, peer: IpAddr) -> Client {
+        match req.extensions().get::<ClientIdentity>() {
+            Some(ClientIdentity(identity)) => Client::Identity(identity.clone()),
+            None => Client::Ip(self.client_ip(req.headers(), peer)),
+        }
+    }
+
+    fn client_ip(&self, headers: &HeaderMap, peer: IpAddr) -> IpAddr {
+        client_ip(headers, peer, self.trusted_proxies)
+    }
+}
+
+/// The client address: the socket peer, or with `trusted_proxies` proxies in front, the entry
+/// that many places from the end of `X-Forwarded-For` (the last one added by a proxy we trust).
+/// Entries further left are client-controlled and ignored.
+pub fn client_ip(headers: &HeaderMap, peer: IpAddr, trusted_proxies: usize) -> IpAddr {
+    if trusted_proxies == 0 {
+        return peer;
+    }
+    let forwarded: Vec<&str> = headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(str::trim)
+        .collect();
+    forwarded
+        .len()
+        .checked_sub(trusted_proxies)
+        .and_then(|i| forwarded[i].parse().ok())
+        .unwrap_or(peer)
+}
+
+pub async fn run_eviction(state: Arc<AppState>) {
+    let mut interval = tokio::time::interval(EVICTION_INTERVAL);
+    loop {
+        interval.tick().await;
+        let now = Instant::now();
+        if let Some(ref limiter) = state.rate_limiter {
+            limiter.evict_full(now);
+        }
+        state.job_status_limiter.evict_full(now);
+    }
+}
+
+pub async fn rate_limit(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    req: Request,
+    next: Next,
+) -> Response {
+    match state.rate_limiter {
+        Some(ref limiter) => limit(&state, limiter, peer.ip(), req, next).await,
+        None => next.run(req).await,
+    }
+}
+
+/// The limit on `POST /jobs/status`, which applies whether or not `RATE_LIMIT_RPS` is set.
+pub async fn rate_limit_job_status(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    req: Request,
+    next: Next,
+) -> Response {
+    limit(&state, &state.job_status_limiter, peer.ip(), req, next).await
+}
+
+async fn limit(state: &AppState, limiter: &RateLimiter, peer: IpAddr, req: Request, next: Next) -> Response {
+    let client = limiter.client(&req, peer);
+    match limiter.check(&client, Instant::now()) {
+        Ok(()) => next.run(req).await,
+        Err(wait) => {
+            let retry_after = wait.as_secs_f64().ceil().max(1.0) as u64;
+            warn!("Rate limited {} (retry after {}s)", client, retry_after);
+            state.metrics.rate_limited.inc();
+            if let Client::Identity(ref identity) = client {
+                state.metrics.rate_limit_hits.inc(identity);
+            }
+            let error = ConversionError::new(
+                StatusCode::TOO_MANY_REQUESTS,
+                "RATE_LIMITED",
+                format!("Too many requests; retry in {} seconds", retry_after),
+            );
+            ([(header::RETRY_AFTER, retry_after.to_string())], error).into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_bucket() {
+        let limiter = RateLimiter::new(2, 0.5, 0);
+        let ip = Client::Ip("192.0.2.1".parse().unwrap());
+        let start = Instant::now();
+        assert!(limiter.check(&ip, start).is_ok());
+        assert!(limiter.check(&ip, start).is_ok());
+        assert_eq!(limiter.check(&ip, start), Err(Duration::from_secs(2)));
+        // Another client has its own bucket
+        assert!(limiter.check(&Client::Ip("192.0.2.2".parse().unwrap()), start).is_ok());
+
+        assert!(limiter.check(&ip, start + Duration::from_secs(2)).is_ok());
+        assert_eq!(limiter.evict_full(start + Duration::from_secs(2)), 1);
+        assert_eq!(limiter.evict_full(start + Duration::from_secs(10)), 1);
+    }
+
+    #[test]
+    fn test_identity_buckets() {
+        let overrides = HashMap::from([("ci".to_string(), Rate { per_second: 10.0, burst: 3 })]);
+        let limiter = RateLimiter::new(1, 1.0, 0).with_overrides(overrides);
+        let start = Instant::now();
+        let alice = Client::Identity("alice".to_string());
+        assert!(limiter.check(&alice, start).is_ok());
+        assert_eq!(limiter.check(&alice, start), Err(Duration::from_secs(1)));
+        // The same address unauthenticated, and a client with a higher rate, are counted apart
+        assert!(limiter.check(&Client::Ip("192.0.2.1".parse().unwrap()), start).is_ok());
+        let ci = Client::Identity("ci".to_string());
+        for _ in 0..3 {
+            assert!(limiter.check(&ci, start).is_ok());
+        }
+        assert_eq!(limiter.check(&ci, start), Err(Duration::from_millis(100)));
+        assert_eq!(limiter.evict_full(start + Duration::from_secs(1)), 3);
+    }
+
+    #[test]
+    fn test_client() {
+        let limiter = RateLimiter::new(1, 1.0, 0);
+        let peer: IpAddr = "10.0.0.1".parse().unwrap();
+        let mut req = Request::new(axum::body::Body::empty());
+        assert_eq!(limiter.client(&req, peer), Client::Ip(peer));
+        req.extensions_mut().insert(ClientIdentity("alice".to_string()));
+        assert_eq!(limiter.client(&req, peer), Client::Identity("alice".to_string()));
+    }
+
+    #[test]
+    fn test_client_ip() {
+        let peer: IpAddr = "10.0.0.1".parse().unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", "203.0.113.9, 198.51.100.7".parse().unwrap());
+
+        assert_eq!(RateLimiter::new(1, 1.0, 0).client_ip(&headers, peer), peer);
+        assert_eq!(RateLimiter::new(1, 1.0, 1).client_ip(&headers, peer), "198.51.100.7".parse::<IpAddr>().unwrap());
+        assert_eq!(RateLimiter::new(1, 1.0, 2).client_ip(&headers, peer), "203.0.113.9".parse::<IpAddr>().unwrap());
+        // Fewer entries than trusted proxies: the header cannot be trusted
+        assert_eq!(RateLimiter::new(1, 1.0, 3).client_ip(&headers, peer), peer);
+    }
+}
@@ -0,0 +1,316 @@
+//! `POST /render`: every page of a document as an image, zipped as `page_001.png`,
+//! `page_002.png`...
+//!
+//! LibreOffice's image export only writes the first page, so the document is first converted to
+//! a PDF of the requested pages, which is cut into single pages as `/split` does. Each page is then
+//! exported on its own, at its size in points scaled to `dpi`.
+
+use axum::{
+    extract::{ConnectInfo, Multipart, Query, State},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Extension,
+};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use tokio::fs;
+use tokio::time::Instant;
+use tracing::{error, info, info_span, Instrument};
+
+use crate::audit::{self, Requester};
+use crate::error::ConversionError;
+use crate::formats::{self, OutputFormat};
+use crate::merge::{self, Merger};
+use crate::pdf::Document;
+use crate::telemetry::RequestId;
+use crate::thumbnail::JpegExport;
+use crate::zip::ZipWriter;
+use crate::{busy_response, convert_file, disk, export, file_response, filename_header, receive_upload, run_conversion, shutting_down_response};
+use crate::{AppState, ConvertOptions, ConvertedFile, Disposition, UploadedFile};
+
+const DEFAULT_DPI: u32 = 150;
+const MAX_DPI: u32 = 600;
+const DEFAULT_QUALITY: u32 = 85;
+/// Pixels on either side of a page image, whatever its size in points.
+const MAX_PIXELS: u32 = 16384;
+/// The page size assumed when the PDF does not give one: A4, in points.
+const A4: (f64, f64) = (595.304, 841.89);
+
+/// The `image_format`, `dpi`, `quality` and `pages` fields.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RenderOptions {
+    /// `png` or `jpg`.
+    pub format: OutputFormat,
+    pub dpi: u32,
+    /// JPEG quality, 1 to 100; PNG is lossless and ignores it.
+    pub quality: u32,
+    /// Sorted 1-based page numbers; `None` renders them all.
+    pub pages: Option<Vec<u32>>,
+}
+
+impl RenderOptions {
+    pub fn from_request(params: &HashMap<String, String>, fields: &HashMap<String, String>) -> Result<Self, ConversionError> {
+        let lookup = |name: &str| {
+            let value = fields.get(name).or_else(|| params.get(name))?.trim();
+            (!value.is_empty()).then_some(value)
+        };
+        let invalid = |message: String| ConversionError::new(StatusCode::BAD_REQUEST, "INVALID_RENDER_OPTIONS", message);
+        let number = |name: &str, default: u32, max: u32| match lookup(name) {
+            None => Ok(default),
+            Some(raw) => raw
+                .parse()
+                .ok()
+                .filter(|n| (1..=max).contains(n))
+                .ok_or_else(|| invalid(format!("{} must be an integer from 1 to {}, not {:?}", name, max, raw))),
+        };
+        let format = match lookup("image_format").map(str::to_ascii_lowercase).as_deref() {
+            None | Some("png") => formats::PNG,
+            Some("jpg" | "jpeg") => formats::JPG,
+            Some(other) => return Err(invalid(format!("image_format must be png or jpeg, not {:?}", other))),
+        };
+        Ok(Self {
+            format,
+            dpi: number("dpi", DEFAULT_DPI, MAX_DPI)?,
+            quality: number("quality", DEFAULT_QUALITY, 100)?,
+            pages: lookup("pages").map(export::parse_pages).transpose()?,
+        })
+    }
+}
+
+pub async fn render(
+    State(state): State<Arc<AppState>>,
+    Extension(RequestId(request_id)): Extension<RequestId>,
+    peer: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+    Query(params): Query<HashMap<String, String>>,
+    mut multipart: Multipart,
+) -> Response {
+    if state.draining.load(Ordering::SeqCst) {
+        return shutting_down_response();
+    }
+    let Some(slot) = state.try_conversion_slot() else {
+        return busy_response();
+    };
+
+    let _active_dir = state.active_dirs.track(request_id);
+    let work_dir = state.request_dir(request_id);
+    if let Err(e) = fs::create_dir_all(&work_dir).await {
+        error!("Failed to create work dir: {}", e);
+        return ConversionError::internal().into_response();
+    }
+    let result = async {
+        disk::ensure_free_space(&work_dir, state.min_free_bytes)?;
+        let upload = receive_upload(&mut multipart, filename_header(&headers).as_deref(), &work_dir, &state, Some(&state.allowed_input_types))
+            .instrument(info_span!("multipart"))
+            .await?;
+        let [ref file] = upload.files[..] else {
+            return Err(ConversionError::new(StatusCode::BAD_REQUEST, "TOO_MANY_FILES", "Pages are rendered from one file at a time"));
+        };
+        let opts = RenderOptions::from_request(&params, &upload.fields)?;
+        let started = Instant::now();
+        let rendered = render_pages(&state, &work_dir, file, &opts).await;
+        let requester = Requester::new(&state, request_id, &headers, peer);
+        let archive = audit::record(&state, requester, file, opts.format.extension, started.elapsed(), rendered).await?;
+        let stem = file.path.file_stem().unwrap_or_default().to_string_lossy().to_string();
+        Ok((stem, archive))
+    }
+    .await;
+    drop(slot);
+    let _ = fs::remove_dir_all(&work_dir).instrument(info_span!("cleanup")).await;
+
+    match result {
+        Ok((stem, archive)) => {
+            let converted = ConvertedFile {
+                filename: format!("{}_pages.zip", stem),
+                content_type: "application/zip",
+                content: archive,
+                headers: Vec::new(),
+            };
+            file_response(&converted, Disposition::Attachment)
+        }
+        Err(e) => e.into_response(),
+    }
+}
+
+/// Renders the pages `opts` asks for and zips the images, stopping once they add up to more than
+/// `MAX_RENDER_BYTES`.
+async fn render_pages(state: &AppState, work_dir: &Path, file: &UploadedFile, opts: &RenderOptions) -> Result<Vec<u8>, ConversionError> {
+    let selected = ConvertOptions {
+        pages: opts.pages.clone(),
+        ..Default::default()
+    };
+    let pdf = run_conversion(state, work_dir, file, &selected).await?;
+    let pages = tokio::task::spawn_blocking(move || single_pages(&pdf.content))
+        .instrument(info_span!("split"))
+        .await
+        .map_err(|e| {
+            error!("PDF split panicked: {}", e);
+            ConversionError::internal()
+        })?
+        .map_err(|e| {
+            error!("Failed to split the converted PDF: {}", e);
+            ConversionError::internal()
+        })?;
+
+    let mut archive = ZipWriter::new();
+    let mut total = 0;
+    for (n, page) in pages.iter().enumerate() {
+        // Named after the page of the document, not its place in the selection
+        let number = opts.pages.as_ref().and_then(|pages| pages.get(n).copied()).unwrap_or(n as u32 + 1);
+        let name = format!("page_{:03}", number);
+        // A directory per page, so each export finds only its own image in `out/`
+        let path = work_dir.join("render").join(&name).join(format!("{}.pdf", name));
+        let written = async {
+            fs::create_dir_all(path.parent().unwrap_or(work_dir)).await?;
+            fs::write(&path, page).await
+        };
+        if let Err(e) = written.await {
+            error!("Failed to write page PDF: {}", e);
+            return Err(ConversionError::internal());
+        }
+
+        let (width, height) = pixel_size(export::pdf_page_size(page), opts.dpi);
+        let image = ConvertOptions {
+            format: opts.format,
+            jpeg: Some(JpegExport {
+                width,
+                height,
+                quality: opts.quality,
+            }),
+            ..Default::default()
+        };
+        let page_file = UploadedFile {
+            path: path.clone(),
+            size: page.len() as u64,
+            kind: "pdf",
+            digest: None,
+        };
+        let rendered = convert_file(state, work_dir, &page_file, &image).await?;
+        let _ = fs::remove_dir_all(path.parent().unwrap_or(work_dir)).await;
+
+        total += rendered.content.len() as u64;
+        if total > state.max_render_bytes {
+            error!("Rendered pages exceed MAX_RENDER_BYTES ({}) at page {}", state.max_render_bytes, number);
+            return Err(ConversionError::new(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "RENDER_TOO_LARGE",
+                format!("The rendered pages add up to more than the limit of {} bytes", state.max_render_bytes),
+            )
+            .with_detail("limit", state.max_render_bytes)
+            .with_detail("page", number));
+        }
+        archive.add(&format!("{}.{}", name, opts.format.extension), &rendered.content);
+    }
+    info!("Rendered {} pages as {} ({} bytes)", pages.len(), opts.format.extension, total);
+    Ok(archive.finish())
+}
+
+/// Each page of the PDF `content` as a PDF of its own.
+fn single_pages(content: &[u8]) -> Result<Vec<Vec<u8>>, String> {
+    let mut document = Document::parse(content)?;
+    let pages = merge::pages(&mut document)?;
+    pages
+        .into_iter()
+        .map(|page| {
+            let mut single = Merger::new();
+            single.append_pages(&mut document, vec![page], None)?;
+            Ok(single.finish())
+        })
+        .collect()
+}
+
+/// The pixel size of a page of `page` points (A4 when unknown) at `dpi`.
+fn pixel_size(page: Option<(f64, f64)>, dpi: u32) -> (u32, u32) {
+    let (width, height) = page.unwrap_or(A4);
+    let pixels = |points: f64| ((points / 72.0 * dpi as f64).round() as u32).clamp(1, MAX_PIXELS);
+    (pixels(width), pixels(height))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pdf::tests::sample_pdf;
+    use crate::service::MockConversionService;
+    use crate::tests::test_state;
+    use crate::zip::ZipReader;
+    use axum::{body::Body, extract::Request, http::header, routing::post, Router};
+    use tower::ServiceExt;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_options() {
+        let opts = RenderOptions::from_request(&HashMap::new(), &HashMap::new()).unwrap();
+        assert_eq!((opts.format.extension, opts.dpi, opts.quality, opts.pages), ("png", 150, 85, None));
+
+        let fields = HashMap::from([("image_format".to_string(), "JPEG".to_string()), ("pages".to_string(), "3,1".to_string())]);
+        let params = HashMap::from([("dpi".to_string(), "300".to_string()), ("quality".to_string(), "60".to_string())]);
+        let opts = RenderOptions::from_request(&params, &fields).unwrap();
+        assert_eq!((opts.format, opts.dpi, opts.quality, opts.pages), (formats::JPG, 300, 60, Some(vec![1, 3])));
+
+        for (name, bad) in [("dpi", "0"), ("dpi", "601"), ("quality", "101"), ("image_format", "gif")] {
+            let fields = HashMap::from([(name.to_string(), bad.to_string())]);
+            let err = RenderOptions::from_request(&HashMap::new(), &fields).unwrap_err();
+            assert_eq!(err.code, "INVALID_RENDER_OPTIONS", "{}={}", name, bad);
+        }
+        let fields = HashMap::from([("pages".to_string(), "2-1".to_string())]);
+        assert_eq!(RenderOptions::from_request(&HashMap::new(), &fields).unwrap_err().code, "INVALID_PAGE_RANGE");
+    }
+
+    #[test]
+    fn test_pixel_size() {
+        // US Letter at 150 and 300 DPI
+        assert_eq!(pixel_size(Some((612.0, 792.0)), 150), (1275, 1650));
+        assert_eq!(pixel_size(Some((612.0, 792.0)), 300), (2550, 3300));
+        assert_eq!(pixel_size(None, 72), (595, 842));
+        assert_eq!(pixel_size(Some((14400.0, 1.0)), 600), (MAX_PIXELS, 8));
+    }
+
+    #[tokio::test]
+    async fn test_render() {
+        let send = |state: AppState, fields: &str| {
+            let app = Router::new()
+                .route("/render", post(render))
+                .layer(Extension(RequestId(Uuid::new_v4())))
+                .with_state(Arc::new(state));
+            let mut body = format!("{}--b\r\nContent-Disposition: form-data; name=\"file\"; filename=\"deck.pdf\"\r\n\r\n", fields).into_bytes();
+            body.extend_from_slice(&sample_pdf(&["one", "two", "three"]));
+            body.extend_from_slice(b"\r\n--b--\r\n");
+            let request = Request::builder()
+                .method("POST")
+                .uri("/render")
+                .header(header::CONTENT_TYPE, "multipart/form-data; boundary=b")
+                .body(Body::from(body))
+                .unwrap();
+            app.oneshot(request)
+        };
+        // The mock "renders" each page by copying its PDF
+        let mock = || AppState {
+            converter: Arc::new(MockConversionService),
+            allowed_input_types: vec!["pdf".to_string()],
+            ..test_state()
+        };
+
+        let response = send(mock(), "").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "application/zip");
+        assert_eq!(response.headers()[header::CONTENT_DISPOSITION], "attachment; filename=\"deck_pages.zip\"");
+        let archive = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let zip = ZipReader::new(&archive).unwrap();
+        assert_eq!(zip.names().collect::<Vec<_>>(), ["page_001.png", "page_002.png", "page_003.png"]);
+        let page = zip.read("page_002.png", usize::MAX).unwrap();
+        assert_eq!(export::count_pdf_pages(&page), 1);
+
+        let response = send(mock(), "--b\r\nContent-Disposition: form-data; name=\"image_format\"\r\n\r\njpeg\r\n").await.unwrap();
+        let archive = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(ZipReader::new(&archive).unwrap().names().collect::<Vec<_>>(), ["page_001.jpg", "page_002.jpg", "page_003.jpg"]);
+
+        let small = AppState { max_render_bytes: 10, ..mock() };
+        let response = send(small, "").await.unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        let body: serde_json::Value = serde_json::from_slice(&axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap()).unwrap();
+        assert_eq!((body["code"].as_str(), body["page"].as_u64()), (Some("RENDER_TOO_LARGE"), Some(1)));
+    }
+}
@@ -0,0 +1,94 @@
+//! Upload of converted files to an S3-compatible bucket.
+
+use std::env;
+use std::fmt::Write as _;
+use std::time::Duration;
+
+use crate::crypto::sha256;
+use crate::outbound::{self, AwsSigV4};
+
+const UPLOAD_TIMEOUT: Duration = Duration::from_secs(120);
+
+pub struct S3Config {
+    bucket: String,
+    /// e.g. `https://s3.eu-west-1.amazonaws.com` or `http://minio:9000`; objects are addressed path-style.
+    endpoint: String,
+    region: String,
+    access_key: String,
+    secret_key: String,
+}
+
+impl S3Config {
+    /// Reads `S3_BUCKET`, `S3_ENDPOINT`, `AWS_ACCESS_KEY_ID` and `AWS_SECRET_ACCESS_KEY` (plus the
+    /// optional `AWS_REGION`). Returns `None` unless the bucket or endpoint is set, and an error
+    /// naming the missing variables if only some are.
+    pub fn from_env() -> Result<Option<Self>, String> {
+        if env::var_os("S3_BUCKET").is_none() && env::var_os("S3_ENDPOINT").is_none() {
+            return Ok(None);
+        }
+        let vars = ["S3_BUCKET", "S3_ENDPOINT", "AWS_ACCESS_KEY_ID", "AWS_SECRET_ACCESS_KEY"].map(|name| (name, env::var(name).ok()));
+        let missing: Vec<&str> = vars.iter().filter(|(_, value)| value.is_none()).map(|(name, _)| *name).collect();
+        if !missing.is_empty() {
+            return Err(format!("S3 upload is partially configured; also set {}", missing.join(", ")));
+        }
+        let [bucket, endpoint, access_key, secret_key] = vars.map(|(_, value)| value.unwrap_or_default());
+        Ok(Some(Self {
+            bucket,
+            endpoint: endpoint.trim_end_matches('/').to_string(),
+            region: env::var("AWS_REGION").unwrap_or_else(|_| "us-east-1".to_string()),
+            access_key,
+            secret_key,
+        }))
+    }
+
+    pub fn bucket(&self) -> &str {
+        &self.bucket
+    }
+
+    /// Stores `body` under `key` and returns its `s3://bucket/key` URI.
+    pub async fn put_object(&self, key: &str, content_type: &str, body: &[u8]) -> Result<String, String> {
+        let url = format!("{}/{}/{}", self.endpoint, encode_path(&self.bucket), encode_path(key));
+        let auth = AwsSigV4 {
+            region: &self.region,
+            service: "s3",
+            access_key: &self.access_key,
+            secret_key: &self.secret_key,
+        };
+        let headers = [("x-amz-content-sha256", hex(&sha256(body)))];
+        match outbound::put_signed(&url, content_type, &headers, &auth, body, UPLOAD_TIMEOUT).await? {
+            200..=299 => Ok(format!("s3://{}/{}", self.bucket, key)),
+            status => Err(format!("S3 returned HTTP {}", status)),
+        }
+    }
+}
+
+/// Percent-encodes everything except RFC 3986 unreserved characters and `/`, as SigV4 expects.
+fn encode_path(path: &str) -> String {
+    path.bytes().fold(String::with_capacity(path.len()), |mut out, b| {
+        if b.is_ascii_alphanumeric() || b"-_.~/".contains(&b) {
+            out.push(b as char);
+        } else {
+            let _ = write!(out, "%{:02X}", b);
+        }
+        out
+    })
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::with_capacity(bytes.len() * 2), |mut out, b| {
+        let _ = write!(out, "{:02x}", b);
+        out
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_path() {
+        assert_eq!(encode_path("1234/report (2).pdf"), "1234/report%20%282%29.pdf");
+        assert_eq!(encode_path("a/b_c-d~e.pdf"), "a/b_c-d~e.pdf");
+        assert_eq!(encode_path("é"), "%C3%A9");
+    }
+}
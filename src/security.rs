@@ -0,0 +1,141 @@
+//! Security headers added to every response, so the upload page cannot be framed or have
+//! scripts injected into it. Each header is set by an env var; an empty value leaves it out, for
+//! operators embedding the page in their own frontend.
+
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderName, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+use std::sync::Arc;
+
+/// The env var, header and default value of each header sent on every response.
+const HEADERS: [(&str, HeaderName, &str); 4] = [
+    ("CONTENT_SECURITY_POLICY", header::CONTENT_SECURITY_POLICY, "default-src 'self'"),
+    ("X_FRAME_OPTIONS", header::X_FRAME_OPTIONS, "DENY"),
+    ("X_CONTENT_TYPE_OPTIONS", header::X_CONTENT_TYPE_OPTIONS, "nosniff"),
+    ("REFERRER_POLICY", header::REFERRER_POLICY, "no-referrer"),
+];
+
+/// Only sent over HTTPS, where browsers heed it.
+const HSTS: (&str, HeaderName, &str) = ("STRICT_TRANSPORT_SECURITY", header::STRICT_TRANSPORT_SECURITY, "max-age=31536000");
+
+#[derive(Debug)]
+pub struct SecurityHeaders {
+    headers: Vec<(HeaderName, HeaderValue)>,
+    hsts: Option<HeaderValue>,
+}
+
+impl Default for SecurityHeaders {
+    fn default() -> Self {
+        Self::new(|_| None).expect("the default header values are valid")
+    }
+}
+
+impl SecurityHeaders {
+    /// Builds the headers from `setting(env var)`, using the default for an unset variable.
+    pub fn new(mut setting: impl FnMut(&str) -> Option<String>) -> Result<Self, String> {
+        let mut value = |(name, _, default): &(&str, HeaderName, &str)| -> Result<Option<HeaderValue>, String> {
+            let raw = setting(name).unwrap_or_else(|| default.to_string());
+            let raw = raw.trim();
+            if raw.is_empty() {
+                return Ok(None);
+            }
+            HeaderValue::from_str(raw).map(Some).map_err(|_| format!("Invalid {}: not a valid header value", name))
+        };
+        let mut headers = Vec::new();
+        for entry in &HEADERS {
+            if let Some(v) = value(entry)? {
+                headers.push((entry.1.clone(), v));
+            }
+        }
+        let hsts = value(&HSTS)?;
+        Ok(Self { headers, hsts })
+    }
+}
+
+/// TLS is terminated in front of the service, so HTTPS shows up as the proxy's
+/// `X-Forwarded-Proto`.
+fn is_https(request: &Request) -> bool {
+    request
+        .headers()
+        .get("x-forwarded-proto")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split(',').next())
+        .is_some_and(|proto| proto.trim().eq_ignore_ascii_case("https"))
+}
+
+/// Adds the headers a handler has not set itself.
+pub async fn add_headers(State(security): State<Arc<SecurityHeaders>>, request: Request, next: Next) -> Response {
+    let https = is_https(&request);
+    let mut response = next.run(request).await;
+    let headers = response.headers_mut();
+    for (name, value) in &security.headers {
+        headers.entry(name.clone()).or_insert_with(|| value.clone());
+    }
+    if https && let Some(ref hsts) = security.hsts {
+        headers.entry(header::STRICT_TRANSPORT_SECURITY).or_insert_with(|| hsts.clone());
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, middleware, routing::get, Router};
+    use std::collections::HashMap;
+    use tower::ServiceExt;
+
+    async fn response_headers(security: SecurityHeaders, forwarded_proto: Option<&str>) -> axum::http::HeaderMap {
+        let app = Router::new()
+            .route("/", get(|| async { "ok" }))
+            .layer(middleware::from_fn_with_state(Arc::new(security), add_headers));
+        let mut request = Request::builder().uri("/");
+        if let Some(proto) = forwarded_proto {
+            request = request.header("x-forwarded-proto", proto);
+        }
+        app.oneshot(request.body(Body::empty()).unwrap()).await.unwrap().headers().clone()
+    }
+
+    #[tokio::test]
+    async fn test_default_headers() {
+        let headers = response_headers(SecurityHeaders::default(), None).await;
+        assert_eq!(headers[header::CONTENT_SECURITY_POLICY], "default-src 'self'");
+        assert_eq!(headers[header::X_FRAME_OPTIONS], "DENY");
+        assert_eq!(headers[header::X_CONTENT_TYPE_OPTIONS], "nosniff");
+        assert_eq!(headers[header::REFERRER_POLICY], "no-referrer");
+        assert!(!headers.contains_key(header::STRICT_TRANSPORT_SECURITY));
+
+        let headers = response_headers(SecurityHeaders::default(), Some("https")).await;
+        assert_eq!(headers[header::STRICT_TRANSPORT_SECURITY], "max-age=31536000");
+    }
+
+    #[tokio::test]
+    async fn test_configured_headers() {
+        let settings = HashMap::from([
+            ("CONTENT_SECURITY_POLICY", "default-src 'self'; frame-ancestors https://app.example.com"),
+            ("X_FRAME_OPTIONS", ""),
+            ("STRICT_TRANSPORT_SECURITY", " "),
+        ]);
+        let security = SecurityHeaders::new(|name| settings.get(name).map(|v| v.to_string())).unwrap();
+        let headers = response_headers(security, Some("https")).await;
+        assert_eq!(headers[header::CONTENT_SECURITY_POLICY], "default-src 'self'; frame-ancestors https://app.example.com");
+        assert!(!headers.contains_key(header::X_FRAME_OPTIONS));
+        assert!(!headers.contains_key(header::STRICT_TRANSPORT_SECURITY));
+        assert_eq!(headers[header::REFERRER_POLICY], "no-referrer");
+
+        let err = SecurityHeaders::new(|name| (name == "REFERRER_POLICY").then(|| "a\nb".to_string())).unwrap_err();
+        assert!(err.contains("REFERRER_POLICY"));
+    }
+
+    #[tokio::test]
+    async fn test_handler_headers_win() {
+        let app = Router::new()
+            .route("/framed", get(|| async { ([(header::X_FRAME_OPTIONS, "SAMEORIGIN")], "ok") }))
+            .layer(middleware::from_fn_with_state(Arc::new(SecurityHeaders::default()), add_headers));
+        let request = Request::builder().uri("/framed").body(Body::empty()).unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.headers()[header::X_FRAME_OPTIONS], "SAMEORIGIN");
+    }
+}
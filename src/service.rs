@@ -0,0 +1,698 @@
+//! The step that turns an uploaded file into converted files. It sits behind the
+//! `ConversionService` trait so that everything around it, from reading the outputs back to the
+//! response, can be tested without LibreOffice.
+
+use axum::{async_trait, http::StatusCode};
+use serde_json::Value;
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::fs;
+use tokio::process::Command;
+use tokio::time::{timeout_at, Instant};
+use tracing::{debug, error, info, warn, Span};
+
+use crate::backend::{self, ConverterBackend};
+use crate::error::ConversionError;
+use crate::pool::LibreOfficePool;
+use crate::{conversion_timeout_error, decrypt, detect, formats, markdown, properties, run_until, ConvertOptions};
+
+/// Bytes of stderr put in a `libreoffice_error`.
+const MAX_STDERR_EXCERPT: usize = 1000;
+
+#[async_trait]
+pub trait ConversionService: Send + Sync {
+    /// Converts `input` as `opts` ask and returns the directory the output files were written
+    /// to, named after the input with the output format's extension. `work_dir` holds the
+    /// request's scratch files.
+    async fn convert(&self, input: &Path, work_dir: &Path, opts: &ConvertOptions) -> Result<PathBuf, ConversionError>;
+
+    /// What converts `input`, for the `X-Conversion-Backend` header.
+    fn backend_label(&self, input: &Path, opts: &ConvertOptions) -> String;
+
+    /// The settings that switched on an optional conversion feature, for `/admin/status`.
+    fn enabled_features(&self) -> Vec<&'static str> {
+        Vec::new()
+    }
+}
+
+/// Flags every LibreOffice process is started with, one-off or pooled.
+#[derive(Clone, Debug, Default)]
+pub struct LaunchFlags {
+    /// `LO_SAFE_MODE`: start LibreOffice with `--safe-mode`.
+    pub safe_mode: bool,
+    /// `LO_EXTRA_ARGS`: appended after the flags the service sets itself.
+    pub extra_args: Vec<String>,
+}
+
+/// Flags `LO_EXTRA_ARGS` may not contain: they would open LibreOffice to connections, or point
+/// it at another profile, bypassing the macro settings seeded by `prepare_profile`.
+const BLOCKED_ARGS: &[&str] = &["--shell", "--accept", "--unaccept", "-env:"];
+
+/// Splits `LO_EXTRA_ARGS` into arguments the way a shell would: on whitespace, except inside
+/// single or double quotes, with backslash escaping the next character outside single quotes.
+pub fn parse_extra_args(raw: &str) -> Result<Vec<String>, String> {
+    let mut args = Vec::new();
+    let mut current: Option<String> = None;
+    let mut quote = None;
+    let mut chars = raw.chars();
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (None, '\\') | (Some('"'), '\\') => {
+                let escaped = chars.next().ok_or("Invalid LO_EXTRA_ARGS: ends with a backslash")?;
+                current.get_or_insert_default().push(escaped);
+            }
+            (None, '\'' | '"') => {
+                quote = Some(c);
+                current.get_or_insert_default();
+            }
+            (None, c) if c.is_whitespace() => args.extend(current.take()),
+            (_, c) => current.get_or_insert_default().push(c),
+        }
+    }
+    if quote.is_some() {
+        return Err("Invalid LO_EXTRA_ARGS: unterminated quote".to_string());
+    }
+    args.extend(current);
+    if let Some(arg) = args.iter().find(|arg| arg.contains("..") || BLOCKED_ARGS.iter().any(|blocked| arg.starts_with(blocked))) {
+        return Err(format!("Invalid LO_EXTRA_ARGS: {:?} is not allowed", arg));
+    }
+    Ok(args)
+}
+
+/// Default `LO_MAX_RETRIES`.
+pub const DEFAULT_MAX_RETRIES: u32 = 2;
+
+/// Wait before the first retry, doubled for each one after it.
+const RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// Default `LO_RETRY_PATTERNS`: stderr of LibreOffice runs that failed while starting up rather
+/// than on the document, and tend to succeed on a second try.
+pub const DEFAULT_RETRY_PATTERNS: &[&str] = &[
+    "User installation could not be completed",
+    "DeploymentException",
+    "Failed to open display",
+    "Can't open display",
+];
+
+/// When a failed one-off LibreOffice run is tried again.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    /// `LO_MAX_RETRIES`: further attempts after the first.
+    pub max_retries: u32,
+    /// `LO_RETRY_PATTERNS`: only failures whose stderr contains one of these are retried.
+    pub patterns: Vec<String>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: DEFAULT_MAX_RETRIES,
+            patterns: DEFAULT_RETRY_PATTERNS.iter().map(|p| p.to_string()).collect(),
+        }
+    }
+}
+
+impl RetryPolicy {
+    fn is_transient(&self, stderr: &[u8]) -> bool {
+        let stderr = String::from_utf8_lossy(stderr);
+        self.patterns.iter().any(|pattern| stderr.contains(pattern.as_str()))
+    }
+
+    /// How long to wait before retry number `retry` (from 1).
+    fn delay(retry: u32) -> Duration {
+        RETRY_DELAY * 2u32.saturating_pow(retry - 1)
+    }
+}
+
+/// Converts with LibreOffice, or with Chromium and Pandoc for the inputs they are configured for.
+pub struct LibreOfficeService {
+    /// `LIBREOFFICE_PATH`: what is run for one-off conversions.
+    pub libreoffice_path: String,
+    /// `CHROMIUM_PATH`: when set, HTML is printed to PDF by headless Chromium instead.
+    pub chromium_path: Option<String>,
+    /// `PANDOC_PATH`: when set, Markdown is typeset to PDF by Pandoc instead.
+    pub pandoc_path: Option<String>,
+    /// `QPDF_PATH`: linearizes PDFs when `linearize` asks for it.
+    pub qpdf_path: Option<String>,
+    /// `MSOFFCRYPTO_PATH`: decrypts password-protected Office inputs with `input_password`.
+    pub msoffcrypto_path: Option<String>,
+    pub pool: Option<LibreOfficePool>,
+    pub timeout: Duration,
+    /// Add LibreOffice's stderr to `CONVERSION_FAILED` errors (development only).
+    pub include_errors: bool,
+    /// `LO_SAFE_MODE` and `LO_EXTRA_ARGS`.
+    pub flags: LaunchFlags,
+    /// `LO_MAX_RETRIES` and `LO_RETRY_PATTERNS`.
+    pub retry: RetryPolicy,
+    /// `CJK_FONT_DIR`: extra fonts made available to LibreOffice through its profile.
+    pub font_dir: Option<PathBuf>,
+}
+
+impl LibreOfficeService {
+    /// The backend for an input with `extension`, and whether it is Markdown to be rendered to
+    /// HTML first.
+    fn select_backend(&self, extension: &str, opts: &ConvertOptions) -> (ConverterBackend<'_>, bool) {
+        let plain_pdf = opts.format == formats::PDF && filter_data(opts).is_empty() && opts.paper_size.is_none();
+        let backend = backend::select_backend(extension, self.chromium_path.as_deref(), self.pandoc_path.as_deref(), plain_pdf);
+        // Without Pandoc, Markdown is converted as HTML: LibreOffice would read it as plain text
+        if backend::is_markdown(extension) && backend == ConverterBackend::LibreOffice {
+            return (backend::select_backend("html", self.chromium_path.as_deref(), None, plain_pdf), true);
+        }
+        (backend, false)
+    }
+}
+
+/// The export filter settings for `opts`.
+fn filter_data(opts: &ConvertOptions) -> serde_json::Map<String, Value> {
+    match opts.jpeg {
+        Some(ref jpeg) => jpeg.filter_data(),
+        None => opts.pdf_filter_data(),
+    }
+}
+
+fn extension(path: &Path) -> String {
+    path.extension().unwrap_or_default().to_string_lossy().to_string()
+}
+
+#[async_trait]
+impl ConversionService for LibreOfficeService {
+    async fn convert(&self, input: &Path, work_dir: &Path, opts: &ConvertOptions) -> Result<PathBuf, ConversionError> {
+        let start_time = std::time::Instant::now();
+        let deadline = Instant::now() + self.timeout;
+        // Output goes to its own directory so it can never overwrite the input (e.g. docx -> docx)
+        let out_dir = input.parent().unwrap_or(work_dir).join("out");
+        let filter_data = filter_data(opts);
+        if opts.linearize && self.qpdf_path.is_none() {
+            return Err(ConversionError::new(
+                StatusCode::BAD_REQUEST,
+                "LINEARIZE_UNAVAILABLE",
+                "linearize needs qpdf; set QPDF_PATH",
+            ));
+        }
+        let mut input_extension = extension(input);
+        let infilter = input_extension.eq_ignore_ascii_case("csv").then(|| opts.csv.infilter());
+        if backend::is_markdown(&input_extension) && !markdown::OUTPUTS.contains(&opts.format.extension) {
+            return Err(ConversionError::new(
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "UNSUPPORTED_CONVERSION",
+                format!("Markdown can only be converted to {}", markdown::OUTPUTS.join(", ")),
+            )
+            .with_detail("format", opts.format.extension));
+        }
+        let (backend, render_markdown) = self.select_backend(&input_extension, opts);
+        let mut file_path = input;
+        let decrypted = match detect::is_encrypted_package(input).await {
+            Ok(true) => Some(decrypt::decrypt(self.msoffcrypto_path.as_deref(), input, opts.input_password.as_ref(), deadline, self.timeout).await?),
+            Ok(false) => None,
+            Err(e) => {
+                error!("Failed to inspect input: {}", e);
+                return Err(ConversionError::internal());
+            }
+        };
+        if let Some(ref decrypted) = decrypted {
+            file_path = decrypted;
+        }
+        let rendered_html;
+        if render_markdown {
+            rendered_html = markdown::render_file(input).await.map_err(|e| {
+                error!("Failed to render Markdown: {}", e);
+                ConversionError::internal()
+            })?;
+            file_path = &rendered_html;
+            input_extension = "html".to_string();
+        }
+        info!("Converting file: {:?} to {} with {}", file_path, opts.format.extension, self.backend_label(input, opts));
+
+        let result = match (backend, &self.pool) {
+            (ConverterBackend::Chromium(chromium), _) => {
+                if let Err(e) = fs::create_dir_all(&out_dir).await {
+                    error!("Failed to create output dir: {}", e);
+                    return Err(ConversionError::internal());
+                }
+                let stem = file_path.file_stem().unwrap_or_default().to_string_lossy();
+                let output = out_dir.join(format!("{}.pdf", stem));
+                let user_data_dir = work_dir.join("chrome");
+                let command = backend::chromium_command(chromium, file_path, &output, &user_data_dir);
+                let result = run_until(command, deadline).await;
+                // A Chromium profile runs to megabytes; do not leave it around until the work dir goes
+                let _ = fs::remove_dir_all(&user_data_dir).await;
+                result
+            }
+            (ConverterBackend::Pandoc(pandoc), _) => {
+                if let Err(e) = fs::create_dir_all(&out_dir).await {
+                    error!("Failed to create output dir: {}", e);
+                    return Err(ConversionError::internal());
+                }
+                let stem = file_path.file_stem().unwrap_or_default().to_string_lossy();
+                let output = out_dir.join(format!("{}.pdf", stem));
+                run_until(backend::pandoc_command(pandoc, file_path, &output), deadline).await
+            }
+            (ConverterBackend::LibreOffice, Some(pool)) => {
+                let Ok(lease) = timeout_at(deadline, pool.lease()).await else {
+                    error!("Timed out waiting for a LibreOffice worker");
+                    return Err(conversion_timeout_error(self.timeout));
+                };
+                if let Err(e) = fs::create_dir_all(&out_dir).await {
+                    error!("Failed to create output dir: {}", e);
+                    return Err(ConversionError::internal());
+                }
+                let stem = file_path.file_stem().unwrap_or_default().to_string_lossy();
+                let output = out_dir.join(format!("{}.{}", stem, opts.format.extension));
+                let result = run_until(lease.command(file_path, &output, opts.format.extension, &filter_data, infilter.as_deref(), opts.paper_size), deadline).await;
+                if matches!(result, Ok(None)) {
+                    // The worker is most likely stuck on this document
+                    lease.restart_worker();
+                }
+                result
+            }
+            (ConverterBackend::LibreOffice, None) => {
+                // Pages are resized through UNO, which only the pool's workers are driven by
+                if opts.paper_size.is_some() {
+                    return Err(ConversionError::new(
+                        StatusCode::BAD_REQUEST,
+                        "PAPER_SIZE_UNAVAILABLE",
+                        "paper_size needs the LibreOffice worker pool; set LO_POOL_SIZE",
+                    ));
+                }
+                let convert_to = if filter_data.is_empty() {
+                    opts.format.extension.to_string()
+                } else {
+                    format!(
+                        "{}:{}:{}",
+                        opts.format.extension,
+                        formats::export_filter(opts.format.extension, &input_extension),
+                        Value::Object(filter_data)
+                    )
+                };
+
+                let profile = work_dir.join("user");
+                let mut retries = 0;
+                loop {
+                    if let Err(e) = prepare_profile(&profile, self.font_dir.as_deref()).await {
+                        error!("Failed to prepare the LibreOffice profile: {}", e);
+                        return Err(ConversionError::internal());
+                    }
+                    let command = libreoffice_command(
+                        &self.libreoffice_path,
+                        &convert_to,
+                        &out_dir,
+                        work_dir,
+                        infilter.as_deref(),
+                        &self.flags,
+                        file_path,
+                    );
+                    debug!("Running {:?}", command.as_std());
+                    let result = run_until(command, deadline).await;
+                    let Ok(Some(ref out)) = result else {
+                        break result;
+                    };
+                    if out.status.success() || retries == self.retry.max_retries || !self.retry.is_transient(&out.stderr) {
+                        break result;
+                    }
+                    retries += 1;
+                    let delay = RetryPolicy::delay(retries);
+                    if Instant::now() + delay >= deadline {
+                        break result;
+                    }
+                    warn!(
+                        "LibreOffice failed to start ({}), retrying in {:?} ({}/{})",
+                        String::from_utf8_lossy(&out.stderr).trim(),
+                        delay,
+                        retries,
+                        self.retry.max_retries
+                    );
+                    // A fresh profile, in case a lock file or half-written settings were the cause
+                    let _ = fs::remove_dir_all(&profile).await;
+                    tokio::time::sleep(delay).await;
+                }
+            }
+        };
+        // The plain copy of an encrypted input is not kept a moment longer than needed
+        if let Some(ref decrypted) = decrypted {
+            let _ = fs::remove_file(decrypted).await;
+        }
+
+        match result {
+            Ok(Some(out)) => {
+                // Recorded on the caller's `libreoffice` span
+                let span = Span::current();
+                if let Some(code) = out.status.code() {
+                    span.record("process.exit_code", code);
+                }
+                span.record("stderr.length", out.stderr.len());
+                let duration = start_time.elapsed();
+                info!("Conversion finished in {:?}", duration);
+                if let Some(signal) = out.status.signal() {
+                    error!("LibreOffice was killed by signal {}: stderr: {}", signal, String::from_utf8_lossy(&out.stderr));
+                    return Err(ConversionError::new(StatusCode::INTERNAL_SERVER_ERROR, "CONVERSION_CRASHED", "The converter crashed"));
+                }
+                if !out.status.success() {
+                    error!("LibreOffice failed: stderr: {}", String::from_utf8_lossy(&out.stderr));
+                    let mut error = ConversionError::new(StatusCode::INTERNAL_SERVER_ERROR, "CONVERSION_FAILED", "Conversion failed");
+                    if self.include_errors {
+                        error = error.with_detail("libreoffice_error", stderr_excerpt(&out.stderr));
+                    }
+                    return Err(error);
+                }
+                // Before linearizing, which an incremental update would undo
+                if !opts.properties.is_empty() {
+                    properties::apply_to_outputs(&out_dir, &opts.properties).await?;
+                }
+                if opts.linearize
+                    && let Some(ref qpdf) = self.qpdf_path
+                {
+                    linearize(qpdf, &out_dir, deadline, self.timeout).await?;
+                }
+                Ok(out_dir)
+            }
+            Ok(None) => {
+                error!("LibreOffice timed out after {:?}", self.timeout);
+                Err(conversion_timeout_error(self.timeout))
+            }
+            Err(e) => {
+                error!("Failed to run LibreOffice: {}", e);
+                Err(ConversionError::new(StatusCode::INTERNAL_SERVER_ERROR, "CONVERSION_EXECUTION_FAILED", "Conversion execution failed"))
+            }
+        }
+    }
+
+    fn backend_label(&self, input: &Path, opts: &ConvertOptions) -> String {
+        let (backend, render_markdown) = self.select_backend(&extension(input), opts);
+        let name = backend.name().to_ascii_lowercase();
+        if render_markdown {
+            format!("markdown+{}", name)
+        } else {
+            name
+        }
+    }
+
+    fn enabled_features(&self) -> Vec<&'static str> {
+        [
+            ("LO_POOL_SIZE", self.pool.is_some()),
+            ("CHROMIUM_PATH", self.chromium_path.is_some()),
+            ("PANDOC_PATH", self.pandoc_path.is_some()),
+            ("QPDF_PATH", self.qpdf_path.is_some()),
+            ("MSOFFCRYPTO_PATH", self.msoffcrypto_path.is_some()),
+            ("INCLUDE_LIBREOFFICE_ERRORS", self.include_errors),
+            ("LO_SAFE_MODE", self.flags.safe_mode),
+            ("LO_EXTRA_ARGS", !self.flags.extra_args.is_empty()),
+            ("CJK_FONT_DIR", self.font_dir.is_some()),
+        ]
+        .into_iter()
+        .filter_map(|(name, enabled)| enabled.then_some(name))
+        .collect()
+    }
+}
+
+/// Rewrites the PDFs in `out_dir` linearized, with `qpdf --linearize`.
+async fn linearize(qpdf: &str, out_dir: &Path, deadline: Instant, timeout: Duration) -> Result<(), ConversionError> {
+    let failed = || ConversionError::new(StatusCode::INTERNAL_SERVER_ERROR, "LINEARIZE_FAILED", "Linearizing the PDF failed");
+    let mut entries = fs::read_dir(out_dir).await.map_err(|e| {
+        error!("Failed to list output dir: {}", e);
+        ConversionError::internal()
+    })?;
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let path = entry.path();
+        if path.extension().is_none_or(|e| e != "pdf") {
+            continue;
+        }
+        // Written beside the output, which must keep its name, then moved over it
+        let linearized = path.with_extension("linearized");
+        let mut command = Command::new(qpdf);
+        command.arg("--linearize").arg(&path).arg(&linearized);
+        match run_until(command, deadline).await {
+            // 3 means success with warnings, which qpdf gives for many slightly malformed files
+            Ok(Some(out)) if matches!(out.status.code(), Some(0 | 3)) => {}
+            Ok(Some(out)) => {
+                error!("qpdf failed: stderr: {}", String::from_utf8_lossy(&out.stderr));
+                return Err(failed());
+            }
+            Ok(None) => {
+                error!("qpdf timed out");
+                return Err(conversion_timeout_error(timeout));
+            }
+            Err(e) => {
+                error!("Failed to run qpdf: {}", e);
+                return Err(failed());
+            }
+        }
+        fs::rename(&linearized, &path).await.map_err(|e| {
+            error!("Failed to replace output with its linearized copy: {}", e);
+            failed()
+        })?;
+    }
+    Ok(())
+}
+
+/// The start of a converter's stderr, for error responses: it can mention paths and document
+/// contents, so it is only sent when `INCLUDE_LIBREOFFICE_ERRORS` is set.
+fn stderr_excerpt(stderr: &[u8]) -> String {
+    if stderr.len() <= MAX_STDERR_EXCERPT {
+        return String::from_utf8_lossy(stderr).to_string();
+    }
+    format!("{}...(truncated)", String::from_utf8_lossy(&stderr[..MAX_STDERR_EXCERPT]))
+}
+
+/// Settings seeded into every LibreOffice profile: macros in uploaded documents are never run,
+/// whatever they are signed with. LibreOffice has no command-line switch for this.
+const PROFILE_SETTINGS: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<oor:items xmlns:oor="http://openoffice.org/2001/registry" xmlns:xs="http://www.w3.org/2001/XMLSchema" xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance">
+<item oor:path="/org.openoffice.Office.Common/Security/Scripting"><prop oor:name="DisableMacrosExecution" oor:op="fuse"><value>true</value></prop></item>
+<item oor:path="/org.openoffice.Office.Common/Security/Scripting"><prop oor:name="MacroSecurityLevel" oor:op="fuse"><value>3</value></prop></item>
+</oor:items>
+"#;
+
+/// Sets up the profile at `user_installation` (a `UserInstallation` directory) before LibreOffice
+/// starts on it:
+///
+/// - `PROFILE_SETTINGS` are seeded, unless LibreOffice has already created its settings file; it
+///   keeps them when it rewrites the file.
+/// - `font_dir`, if given, is linked in as the profile's `user/fonts`, a directory LibreOffice
+///   loads fonts from in addition to the system's.
+pub async fn prepare_profile(user_installation: &Path, font_dir: Option<&Path>) -> std::io::Result<()> {
+    let user = user_installation.join("user");
+    fs::create_dir_all(&user).await?;
+    let settings = user.join("registrymodifications.xcu");
+    if !fs::try_exists(&settings).await? {
+        fs::write(settings, PROFILE_SETTINGS).await?;
+    }
+    if let Some(font_dir) = font_dir {
+        let fonts = user.join("fonts");
+        if fs::symlink_metadata(&fonts).await.is_err() {
+            fs::symlink(font_dir, fonts).await?;
+        }
+    }
+    Ok(())
+}
+
+/// A one-off `<binary> --convert-to` run writing into `out_dir`, reading `input` with the
+/// `infilter` import filter if given.
+fn libreoffice_command(
+    binary: &str,
+    convert_to: &str,
+    out_dir: &Path,
+    work_dir: &Path,
+    infilter: Option<&str>,
+    flags: &LaunchFlags,
+    input: &Path,
+) -> Command {
+    // Optimized flags for faster startup
+    let mut command = Command::new(binary);
+    command
+        // The profile lives in the work dir to avoid conflicts and permission issues. It is passed as a
+        // bootstrap variable in the environment, which every LibreOffice version reads, not as -env:
+        .env("UserInstallation", format!("file://{}/user", work_dir.display()))
+        .arg("--headless")
+        .arg("--nodefault")
+        .arg("--nofirststartwizard")
+        .arg("--nolockcheck")
+        .arg("--nologo")
+        .arg("--norestore");
+    if flags.safe_mode {
+        // Leaves out user extensions and customisations
+        command.arg("--safe-mode");
+    }
+    command
+        .arg("--convert-to")
+        .arg(convert_to)
+        .arg("--outdir")
+        .arg(out_dir);
+    if let Some(infilter) = infilter {
+        command.arg(format!("--infilter={}", infilter));
+    }
+    command.args(&flags.extra_args).arg(input);
+    command
+}
+
+/// Stands in for a converter in tests: the "converted" file is a copy of the input.
+#[cfg(test)]
+pub struct MockConversionService;
+
+#[cfg(test)]
+#[async_trait]
+impl ConversionService for MockConversionService {
+    async fn convert(&self, input: &Path, work_dir: &Path, opts: &ConvertOptions) -> Result<PathBuf, ConversionError> {
+        let out_dir = input.parent().unwrap_or(work_dir).join("out");
+        let stem = input.file_stem().unwrap_or_default().to_string_lossy();
+        let copied = async {
+            fs::create_dir_all(&out_dir).await?;
+            fs::copy(input, out_dir.join(format!("{}.{}", stem, opts.format.extension))).await
+        };
+        copied.await.map_err(|_| ConversionError::internal())?;
+        Ok(out_dir)
+    }
+
+    fn backend_label(&self, _input: &Path, _opts: &ConvertOptions) -> String {
+        "mock".to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::formats::CsvImport;
+
+    fn service() -> LibreOfficeService {
+        LibreOfficeService {
+            libreoffice_path: "soffice".to_string(),
+            chromium_path: Some("chromium".to_string()),
+            pandoc_path: None,
+            qpdf_path: None,
+            msoffcrypto_path: None,
+            pool: None,
+            timeout: Duration::from_secs(60),
+            include_errors: false,
+            flags: LaunchFlags::default(),
+            retry: RetryPolicy::default(),
+            font_dir: None,
+        }
+    }
+
+    #[test]
+    fn test_backend_label() {
+        let opts = ConvertOptions::default();
+        assert_eq!(service().backend_label(Path::new("a.docx"), &opts), "libreoffice");
+        assert_eq!(service().backend_label(Path::new("a.html"), &opts), "chromium");
+        assert_eq!(service().backend_label(Path::new("a.md"), &opts), "markdown+chromium");
+        assert_eq!(service().enabled_features(), ["CHROMIUM_PATH"]);
+    }
+
+    #[test]
+    fn test_libreoffice_command_uses_configured_binary() {
+        let command = libreoffice_command("/nix/store/abc-libreoffice/bin/soffice", "pdf", Path::new("/w/0/out"), Path::new("/w/0"), None, &LaunchFlags::default(), Path::new("/w/0/a.docx"));
+        let command = command.as_std();
+        assert_eq!(command.get_program(), "/nix/store/abc-libreoffice/bin/soffice");
+        let args: Vec<_> = command.get_args().map(|a| a.to_string_lossy().to_string()).collect();
+        assert_eq!(args[args.len() - 5..], ["--convert-to", "pdf", "--outdir", "/w/0/out", "/w/0/a.docx"]);
+        assert!(!args.iter().any(|a| a.starts_with("-env:")));
+        let envs: Vec<_> = command.get_envs().collect();
+        assert_eq!(envs, [("UserInstallation".as_ref(), Some("file:///w/0/user".as_ref()))]);
+
+        let infilter = CsvImport::default().infilter();
+        let command = libreoffice_command("soffice", "pdf", Path::new("/w/0/out"), Path::new("/w/0"), Some(&infilter), &LaunchFlags::default(), Path::new("/w/0/a.csv"));
+        let args: Vec<_> = command.as_std().get_args().map(|a| a.to_string_lossy().to_string()).collect();
+        assert_eq!(args[args.len() - 2..], ["--infilter=Text - txt - csv (StarCalc):44,34,76,1", "/w/0/a.csv"]);
+        assert!(!args.contains(&"--safe-mode".to_string()));
+    }
+
+    #[test]
+    fn test_extra_args() {
+        let args = parse_extra_args(r#" --infilter="Calc MS Excel 2007 XML"  -o 'a "b"' c\ d "" "#).unwrap();
+        assert_eq!(args, ["--infilter=Calc MS Excel 2007 XML", "-o", r#"a "b""#, "c d", ""]);
+        assert!(parse_extra_args("").unwrap().is_empty());
+        assert!(parse_extra_args("'open").unwrap_err().contains("unterminated"));
+        for blocked in ["--accept=socket,host=0,port=2002;urp;", "--unaccept=all", "--shell", "-env:UserInstallation=file:///tmp", "--writer ../x"] {
+            assert!(parse_extra_args(blocked).unwrap_err().contains("not allowed"), "{}", blocked);
+        }
+
+        // After the fixed flags, before the input
+        let flags = LaunchFlags { safe_mode: false, extra_args: vec!["--language=de".to_string()] };
+        let command = libreoffice_command("soffice", "pdf", Path::new("/w/0/out"), Path::new("/w/0"), None, &flags, Path::new("/w/0/a.docx"));
+        let args: Vec<_> = command.as_std().get_args().map(|a| a.to_string_lossy().to_string()).collect();
+        assert_eq!(args[args.len() - 3..], ["/w/0/out", "--language=de", "/w/0/a.docx"]);
+    }
+
+    #[tokio::test]
+    async fn test_retries_transient_failures() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join(format!("retry-test-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        // Fails to start on the first run, as LibreOffice does when its profile is broken
+        let script = dir.join("soffice");
+        std::fs::write(
+            &script,
+            format!(
+                "#!/bin/sh\necho run >> {0}/runs\n[ $(wc -l < {0}/runs) -ge 2 ] || {{ echo 'User installation could not be completed' >&2; exit 1; }}\n\
+                 while [ $# -gt 1 ]; do [ \"$1\" = --outdir ] && out=$2; shift; done\nmkdir -p \"$out\" && cp \"$1\" \"$out/a.pdf\"\n",
+                dir.display()
+            ),
+        )
+        .unwrap();
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let input = dir.join("a.docx");
+        std::fs::write(&input, b"doc").unwrap();
+
+        let service = LibreOfficeService {
+            libreoffice_path: script.to_string_lossy().to_string(),
+            ..service()
+        };
+        let out_dir = service.convert(&input, &dir, &ConvertOptions::default()).await.unwrap();
+        assert_eq!(std::fs::read(out_dir.join("a.pdf")).unwrap(), b"doc");
+        assert_eq!(std::fs::read_to_string(dir.join("runs")).unwrap().lines().count(), 2);
+
+        // Other failures are not retried
+        let service = LibreOfficeService {
+            retry: RetryPolicy { max_retries: 2, patterns: vec!["something else".to_string()] },
+            ..service
+        };
+        std::fs::write(dir.join("runs"), "").unwrap();
+        assert_eq!(service.convert(&input, &dir, &ConvertOptions::default()).await.err().map(|e| e.code), Some("CONVERSION_FAILED"));
+        assert_eq!(std::fs::read_to_string(dir.join("runs")).unwrap().lines().count(), 1);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_profile_hardening() {
+        let flags = LaunchFlags { safe_mode: true, extra_args: Vec::new() };
+        let command = libreoffice_command("soffice", "pdf", Path::new("/w/0/out"), Path::new("/w/0"), None, &flags, Path::new("/w/0/a.docm"));
+        let args: Vec<_> = command.as_std().get_args().map(|a| a.to_string_lossy().to_string()).collect();
+        assert!(args.contains(&"--safe-mode".to_string()));
+        assert!(args.contains(&"--headless".to_string()));
+
+        let dir = std::env::temp_dir().join(format!("profile-test-{}", uuid::Uuid::new_v4()));
+        prepare_profile(&dir, None).await.unwrap();
+        let settings = std::fs::read_to_string(dir.join("user/registrymodifications.xcu")).unwrap();
+        assert!(settings.contains(r#"oor:name="DisableMacrosExecution" oor:op="fuse"><value>true</value>"#));
+        // A profile LibreOffice has written to is left alone
+        std::fs::write(dir.join("user/registrymodifications.xcu"), "kept").unwrap();
+        prepare_profile(&dir, None).await.unwrap();
+        assert_eq!(std::fs::read_to_string(dir.join("user/registrymodifications.xcu")).unwrap(), "kept");
+        assert!(!dir.join("user/fonts").exists());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_profile_fonts() {
+        let dir = std::env::temp_dir().join(format!("profile-test-{}", uuid::Uuid::new_v4()));
+        let font_dir = dir.join("cjk");
+        std::fs::create_dir_all(&font_dir).unwrap();
+        std::fs::write(font_dir.join("NotoSansCJK.ttc"), b"font").unwrap();
+        let profile = dir.join("profile");
+        // Twice, as for a pool worker restarting on its profile
+        prepare_profile(&profile, Some(&font_dir)).await.unwrap();
+        prepare_profile(&profile, Some(&font_dir)).await.unwrap();
+        assert_eq!(std::fs::read_link(profile.join("user/fonts")).unwrap(), font_dir);
+        assert_eq!(std::fs::read(profile.join("user/fonts/NotoSansCJK.ttc")).unwrap(), b"font");
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_stderr_excerpt() {
+        assert_eq!(stderr_excerpt(b"Error: source file could not be loaded\n"), "Error: source file could not be loaded\n");
+        let excerpt = stderr_excerpt(&[b'x'; MAX_STDERR_EXCERPT + 1]);
+        assert_eq!(excerpt, format!("{}...(truncated)", "x".repeat(MAX_STDERR_EXCERPT)));
+    }
+}
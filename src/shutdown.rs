@@ -0,0 +1,140 @@
+//! Graceful shutdown on SIGTERM or Ctrl-C.
+//!
+//! LibreOffice processes run in their own process groups, registered here while they are alive,
+//! so whatever is still running when the grace period ends can be killed along with its
+//! `soffice.bin` grandchildren instead of outliving the server. A group whose child is dropped
+//! before it has been waited for (a cancelled job's conversion, say) is killed there and then.
+
+use std::collections::BTreeSet;
+use std::io;
+use std::path::Path;
+use std::sync::Mutex;
+use tokio::fs;
+use tokio::signal::unix::{signal, SignalKind};
+use uuid::Uuid;
+
+pub const DEFAULT_GRACE_SECS: u64 = 30;
+
+/// Every LibreOffice process group currently running.
+pub static PROCESS_GROUPS: ProcessGroups = ProcessGroups::new();
+
+/// Resolves once SIGTERM or SIGINT (Ctrl-C) is received.
+pub async fn signal_received() {
+    let mut terminate = signal(SignalKind::terminate()).expect("failed to install SIGTERM handler");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate.recv() => {}
+    }
+}
+
+pub struct ProcessGroups(Mutex<BTreeSet<i32>>);
+
+/// Keeps a process group registered until dropped, and kills it then unless its leader has been
+/// reaped: `kill_on_drop` only reaches the leader itself.
+pub struct ProcessGroup<'a> {
+    groups: &'a ProcessGroups,
+    pgid: Option<i32>,
+    reaped: bool,
+}
+
+impl ProcessGroups {
+    const fn new() -> Self {
+        Self(Mutex::new(BTreeSet::new()))
+    }
+
+    /// Registers the process group of a child spawned with `process_group(0)`.
+    pub fn track(&self, pid: Option<u32>) -> ProcessGroup<'_> {
+        let pgid = pid.map(|pid| pid as i32);
+        if let Some(pgid) = pgid {
+            self.0.lock().unwrap().insert(pgid);
+        }
+        ProcessGroup { groups: self, pgid, reaped: false }
+    }
+
+    /// SIGKILLs every registered process group and returns how many there were.
+    pub fn kill_all(&self) -> usize {
+        let groups = self.0.lock().unwrap();
+        for &pgid in groups.iter() {
+            // SAFETY: signalling process groups we created; no memory is shared
+            unsafe {
+                libc::kill(-pgid, libc::SIGKILL);
+            }
+        }
+        groups.len()
+    }
+}
+
+impl ProcessGroup<'_> {
+    /// SIGKILLs the group, leader and grandchildren alike.
+    pub fn kill(&self) {
+        if let Some(pgid) = self.pgid
+            && !self.reaped
+        {
+            // SAFETY: signalling a process group we created; no memory is shared
+            unsafe {
+                libc::kill(-pgid, libc::SIGKILL);
+            }
+        }
+    }
+
+    /// Records that the leader has been waited for, after which its pid, and so the group id,
+    /// may be reused and must no longer be signalled.
+    pub fn reaped(&mut self) {
+        self.reaped = true;
+    }
+}
+
+impl Drop for ProcessGroup<'_> {
+    fn drop(&mut self) {
+        self.kill();
+        if let Some(pgid) = self.pgid {
+            self.groups.0.lock().unwrap().remove(&pgid);
+        }
+    }
+}
+
+/// Removes the per-request work directories (named by UUID) and parked downloads under
+/// `temp_dir`, leaving worker profiles in place. Returns the number of entries removed.
+pub async fn remove_work_dirs(temp_dir: &Path) -> io::Result<usize> {
+    let mut removed = 0;
+    let mut entries = fs::read_dir(temp_dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        if (Uuid::parse_str(&name).is_ok() || name == "downloads") && entry.file_type().await?.is_dir() {
+            fs::remove_dir_all(entry.path()).await?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::process::Command;
+
+    #[tokio::test]
+    async fn test_kill_all() {
+        let groups = ProcessGroups::new();
+        let mut child = Command::new("sleep").arg("30").process_group(0).spawn().unwrap();
+        let group = groups.track(child.id());
+        assert_eq!(groups.kill_all(), 1);
+        assert!(!child.wait().await.unwrap().success());
+        drop(group);
+        assert_eq!(groups.kill_all(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_remove_work_dirs() {
+        let temp_dir = Path::new("/tmp/convert/shutdown-test");
+        let work_dir = temp_dir.join(Uuid::new_v4().to_string());
+        fs::create_dir_all(work_dir.join("0")).await.unwrap();
+        fs::create_dir_all(temp_dir.join("pool/0")).await.unwrap();
+
+        assert_eq!(remove_work_dirs(temp_dir).await.unwrap(), 1);
+        assert!(!work_dir.exists());
+        assert!(temp_dir.join("pool/0").exists());
+        let _ = fs::remove_dir_all(temp_dir).await;
+    }
+}
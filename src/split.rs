@@ -0,0 +1,210 @@
+//! `POST /split`: cuts a PDF into parts and returns them zipped, as `part_001.pdf`,
+//! `part_002.pdf`... Parts are written by the same page copying as `/merge`.
+
+use axum::{
+    extract::{Multipart, State},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Extension,
+};
+use std::collections::HashMap;
+use std::ops::Range;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use tokio::fs;
+use tracing::{error, info, info_span, Instrument};
+
+use crate::error::ConversionError;
+use crate::export::MAX_PAGE;
+use crate::merge::{self, invalid_pdf, Merger};
+use crate::pdf::Document;
+use crate::telemetry::RequestId;
+use crate::zip::ZipWriter;
+use crate::{busy_response, disk, file_response, filename_header, receive_upload, shutting_down_response};
+use crate::{AppState, ConvertedFile, Disposition};
+
+/// Where the parts begin: every `pages_per_part` pages, or at the `split_at` pages.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SplitMode {
+    Every(u32),
+    /// Sorted 1-based pages that start a new part.
+    At(Vec<u32>),
+}
+
+impl SplitMode {
+    pub fn from_fields(fields: &HashMap<String, String>) -> Result<Self, ConversionError> {
+        let field = |name: &str| fields.get(name).map(|v| v.trim()).filter(|v| !v.is_empty());
+        let invalid = |message: String| ConversionError::new(StatusCode::BAD_REQUEST, "INVALID_SPLIT_OPTIONS", message);
+        match (field("pages_per_part"), field("split_at")) {
+            (Some(_), Some(_)) => Err(ConversionError::new(
+                StatusCode::BAD_REQUEST,
+                "SPLIT_OPTION_CONFLICT",
+                "pages_per_part and split_at cannot be combined",
+            )),
+            (None, None) => Ok(SplitMode::Every(1)),
+            (Some(raw), None) => match raw.parse() {
+                Ok(n) if (1..=MAX_PAGE).contains(&n) => Ok(SplitMode::Every(n)),
+                _ => Err(invalid(format!("pages_per_part must be an integer from 1 to {}, not {:?}", MAX_PAGE, raw))),
+            },
+            (None, Some(raw)) => {
+                let mut pages = raw
+                    .split(',')
+                    .map(|page| match page.trim().parse() {
+                        Ok(n) if (1..=MAX_PAGE).contains(&n) => Ok(n),
+                        _ => Err(invalid(format!("Invalid page {:?} in split_at; expected a number from 1 to {}", page.trim(), MAX_PAGE))),
+                    })
+                    .collect::<Result<Vec<u32>, _>>()?;
+                pages.sort_unstable();
+                pages.dedup();
+                Ok(SplitMode::At(pages))
+            }
+        }
+    }
+
+    /// The page index ranges of the parts of a `page_count`-page document.
+    fn parts(&self, page_count: usize) -> Result<Vec<Range<usize>>, ConversionError> {
+        let starts: Vec<usize> = match self {
+            SplitMode::Every(n) => (0..page_count).step_by(*n as usize).collect(),
+            SplitMode::At(pages) => {
+                if let Some(&beyond) = pages.iter().find(|&&page| page as usize > page_count) {
+                    return Err(ConversionError::new(
+                        StatusCode::BAD_REQUEST,
+                        "PAGE_OUT_OF_RANGE",
+                        format!("Page {} is beyond the end of the document", beyond),
+                    )
+                    .with_detail("page", beyond));
+                }
+                // The first part always starts at page 1, listed or not
+                let mut starts = vec![0];
+                starts.extend(pages.iter().map(|&page| page as usize - 1).filter(|&start| start > 0));
+                starts
+            }
+        };
+        let ends = starts.iter().skip(1).copied().chain([page_count]);
+        Ok(starts.iter().zip(ends).map(|(&start, end)| start..end).collect())
+    }
+}
+
+pub async fn split(
+    State(state): State<Arc<AppState>>,
+    Extension(RequestId(request_id)): Extension<RequestId>,
+    headers: HeaderMap,
+    mut multipart: Multipart,
+) -> Response {
+    if state.draining.load(Ordering::SeqCst) {
+        return shutting_down_response();
+    }
+    let Some(slot) = state.try_conversion_slot() else {
+        return busy_response();
+    };
+    let _active_dir = state.active_dirs.track(request_id);
+    let work_dir = state.request_dir(request_id);
+    if let Err(e) = fs::create_dir_all(&work_dir).await {
+        error!("Failed to create work dir: {}", e);
+        return ConversionError::internal().into_response();
+    }
+
+    let result = async {
+        disk::ensure_free_space(&work_dir, state.min_free_bytes)?;
+        let upload = receive_upload(&mut multipart, filename_header(&headers).as_deref(), &work_dir, &state, None)
+            .instrument(info_span!("multipart"))
+            .await?;
+        let [ref file] = upload.files[..] else {
+            return Err(ConversionError::new(StatusCode::BAD_REQUEST, "TOO_MANY_FILES", "PDFs are split one at a time"));
+        };
+        let mode = SplitMode::from_fields(&upload.fields)?;
+        let name = file.path.file_name().unwrap_or_default().to_string_lossy().to_string();
+        if file.kind != "pdf" {
+            return Err(invalid_pdf(&name, &format!("detected as {}", file.kind)));
+        }
+        let content = fs::read(&file.path).await.map_err(|e| {
+            error!("Failed to read upload: {}", e);
+            ConversionError::internal()
+        })?;
+        let stem = file.path.file_stem().unwrap_or_default().to_string_lossy().to_string();
+        let archive = tokio::task::spawn_blocking(move || split_document(&name, &content, &mode))
+            .instrument(info_span!("split"))
+            .await
+            .map_err(|e| {
+                error!("PDF split panicked: {}", e);
+                ConversionError::internal()
+            })??;
+        Ok((stem, archive))
+    }
+    .await;
+    drop(slot);
+    let _ = fs::remove_dir_all(&work_dir).instrument(info_span!("cleanup")).await;
+
+    match result {
+        Ok((stem, archive)) => {
+            let converted = ConvertedFile {
+                filename: format!("{}_parts.zip", stem),
+                content_type: "application/zip",
+                content: archive,
+                headers: Vec::new(),
+            };
+            file_response(&converted, Disposition::Attachment)
+        }
+        Err(e) => e.into_response(),
+    }
+}
+
+/// Splits the PDF `content` and zips the parts.
+fn split_document(name: &str, content: &[u8], mode: &SplitMode) -> Result<Vec<u8>, ConversionError> {
+    let mut document = Document::parse(content).map_err(|e| invalid_pdf(name, &e))?;
+    let pages = merge::pages(&mut document).map_err(|e| invalid_pdf(name, &e))?;
+    let parts = mode.parts(pages.len())?;
+    let mut archive = ZipWriter::new();
+    for (n, range) in parts.iter().enumerate() {
+        let mut part = Merger::new();
+        part.append_pages(&mut document, pages[range.clone()].to_vec(), None)
+            .map_err(|e| invalid_pdf(name, &e))?;
+        archive.add(&format!("part_{:03}.pdf", n + 1), &part.finish());
+    }
+    info!("Split {} pages into {} parts", pages.len(), parts.len());
+    Ok(archive.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::export;
+    use crate::pdf::tests::sample_pdf;
+    use crate::zip::ZipReader;
+
+    fn fields(entries: &[(&str, &str)]) -> HashMap<String, String> {
+        entries.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn test_split_mode() {
+        assert_eq!(SplitMode::from_fields(&fields(&[])).unwrap(), SplitMode::Every(1));
+        assert_eq!(SplitMode::from_fields(&fields(&[("pages_per_part", " 3 ")])).unwrap(), SplitMode::Every(3));
+        assert_eq!(SplitMode::from_fields(&fields(&[("split_at", "5, 2,5")])).unwrap(), SplitMode::At(vec![2, 5]));
+        for bad in [("pages_per_part", "0"), ("pages_per_part", "two"), ("split_at", "2,,4"), ("split_at", "1-3")] {
+            assert_eq!(SplitMode::from_fields(&fields(&[bad])).unwrap_err().code, "INVALID_SPLIT_OPTIONS");
+        }
+        let both = fields(&[("pages_per_part", "2"), ("split_at", "3")]);
+        assert_eq!(SplitMode::from_fields(&both).unwrap_err().code, "SPLIT_OPTION_CONFLICT");
+
+        assert_eq!(SplitMode::Every(2).parts(5).unwrap(), [0..2, 2..4, 4..5]);
+        assert_eq!(SplitMode::At(vec![1, 3]).parts(5).unwrap(), [0..2, 2..5]);
+        assert_eq!(SplitMode::At(vec![6]).parts(5).unwrap_err().code, "PAGE_OUT_OF_RANGE");
+    }
+
+    #[test]
+    fn test_split_document() {
+        let pdf = sample_pdf(&["p1", "p2", "p3"]);
+        let archive = split_document("a.pdf", &pdf, &SplitMode::Every(2)).unwrap();
+        let zip = ZipReader::new(&archive).unwrap();
+        let first = zip.read("part_001.pdf", usize::MAX).unwrap();
+        let second = zip.read("part_002.pdf", usize::MAX).unwrap();
+        assert!(zip.read("part_003.pdf", usize::MAX).is_none());
+        assert_eq!(export::count_pdf_pages(&first), 2);
+        assert_eq!(export::count_pdf_pages(&second), 1);
+        assert!(String::from_utf8_lossy(&second).contains("(p3) Tj"));
+        assert!(!String::from_utf8_lossy(&second).contains("(p1) Tj"));
+
+        assert_eq!(split_document("a.pdf", b"%PDF-1.4", &SplitMode::Every(1)).unwrap_err().code, "INVALID_PDF");
+    }
+}
@@ -0,0 +1,436 @@
+//! Logging and optional OpenTelemetry trace export.
+//!
+//! When `OTEL_EXPORTER_OTLP_ENDPOINT` is set, finished spans are batched and sent to
+//! `<endpoint>/v1/traces` using OTLP/HTTP with JSON encoding. Incoming W3C `traceparent` headers
+//! become the parent of the request span. Without the env var no exporter layer is installed.
+//!
+//! Every request also gets an id, echoed in `X-Request-Id` and recorded on its spans along with
+//! the client's IP, and a line in the access log once it is answered.
+
+use axum::{
+    body::HttpBody,
+    extract::{ConnectInfo, Request, State},
+    http::HeaderValue,
+    middleware::Next,
+    response::Response,
+};
+use serde_json::{json, Value};
+use std::env;
+use std::fmt::Write as _;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{error, info, info_span, warn, Instrument, Span, Subscriber};
+use tracing_subscriber::layer::{Context, SubscriberExt};
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Layer};
+use uuid::Uuid;
+
+use crate::{outbound, ratelimit};
+
+const MAX_QUEUED_SPANS: usize = 4096;
+const MAX_BATCH: usize = 512;
+const FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+const EXPORT_TIMEOUT: Duration = Duration::from_secs(10);
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+/// Longer (or non-printable) client ids are replaced rather than copied into every log line.
+const MAX_REQUEST_ID_LEN: usize = 128;
+/// Query parameters whose values are credentials, masked in the access log.
+const SECRET_PARAMS: &[&str] = &["token", "password"];
+
+/// Installs the global subscriber (and exporter, if configured). Returns whether OTLP is enabled.
+pub fn init() -> bool {
+    let exporter = env::var("OTEL_EXPORTER_OTLP_ENDPOINT").ok().map(|endpoint| {
+        let service_name = env::var("OTEL_SERVICE_NAME").unwrap_or_else(|_| "office2pdf".to_string());
+        let (tx, rx) = mpsc::channel(MAX_QUEUED_SPANS);
+        tokio::spawn(export_loop(rx, traces_url(&endpoint), service_name));
+        OtlpLayer { tx }
+    });
+    let enabled = exporter.is_some();
+
+    tracing_subscriber::registry()
+        .with(EnvFilter::from_default_env())
+        .with(tracing_subscriber::fmt::layer())
+        .with(exporter)
+        .init();
+    enabled
+}
+
+fn traces_url(endpoint: &str) -> String {
+    format!("{}/v1/traces", endpoint.trim_end_matches('/'))
+}
+
+/// Middleware wrapping each request in a server span that continues the caller's trace.
+pub async fn trace_request(req: Request, next: Next) -> Response {
+    let traceparent = req
+        .headers()
+        .get("traceparent")
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default()
+        .to_string();
+    let span = tracing::info_span!(
+        "request",
+        http.method = %req.method(),
+        http.route = req.uri().path(),
+        http.status_code = tracing::field::Empty,
+        traceparent = traceparent.as_str(),
+        request_id = tracing::field::Empty,
+        client.ip = tracing::field::Empty,
+    );
+    let response = next.run(req).instrument(span.clone()).await;
+    span.record("http.status_code", response.status().as_u16());
+    response
+}
+
+/// Generated for every request and stored in its extensions; names the work directory (and job,
+/// if any). Doubles as the `X-Request-Id` when the client did not send one.
+#[derive(Clone, Copy, Debug)]
+pub struct RequestId(pub Uuid);
+
+/// Echoes the client's `X-Request-Id` (or a new UUID) on the response and records it as the
+/// `request_id` of the request span, opening one if `trace_request` did not. The span also gets
+/// the client's address as `client.ip`, taken from `X-Forwarded-For` only as far as the
+/// `trusted_proxies` proxies in front of the service vouch for it (see `ratelimit::client_ip`).
+pub async fn request_id(
+    State(trusted_proxies): State<usize>,
+    peer: Option<ConnectInfo<SocketAddr>>,
+    mut req: Request,
+    next: Next,
+) -> Response {
+    let id = Uuid::new_v4();
+    let header = req
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .filter(|v| is_usable_request_id(v.as_bytes()))
+        .cloned()
+        .unwrap_or_else(|| HeaderValue::from_str(&id.to_string()).unwrap());
+    let value = header.to_str().unwrap_or_default().to_string();
+    req.extensions_mut().insert(RequestId(id));
+
+    let client_ip = peer.map(|ConnectInfo(peer)| ratelimit::client_ip(req.headers(), peer.ip(), trusted_proxies).to_string());
+
+    let mut span = Span::current();
+    let opened = !span.has_field("request_id");
+    if opened {
+        span = info_span!("request", request_id = tracing::field::Empty, client.ip = tracing::field::Empty);
+    }
+    span.record("request_id", value.as_str());
+    if let Some(ref ip) = client_ip {
+        span.record("client.ip", ip.as_str());
+    }
+    let mut response = if opened { next.run(req).instrument(span).await } else { next.run(req).await };
+    response.headers_mut().insert(REQUEST_ID_HEADER, header);
+    response
+}
+
+/// Logs each request once it is answered: method, path, query string, status, response size
+/// (when known up front; streamed bodies have none) and duration, as structured fields. Answered
+/// at `info` level, client errors at `warn` and server errors at `error`. Request bodies are never
+/// logged, and credentials in the query string are masked.
+pub async fn access_log(req: Request, next: Next) -> Response {
+    let started = Instant::now();
+    let method = req.method().clone();
+    let path = req.uri().path().to_string();
+    let query = req.uri().query().map(masked_query).unwrap_or_default();
+    let response = next.run(req).await;
+    let status = response.status().as_u16();
+    let bytes = response.body().size_hint().exact();
+    let duration_ms = started.elapsed().as_millis() as u64;
+    match status {
+        500.. => error!(%method, path, query, status, bytes, duration_ms, "request failed"),
+        400..500 => warn!(%method, path, query, status, bytes, duration_ms, "request rejected"),
+        _ => info!(%method, path, query, status, bytes, duration_ms, "request completed"),
+    }
+    response
+}
+
+/// `query` with the values of `SECRET_PARAMS` replaced.
+fn masked_query(query: &str) -> String {
+    query
+        .split('&')
+        .map(|pair| match pair.split_once('=') {
+            Some((name, _)) if SECRET_PARAMS.contains(&name) => format!("{}=***", name),
+            _ => pair.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+fn is_usable_request_id(value: &[u8]) -> bool {
+    !value.is_empty() && value.len() <= MAX_REQUEST_ID_LEN && value.iter().all(|b| b.is_ascii_graphic())
+}
+
+/// Parses a W3C `traceparent` header into (trace id, parent span id).
+fn parse_traceparent(value: &str) -> Option<([u8; 16], [u8; 8])> {
+    let mut parts = value.trim().split('-');
+    let (version, trace_id, span_id, _flags) = (parts.next()?, parts.next()?, parts.next()?, parts.next()?);
+    if version != "00" || trace_id.len() != 32 || span_id.len() != 16 {
+        return None;
+    }
+    let trace_id: [u8; 16] = decode_hex(trace_id)?.try_into().ok()?;
+    let span_id: [u8; 8] = decode_hex(span_id)?.try_into().ok()?;
+    // All-zero ids are invalid per the spec
+    if trace_id == [0; 16] || span_id == [0; 8] {
+        return None;
+    }
+    Some((trace_id, span_id))
+}
+
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::with_capacity(bytes.len() * 2), |mut out, b| {
+        let _ = write!(out, "{:02x}", b);
+        out
+    })
+}
+
+fn nanos(t: SystemTime) -> String {
+    t.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos().to_string()
+}
+
+struct SpanData {
+    name: &'static str,
+    trace_id: [u8; 16],
+    span_id: [u8; 8],
+    parent_span_id: Option<[u8; 8]>,
+    start: SystemTime,
+    attributes: Vec<(&'static str, Value)>,
+}
+
+impl SpanData {
+    fn to_otlp(&self, end: SystemTime) -> Value {
+        let attributes: Vec<Value> = self
+            .attributes
+            .iter()
+            .map(|(key, value)| json!({ "key": key, "value": value }))
+            .collect();
+        json!({
+            "traceId": encode_hex(&self.trace_id),
+            "spanId": encode_hex(&self.span_id),
+            "parentSpanId": self.parent_span_id.map(|id| encode_hex(&id)).unwrap_or_default(),
+            "name": self.name,
+            // SPAN_KIND_SERVER for the request span, SPAN_KIND_INTERNAL for everything below it
+            "kind": if self.name == "request" { 2 } else { 1 },
+            "startTimeUnixNano": nanos(self.start),
+            "endTimeUnixNano": nanos(end),
+            "attributes": attributes,
+        })
+    }
+}
+
+/// Collects span fields as OTLP `AnyValue`s.
+struct AttributeVisitor<'a>(&'a mut Vec<(&'static str, Value)>);
+
+impl AttributeVisitor<'_> {
+    fn set(&mut self, key: &'static str, value: Value) {
+        self.0.retain(|(k, _)| *k != key);
+        self.0.push((key, value));
+    }
+}
+
+impl Visit for AttributeVisitor<'_> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.set(field.name(), json!({ "stringValue": value }));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.set(field.name(), json!({ "intValue": value.to_string() }));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.set(field.name(), json!({ "intValue": value.to_string() }));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.set(field.name(), json!({ "boolValue": value }));
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.set(field.name(), json!({ "doubleValue": value }));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.set(field.name(), json!({ "stringValue": format!("{:?}", value) }));
+    }
+}
+
+struct OtlpLayer {
+    tx: mpsc::Sender<Value>,
+}
+
+impl<S> Layer<S> for OtlpLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut attributes = Vec::new();
+        attrs.record(&mut AttributeVisitor(&mut attributes));
+
+        let span_id: [u8; 8] = Uuid::new_v4().as_bytes()[..8].try_into().unwrap();
+        let parent = span
+            .parent()
+            .and_then(|p| p.extensions().get::<SpanData>().map(|d| (d.trace_id, d.span_id)));
+        let remote = attributes.iter().position(|(k, _)| *k == "traceparent").and_then(|i| {
+            let (_, value) = attributes.remove(i);
+            value["stringValue"].as_str().and_then(parse_traceparent)
+        });
+        let (trace_id, parent_span_id) = match parent.or(remote) {
+            Some((trace_id, parent_id)) => (trace_id, Some(parent_id)),
+            None => (*Uuid::new_v4().as_bytes(), None),
+        };
+
+        span.extensions_mut().insert(SpanData {
+            name: attrs.metadata().name(),
+            trace_id,
+            span_id,
+            parent_span_id,
+            start: SystemTime::now(),
+            attributes,
+        });
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id)
+            && let Some(data) = span.extensions_mut().get_mut::<SpanData>()
+        {
+            values.record(&mut AttributeVisitor(&mut data.attributes));
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(&id)
+            && let Some(data) = span.extensions_mut().remove::<SpanData>()
+        {
+            // Drop spans rather than block the request when the collector falls behind
+            let _ = self.tx.try_send(data.to_otlp(SystemTime::now()));
+        }
+    }
+}
+
+async fn export_loop(mut rx: mpsc::Receiver<Value>, url: String, service_name: String) {
+    let mut batch = Vec::new();
+    loop {
+        let flush = tokio::time::sleep(FLUSH_INTERVAL);
+        tokio::pin!(flush);
+        while batch.len() < MAX_BATCH {
+            tokio::select! {
+                span = rx.recv() => match span {
+                    Some(span) => batch.push(span),
+                    None => return,
+                },
+                _ = &mut flush => break,
+            }
+        }
+        if batch.is_empty() {
+            continue;
+        }
+
+        let body = json!({
+            "resourceSpans": [{
+                "resource": { "attributes": [{ "key": "service.name", "value": { "stringValue": service_name } }] },
+                "scopeSpans": [{ "scope": { "name": "office2pdf" }, "spans": std::mem::take(&mut batch) }],
+            }]
+        });
+        match outbound::post(&url, None, "application/json", &[], body.to_string().as_bytes(), EXPORT_TIMEOUT).await {
+            Ok(status) if (200..300).contains(&status) => {}
+            Ok(status) => warn!("OTLP export to {} returned HTTP {}", url, status),
+            Err(e) => warn!("OTLP export to {} failed: {}", url, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, middleware, routing::get, Extension, Router};
+    use tower::ServiceExt;
+
+    #[test]
+    fn test_masked_query() {
+        assert_eq!(masked_query("async=true&token=abc.def&format=pdf"), "async=true&token=***&format=pdf");
+        assert_eq!(masked_query("password=x&tokens=1&flag"), "password=***&tokens=1&flag");
+    }
+
+    #[tokio::test]
+    async fn test_request_id() {
+        let app = Router::new()
+            .route("/", get(|Extension(id): Extension<RequestId>| async move { id.0.to_string() }))
+            .layer(middleware::from_fn_with_state(0, request_id));
+        let request = |id: Option<&str>| {
+            let builder = Request::builder().uri("/");
+            let builder = match id {
+                Some(id) => builder.header(REQUEST_ID_HEADER, id),
+                None => builder,
+            };
+            builder.body(Body::empty()).unwrap()
+        };
+
+        let response = app.clone().oneshot(request(Some("client-trace-42"))).await.unwrap();
+        assert_eq!(response.headers()[REQUEST_ID_HEADER], "client-trace-42");
+
+        // Without a usable header the generated id is the one handlers see
+        for id in [None, Some("has spaces"), Some(&"x".repeat(129)[..])] {
+            let response = app.clone().oneshot(request(id)).await.unwrap();
+            let header = response.headers()[REQUEST_ID_HEADER].to_str().unwrap().to_string();
+            let body = axum::body::to_bytes(response.into_body(), 1024).await.unwrap();
+            assert_eq!(header.as_bytes(), &body[..]);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_request_span_client_ip() {
+        use axum::extract::connect_info::MockConnectInfo;
+
+        let (tx, mut rx) = mpsc::channel(16);
+        let _subscriber = tracing_subscriber::registry().with(OtlpLayer { tx }).set_default();
+        let app = Router::new()
+            .route("/", get(|| async {}))
+            .layer(middleware::from_fn_with_state(1, request_id))
+            .layer(MockConnectInfo(SocketAddr::from(([10, 0, 0, 1], 4000))));
+        // Only the entry added by the one trusted proxy counts, not what the client claimed
+        let request = Request::builder()
+            .uri("/")
+            .header("x-forwarded-for", "198.51.100.1, 203.0.113.9")
+            .body(Body::empty())
+            .unwrap();
+        app.oneshot(request).await.unwrap();
+
+        let span = rx.try_recv().unwrap();
+        assert_eq!(span["name"], "request");
+        let attributes = span["attributes"].as_array().unwrap();
+        assert!(attributes.contains(&json!({"key": "client.ip", "value": {"stringValue": "203.0.113.9"}})));
+    }
+
+    #[test]
+    fn test_parse_traceparent() {
+        let (trace_id, span_id) =
+            parse_traceparent("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01").unwrap();
+        assert_eq!(encode_hex(&trace_id), "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(encode_hex(&span_id), "00f067aa0ba902b7");
+
+        assert!(parse_traceparent("").is_none());
+        assert!(parse_traceparent("01-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01").is_none());
+        assert!(parse_traceparent("00-00000000000000000000000000000000-00f067aa0ba902b7-01").is_none());
+        assert!(parse_traceparent("00-4bf92f3577b34da6a3ce929d0e0e473z-00f067aa0ba902b7-01").is_none());
+    }
+
+    #[test]
+    fn test_traces_url() {
+        assert_eq!(traces_url("http://collector:4318/"), "http://collector:4318/v1/traces");
+    }
+}
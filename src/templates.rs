@@ -0,0 +1,111 @@
+//! Office Open XML templates: `.dotx`, `.xltx` and `.potx`, and their macro-enabled `.dotm`,
+//! `.xltm` and `.potm` variants.
+//!
+//! Converted straight to PDF, a template comes out the way LibreOffice edits a template, not the
+//! way Office opens one: fields that fill in on a new document stay empty, and some exports treat
+//! it as the template file it is. So templates are converted in two passes. The first converts
+//! the template to its base format (`docx`, `xlsx` or `pptx`), which instantiates it: the result
+//! is an ordinary document with the template's content and defaults filled in, as "New from
+//! template" gives. The second converts that document as the request asks, like any upload of
+//! that format. Each pass runs under the conversion timeout of its own.
+
+use std::path::Path;
+use tokio::fs;
+use tracing::{error, info};
+
+use crate::error::ConversionError;
+use crate::formats::{self, OutputFormat};
+use crate::{convert_file, AppState, ConvertOptions, UploadedFile};
+
+/// Template extensions and the format each instantiates to.
+pub const TEMPLATE_EXTENSIONS: &[(&str, &str)] =
+    &[("dotx", "docx"), ("dotm", "docx"), ("xltx", "xlsx"), ("xltm", "xlsx"), ("potx", "pptx"), ("potm", "pptx")];
+
+/// The format a template at `path` instantiates to, or `None` if it is not a template.
+pub fn base_format(path: &Path) -> Option<OutputFormat> {
+    let extension = path.extension()?.to_string_lossy().to_ascii_lowercase();
+    let (_, base) = TEMPLATE_EXTENSIONS.iter().find(|(template, _)| *template == extension)?;
+    formats::output_format(base)
+}
+
+/// The first pass: converts the template `file` to `base` and returns the document it gives,
+/// in a directory of its own next to the upload so that the second pass's output does not mix
+/// with this one's.
+pub async fn instantiate(
+    state: &AppState,
+    work_dir: &Path,
+    file: &UploadedFile,
+    opts: &ConvertOptions,
+    base: OutputFormat,
+) -> Result<UploadedFile, ConversionError> {
+    // Only what it takes to open the template; the options apply to the second pass
+    let first_pass = ConvertOptions {
+        format: base,
+        input_password: opts.input_password.clone(),
+        ..ConvertOptions::default()
+    };
+    let document = Box::pin(convert_file(state, work_dir, file, &first_pass)).await?;
+
+    let upload_dir = file.path.parent().unwrap_or(work_dir);
+    let dir = upload_dir.join("template");
+    let path = dir.join(&document.filename);
+    let written = async {
+        fs::create_dir_all(&dir).await?;
+        fs::write(&path, &document.content).await
+    };
+    if let Err(e) = written.await {
+        error!("Failed to write the instantiated template: {}", e);
+        return Err(ConversionError::internal());
+    }
+    // The second pass writes to an `out` directory of its own
+    let _ = fs::remove_dir_all(upload_dir.join("out")).await;
+    info!("Instantiated template {:?} as {}", file.path.file_name().unwrap_or_default(), base.extension);
+    Ok(UploadedFile {
+        path,
+        size: document.content.len() as u64,
+        kind: base.extension,
+        digest: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::test_state;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_base_format() {
+        assert_eq!(base_format(Path::new("/tmp/Invoice.DOTX")).map(|f| f.extension), Some("docx"));
+        assert_eq!(base_format(Path::new("budget.xltm")).map(|f| f.extension), Some("xlsx"));
+        assert_eq!(base_format(Path::new("deck.potx")).map(|f| f.extension), Some("pptx"));
+        assert_eq!(base_format(Path::new("letter.docx")), None);
+        assert_eq!(base_format(Path::new("dotx")), None);
+    }
+
+    #[tokio::test]
+    async fn test_two_passes() {
+        let work_dir = PathBuf::from(format!("/tmp/convert/templates-test-{}", uuid::Uuid::new_v4()));
+        let upload_dir = work_dir.join("0");
+        std::fs::create_dir_all(&upload_dir).unwrap();
+        let path = upload_dir.join("Invoice.dotx");
+        std::fs::write(&path, b"template").unwrap();
+        let file = UploadedFile { path, size: 8, kind: "docx", digest: None };
+
+        let state = AppState {
+            converter: std::sync::Arc::new(crate::service::MockConversionService),
+            ..test_state()
+        };
+        let converted = convert_file(&state, &work_dir, &file, &ConvertOptions::default()).await.unwrap();
+        assert_eq!(converted.filename, "Invoice.pdf");
+        assert!(converted.headers.contains(&(crate::INTERMEDIATE_FORMAT_HEADER, "docx".to_string())));
+        assert!(upload_dir.join("template/Invoice.docx").exists());
+
+        // Asking for the base format itself takes one pass
+        let docx = ConvertOptions { format: formats::output_format("docx").unwrap(), ..Default::default() };
+        let converted = convert_file(&state, &work_dir, &file, &docx).await.unwrap();
+        assert_eq!(converted.filename, "Invoice.docx");
+        assert!(!converted.headers.iter().any(|(name, _)| *name == crate::INTERMEDIATE_FORMAT_HEADER));
+        std::fs::remove_dir_all(&work_dir).unwrap();
+    }
+}
@@ -0,0 +1,212 @@
+//! `POST /thumbnail`: a JPEG preview of one page of a document.
+//!
+//! The page is first exported on its own as a PDF, which also picks it out of PDF inputs. That
+//! PDF is then rendered by LibreOffice's JPEG export, scaled to fit `width` x `height`.
+
+use axum::{
+    extract::{ConnectInfo, Multipart, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Extension,
+};
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use tokio::fs;
+use tokio::time::Instant;
+use tracing::{error, info_span, Instrument};
+
+use crate::audit::{self, Requester};
+use crate::error::ConversionError;
+use crate::telemetry::RequestId;
+use crate::{busy_response, convert_file, disk, export, filename_header, formats, receive_upload, run_conversion, shutting_down_response};
+use crate::{AppState, ConvertOptions, UploadedFile};
+
+pub const DEFAULT_WIDTH: u32 = 800;
+/// An A4 page at `DEFAULT_WIDTH`.
+pub const DEFAULT_HEIGHT: u32 = 1132;
+const DEFAULT_QUALITY: u32 = 85;
+const MAX_DIMENSION: u32 = 4096;
+
+/// The `width`, `height`, `page` and `thumb_quality` fields.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ThumbnailOptions {
+    /// The bounding box the page is scaled into, keeping its aspect ratio.
+    pub width: u32,
+    pub height: u32,
+    /// 1-based.
+    pub page: u32,
+    /// JPEG quality, 1 to 100.
+    pub quality: u32,
+}
+
+impl ThumbnailOptions {
+    pub fn from_request(params: &HashMap<String, String>, fields: &HashMap<String, String>) -> Result<Self, ConversionError> {
+        let lookup = |name: &str| {
+            let value = fields.get(name).or_else(|| params.get(name))?.trim();
+            (!value.is_empty()).then_some(value)
+        };
+        let number = |name: &str, default: u32, max: u32| match lookup(name) {
+            None => Ok(default),
+            Some(raw) => raw.parse().ok().filter(|n| (1..=max).contains(n)).ok_or_else(|| {
+                ConversionError::new(
+                    StatusCode::BAD_REQUEST,
+                    "INVALID_THUMBNAIL_OPTIONS",
+                    format!("{} must be an integer from 1 to {}, not {:?}", name, max, raw),
+                )
+            }),
+        };
+        Ok(Self {
+            width: number("width", DEFAULT_WIDTH, MAX_DIMENSION)?,
+            height: number("height", DEFAULT_HEIGHT, MAX_DIMENSION)?,
+            page: number("page", 1, export::MAX_PAGE)?,
+            quality: number("thumb_quality", DEFAULT_QUALITY, 100)?,
+        })
+    }
+}
+
+/// Settings for LibreOffice's JPEG export filter.
+#[derive(Clone, Debug, PartialEq)]
+pub struct JpegExport {
+    pub width: u32,
+    pub height: u32,
+    pub quality: u32,
+}
+
+impl JpegExport {
+    pub fn filter_data(&self) -> Map<String, Value> {
+        let mut data = Map::new();
+        data.insert("PixelWidth".into(), json!({ "type": "long", "value": self.width.to_string() }));
+        data.insert("PixelHeight".into(), json!({ "type": "long", "value": self.height.to_string() }));
+        data.insert("Quality".into(), json!({ "type": "long", "value": self.quality.to_string() }));
+        data
+    }
+}
+
+pub async fn thumbnail(
+    State(state): State<Arc<AppState>>,
+    Extension(RequestId(request_id)): Extension<RequestId>,
+    peer: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+    Query(params): Query<HashMap<String, String>>,
+    mut multipart: Multipart,
+) -> Response {
+    if state.draining.load(Ordering::SeqCst) {
+        return shutting_down_response();
+    }
+    let Some(slot) = state.try_conversion_slot() else {
+        return busy_response();
+    };
+
+    let _active_dir = state.active_dirs.track(request_id);
+    let work_dir = state.request_dir(request_id);
+    if let Err(e) = fs::create_dir_all(&work_dir).await {
+        error!("Failed to create work dir: {}", e);
+        return ConversionError::internal().into_response();
+    }
+    let result = async {
+        disk::ensure_free_space(&work_dir, state.min_free_bytes)?;
+        let upload = receive_upload(&mut multipart, filename_header(&headers).as_deref(), &work_dir, &state, Some(&state.allowed_input_types))
+            .instrument(info_span!("multipart"))
+            .await?;
+        let [ref file] = upload.files[..] else {
+            return Err(ConversionError::new(StatusCode::BAD_REQUEST, "TOO_MANY_FILES", "Thumbnails are made from one file at a time"));
+        };
+        let opts = ThumbnailOptions::from_request(&params, &upload.fields)?;
+        let started = Instant::now();
+        let rendered = render(&state, &work_dir, file, &opts).await;
+        let requester = Requester::new(&state, request_id, &headers, peer);
+        audit::record(&state, requester, file, formats::JPG.extension, started.elapsed(), rendered).await
+    }
+    .await;
+    drop(slot);
+    let _ = fs::remove_dir_all(&work_dir).instrument(info_span!("cleanup")).await;
+
+    match result {
+        Ok(jpeg) => ([(header::CONTENT_TYPE, formats::JPG.content_type)], jpeg).into_response(),
+        Err(e) => e.into_response(),
+    }
+}
+
+async fn render(state: &AppState, work_dir: &Path, file: &UploadedFile, opts: &ThumbnailOptions) -> Result<Vec<u8>, ConversionError> {
+    let single_page = ConvertOptions {
+        pages: Some(vec![opts.page]),
+        ..Default::default()
+    };
+    let pdf = run_conversion(state, work_dir, file, &single_page).await?;
+
+    // Next to the upload directories, so the JPEG lands in `thumb/out/`
+    let path = work_dir.join("thumb").join("page.pdf");
+    let written = async {
+        fs::create_dir_all(path.parent().unwrap_or(work_dir)).await?;
+        fs::write(&path, &pdf.content).await
+    };
+    if let Err(e) = written.await {
+        error!("Failed to write page PDF: {}", e);
+        return Err(ConversionError::internal());
+    }
+
+    let (width, height) = fit_within(export::pdf_page_size(&pdf.content), opts.width, opts.height);
+    let jpeg = ConvertOptions {
+        format: formats::JPG,
+        jpeg: Some(JpegExport {
+            width,
+            height,
+            quality: opts.quality,
+        }),
+        ..Default::default()
+    };
+    let page = UploadedFile {
+        path,
+        size: pdf.content.len() as u64,
+        kind: "pdf",
+        digest: None,
+    };
+    Ok(convert_file(state, work_dir, &page, &jpeg).await?.content)
+}
+
+/// The largest size with the page's aspect ratio that fits in `width` x `height`. Without a
+/// known page size the box is used as it is.
+fn fit_within(page: Option<(f64, f64)>, width: u32, height: u32) -> (u32, u32) {
+    let Some((page_width, page_height)) = page else {
+        return (width, height);
+    };
+    let scale = (width as f64 / page_width).min(height as f64 / page_height);
+    let fit = |side: f64, max: u32| ((side * scale).round() as u32).clamp(1, max);
+    (fit(page_width, width), fit(page_height, height))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_options() {
+        let opts = ThumbnailOptions::from_request(&HashMap::new(), &HashMap::new()).unwrap();
+        assert_eq!((opts.width, opts.height, opts.page, opts.quality), (800, 1132, 1, 85));
+
+        let fields = HashMap::from([("width".to_string(), "400".to_string()), ("page".to_string(), " 3 ".to_string())]);
+        let params = HashMap::from([("width".to_string(), "200".to_string()), ("thumb_quality".to_string(), "60".to_string())]);
+        let opts = ThumbnailOptions::from_request(&params, &fields).unwrap();
+        assert_eq!((opts.width, opts.page, opts.quality), (400, 3, 60));
+
+        for (name, bad) in [("width", "0"), ("height", "5000"), ("page", "-1"), ("thumb_quality", "101"), ("page", "two")] {
+            let fields = HashMap::from([(name.to_string(), bad.to_string())]);
+            let err = ThumbnailOptions::from_request(&HashMap::new(), &fields).unwrap_err();
+            assert_eq!(err.code, "INVALID_THUMBNAIL_OPTIONS", "{}={}", name, bad);
+        }
+    }
+
+    #[test]
+    fn test_fit_within() {
+        // A4 portrait and landscape in the default box
+        assert_eq!(fit_within(Some((595.304, 841.89)), 800, 1132), (800, 1131));
+        assert_eq!(fit_within(Some((841.89, 595.304)), 800, 1132), (800, 566));
+        assert_eq!(fit_within(Some((612.0, 792.0)), 300, 300), (232, 300));
+        assert_eq!(fit_within(None, 640, 480), (640, 480));
+        assert_eq!(JpegExport { width: 1, height: 2, quality: 85 }.filter_data()["Quality"]["value"], "85");
+    }
+}
@@ -0,0 +1,125 @@
+# Converts a document through an already running LibreOffice listener.
+#
+# Usage: python3 -c <this script> <pipe-name> <input> <output> <format>
+#            [<filter-data-json> [<import-filter> [<paper-size>]]]
+#
+# <filter-data-json> uses the same {"Name": {"type": ..., "value": ...}} form as
+# `--convert-to pdf:<filter>:<json>`; <import-filter> the same <name>:<options> form as `--infilter`
+# (empty for none). <paper-size> is <width>x<height> in 1/100 mm, portrait; every page is set to it,
+# turned for landscape pages.
+#
+# Run by the worker pool (src/pool.rs) instead of spawning a fresh `libreoffice --convert-to`,
+# which saves the 1-3 s LibreOffice start-up on every request.
+import json
+import sys
+import time
+
+import uno
+from com.sun.star.beans import PropertyValue
+from com.sun.star.connection import NoConnectException
+
+# Export filter per output format, keyed by the kind of document that was loaded.
+FILTERS = {
+    "pdf": {"text": "writer_pdf_Export", "spreadsheet": "calc_pdf_Export",
+            "presentation": "impress_pdf_Export", "drawing": "draw_pdf_Export"},
+    "png": {"text": "writer_png_Export", "spreadsheet": "calc_png_Export",
+            "presentation": "impress_png_Export", "drawing": "draw_png_Export"},
+    "jpg": {"text": "writer_jpg_Export", "spreadsheet": "calc_jpg_Export",
+            "presentation": "impress_jpg_Export", "drawing": "draw_jpg_Export"},
+    "html": {"text": "HTML (StarWriter)", "spreadsheet": "HTML (StarCalc)",
+             "presentation": "impress_html_Export", "drawing": "draw_html_Export"},
+    "txt": {"text": "Text"},
+    "rtf": {"text": "Rich Text Format", "spreadsheet": "Rich Text Format (StarCalc)"},
+    "csv": {"spreadsheet": "Text - txt - csv (StarCalc)"},
+    "docx": {"text": "MS Word 2007 XML"},
+    "xlsx": {"spreadsheet": "Calc MS Excel 2007 XML"},
+    "pptx": {"presentation": "Impress MS PowerPoint 2007 XML"},
+    "odt": {"text": "writer8"},
+    "ods": {"spreadsheet": "calc8"},
+    "odp": {"presentation": "impress8"},
+}
+
+SERVICES = [
+    ("com.sun.star.text.TextDocument", "text"),
+    ("com.sun.star.sheet.SpreadsheetDocument", "spreadsheet"),
+    ("com.sun.star.presentation.PresentationDocument", "presentation"),
+    ("com.sun.star.drawing.DrawingDocument", "drawing"),
+]
+
+
+def prop(name, value):
+    p = PropertyValue()
+    p.Name = name
+    p.Value = value
+    return p
+
+
+def filter_value(typed):
+    kind, value = typed["type"], typed["value"]
+    if kind == "boolean":
+        return value in (True, "true")
+    if kind == "long":
+        return int(value)
+    return value
+
+
+def set_paper_size(doc, kind, width, height):
+    def size_for(landscape):
+        return (height, width) if landscape else (width, height)
+
+    if kind in ("presentation", "drawing"):
+        pages = doc.getDrawPages()
+        for i in range(pages.getCount()):
+            page = pages.getByIndex(i)
+            page.Width, page.Height = size_for(page.Width > page.Height)
+    else:
+        styles = doc.getStyleFamilies().getByName("PageStyles")
+        for name in styles.getElementNames():
+            style = styles.getByName(name)
+            style.Width, style.Height = size_for(style.IsLandscape)
+
+
+def connect(pipe_name, attempts=50):
+    local = uno.getComponentContext()
+    resolver = local.ServiceManager.createInstanceWithContext("com.sun.star.bridge.UnoUrlResolver", local)
+    url = "uno:pipe,name=%s;urp;StarOffice.ComponentContext" % pipe_name
+    for _ in range(attempts):
+        try:
+            return resolver.resolve(url)
+        except NoConnectException:
+            # The listener may still be starting (or restarting after a crash)
+            time.sleep(0.1)
+    return resolver.resolve(url)
+
+
+def main():
+    pipe_name, input_path, output_path, fmt = sys.argv[1:5]
+    filter_data = json.loads(sys.argv[5]) if len(sys.argv) > 5 else {}
+    load_props = [prop("Hidden", True)]
+    if len(sys.argv) > 6 and sys.argv[6]:
+        import_filter, _, import_options = sys.argv[6].partition(":")
+        load_props += [prop("FilterName", import_filter), prop("FilterOptions", import_options)]
+    ctx = connect(pipe_name)
+    desktop = ctx.ServiceManager.createInstanceWithContext("com.sun.star.frame.Desktop", ctx)
+    doc = desktop.loadComponentFromURL(uno.systemPathToFileUrl(input_path), "_blank", 0, tuple(load_props))
+    if doc is None:
+        sys.exit("could not load %s" % input_path)
+    try:
+        kind = next((k for service, k in SERVICES if doc.supportsService(service)), None)
+        filter_name = FILTERS.get(fmt, {}).get(kind)
+        if filter_name is None:
+            sys.exit("no %s export filter for %s documents" % (fmt, kind))
+        if len(sys.argv) > 7:
+            width, height = (int(n) for n in sys.argv[7].split("x"))
+            set_paper_size(doc, kind, width, height)
+        props = [prop("FilterName", filter_name), prop("Overwrite", True)]
+        if filter_data:
+            data = tuple(prop(name, filter_value(typed)) for name, typed in filter_data.items())
+            props.append(prop("FilterData", uno.Any("[]com.sun.star.beans.PropertyValue", data)))
+        # uno.invoke keeps the FilterData sequence typed; a plain call would pass an untyped tuple
+        uno.invoke(doc, "storeToURL", (uno.systemPathToFileUrl(output_path), tuple(props)))
+    finally:
+        doc.close(True)
+
+
+main()
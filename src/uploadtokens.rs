@@ -0,0 +1,298 @@
+//! Single-use upload links: `POST /upload-token` (admin only) issues a token, and
+//! `/convert?token=<token>` then accepts one upload without an API key, e.g. from a client the
+//! link was emailed to.
+//!
+//! Like download links, the token carries everything needed to check it, signed:
+//! `base64url(id || expiry || pattern length || allowed_filename || subject || HMAC-SHA256(...))`.
+//! The HMAC key is random per process, so tokens do not survive a restart. Redeeming a token
+//! records its id until it expires, so it works once.
+
+use axum::{
+    body::Bytes,
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io::{self, Read};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::info;
+use uuid::Uuid;
+
+use crate::crypto::{base64_decode, base64url_encode, constant_time_eq, hmac_sha256};
+use crate::error::ConversionError;
+use crate::{admin, apikeys, unauthorized, AppState};
+
+/// The query parameter of `/convert` carrying the token.
+pub const QUERY_PARAM: &str = "token";
+pub const DEFAULT_TTL_SECS: u64 = 900;
+pub const MAX_TTL_SECS: u64 = 7 * 24 * 3600;
+/// Longest `allowed_filename` and `subject`, in bytes.
+const MAX_FIELD_LEN: usize = 255;
+/// id (16 bytes) + expiry (8) + pattern length (2); the pattern and subject follow.
+const HEADER_LEN: usize = 26;
+const MAC_LEN: usize = 32;
+
+/// What a redeemed token allows.
+#[derive(Clone, Debug, PartialEq)]
+pub struct UploadGrant {
+    pub id: Uuid,
+    /// Glob (`*` and `?`, case-insensitive) every uploaded file name must match.
+    pub allowed_filename: String,
+    /// Who the token was issued to, for the logs.
+    pub subject: Option<String>,
+}
+
+impl UploadGrant {
+    /// Rejects an upload named `filename` that the token does not cover.
+    pub fn check_filename(&self, filename: &str) -> Result<(), ConversionError> {
+        if glob_matches(&self.allowed_filename, filename) {
+            return Ok(());
+        }
+        Err(ConversionError::new(
+            StatusCode::FORBIDDEN,
+            "FILENAME_NOT_ALLOWED",
+            format!("The upload token only accepts files named {:?}", self.allowed_filename),
+        )
+        .with_detail("allowed_filename", self.allowed_filename.clone()))
+    }
+}
+
+pub struct UploadTokens {
+    key: [u8; 32],
+    /// Ids of redeemed tokens, with their expiry; dropped once expired, as the token is then
+    /// refused anyway.
+    used: Mutex<HashMap<Uuid, u64>>,
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+impl UploadTokens {
+    pub fn new() -> io::Result<Self> {
+        let mut key = [0u8; 32];
+        std::fs::File::open("/dev/urandom")?.read_exact(&mut key)?;
+        Ok(Self {
+            key,
+            used: Mutex::new(HashMap::new()),
+        })
+    }
+
+    fn sign(&self, grant: &UploadGrant, expires_at: u64) -> String {
+        let mut token = grant.id.as_bytes().to_vec();
+        token.extend_from_slice(&expires_at.to_be_bytes());
+        token.extend_from_slice(&(grant.allowed_filename.len() as u16).to_be_bytes());
+        token.extend_from_slice(grant.allowed_filename.as_bytes());
+        token.extend_from_slice(grant.subject.as_deref().unwrap_or_default().as_bytes());
+        let mac = hmac_sha256(&self.key, &token);
+        token.extend_from_slice(&mac);
+        base64url_encode(&token)
+    }
+
+    /// Checks the token's MAC and expiry and returns what it allows and when it expires.
+    fn verify(&self, token: &str, now: u64) -> Result<(UploadGrant, u64), ConversionError> {
+        let invalid = || ConversionError::new(StatusCode::FORBIDDEN, "INVALID_UPLOAD_TOKEN", "Invalid upload token");
+        let bytes = base64_decode(token).filter(|b| b.len() >= HEADER_LEN + MAC_LEN).ok_or_else(invalid)?;
+        let (signed, mac) = bytes.split_at(bytes.len() - MAC_LEN);
+        if !constant_time_eq(&hmac_sha256(&self.key, signed), mac) {
+            return Err(invalid());
+        }
+        let expires_at = u64::from_be_bytes(signed[16..24].try_into().unwrap());
+        if expires_at <= now {
+            return Err(ConversionError::new(StatusCode::GONE, "UPLOAD_TOKEN_EXPIRED", "Upload token has expired"));
+        }
+        let pattern_len = u16::from_be_bytes(signed[24..26].try_into().unwrap()) as usize;
+        let (pattern, subject) = signed[HEADER_LEN..].split_at_checked(pattern_len).ok_or_else(invalid)?;
+        let text = |bytes: &[u8]| String::from_utf8(bytes.to_vec()).map_err(|_| invalid());
+        let grant = UploadGrant {
+            id: Uuid::from_slice(&signed[..16]).unwrap(),
+            allowed_filename: text(pattern)?,
+            subject: Some(text(subject)?).filter(|s| !s.is_empty()),
+        };
+        Ok((grant, expires_at))
+    }
+
+    /// Checks `token` and uses it up.
+    pub fn redeem(&self, token: &str, now: u64) -> Result<UploadGrant, ConversionError> {
+        let (grant, expires_at) = self.verify(token, now)?;
+        let mut used = self.used.lock().unwrap();
+        used.retain(|_, &mut expiry| expiry > now);
+        if used.insert(grant.id, expires_at).is_some() {
+            return Err(ConversionError::new(StatusCode::GONE, "UPLOAD_TOKEN_USED", "Upload token was already used"));
+        }
+        Ok(grant)
+    }
+
+    /// Redeems the `token` query parameter of `query`, if there is one.
+    pub fn redeem_query(&self, query: &HashMap<String, String>) -> Option<Result<UploadGrant, ConversionError>> {
+        let token = query.get(QUERY_PARAM)?;
+        let redeemed = self.redeem(token, now());
+        if let Ok(ref grant) = redeemed {
+            info!("Upload authorized by token {} ({})", grant.id, grant.subject.as_deref().unwrap_or("no subject"));
+        }
+        Some(redeemed)
+    }
+}
+
+/// A parsed `POST /upload-token` body.
+#[derive(Debug, PartialEq)]
+struct TokenRequest {
+    ttl_secs: u64,
+    allowed_filename: String,
+    subject: Option<String>,
+}
+
+impl TokenRequest {
+    fn parse(body: &[u8]) -> Result<Self, ConversionError> {
+        let invalid = |message: String| ConversionError::new(StatusCode::BAD_REQUEST, "INVALID_JSON_BODY", message);
+        let body: Value = if body.is_empty() { json!({}) } else { serde_json::from_slice(body).unwrap_or(Value::Null) };
+        let Value::Object(body) = body else {
+            return Err(invalid("The body must be a JSON object".to_string()));
+        };
+        let ttl_secs = match body.get("ttl_secs") {
+            None | Some(Value::Null) => DEFAULT_TTL_SECS,
+            Some(ttl) => ttl
+                .as_u64()
+                .filter(|ttl| (1..=MAX_TTL_SECS).contains(ttl))
+                .ok_or_else(|| invalid(format!("ttl_secs must be a whole number from 1 to {}", MAX_TTL_SECS)))?,
+        };
+        let text = |name: &str| match body.get(name) {
+            None | Some(Value::Null) => Ok(None),
+            Some(Value::String(s)) if !s.is_empty() && s.len() <= MAX_FIELD_LEN => Ok(Some(s.clone())),
+            Some(_) => Err(invalid(format!("{} must be a string of 1 to {} bytes", name, MAX_FIELD_LEN))),
+        };
+        Ok(Self {
+            ttl_secs,
+            allowed_filename: text("allowed_filename")?.unwrap_or_else(|| "*".to_string()),
+            subject: text("subject")?,
+        })
+    }
+}
+
+/// `POST /upload-token`.
+pub async fn create(State(state): State<Arc<AppState>>, headers: HeaderMap, body: Bytes) -> Response {
+    if !apikeys::from_headers(&headers).is_some_and(|key| state.is_admin_key(key)) {
+        state.metrics.auth_failures.inc();
+        return unauthorized().into_response();
+    }
+    let request = match TokenRequest::parse(&body) {
+        Ok(request) => request,
+        Err(e) => return e.into_response(),
+    };
+    let grant = UploadGrant {
+        id: Uuid::new_v4(),
+        allowed_filename: request.allowed_filename,
+        subject: request.subject,
+    };
+    let expires_at = now() + request.ttl_secs;
+    let token = state.upload_tokens.sign(&grant, expires_at);
+    info!(
+        "Issued upload token {} ({}) for {:?}, valid {}s",
+        grant.id,
+        grant.subject.as_deref().unwrap_or("no subject"),
+        grant.allowed_filename,
+        request.ttl_secs
+    );
+    Json(json!({
+        "token": token,
+        "upload_url": format!("/convert?{}={}", QUERY_PARAM, token),
+        "expires_at": admin::rfc3339(expires_at),
+    }))
+    .into_response()
+}
+
+/// Whether `name` matches `pattern`, where `*` stands for any run of characters and `?` for one;
+/// letters match regardless of case.
+pub fn glob_matches(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.to_lowercase().chars().collect();
+    let name: Vec<char> = name.to_lowercase().chars().collect();
+    let (mut p, mut n) = (0, 0);
+    // Where the last `*` was, and how much of `name` it has taken so far
+    let mut star: Option<(usize, usize)> = None;
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, n));
+            p += 1;
+        } else if let Some((star_p, star_n)) = star {
+            // Let the `*` take one more character and try again
+            p = star_p + 1;
+            n = star_n + 1;
+            star = Some((star_p, star_n + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn grant(allowed_filename: &str, subject: Option<&str>) -> UploadGrant {
+        UploadGrant {
+            id: Uuid::new_v4(),
+            allowed_filename: allowed_filename.to_string(),
+            subject: subject.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn test_token_roundtrip() {
+        let tokens = UploadTokens::new().unwrap();
+        let issued = grant("*.docx", Some("client@example.com"));
+        let token = tokens.sign(&issued, 1_000);
+        assert_eq!(tokens.verify(&token, 999).unwrap(), (issued.clone(), 1_000));
+        assert_eq!(tokens.verify(&token, 1_000).unwrap_err().code, "UPLOAD_TOKEN_EXPIRED");
+
+        // Widening the pattern invalidates the MAC
+        let mut bytes = base64_decode(&token).unwrap();
+        bytes[HEADER_LEN] = b'?';
+        assert_eq!(tokens.verify(&base64url_encode(&bytes), 999).unwrap_err().code, "INVALID_UPLOAD_TOKEN");
+        assert_eq!(tokens.verify("short", 999).unwrap_err().code, "INVALID_UPLOAD_TOKEN");
+        // Tokens from another process (key) are refused
+        assert_eq!(UploadTokens::new().unwrap().verify(&token, 999).unwrap_err().code, "INVALID_UPLOAD_TOKEN");
+
+        assert_eq!(tokens.redeem(&token, 999).unwrap(), issued);
+        assert_eq!(tokens.redeem(&token, 999).unwrap_err().code, "UPLOAD_TOKEN_USED");
+        let anonymous = grant("*", None);
+        assert_eq!(tokens.redeem(&tokens.sign(&anonymous, 2_000), 1_500).unwrap(), anonymous);
+        // The first token has expired by now, so it is no longer remembered
+        assert_eq!(tokens.used.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_parse_request() {
+        assert_eq!(
+            TokenRequest::parse(br#"{"ttl_secs": 300, "allowed_filename": "*.docx"}"#).unwrap(),
+            TokenRequest { ttl_secs: 300, allowed_filename: "*.docx".to_string(), subject: None }
+        );
+        assert_eq!(
+            TokenRequest::parse(b"").unwrap(),
+            TokenRequest { ttl_secs: DEFAULT_TTL_SECS, allowed_filename: "*".to_string(), subject: None }
+        );
+        assert!(TokenRequest::parse(br#"{"ttl_secs": 0}"#).is_err());
+        assert!(TokenRequest::parse(br#"{"ttl_secs": 1e9}"#).is_err());
+        assert!(TokenRequest::parse(br#"{"allowed_filename": ""}"#).is_err());
+        assert!(TokenRequest::parse(br#"{"subject": 5}"#).is_err());
+        assert!(TokenRequest::parse(b"[]").is_err());
+    }
+
+    #[test]
+    fn test_glob_matches() {
+        assert!(glob_matches("*.docx", "Report.DOCX"));
+        assert!(glob_matches("*", "anything"));
+        assert!(glob_matches("q?-*.xlsx", "q3-sales.final.xlsx"));
+        assert!(glob_matches("a*b*c", "aXbYbZc"));
+        assert!(!glob_matches("*.docx", "report.docx.exe"));
+        assert!(!glob_matches("q?.xlsx", "q10.xlsx"));
+        assert!(grant("*.pdf", None).check_filename("a.docx").is_err());
+    }
+}
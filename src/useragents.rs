@@ -0,0 +1,100 @@
+//! `BLOCKED_USER_AGENTS`: turning away vulnerability scanners and similar tools by their
+//! `User-Agent` before a request reaches any handler. Off unless configured; a client can send
+//! whatever `User-Agent` it likes, so this only keeps out tools that announce themselves.
+
+use axum::{
+    extract::{Request, State},
+    http::{header, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::sync::Arc;
+use tracing::warn;
+
+use crate::error::ConversionError;
+use crate::uploadtokens::glob_matches;
+
+#[derive(Debug, Default)]
+pub struct BlockedUserAgents {
+    /// Lowercased. Entries with `*` or `?` are globs matched against the whole header; the others
+    /// match anywhere in it.
+    patterns: Vec<String>,
+}
+
+impl BlockedUserAgents {
+    /// Parses the comma-separated `BLOCKED_USER_AGENTS`.
+    pub fn parse(raw: Option<&str>) -> Self {
+        let patterns = raw
+            .unwrap_or_default()
+            .split(',')
+            .map(|p| p.trim().to_lowercase())
+            .filter(|p| !p.is_empty())
+            .collect();
+        Self { patterns }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.patterns.is_empty()
+    }
+
+    /// The entry `user_agent` matches, if any.
+    fn matching(&self, user_agent: &str) -> Option<&str> {
+        let user_agent = user_agent.to_lowercase();
+        self.patterns
+            .iter()
+            .find(|p| if p.contains(['*', '?']) { glob_matches(p, &user_agent) } else { user_agent.contains(p.as_str()) })
+            .map(String::as_str)
+    }
+}
+
+pub async fn block(State(blocked): State<Arc<BlockedUserAgents>>, req: Request, next: Next) -> Response {
+    let user_agent = req.headers().get(header::USER_AGENT).map(|v| String::from_utf8_lossy(v.as_bytes()).into_owned());
+    if let Some(ref user_agent) = user_agent
+        && let Some(pattern) = blocked.matching(user_agent)
+    {
+        warn!("Blocked user agent {:?} (matches {:?})", user_agent, pattern);
+        return ConversionError::new(StatusCode::FORBIDDEN, "USER_AGENT_BLOCKED", "Requests from this client are not accepted").into_response();
+    }
+    next.run(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_matching() {
+        let blocked = BlockedUserAgents::parse(Some(" Nuclei , sqlmap/*,, nikto"));
+        // Exact, substring and case-insensitive matches
+        assert_eq!(blocked.matching("nuclei"), Some("nuclei"));
+        assert_eq!(blocked.matching("Mozilla/5.0 (compatible; Nuclei - Open-source project)"), Some("nuclei"));
+        assert_eq!(blocked.matching("Mozilla/5.00 (NIKTO/2.1.6)"), Some("nikto"));
+        // Globs match the whole header
+        assert_eq!(blocked.matching("SQLMap/1.7.2#stable (https://sqlmap.org)"), Some("sqlmap/*"));
+        assert_eq!(blocked.matching("python sqlmap/1.7"), None);
+        assert_eq!(blocked.matching("curl/8.5.0"), None);
+
+        assert!(BlockedUserAgents::parse(None).is_empty());
+        assert!(BlockedUserAgents::parse(Some(" , ")).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_block() {
+        use axum::{body::Body, middleware, routing::get, Router};
+        use tower::ServiceExt;
+
+        let blocked = Arc::new(BlockedUserAgents::parse(Some("nuclei")));
+        let app = Router::new().route("/", get(|| async {})).layer(middleware::from_fn_with_state(blocked, block));
+        let request = |user_agent: Option<&str>| {
+            let builder = Request::builder().uri("/");
+            let builder = match user_agent {
+                Some(user_agent) => builder.header(header::USER_AGENT, user_agent),
+                None => builder,
+            };
+            builder.body(Body::empty()).unwrap()
+        };
+        assert_eq!(app.clone().oneshot(request(Some("Nuclei v3"))).await.unwrap().status(), StatusCode::FORBIDDEN);
+        assert_eq!(app.clone().oneshot(request(Some("curl/8.5.0"))).await.unwrap().status(), StatusCode::OK);
+        assert_eq!(app.oneshot(request(None)).await.unwrap().status(), StatusCode::OK);
+    }
+}
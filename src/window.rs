@@ -0,0 +1,96 @@
+//! The output side of the decompressors: the recent output that back-references copy from, with
+//! what has fallen out of their reach handed on in chunks, so a stream is decompressed holding
+//! little more than its window however large its output.
+
+/// Why a stream could not be decompressed.
+#[derive(Debug, PartialEq)]
+pub enum DecodeError {
+    /// The stream is truncated, not valid, or uses a feature not supported here.
+    Corrupt,
+    /// The output would exceed the caller's limit.
+    TooLarge,
+    /// The output stopped being wanted.
+    Closed,
+}
+
+/// Where decompressed output goes, in order.
+pub type Sink<'a> = &'a mut dyn FnMut(&[u8]) -> Result<(), DecodeError>;
+
+/// Output gathered past the window before it is handed on, at least.
+const CHUNK: usize = 64 * 1024;
+
+pub struct Output<'a> {
+    buf: Vec<u8>,
+    /// How far back copies may reach.
+    window: usize,
+    /// Bytes produced so far, including those handed on.
+    produced: usize,
+    max_len: usize,
+    sink: Sink<'a>,
+}
+
+impl<'a> Output<'a> {
+    pub fn new(window: usize, max_len: usize, sink: Sink<'a>) -> Self {
+        Output { buf: Vec::new(), window, produced: 0, max_len, sink }
+    }
+
+    pub fn produced(&self) -> usize {
+        self.produced
+    }
+
+    fn grow(&mut self, n: usize) -> Result<(), DecodeError> {
+        if n > self.max_len - self.produced {
+            return Err(DecodeError::TooLarge);
+        }
+        self.produced += n;
+        Ok(())
+    }
+
+    /// Hands on what copies can no longer reach, once that is worth it. Waiting until it is at
+    /// least a window's worth keeps the cost of moving the window down to once per byte.
+    fn spill(&mut self) -> Result<(), DecodeError> {
+        if self.buf.len() - self.window.min(self.buf.len()) >= CHUNK.max(self.window) {
+            let n = self.buf.len() - self.window;
+            (self.sink)(&self.buf[..n])?;
+            self.buf.drain(..n);
+        }
+        Ok(())
+    }
+
+    pub fn push(&mut self, byte: u8) -> Result<(), DecodeError> {
+        self.grow(1)?;
+        self.buf.push(byte);
+        self.spill()
+    }
+
+    pub fn extend(&mut self, data: &[u8]) -> Result<(), DecodeError> {
+        self.grow(data.len())?;
+        self.buf.extend_from_slice(data);
+        self.spill()
+    }
+
+    /// Appends `len` bytes copied from `dist` bytes back, which may overlap what they produce.
+    pub fn copy(&mut self, dist: usize, len: usize) -> Result<(), DecodeError> {
+        if dist == 0 || dist > self.buf.len() || dist > self.window {
+            return Err(DecodeError::Corrupt);
+        }
+        self.grow(len)?;
+        let start = self.buf.len() - dist;
+        if dist >= len {
+            self.buf.extend_from_within(start..start + len);
+        } else {
+            for i in 0..len {
+                self.buf.push(self.buf[start + i]);
+            }
+        }
+        self.spill()
+    }
+
+    /// Hands on the rest of the output.
+    pub fn finish(self) -> Result<(), DecodeError> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+        (self.sink)(&self.buf)
+    }
+}
@@ -0,0 +1,291 @@
+//! Minimal in-memory ZIP archive writer, plus enough of a reader to list an archive's entries
+//! and extract small ones.
+//!
+//! Entries are written with the "stored" method (no compression): the archives we build hold
+//! PDFs and PNGs, which are already compressed, so deflating them would only cost CPU.
+
+use crate::inflate;
+
+const LOCAL_HEADER_SIG: u32 = 0x0403_4b50;
+const CENTRAL_HEADER_SIG: u32 = 0x0201_4b50;
+const END_OF_CENTRAL_DIR_SIG: u32 = 0x0605_4b50;
+/// 1980-01-01 00:00, the earliest timestamp representable in a ZIP header.
+const DOS_DATE: u16 = (1 << 5) | 1;
+const DOS_TIME: u16 = 0;
+/// Bit 0: the entry is encrypted.
+const FLAG_ENCRYPTED: u16 = 1;
+/// Bit 11: file names are UTF-8.
+const FLAG_UTF8: u16 = 1 << 11;
+
+struct CentralEntry {
+    name: String,
+    crc: u32,
+    size: u32,
+    offset: u32,
+}
+
+pub struct ZipWriter {
+    buf: Vec<u8>,
+    entries: Vec<CentralEntry>,
+}
+
+impl ZipWriter {
+    pub fn new() -> Self {
+        Self {
+            buf: Vec::new(),
+            entries: Vec::new(),
+        }
+    }
+
+    pub fn add(&mut self, name: &str, data: &[u8]) {
+        let crc = crc32(data);
+        let size = data.len() as u32;
+        let offset = self.buf.len() as u32;
+
+        put_u32(&mut self.buf, LOCAL_HEADER_SIG);
+        put_u16(&mut self.buf, 20); // version needed to extract
+        put_u16(&mut self.buf, FLAG_UTF8);
+        put_u16(&mut self.buf, 0); // method: stored
+        put_u16(&mut self.buf, DOS_TIME);
+        put_u16(&mut self.buf, DOS_DATE);
+        put_u32(&mut self.buf, crc);
+        put_u32(&mut self.buf, size); // compressed size
+        put_u32(&mut self.buf, size); // uncompressed size
+        put_u16(&mut self.buf, name.len() as u16);
+        put_u16(&mut self.buf, 0); // extra field length
+        self.buf.extend_from_slice(name.as_bytes());
+        self.buf.extend_from_slice(data);
+
+        self.entries.push(CentralEntry {
+            name: name.to_string(),
+            crc,
+            size,
+            offset,
+        });
+    }
+
+    pub fn finish(mut self) -> Vec<u8> {
+        let central_start = self.buf.len() as u32;
+        for entry in &self.entries {
+            put_u32(&mut self.buf, CENTRAL_HEADER_SIG);
+            put_u16(&mut self.buf, 20); // version made by
+            put_u16(&mut self.buf, 20); // version needed to extract
+            put_u16(&mut self.buf, FLAG_UTF8);
+            put_u16(&mut self.buf, 0); // method: stored
+            put_u16(&mut self.buf, DOS_TIME);
+            put_u16(&mut self.buf, DOS_DATE);
+            put_u32(&mut self.buf, entry.crc);
+            put_u32(&mut self.buf, entry.size);
+            put_u32(&mut self.buf, entry.size);
+            put_u16(&mut self.buf, entry.name.len() as u16);
+            put_u16(&mut self.buf, 0); // extra field length
+            put_u16(&mut self.buf, 0); // comment length
+            put_u16(&mut self.buf, 0); // disk number
+            put_u16(&mut self.buf, 0); // internal attributes
+            put_u32(&mut self.buf, 0); // external attributes
+            put_u32(&mut self.buf, entry.offset);
+            self.buf.extend_from_slice(entry.name.as_bytes());
+        }
+        let central_size = self.buf.len() as u32 - central_start;
+
+        put_u32(&mut self.buf, END_OF_CENTRAL_DIR_SIG);
+        put_u16(&mut self.buf, 0); // this disk
+        put_u16(&mut self.buf, 0); // disk with central directory
+        put_u16(&mut self.buf, self.entries.len() as u16);
+        put_u16(&mut self.buf, self.entries.len() as u16);
+        put_u32(&mut self.buf, central_size);
+        put_u32(&mut self.buf, central_start);
+        put_u16(&mut self.buf, 0); // comment length
+        self.buf
+    }
+}
+
+/// Size of the end of central directory record without its trailing comment.
+pub const END_OF_CENTRAL_DIR_LEN: usize = 22;
+/// The record may be followed by a comment of up to 64 KiB.
+pub const MAX_END_OF_CENTRAL_DIR_SEARCH: usize = END_OF_CENTRAL_DIR_LEN + u16::MAX as usize;
+
+/// Finds the end of central directory record in the last bytes of an archive and returns the
+/// central directory's (offset, size).
+pub fn find_central_directory(tail: &[u8]) -> Option<(u64, u64)> {
+    let sig = END_OF_CENTRAL_DIR_SIG.to_le_bytes();
+    let start = (0..=tail.len().checked_sub(END_OF_CENTRAL_DIR_LEN)?).rev().find(|&i| tail[i..i + 4] == sig)?;
+    let eocd = &tail[start..];
+    Some((get_u32(eocd, 16)? as u64, get_u32(eocd, 12)? as u64))
+}
+
+/// An entry as described by the central directory.
+struct DirectoryEntry {
+    name: String,
+    flags: u16,
+    method: u16,
+    crc: u32,
+    compressed_size: u32,
+    offset: u32,
+}
+
+fn central_directory_entries(central: &[u8]) -> Vec<DirectoryEntry> {
+    let mut entries = Vec::new();
+    let mut pos = 0;
+    while get_u32(central, pos) == Some(CENTRAL_HEADER_SIG) {
+        let (Some(name_len), Some(extra_len), Some(comment_len)) =
+            (get_u16(central, pos + 28), get_u16(central, pos + 30), get_u16(central, pos + 32))
+        else {
+            break;
+        };
+        let (Some(flags), Some(method), Some(crc), Some(compressed_size), Some(offset), Some(name)) = (
+            get_u16(central, pos + 8),
+            get_u16(central, pos + 10),
+            get_u32(central, pos + 16),
+            get_u32(central, pos + 20),
+            get_u32(central, pos + 42),
+            central.get(pos + 46..pos + 46 + name_len as usize),
+        ) else {
+            break;
+        };
+        entries.push(DirectoryEntry {
+            name: String::from_utf8_lossy(name).to_string(),
+            flags,
+            method,
+            crc,
+            compressed_size,
+            offset,
+        });
+        pos += 46 + name_len as usize + extra_len as usize + comment_len as usize;
+    }
+    entries
+}
+
+/// Lists the entry names in a central directory.
+pub fn central_directory_names(central: &[u8]) -> Vec<String> {
+    central_directory_entries(central).into_iter().map(|e| e.name).collect()
+}
+
+/// Reads entries out of an archive held in memory. Only the stored and deflate methods are
+/// supported, which is all office documents use.
+pub struct ZipReader<'a> {
+    archive: &'a [u8],
+    entries: Vec<DirectoryEntry>,
+}
+
+impl<'a> ZipReader<'a> {
+    /// Returns `None` if `archive` has no readable central directory.
+    pub fn new(archive: &'a [u8]) -> Option<Self> {
+        let tail = &archive[archive.len().saturating_sub(MAX_END_OF_CENTRAL_DIR_SEARCH)..];
+        let (offset, size) = find_central_directory(tail)?;
+        let central = archive.get(offset as usize..offset.checked_add(size)? as usize)?;
+        Some(Self {
+            archive,
+            entries: central_directory_entries(central),
+        })
+    }
+
+    /// The entry names, in the order of the central directory.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.entries.iter().map(|e| e.name.as_str())
+    }
+
+    /// Whether any entry is encrypted, which `read` cannot undo.
+    pub fn has_encrypted_entries(&self) -> bool {
+        self.entries.iter().any(|e| e.flags & FLAG_ENCRYPTED != 0)
+    }
+
+    /// The content of entry `name`, or `None` if it is missing, encrypted, corrupt, or larger than
+    /// `max_len`.
+    pub fn read(&self, name: &str, max_len: usize) -> Option<Vec<u8>> {
+        let entry = self.entries.iter().find(|e| e.name == name && e.flags & FLAG_ENCRYPTED == 0)?;
+        let header = self.archive.get(entry.offset as usize..)?;
+        if get_u32(header, 0) != Some(LOCAL_HEADER_SIG) {
+            return None;
+        }
+        let start = 30 + get_u16(header, 26)? as usize + get_u16(header, 28)? as usize;
+        let data = header.get(start..start.checked_add(entry.compressed_size as usize)?)?;
+        let content = match entry.method {
+            0 if data.len() <= max_len => data.to_vec(),
+            8 => inflate::inflate(data, max_len).ok()?,
+            _ => return None,
+        };
+        (crc32(&content) == entry.crc).then_some(content)
+    }
+}
+
+fn get_u16(buf: &[u8], at: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(buf.get(at..at + 2)?.try_into().ok()?))
+}
+
+fn get_u32(buf: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(buf.get(at..at + 4)?.try_into().ok()?))
+}
+
+fn put_u16(buf: &mut Vec<u8>, v: u16) {
+    buf.extend_from_slice(&v.to_le_bytes());
+}
+
+fn put_u32(buf: &mut Vec<u8>, v: u32) {
+    buf.extend_from_slice(&v.to_le_bytes());
+}
+
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut c = i as u32;
+        let mut k = 0;
+        while k < 8 {
+            c = if c & 1 != 0 { 0xEDB8_8320 ^ (c >> 1) } else { c >> 1 };
+            k += 1;
+        }
+        table[i] = c;
+        i += 1;
+    }
+    table
+};
+
+pub fn crc32(data: &[u8]) -> u32 {
+    crc32_update(0, data)
+}
+
+/// Extends `crc`, the CRC-32 of what came before `data`, over `data`.
+pub fn crc32_update(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = crc ^ 0xFFFF_FFFF;
+    for &b in data {
+        crc = CRC32_TABLE[((crc ^ b as u32) & 0xFF) as usize] ^ (crc >> 8);
+    }
+    crc ^ 0xFFFF_FFFF
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn test_archive_layout() {
+        let mut zip = ZipWriter::new();
+        zip.add("a.txt", b"hello");
+        zip.add("b.txt", b"world!");
+        let bytes = zip.finish();
+
+        assert_eq!(&bytes[..4], &LOCAL_HEADER_SIG.to_le_bytes());
+        // End of central directory record is the last 22 bytes
+        let eocd = &bytes[bytes.len() - 22..];
+        assert_eq!(&eocd[..4], &END_OF_CENTRAL_DIR_SIG.to_le_bytes());
+        assert_eq!(u16::from_le_bytes([eocd[10], eocd[11]]), 2);
+        let central_start = u32::from_le_bytes([eocd[16], eocd[17], eocd[18], eocd[19]]) as usize;
+        assert_eq!(&bytes[central_start..central_start + 4], &CENTRAL_HEADER_SIG.to_le_bytes());
+
+        let (offset, size) = find_central_directory(&bytes).unwrap();
+        let central = &bytes[offset as usize..(offset + size) as usize];
+        assert_eq!(central_directory_names(central), ["a.txt", "b.txt"]);
+
+        let reader = ZipReader::new(&bytes).unwrap();
+        assert_eq!(reader.read("b.txt", 1024).as_deref(), Some(&b"world!"[..]));
+        assert_eq!(reader.read("b.txt", 5), None);
+        assert_eq!(reader.read("c.txt", 1024), None);
+    }
+}
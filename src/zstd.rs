@@ -0,0 +1,832 @@
+//! Zstandard (RFC 8878) decompression, for request bodies sent with `Content-Encoding: zstd`.
+//!
+//! Frames are read block by block, and each block's output goes through an [`Output`] window of
+//! the frame's window size, so little more than that is held at once. Windows are limited to
+//! 8 MiB, the most a sender of `Content-Encoding: zstd` may use (RFC 9659), and dictionaries are
+//! not supported, as HTTP has no way to agree on one.
+
+use std::io::{ErrorKind, Read};
+
+use crate::window::{DecodeError, Output, Sink};
+
+const MAGIC: u32 = 0xFD2F_B528;
+/// Skippable frames, ignored, start with any of 16 magic numbers from this one.
+const SKIPPABLE_MAGIC: u32 = 0x184D_2A50;
+const MAX_WINDOW: u64 = 8 * 1024 * 1024;
+const MAX_BLOCK: usize = 128 * 1024;
+const MAX_HUFFMAN_BITS: u32 = 11;
+
+/// The codes of one of the three sequence fields: their baselines and extra bits, and the
+/// predefined distribution (RFC 8878, section 3.1.1.3.2.2).
+struct Field {
+    max_symbol: usize,
+    max_log: u32,
+    predefined: &'static [i32],
+    predefined_log: u32,
+    baselines: &'static [u32],
+    extra_bits: &'static [u32],
+}
+
+const LITERAL_LENGTHS: Field = Field {
+    max_symbol: 35,
+    max_log: 9,
+    predefined: &[4, 3, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 1, 1, 1, 2, 2, 2, 2, 2, 2, 2, 2, 2, 3, 2, 1, 1, 1, 1, 1, -1, -1, -1, -1],
+    predefined_log: 6,
+    baselines: &[
+        0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 18, 20, 22, 24, 28, 32, 40, 48, 64, 128, 256, 512, 1024, 2048,
+        4096, 8192, 16384, 32768, 65536,
+    ],
+    extra_bits: &[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 3, 3, 4, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16],
+};
+
+const MATCH_LENGTHS: Field = Field {
+    max_symbol: 52,
+    max_log: 9,
+    predefined: &[
+        1, 4, 3, 2, 2, 2, 2, 2, 2, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+        1, 1, 1, 1, -1, -1, -1, -1, -1, -1, -1,
+    ],
+    predefined_log: 6,
+    baselines: &[
+        3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31, 32, 33, 34,
+        35, 37, 39, 41, 43, 47, 51, 59, 67, 83, 99, 131, 259, 515, 1027, 2051, 4099, 8195, 16387, 32771, 65539,
+    ],
+    extra_bits: &[
+        0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 3, 3, 4,
+        4, 5, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16,
+    ],
+};
+
+/// Offset codes are the number of extra bits, with a baseline of one shifted left by as many.
+const OFFSETS: Field = Field {
+    max_symbol: 31,
+    max_log: 8,
+    predefined: &[1, 1, 1, 1, 1, 1, 2, 2, 2, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, -1, -1, -1, -1, -1],
+    predefined_log: 5,
+    baselines: &[],
+    extra_bits: &[],
+};
+
+/// Decompresses the frames read from `input`, handing the output to `sink` as it goes. What was
+/// handed on before a failure is to be thrown away.
+pub fn decode_from(mut input: impl Read, max_len: usize, sink: Sink) -> Result<(), DecodeError> {
+    let mut produced = 0;
+    let mut frames = 0;
+    loop {
+        let mut magic = [0; 4];
+        let read = read_up_to(&mut input, &mut magic)?;
+        if read == 0 && frames > 0 {
+            return Ok(());
+        }
+        if read < magic.len() {
+            return Err(DecodeError::Corrupt);
+        }
+        match u32::from_le_bytes(magic) {
+            MAGIC => produced += frame(&mut input, max_len - produced, sink)?,
+            magic if magic & !0xF == SKIPPABLE_MAGIC => {
+                let size = read_le(&mut input, 4)?;
+                let skipped = std::io::copy(&mut (&mut input).take(size), &mut std::io::sink()).map_err(|_| DecodeError::Corrupt)?;
+                if skipped != size {
+                    return Err(DecodeError::Corrupt);
+                }
+            }
+            _ => return Err(DecodeError::Corrupt),
+        }
+        frames += 1;
+    }
+}
+
+/// Reads until `buf` is full or the input ends, returning how much was read.
+fn read_up_to(input: &mut impl Read, buf: &mut [u8]) -> Result<usize, DecodeError> {
+    let mut read = 0;
+    while read < buf.len() {
+        match input.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(_) => return Err(DecodeError::Corrupt),
+        }
+    }
+    Ok(read)
+}
+
+fn read_exact(input: &mut impl Read, buf: &mut [u8]) -> Result<(), DecodeError> {
+    if read_up_to(input, buf)? < buf.len() {
+        return Err(DecodeError::Corrupt);
+    }
+    Ok(())
+}
+
+/// Reads a little-endian number of `len` bytes, at most eight.
+fn read_le(input: &mut impl Read, len: usize) -> Result<u64, DecodeError> {
+    let mut bytes = [0; 8];
+    read_exact(input, &mut bytes[..len])?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+/// Everything a block may reuse from those before it in the frame.
+struct FrameState {
+    /// The three most recent offsets.
+    offsets: [usize; 3],
+    huffman: Option<HuffmanTable>,
+    literal_lengths: Option<Fse>,
+    offset_codes: Option<Fse>,
+    match_lengths: Option<Fse>,
+}
+
+/// Decompresses a frame after its magic number, returning the size of its content.
+fn frame(input: &mut impl Read, max_len: usize, sink: Sink) -> Result<usize, DecodeError> {
+    let descriptor = read_le(input, 1)? as u8;
+    let single_segment = descriptor & 0x20 != 0;
+    let has_checksum = descriptor & 0x04 != 0;
+    if descriptor & 0x08 != 0 {
+        // Reserved
+        return Err(DecodeError::Corrupt);
+    }
+    let window = match single_segment {
+        true => None,
+        false => {
+            let byte = read_le(input, 1)?;
+            let base = 1u64 << (10 + (byte >> 3));
+            Some(base + base / 8 * (byte & 7))
+        }
+    };
+    if read_le(input, [0, 1, 2, 4][(descriptor & 3) as usize])? != 0 {
+        // A dictionary
+        return Err(DecodeError::Corrupt);
+    }
+    let content_size = match (descriptor >> 6, single_segment) {
+        (0, false) => None,
+        (0, true) => Some(read_le(input, 1)?),
+        (1, _) => Some(read_le(input, 2)? + 256),
+        (2, _) => Some(read_le(input, 4)?),
+        _ => Some(read_le(input, 8)?),
+    };
+    let window = window.or(content_size).ok_or(DecodeError::Corrupt)?;
+    if window > MAX_WINDOW {
+        return Err(DecodeError::Corrupt);
+    }
+
+    let mut hash = Xxh64::new();
+    let mut hashed = |chunk: &[u8]| {
+        hash.update(chunk);
+        sink(chunk)
+    };
+    let mut out = Output::new(window as usize, max_len, &mut hashed);
+    let mut state = FrameState { offsets: [1, 4, 8], huffman: None, literal_lengths: None, offset_codes: None, match_lengths: None };
+    let mut block = Vec::new();
+    loop {
+        let header = read_le(input, 3)?;
+        let size = (header >> 3) as usize;
+        if size > MAX_BLOCK {
+            return Err(DecodeError::Corrupt);
+        }
+        match (header >> 1) & 3 {
+            0 => {
+                block.resize(size, 0);
+                read_exact(input, &mut block)?;
+                out.extend(&block)?;
+            }
+            1 => {
+                let byte = read_le(input, 1)? as u8;
+                out.extend(&vec![byte; size])?;
+            }
+            2 => {
+                block.resize(size, 0);
+                read_exact(input, &mut block)?;
+                compressed_block(&block, &mut state, &mut out)?;
+            }
+            _ => return Err(DecodeError::Corrupt),
+        }
+        if header & 1 != 0 {
+            break;
+        }
+    }
+    let produced = out.produced();
+    out.finish()?;
+    if content_size.is_some_and(|size| size != produced as u64) {
+        return Err(DecodeError::Corrupt);
+    }
+    if has_checksum && read_le(input, 4)? != hash.digest() & 0xFFFF_FFFF {
+        return Err(DecodeError::Corrupt);
+    }
+    Ok(produced)
+}
+
+fn compressed_block(block: &[u8], state: &mut FrameState, out: &mut Output) -> Result<(), DecodeError> {
+    let (literals, used) = literals_section(block, &mut state.huffman)?;
+    sequences_section(&block[used..], state, &literals, out)
+}
+
+/// Decodes the literals section at the start of `block`, returning the literals and its size.
+fn literals_section(block: &[u8], huffman: &mut Option<HuffmanTable>) -> Result<(Vec<u8>, usize), DecodeError> {
+    let byte = |i: usize| block.get(i).map(|&b| b as usize).ok_or(DecodeError::Corrupt);
+    let first = byte(0)?;
+    let (kind, size_format) = (first & 3, (first >> 2) & 3);
+    if kind < 2 {
+        let (size, header) = match size_format {
+            0 | 2 => (first >> 3, 1),
+            1 => (first >> 4 | byte(1)? << 4, 2),
+            _ => (first >> 4 | byte(1)? << 4 | byte(2)? << 12, 3),
+        };
+        if size > MAX_BLOCK {
+            return Err(DecodeError::Corrupt);
+        }
+        return match kind {
+            0 => Ok((block.get(header..header + size).ok_or(DecodeError::Corrupt)?.to_vec(), header + size)),
+            _ => Ok((vec![byte(header)? as u8; size], header + 1)),
+        };
+    }
+
+    let (streams, header, bits) = match size_format {
+        0 => (1, 3, 10),
+        1 => (4, 3, 10),
+        2 => (4, 4, 14),
+        _ => (4, 5, 18),
+    };
+    let mut value = 0;
+    for i in (0..header).rev() {
+        value = value << 8 | byte(i)?;
+    }
+    let regenerated = value >> 4 & ((1 << bits) - 1);
+    let compressed = value >> (4 + bits) & ((1 << bits) - 1);
+    if regenerated > MAX_BLOCK {
+        return Err(DecodeError::Corrupt);
+    }
+    let mut data = block.get(header..header + compressed).ok_or(DecodeError::Corrupt)?;
+    if kind == 2 {
+        let (table, used) = HuffmanTable::read(data)?;
+        *huffman = Some(table);
+        data = &data[used..];
+    }
+    // Treeless literals reuse the previous block's table
+    let table = huffman.as_ref().ok_or(DecodeError::Corrupt)?;
+    let mut literals = Vec::with_capacity(regenerated);
+    if streams == 1 {
+        table.decode(data, regenerated, &mut literals)?;
+    } else {
+        let jump = |i: usize| data.get(i..i + 2).map(|b| u16::from_le_bytes([b[0], b[1]]) as usize).ok_or(DecodeError::Corrupt);
+        let sizes = [jump(0)?, jump(2)?, jump(4)?];
+        let last = data.len().checked_sub(6 + sizes.iter().sum::<usize>()).ok_or(DecodeError::Corrupt)?;
+        let per_stream = regenerated.div_ceil(4);
+        let last_count = regenerated.checked_sub(3 * per_stream).ok_or(DecodeError::Corrupt)?;
+        let mut start = 6;
+        for (size, count) in [(sizes[0], per_stream), (sizes[1], per_stream), (sizes[2], per_stream), (last, last_count)] {
+            table.decode(&data[start..start + size], count, &mut literals)?;
+            start += size;
+        }
+    }
+    Ok((literals, header + compressed))
+}
+
+/// Decodes the sequences section `data` and executes it, with `literals` from the block's
+/// literals section.
+fn sequences_section(data: &[u8], state: &mut FrameState, literals: &[u8], out: &mut Output) -> Result<(), DecodeError> {
+    let byte = |i: usize| data.get(i).map(|&b| b as usize).ok_or(DecodeError::Corrupt);
+    let (count, mut used) = match byte(0)? {
+        0 => return out.extend(literals),
+        first @ 1..=127 => (first, 1),
+        first @ 128..=254 => ((first - 128) << 8 | byte(1)?, 2),
+        _ => (byte(1)? | byte(2)? << 8 | 0x7F00, 3),
+    };
+    let modes = byte(used)?;
+    used += 1;
+    if modes & 3 != 0 {
+        return Err(DecodeError::Corrupt);
+    }
+    for (slot, field, mode) in [
+        (&mut state.literal_lengths, &LITERAL_LENGTHS, modes >> 6),
+        (&mut state.offset_codes, &OFFSETS, modes >> 4 & 3),
+        (&mut state.match_lengths, &MATCH_LENGTHS, modes >> 2 & 3),
+    ] {
+        match mode {
+            0 => *slot = Some(Fse::new(field.predefined, field.predefined_log)?),
+            1 => {
+                let symbol = byte(used)?;
+                if symbol > field.max_symbol {
+                    return Err(DecodeError::Corrupt);
+                }
+                *slot = Some(Fse { log: 0, entries: vec![FseEntry { symbol: symbol as u8, bits: 0, base: 0 }] });
+                used += 1;
+            }
+            2 => {
+                let (table, size) = Fse::read(&data[used..], field.max_log, field.max_symbol)?;
+                *slot = Some(table);
+                used += size;
+            }
+            _ if slot.is_none() => return Err(DecodeError::Corrupt),
+            _ => {}
+        }
+    }
+
+    let (Some(literal_lengths), Some(offset_codes), Some(match_lengths)) = (&state.literal_lengths, &state.offset_codes, &state.match_lengths) else {
+        unreachable!("every table was set or checked above");
+    };
+    let mut bits = BackBits::new(&data[used..])?;
+    let mut literal_length_state = bits.read(literal_lengths.log) as usize;
+    let mut offset_state = bits.read(offset_codes.log) as usize;
+    let mut match_length_state = bits.read(match_lengths.log) as usize;
+    let mut next_literal = 0;
+    for i in 0..count {
+        let literal_code = literal_lengths.entries[literal_length_state].symbol as usize;
+        let offset_code = offset_codes.entries[offset_state].symbol as u32;
+        let match_code = match_lengths.entries[match_length_state].symbol as usize;
+        let offset_value = (1 << offset_code) + bits.read(offset_code) as usize;
+        let match_length = (MATCH_LENGTHS.baselines[match_code] as u64 + bits.read(MATCH_LENGTHS.extra_bits[match_code])) as usize;
+        let literal_length = (LITERAL_LENGTHS.baselines[literal_code] as u64 + bits.read(LITERAL_LENGTHS.extra_bits[literal_code])) as usize;
+
+        // Offset values of 1 to 3 pick a recent offset, shifted by one after no literals
+        let offsets = &mut state.offsets;
+        let offset = match offset_value.checked_sub(3) {
+            Some(offset @ 1..) => {
+                *offsets = [offset, offsets[0], offsets[1]];
+                offset
+            }
+            _ => match offset_value - 1 + (literal_length == 0) as usize {
+                0 => offsets[0],
+                1 => {
+                    *offsets = [offsets[1], offsets[0], offsets[2]];
+                    offsets[0]
+                }
+                2 => {
+                    *offsets = [offsets[2], offsets[0], offsets[1]];
+                    offsets[0]
+                }
+                _ => {
+                    let offset = offsets[0].checked_sub(1).ok_or(DecodeError::Corrupt)?;
+                    *offsets = [offset, offsets[0], offsets[1]];
+                    offset
+                }
+            },
+        };
+
+        if i + 1 < count {
+            literal_length_state = literal_lengths.next(literal_length_state, &mut bits);
+            match_length_state = match_lengths.next(match_length_state, &mut bits);
+            offset_state = offset_codes.next(offset_state, &mut bits);
+        }
+        if bits.overflowed() {
+            return Err(DecodeError::Corrupt);
+        }
+        out.extend(literals.get(next_literal..next_literal + literal_length).ok_or(DecodeError::Corrupt)?)?;
+        next_literal += literal_length;
+        out.copy(offset, match_length)?;
+    }
+    if !bits.finished() {
+        return Err(DecodeError::Corrupt);
+    }
+    out.extend(&literals[next_literal..])
+}
+
+/// A bitstream read backwards from its end, where a 1 bit marks where it starts, each read taking
+/// the next bits down as a number (RFC 8878, section 4.1).
+struct BackBits<'a> {
+    data: &'a [u8],
+    /// Bits left to read; below zero after reading past the start, which reads zeros.
+    pos: isize,
+}
+
+impl<'a> BackBits<'a> {
+    fn new(data: &'a [u8]) -> Result<Self, DecodeError> {
+        let last = *data.last().ok_or(DecodeError::Corrupt)?;
+        if last == 0 {
+            return Err(DecodeError::Corrupt);
+        }
+        Ok(BackBits { data, pos: (data.len() * 8) as isize - last.leading_zeros() as isize - 1 })
+    }
+
+    /// The next `n` bits, at most 56, without reading them.
+    fn peek(&self, n: u32) -> u64 {
+        if n == 0 || self.pos <= 0 {
+            return 0;
+        }
+        let start = self.pos - n as isize;
+        let load = |at: usize| {
+            let mut bytes = [0; 8];
+            let available = self.data.len().saturating_sub(at).min(8);
+            bytes[..available].copy_from_slice(&self.data[at..at + available]);
+            u64::from_le_bytes(bytes)
+        };
+        let word = match start {
+            0.. => load(start as usize / 8) >> (start % 8),
+            _ => load(0) << -start,
+        };
+        word & ((1 << n) - 1)
+    }
+
+    fn read(&mut self, n: u32) -> u64 {
+        let value = self.peek(n);
+        self.pos -= n as isize;
+        value
+    }
+
+    fn overflowed(&self) -> bool {
+        self.pos < 0
+    }
+
+    fn finished(&self) -> bool {
+        self.pos == 0
+    }
+}
+
+/// The Huffman code of a block's literals, as a table indexed by the next `max_bits` bits.
+struct HuffmanTable {
+    max_bits: u32,
+    /// Symbol and code length.
+    entries: Vec<(u8, u8)>,
+}
+
+impl HuffmanTable {
+    /// Reads a Huffman tree description from the start of `data`, returning it with its size.
+    fn read(data: &[u8]) -> Result<(Self, usize), DecodeError> {
+        let header = *data.first().ok_or(DecodeError::Corrupt)? as usize;
+        let (weights, used) = if header < 128 {
+            // Weights compressed with FSE, decoded with two interleaved states
+            let data = data.get(1..1 + header).ok_or(DecodeError::Corrupt)?;
+            let (table, size) = Fse::read(data, 6, 255)?;
+            let mut bits = BackBits::new(&data[size..])?;
+            let mut states = [bits.read(table.log) as usize, bits.read(table.log) as usize];
+            let mut weights = Vec::new();
+            'decode: loop {
+                for i in 0..2 {
+                    if weights.len() >= 255 {
+                        return Err(DecodeError::Corrupt);
+                    }
+                    weights.push(table.entries[states[i]].symbol);
+                    states[i] = table.next(states[i], &mut bits);
+                    if bits.overflowed() {
+                        weights.push(table.entries[states[1 - i]].symbol);
+                        break 'decode;
+                    }
+                }
+            }
+            (weights, 1 + header)
+        } else {
+            let count = header - 127;
+            let data = data.get(1..1 + count.div_ceil(2)).ok_or(DecodeError::Corrupt)?;
+            let weights = (0..count).map(|i| if i % 2 == 0 { data[i / 2] >> 4 } else { data[i / 2] & 0xF }).collect();
+            (weights, 1 + count.div_ceil(2))
+        };
+        Ok((Self::new(weights)?, used))
+    }
+
+    /// Builds the table from the weights of all symbols but the last, whose weight is implied.
+    fn new(mut weights: Vec<u8>) -> Result<Self, DecodeError> {
+        if weights.len() > 255 || weights.iter().any(|&w| w as u32 > MAX_HUFFMAN_BITS) {
+            return Err(DecodeError::Corrupt);
+        }
+        let sum: u32 = weights.iter().filter(|&&w| w > 0).map(|&w| 1 << (w - 1)).sum();
+        if sum == 0 {
+            return Err(DecodeError::Corrupt);
+        }
+        let max_bits = 32 - sum.leading_zeros();
+        let rest = (1 << max_bits) - sum;
+        if !rest.is_power_of_two() || max_bits > MAX_HUFFMAN_BITS {
+            return Err(DecodeError::Corrupt);
+        }
+        weights.push(rest.trailing_zeros() as u8 + 1);
+
+        // Codes go to symbols in order of weight, then of symbol, lightest first
+        let mut entries = vec![(0, 0); 1 << max_bits];
+        let mut next = 0;
+        for weight in 1..=max_bits as u8 {
+            for (symbol, _) in weights.iter().enumerate().filter(|(_, w)| **w == weight) {
+                let span = 1 << (weight - 1);
+                entries[next..next + span].fill((symbol as u8, max_bits as u8 + 1 - weight));
+                next += span;
+            }
+        }
+        Ok(HuffmanTable { max_bits, entries })
+    }
+
+    /// Decodes `count` literals from the stream `data` onto `out`.
+    fn decode(&self, data: &[u8], count: usize, out: &mut Vec<u8>) -> Result<(), DecodeError> {
+        let mut bits = BackBits::new(data)?;
+        for _ in 0..count {
+            let (symbol, len) = self.entries[bits.peek(self.max_bits) as usize];
+            bits.read(len as u32);
+            out.push(symbol);
+        }
+        if !bits.finished() {
+            return Err(DecodeError::Corrupt);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy)]
+struct FseEntry {
+    symbol: u8,
+    bits: u8,
+    base: u16,
+}
+
+/// A finite state entropy decoding table (RFC 8878, section 4.1.1).
+struct Fse {
+    log: u32,
+    entries: Vec<FseEntry>,
+}
+
+impl Fse {
+    /// Reads a table description from the start of `data`, returning the table and its size.
+    fn read(data: &[u8], max_log: u32, max_symbol: usize) -> Result<(Self, usize), DecodeError> {
+        let mut pos = 0;
+        let mut bits = |n: u32, consume: bool| {
+            let mut value = 0;
+            for i in 0..n as usize {
+                let byte = data.get((pos + i) / 8).copied().unwrap_or(0);
+                value |= (((byte >> ((pos + i) % 8)) & 1) as i32) << i;
+            }
+            if consume {
+                pos += n as usize;
+            }
+            value
+        };
+        let log = bits(4, true) as u32 + 5;
+        if log > max_log {
+            return Err(DecodeError::Corrupt);
+        }
+        let mut remaining = (1 << log) + 1;
+        let mut threshold = 1 << log;
+        let mut width = log + 1;
+        let mut counts = Vec::new();
+        while remaining > 1 {
+            if counts.len() > max_symbol {
+                return Err(DecodeError::Corrupt);
+            }
+            // Values below `max` take a bit less
+            let max = 2 * threshold - 1 - remaining;
+            let low = bits(width - 1, false) & (threshold - 1);
+            let value = if low < max {
+                bits(width - 1, true);
+                low
+            } else {
+                let value = bits(width, true);
+                if value >= threshold { value - max } else { value }
+            };
+            let count = value - 1;
+            remaining -= count.abs();
+            counts.push(count);
+            if count == 0 {
+                // Followed by how many more zeros, two bits at a time
+                loop {
+                    let repeat = bits(2, true);
+                    counts.extend(std::iter::repeat_n(0, repeat as usize));
+                    if repeat != 3 {
+                        break;
+                    }
+                }
+            }
+            if remaining < 1 {
+                return Err(DecodeError::Corrupt);
+            }
+            while remaining < threshold {
+                width -= 1;
+                threshold >>= 1;
+            }
+        }
+        let size = pos.div_ceil(8);
+        if remaining != 1 || counts.len() > max_symbol + 1 || size > data.len() {
+            return Err(DecodeError::Corrupt);
+        }
+        Ok((Self::new(&counts, log)?, size))
+    }
+
+    /// Builds the table for normalized counts, -1 standing for "less than one".
+    fn new(counts: &[i32], log: u32) -> Result<Self, DecodeError> {
+        let size = 1 << log;
+        let mut symbols = vec![0u8; size];
+        let mut next = vec![0u32; counts.len()];
+        // Symbols of less than one get a state each at the top of the table
+        let mut high = size;
+        for (symbol, &count) in counts.iter().enumerate() {
+            if count == -1 {
+                high = high.checked_sub(1).ok_or(DecodeError::Corrupt)?;
+                symbols[high] = symbol as u8;
+                next[symbol] = 1;
+            } else {
+                next[symbol] = count as u32;
+            }
+        }
+        let step = (size >> 1) + (size >> 3) + 3;
+        let mut pos = 0;
+        for (symbol, &count) in counts.iter().enumerate() {
+            for _ in 0..count.max(0) {
+                symbols[pos] = symbol as u8;
+                pos = (pos + step) & (size - 1);
+                while pos >= high {
+                    pos = (pos + step) & (size - 1);
+                }
+            }
+        }
+        if pos != 0 {
+            return Err(DecodeError::Corrupt);
+        }
+        let entries = symbols
+            .iter()
+            .map(|&symbol| {
+                let state = next[symbol as usize];
+                next[symbol as usize] += 1;
+                let bits = log - (31 - state.leading_zeros());
+                FseEntry { symbol, bits: bits as u8, base: ((state << bits) - size as u32) as u16 }
+            })
+            .collect();
+        Ok(Fse { log, entries })
+    }
+
+    fn next(&self, state: usize, bits: &mut BackBits) -> usize {
+        let entry = self.entries[state];
+        entry.base as usize + bits.read(entry.bits as u32) as usize
+    }
+}
+
+/// XXH64 with seed 0, whose low 32 bits are a frame's checksum.
+struct Xxh64 {
+    lanes: [u64; 4],
+    pending: Vec<u8>,
+    total: u64,
+}
+
+const PRIME1: u64 = 0x9E37_79B1_85EB_CA87;
+const PRIME2: u64 = 0xC2B2_AE3D_27D4_EB4F;
+const PRIME3: u64 = 0x1656_67B1_9E37_79F9;
+const PRIME4: u64 = 0x85EB_CA77_C2B2_AE63;
+const PRIME5: u64 = 0x27D4_EB2F_1656_67C5;
+
+fn xxh_round(acc: u64, input: u64) -> u64 {
+    acc.wrapping_add(input.wrapping_mul(PRIME2)).rotate_left(31).wrapping_mul(PRIME1)
+}
+
+fn xxh_merge(acc: u64, lane: u64) -> u64 {
+    (acc ^ xxh_round(0, lane)).wrapping_mul(PRIME1).wrapping_add(PRIME4)
+}
+
+impl Xxh64 {
+    fn new() -> Self {
+        Xxh64 { lanes: [PRIME1.wrapping_add(PRIME2), PRIME2, 0, PRIME1.wrapping_neg()], pending: Vec::with_capacity(32), total: 0 }
+    }
+
+    fn update(&mut self, mut data: &[u8]) {
+        self.total += data.len() as u64;
+        if !self.pending.is_empty() {
+            let take = (32 - self.pending.len()).min(data.len());
+            self.pending.extend_from_slice(&data[..take]);
+            data = &data[take..];
+            if self.pending.len() < 32 {
+                return;
+            }
+            let stripe = std::mem::take(&mut self.pending);
+            self.stripe(&stripe);
+        }
+        let mut stripes = data.chunks_exact(32);
+        for stripe in &mut stripes {
+            self.stripe(stripe);
+        }
+        self.pending.extend_from_slice(stripes.remainder());
+    }
+
+    fn stripe(&mut self, stripe: &[u8]) {
+        for (lane, word) in self.lanes.iter_mut().zip(stripe.chunks_exact(8)) {
+            *lane = xxh_round(*lane, u64::from_le_bytes(word.try_into().unwrap()));
+        }
+    }
+
+    fn digest(&self) -> u64 {
+        let [a, b, c, d] = self.lanes;
+        let mut hash = if self.total >= 32 {
+            let hash = a.rotate_left(1).wrapping_add(b.rotate_left(7)).wrapping_add(c.rotate_left(12)).wrapping_add(d.rotate_left(18));
+            self.lanes.iter().fold(hash, |hash, &lane| xxh_merge(hash, lane))
+        } else {
+            PRIME5
+        };
+        hash = hash.wrapping_add(self.total);
+        let mut rest = self.pending.as_slice();
+        while let Some((word, tail)) = rest.split_first_chunk::<8>() {
+            hash = (hash ^ xxh_round(0, u64::from_le_bytes(*word))).rotate_left(27).wrapping_mul(PRIME1).wrapping_add(PRIME4);
+            rest = tail;
+        }
+        if let Some((word, tail)) = rest.split_first_chunk::<4>() {
+            hash = (hash ^ (u32::from_le_bytes(*word) as u64).wrapping_mul(PRIME1)).rotate_left(23).wrapping_mul(PRIME2).wrapping_add(PRIME3);
+            rest = tail;
+        }
+        for &byte in rest {
+            hash = (hash ^ (byte as u64).wrapping_mul(PRIME5)).rotate_left(11).wrapping_mul(PRIME1);
+        }
+        hash ^= hash >> 33;
+        hash = hash.wrapping_mul(PRIME2);
+        hash ^= hash >> 29;
+        hash = hash.wrapping_mul(PRIME3);
+        hash ^ (hash >> 32)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `len` bytes of pseudo-random words and line breaks.
+    fn words(len: usize) -> Vec<u8> {
+        let vocabulary = ["revenue", "quarterly", "grew", "in", "every", "region", "the", "board", "approved", "plan"];
+        let mut state = 1u32;
+        let mut text = Vec::new();
+        while text.len() < len {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            text.extend_from_slice(vocabulary[(state >> 16) as usize % vocabulary.len()].as_bytes());
+            text.push(if (state >> 8).is_multiple_of(9) { b'\n' } else { b' ' });
+        }
+        text.truncate(len);
+        text
+    }
+
+    /// The first 800 bytes of `words(1600)`, 140000 zeros and the rest, from `zstd -19 --check`:
+    /// two compressed blocks, the second using the first's Huffman table for its literals.
+    const WORDS_ZST: &[u8] = &[
+        0x28, 0xb5, 0x2f, 0xfd, 0x04, 0x68, 0xec, 0x06, 0x00, 0xe2, 0x83, 0x0c, 0x10, 0xc0, 0xeb, 0x60, 0xb7, 0x85, 0x5b,
+        0x80, 0x49, 0xa2, 0xc6, 0x56, 0x55, 0xdc, 0x0f, 0x67, 0x92, 0xe0, 0xee, 0x8e, 0xf9, 0xe3, 0x5a, 0xb9, 0xd3, 0x53,
+        0xa1, 0x76, 0xab, 0xf5, 0x12, 0x4f, 0x13, 0x70, 0x8d, 0x0e, 0x1f, 0x37, 0xcb, 0xc1, 0xd5, 0x5b, 0x45, 0x93, 0x84,
+        0xc2, 0xcb, 0xfc, 0x9c, 0x0c, 0x59, 0xa8, 0x71, 0x97, 0x82, 0x96, 0xf6, 0x37, 0x20, 0x84, 0x18, 0xe7, 0xca, 0x3c,
+        0x72, 0x10, 0x5a, 0x42, 0xd2, 0x74, 0xc1, 0x92, 0x28, 0x19, 0xda, 0xd8, 0xff, 0xff, 0x33, 0xdc, 0xfc, 0x98, 0x74,
+        0xd6, 0xd6, 0x5b, 0x90, 0x94, 0x06, 0x6a, 0x6c, 0xb7, 0x78, 0x36, 0x4c, 0xc4, 0x0a, 0x5f, 0x29, 0x34, 0x6d, 0xaf,
+        0xae, 0x22, 0x29, 0xc7, 0x24, 0xfe, 0x08, 0x1c, 0x9e, 0xa0, 0x9c, 0x4a, 0xc3, 0x59, 0xa1, 0x74, 0x69, 0x6b, 0x40,
+        0xac, 0x80, 0xc4, 0x06, 0x75, 0x39, 0xd4, 0x2e, 0x1d, 0x53, 0xe9, 0x3b, 0xc1, 0x52, 0x4b, 0x58, 0x97, 0xe2, 0xe2,
+        0x7c, 0x76, 0xe2, 0x94, 0x66, 0x91, 0xcc, 0xc0, 0xcc, 0xd0, 0x6a, 0x5a, 0x8d, 0x64, 0x3d, 0x31, 0x02, 0x64, 0xb2,
+        0x45, 0xb9, 0x27, 0xdc, 0xa3, 0xf4, 0xec, 0xdf, 0x7a, 0xf8, 0xc0, 0xf5, 0xff, 0x5f, 0x69, 0x31, 0x6d, 0x2f, 0x7d,
+        0xb4, 0xbf, 0x1d, 0x8e, 0x8e, 0x16, 0xbb, 0x28, 0xf6, 0xce, 0x34, 0xa4, 0x93, 0x6c, 0xf6, 0xac, 0x18, 0xa4, 0x6b,
+        0x86, 0xe8, 0x31, 0xc8, 0x14, 0xfd, 0xdd, 0x5f, 0x6e, 0x1e, 0xa0, 0x55, 0xa8, 0x40, 0xe3, 0xa7, 0xfc, 0x0b, 0x78,
+        0xae, 0x02, 0xfd, 0x05, 0x00, 0xc3, 0xc0, 0x01, 0xa9, 0xd0, 0xdd, 0xdd, 0xdd, 0xad, 0x06, 0x5b, 0xe8, 0x21, 0x08,
+        0x01, 0x46, 0x12, 0xa9, 0xa6, 0xea, 0x39, 0x82, 0x20, 0x54, 0x01, 0x8a, 0xc2, 0x05, 0x5e, 0x20, 0x74, 0x0a, 0x52,
+        0x90, 0xca, 0xb0, 0xff, 0xdf, 0x01, 0x40, 0x4e, 0xea, 0x73, 0xbe, 0xa1, 0x5e, 0xad, 0x97, 0xf0, 0xef, 0x85, 0xe9,
+        0xfc, 0xbe, 0xaa, 0xe4, 0x21, 0xd0, 0x95, 0x23, 0x81, 0xf2, 0x4f, 0xb7, 0x29, 0x02, 0x03, 0x9b, 0x6c, 0x1d, 0x48,
+        0x8d, 0xb1, 0x26, 0x41, 0xa1, 0x89, 0x5f, 0x42, 0x4c, 0x41, 0x62, 0x39, 0xd3, 0x6a, 0xd1, 0x67, 0x70, 0xe5, 0x60,
+        0xed, 0x94, 0x8c, 0xd2, 0x9a, 0x01, 0x12, 0x3c, 0x83, 0x9a, 0x89, 0x7f, 0x0a, 0x35, 0x0a, 0xbc, 0x7b, 0x65, 0x7b,
+        0x2f, 0x91, 0xd5, 0x9f, 0xe9, 0xde, 0x72, 0x60, 0x59, 0x9a, 0xe5, 0x24, 0xc4, 0x10, 0x64, 0x1d, 0x18, 0x25, 0xb9,
+        0x61, 0xaa, 0x85, 0xcb, 0xce, 0xc5, 0x1c, 0xb3, 0xaf, 0x41, 0x29, 0x82, 0xdd, 0x08, 0x4f, 0x22, 0xb3, 0x59, 0xb2,
+        0x44, 0xb7, 0x8b, 0x8b, 0xe5, 0xc3, 0xd2, 0x00, 0x8b, 0xc4, 0xdf, 0xc9, 0x54, 0xff, 0xc9, 0xa1, 0x76, 0x97, 0x48,
+        0x21, 0x10, 0xd5, 0xf3, 0x31, 0x95, 0x76, 0xf8, 0x88, 0xb6, 0x48, 0x11, 0x26, 0xea, 0x7d, 0xf1, 0x80, 0x68, 0x04,
+        0x25, 0xd1, 0x5f, 0x5a, 0x40, 0x12, 0x41, 0x09, 0xda, 0x1b,
+    ];
+
+    fn decode(data: &[u8], max_len: usize) -> Result<Vec<u8>, DecodeError> {
+        let mut out = Vec::new();
+        decode_from(data, max_len, &mut |chunk| {
+            out.extend_from_slice(chunk);
+            Ok(())
+        })?;
+        Ok(out)
+    }
+
+    #[test]
+    fn test_decode() {
+        let text = words(1600);
+        let expected = [&text[..800], &[0; 140_000], &text[800..]].concat();
+        assert_eq!(decode(WORDS_ZST, usize::MAX).unwrap(), expected);
+        assert_eq!(decode(WORDS_ZST, 100_000), Err(DecodeError::TooLarge));
+
+        // Frames follow one another, skippable ones ignored
+        let mut frames = WORDS_ZST.to_vec();
+        frames.extend_from_slice(&[0x5f, 0x2a, 0x4d, 0x18, 3, 0, 0, 0, 1, 2, 3]);
+        frames.extend_from_slice(WORDS_ZST);
+        assert_eq!(decode(&frames, usize::MAX).unwrap(), [&expected[..], &expected].concat());
+
+        assert_eq!(decode(&WORDS_ZST[..WORDS_ZST.len() - 1], usize::MAX), Err(DecodeError::Corrupt));
+        let mut checksum = WORDS_ZST.to_vec();
+        *checksum.last_mut().unwrap() ^= 1;
+        assert_eq!(decode(&checksum, usize::MAX), Err(DecodeError::Corrupt));
+        assert_eq!(decode(b"", usize::MAX), Err(DecodeError::Corrupt));
+        assert_eq!(decode(b"not zstd", usize::MAX), Err(DecodeError::Corrupt));
+    }
+
+    #[test]
+    fn test_raw_and_rle_blocks() {
+        // Single segment with a 5-byte content size: a raw block of "hi", then a last RLE block of three "!"
+        let frame = [0x28, 0xb5, 0x2f, 0xfd, 0x20, 5, 0x10, 0, 0, b'h', b'i', 0x1b, 0, 0, b'!'];
+        assert_eq!(decode(&frame, usize::MAX).unwrap(), b"hi!!!");
+        let mut wrong_size = frame;
+        wrong_size[5] = 6;
+        assert_eq!(decode(&wrong_size, usize::MAX), Err(DecodeError::Corrupt));
+
+        // Windows of 8 MiB are fine, 16 MiB too large
+        let frame = |window: u8| [0x28, 0xb5, 0x2f, 0xfd, 0, window, 0x0b, 0, 0, b'!'];
+        assert_eq!(decode(&frame(0x68), usize::MAX).unwrap(), b"!");
+        assert_eq!(decode(&frame(0x70), usize::MAX), Err(DecodeError::Corrupt));
+    }
+
+    #[test]
+    fn test_xxh64() {
+        let digest = |data: &[u8]| {
+            let mut hash = Xxh64::new();
+            hash.update(data);
+            hash.digest()
+        };
+        assert_eq!(digest(b""), 0xEF46_DB37_51D8_E999);
+        assert_eq!(digest(b"abc"), 0x44BC_2CF5_AD77_0999);
+        // Fed in pieces across the 32-byte stripes
+        let data = words(1000);
+        let mut hash = Xxh64::new();
+        for piece in data.chunks(7) {
+            hash.update(piece);
+        }
+        assert_eq!(hash.digest(), digest(&data));
+    }
+}